@@ -1,3 +1,8 @@
+use std::cell::Cell;
+
+use crate::intern::Sym;
+use crate::lexer::Span;
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct Program {
@@ -9,40 +14,42 @@ pub enum TopDecl {
     Struct(StructDecl),
     Const(ConstDecl),
     Func(FuncDef),
-    Var(VarDecl),          // stub for future top-level vars
-    Effect(EffectDecl),    // stub for effect declarations
+    Var(VarDecl),          // a global variable, `i32 counter = 0;`
+    Effect(EffectDecl),    // `effect i32 ask(i32);`, handled by the function `ask`
 }
 
 #[derive(Debug)]
 pub struct EffectDecl {
-    pub name: String,
+    pub name: Sym,
     pub params: Vec<Type>,
     pub ret: Option<Type>,
 }
 
 #[derive(Debug)]
 pub struct StructDecl {
-    pub name: String,
+    pub name: Sym,
     pub fields: Vec<Field>,
 }
 
 #[derive(Debug)]
 pub struct Field {
     pub ty: Type,
-    pub name: String,
+    pub name: Sym,
 }
 
 #[derive(Debug)]
 pub struct ConstDecl {
     pub ty: Type,
-    pub name: String,
-    pub value: Expr,
+    pub name: Sym,
+    pub len: Option<Expr>, // `const i32[3] primes = { 2, 3, 5 };` declares an array of 3
+    pub value: Option<Expr>,
+    pub init: Option<Vec<Expr>>, // `{ ... }`: an array's elements or a struct's fields, in order
 }
 
 #[derive(Debug)]
 pub struct FuncDef {
     pub ret_type: Type,
-    pub name: String,
+    pub name: Sym,
     pub params: Vec<Param>,
     pub body: Block,
 }
@@ -50,12 +57,15 @@ pub struct FuncDef {
 #[derive(Debug)]
 pub struct Param {
     pub ty: Type,
-    pub name: String,
+    pub name: Sym,
+    pub default: Option<Expr>, // used when a call omits this trailing arg
+    pub mutable: bool,         // `mut i32 n`; parameters are immutable otherwise
 }
 
 #[derive(Debug)]
 pub struct Block {
     pub stmts: Vec<Stmt>,
+    pub spans: Vec<Span>, // where each statement starts
 }
 
 #[derive(Debug)]
@@ -67,6 +77,10 @@ pub enum Stmt {
     Return(Option<Expr>),
     If(IfStmt),        // stub
     While(WhileStmt),  // stub
+    For(ForStmt),
+    Break,
+    Continue,
+    Handle(HandleStmt),
 }
 
 #[derive(Debug)]
@@ -82,40 +96,142 @@ pub struct WhileStmt {
     pub body: Block,
 }
 
+// `for (init; cond; step) body`; a missing cond loops forever.
+#[derive(Debug)]
+pub struct ForStmt {
+    pub init: Option<Box<Stmt>>,
+    pub cond: Option<Expr>,
+    pub step: Option<Box<Stmt>>,
+    pub body: Block,
+}
+
+// `handle { body } with { i32 get() { return 4; } }`: while `body` runs,
+// `perform get()` calls the handler, which the parser hoists out like a
+// nested function.
+#[derive(Debug)]
+pub struct HandleStmt {
+    pub body: Block,
+    pub handlers: Vec<(Sym, Sym)>, // (effect, the function handling it)
+}
+
 
 #[derive(Debug)]
 pub struct VarDecl {
     pub ty: Type,
-    pub name: String,
+    pub name: Sym,
+    pub len: Option<Expr>, // `i32 arr[4];` declares an array of 4; `i32 arr[N + 1];` of a constant
     pub value: Option<Expr>,
+    pub init: Option<Vec<Expr>>, // `Point p = { 1, 2 };`: a value per field, in order
+    pub mutable: bool,     // `var i32 x = 0;`; locals are immutable otherwise, globals always mutable
 }
 
 #[derive(Debug)]
 pub struct Assign {
-    pub name: String,
+    pub name: Sym,
+    pub field: Option<Sym>,    // `name.field = value;`
+    pub index: Option<Expr>,   // `name[index] = value;`
     pub value: Expr,
 }
 
 #[derive(Debug)]
 pub struct Type {
-    pub name: String,
+    pub name: Sym,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
-    Number(i64),
-    Ident(String),
+    Number(i128), // range-checked by codegen
+    Ident(Sym),
     Builtin(Builtin),
     Unary { op: String, expr: Box<Expr> },
-    Binary { op: String, left: Box<Expr>, right: Box<Expr> },
-    Call { name: String, args: Vec<Expr> },
+    Binary { op: String, left: Box<Expr>, right: Box<Expr>, span: Span }, // `span` of the operator
+    Call { name: Sym, args: Vec<Expr> },
+    Field { base: Box<Expr>, field: Sym }, // `base.field`
+    Index { base: Box<Expr>, index: Box<Expr> }, // `base[index]`
+    Str(Vec<u8>), // only as an argument of `print`
+    // `typeof(expr)`, the name of `expr`'s type as a string, so only as an
+    // argument of `print`: the type checker fills in `name`, and `expr` is
+    // never evaluated
+    TypeOf { expr: Box<Expr>, name: Cell<Option<Sym>> },
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Builtin {
-    Print(Box<Expr>),
+    // Each argument printed on its own line; if any is a string, they are
+    // all written back to back, with no newlines added.
+    Print(Vec<Expr>),
+    PrintUnsigned(Vec<Expr>), // like Print, reading the values as u32
     Input,
-    Perform(String, Vec<Expr>),
+    Perform(Sym, Vec<Expr>),
+}
+
+// Calls `on_stmt` on each statement in `block` and `on_expr` on each
+// expression, nested ones included, outermost first.
+pub(crate) fn visit_block(block: &mut Block, on_stmt: &mut impl FnMut(&mut Stmt), on_expr: &mut impl FnMut(&mut Expr)) {
+    for stmt in &mut block.stmts {
+        visit_stmt(stmt, on_stmt, on_expr);
+    }
+}
+
+pub(crate) fn visit_stmt(stmt: &mut Stmt, on_stmt: &mut impl FnMut(&mut Stmt), on_expr: &mut impl FnMut(&mut Expr)) {
+    on_stmt(stmt);
+    match stmt {
+        Stmt::VarDecl(v) => {
+            for e in v.len.iter_mut().chain(&mut v.value).chain(v.init.iter_mut().flatten()) {
+                visit_expr(e, on_expr);
+            }
+        }
+        Stmt::ConstDecl(c) => {
+            for e in c.len.iter_mut().chain(&mut c.value).chain(c.init.iter_mut().flatten()) {
+                visit_expr(e, on_expr);
+            }
+        }
+        Stmt::Assign(a) => {
+            for e in a.index.iter_mut().chain([&mut a.value]) {
+                visit_expr(e, on_expr);
+            }
+        }
+        Stmt::Expr(e) | Stmt::Return(Some(e)) => visit_expr(e, on_expr),
+        Stmt::Return(None) | Stmt::Break | Stmt::Continue => {}
+        Stmt::If(i) => {
+            visit_expr(&mut i.cond, on_expr);
+            visit_block(&mut i.then_block, on_stmt, on_expr);
+            if let Some(b) = &mut i.else_block {
+                visit_block(b, on_stmt, on_expr);
+            }
+        }
+        Stmt::While(w) => {
+            visit_expr(&mut w.cond, on_expr);
+            visit_block(&mut w.body, on_stmt, on_expr);
+        }
+        Stmt::For(f) => {
+            for s in f.init.iter_mut().chain(&mut f.step) {
+                visit_stmt(s, on_stmt, on_expr);
+            }
+            if let Some(c) = &mut f.cond {
+                visit_expr(c, on_expr);
+            }
+            visit_block(&mut f.body, on_stmt, on_expr);
+        }
+        Stmt::Handle(h) => visit_block(&mut h.body, on_stmt, on_expr),
+    }
+}
+
+pub(crate) fn visit_expr(expr: &mut Expr, on_expr: &mut impl FnMut(&mut Expr)) {
+    on_expr(expr);
+    match expr {
+        Expr::Unary { expr, .. } | Expr::Field { base: expr, .. } | Expr::TypeOf { expr, .. } => visit_expr(expr, on_expr),
+        Expr::Binary { left, right, .. } | Expr::Index { base: left, index: right } => {
+            visit_expr(left, on_expr);
+            visit_expr(right, on_expr);
+        }
+        Expr::Call { args, .. }
+        | Expr::Builtin(Builtin::Print(args) | Builtin::PrintUnsigned(args) | Builtin::Perform(_, args)) => {
+            for a in args {
+                visit_expr(a, on_expr);
+            }
+        }
+        Expr::Number(_) | Expr::Ident(_) | Expr::Builtin(Builtin::Input) | Expr::Str(_) => {}
+    }
 }
-
@@ -0,0 +1,165 @@
+// src/astjson.rs
+// Hand-rolled JSON rendering of the AST for editors and other tools.
+// Every node is an object whose "node" field names its kind.
+use crate::ast::*;
+
+pub fn program_to_json(p: &Program) -> String {
+    obj("Program", &[("decls", arr(p.decls.iter().map(top_decl)))])
+}
+
+fn top_decl(d: &TopDecl) -> String {
+    match d {
+        TopDecl::Struct(s) => obj("Struct", &[
+            ("name", string(&s.name)),
+            ("fields", arr(s.fields.iter().map(|f| {
+                obj("Field", &[("ty", ty(&f.ty)), ("name", string(&f.name))])
+            }))),
+        ]),
+        TopDecl::Const(c) => const_decl(c),
+        TopDecl::Func(f) => obj("Func", &[
+            ("ret_type", ty(&f.ret_type)),
+            ("name", string(&f.name)),
+            ("params", arr(f.params.iter().map(|p| {
+                obj("Param", &[
+                    ("ty", ty(&p.ty)),
+                    ("name", string(&p.name)),
+                    ("default", opt(p.default.as_ref().map(expr))),
+                    ("mutable", p.mutable.to_string()),
+                ])
+            }))),
+            ("body", block(&f.body)),
+        ]),
+        TopDecl::Var(v) => var_decl(v),
+        TopDecl::Effect(e) => obj("Effect", &[
+            ("name", string(&e.name)),
+            ("params", arr(e.params.iter().map(ty))),
+            ("ret", opt(e.ret.as_ref().map(ty))),
+        ]),
+    }
+}
+
+fn const_decl(c: &ConstDecl) -> String {
+    obj("ConstDecl", &[
+        ("ty", ty(&c.ty)),
+        ("name", string(&c.name)),
+        ("len", opt(c.len.as_ref().map(expr))),
+        ("value", opt(c.value.as_ref().map(expr))),
+        ("init", opt(c.init.as_ref().map(|values| arr(values.iter().map(expr))))),
+    ])
+}
+
+fn var_decl(v: &VarDecl) -> String {
+    obj("VarDecl", &[
+        ("ty", ty(&v.ty)),
+        ("name", string(&v.name)),
+        ("len", opt(v.len.as_ref().map(expr))),
+        ("value", opt(v.value.as_ref().map(expr))),
+        ("init", opt(v.init.as_ref().map(|values| arr(values.iter().map(expr))))),
+        ("mutable", v.mutable.to_string()),
+    ])
+}
+
+fn block(b: &Block) -> String {
+    obj("Block", &[("stmts", arr(b.stmts.iter().map(stmt)))])
+}
+
+fn stmt(s: &Stmt) -> String {
+    match s {
+        Stmt::VarDecl(v) => var_decl(v),
+        Stmt::ConstDecl(c) => const_decl(c),
+        Stmt::Assign(a) => obj("Assign", &[
+            ("name", string(&a.name)),
+            ("field", opt(a.field.as_deref().map(string))),
+            ("index", opt(a.index.as_ref().map(expr))),
+            ("value", expr(&a.value)),
+        ]),
+        Stmt::Expr(e) => obj("ExprStmt", &[("expr", expr(e))]),
+        Stmt::Return(e) => obj("Return", &[("value", opt(e.as_ref().map(expr)))]),
+        Stmt::If(i) => obj("If", &[
+            ("cond", expr(&i.cond)),
+            ("then", block(&i.then_block)),
+            ("else", opt(i.else_block.as_ref().map(block))),
+        ]),
+        Stmt::While(w) => obj("While", &[("cond", expr(&w.cond)), ("body", block(&w.body))]),
+        Stmt::Break => obj("Break", &[]),
+        Stmt::Continue => obj("Continue", &[]),
+        Stmt::For(f) => obj("For", &[
+            ("init", opt(f.init.as_deref().map(stmt))),
+            ("cond", opt(f.cond.as_ref().map(expr))),
+            ("step", opt(f.step.as_deref().map(stmt))),
+            ("body", block(&f.body)),
+        ]),
+        Stmt::Handle(h) => obj("Handle", &[
+            ("body", block(&h.body)),
+            ("handlers", arr(h.handlers.iter().map(|(effect, func)| {
+                obj("Handler", &[("effect", string(effect)), ("func", string(func))])
+            }))),
+        ]),
+    }
+}
+
+fn expr(e: &Expr) -> String {
+    match e {
+        Expr::Number(n) => obj("Number", &[("value", n.to_string())]),
+        Expr::Ident(name) => obj("Ident", &[("name", string(name))]),
+        Expr::Builtin(Builtin::Print(args)) => obj("Print", &[("args", arr(args.iter().map(expr)))]),
+        Expr::Builtin(Builtin::PrintUnsigned(args)) => obj("PrintUnsigned", &[("args", arr(args.iter().map(expr)))]),
+        Expr::Builtin(Builtin::Input) => obj("Input", &[]),
+        Expr::Builtin(Builtin::Perform(name, args)) => obj("Perform", &[
+            ("name", string(name)),
+            ("args", arr(args.iter().map(expr))),
+        ]),
+        Expr::Unary { op, expr: inner } => obj("Unary", &[("op", string(op)), ("expr", expr(inner))]),
+        Expr::Binary { op, left, right, .. } => obj("Binary", &[
+            ("op", string(op)),
+            ("left", expr(left)),
+            ("right", expr(right)),
+        ]),
+        Expr::Call { name, args } => obj("Call", &[
+            ("name", string(name)),
+            ("args", arr(args.iter().map(expr))),
+        ]),
+        Expr::Field { base, field } => obj("Field", &[("base", expr(base)), ("field", string(field))]),
+        Expr::Index { base, index } => obj("Index", &[("base", expr(base)), ("index", expr(index))]),
+        Expr::Str(bytes) => obj("Str", &[("value", string(&String::from_utf8_lossy(bytes)))]),
+        Expr::TypeOf { expr: inner, .. } => obj("TypeOf", &[("expr", expr(inner))]),
+    }
+}
+
+fn ty(t: &Type) -> String {
+    string(&t.name)
+}
+
+// ---- JSON building blocks ----
+
+fn obj(node: &str, fields: &[(&str, String)]) -> String {
+    let mut out = format!("{{\"node\":{}", string(node));
+    for (key, value) in fields {
+        out.push_str(&format!(",{}:{}", string(key), value));
+    }
+    out.push('}');
+    out
+}
+
+fn arr(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn opt(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "null".to_string())
+}
+
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
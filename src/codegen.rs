@@ -1,178 +1,1302 @@
-use std::arch::global_asm;
 // src/codegen.rs
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use crate::ast::*;
-use crate::ir::{Instr, Func, ProgramIR};
+use crate::intern::Sym;
+use crate::lexer::Span;
+use crate::ir::{self, ConstArray, Instr, Func, Global, ProgramIR};
+use crate::timetrace::TimeTrace;
+use crate::typecheck::always_returns;
 
-pub struct Codegen;
+// Errors in a program that parsed. Only statements and binary operators
+// carry spans (for runtime errors), so these name what they are about
+// instead of where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    UndeclaredVariable(Sym),
+    AssignToUndeclared(Sym),
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    UnknownFunction(Sym),
+    DuplicateFunction(Sym),
+    ArgumentCount { name: Sym, expected: usize, got: usize }, // after defaults
+    NotAStruct(String),                           // `x.f` where `x` is no struct local
+    UnknownField { ty: Sym, field: Sym },
+    StructAsValue(Sym),                          // a struct local used without a field
+    UnsupportedStruct(Sym),                      // a local of a struct with non-integer fields
+    IntegerLiteralTooLarge(i128),                // doesn't fit in i64
+    AssignToConst(Sym),                          // a local or top-level const
+    AssignToImmutable(Sym),                      // a local not declared `var`, or a parameter without `mut`
+    NonConstantGlobal(Sym),                      // a global initialized with a non-literal
+    NonConstantConst(Sym),                       // a top-level const that needs run time
+    NonConstantDefault { func: Sym, param: Sym }, // a parameter default that needs run time
+    NonConstantArrayLength(Sym),                 // `i32 a[n];` for a variable `n`
+    StructGlobal(Sym),
+    StructCycle(Vec<Sym>),                       // `A` holds a `B` that holds an `A`: A, B, A
+    NotAnArray(String),                           // `x[i]` where `x` is no array local
+    ArrayAsValue(Sym),                           // an array local used without an index
+    UnsupportedArray(Sym),                       // an array of non-integer elements
+    ArrayLength { name: Sym, len: i128 },
+    IndexOutOfRange { name: Sym, index: i128, len: usize }, // a constant index
+    InitializerCount { name: Sym, ty: Sym, expected: usize, got: usize }, // `Point p = { 1 };`
+    InitializerNotStruct(Sym),                   // `i32 x = { 1 };`
+    LocalConstInitializer(Sym),                  // `const i32[2] a = { 1, 2 };` inside a function
+    NoHandler(Sym),                              // a declared effect with no function of its name
+    EmptyProgram,
+    NoMain,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::UndeclaredVariable(name) => write!(f, "use of undeclared variable `{name}`"),
+            CodegenError::AssignToUndeclared(name) => write!(f, "assignment to undeclared variable `{name}`"),
+            CodegenError::UnknownFunction(name) => write!(f, "call to undefined function `{name}`"),
+            CodegenError::DuplicateFunction(name) => write!(f, "function `{name}` is defined more than once"),
+            CodegenError::ArgumentCount { name, expected, got } => {
+                write!(f, "`{name}` takes {expected} argument(s) but {got} were given")
+            }
+            CodegenError::BreakOutsideLoop => write!(f, "`break` outside of a loop"),
+            CodegenError::ContinueOutsideLoop => write!(f, "`continue` outside of a loop"),
+            CodegenError::NotAStruct(name) => write!(f, "`{name}` is not a struct and has no fields"),
+            CodegenError::UnknownField { ty, field } => write!(f, "struct `{ty}` has no field `{field}`"),
+            CodegenError::StructAsValue(name) => {
+                write!(f, "struct `{name}` cannot be used as a value; access one of its fields")
+            }
+            CodegenError::IntegerLiteralTooLarge(n) => {
+                write!(f, "integer literal `{n}` is too large; the largest is {}", i64::MAX)
+            }
+            CodegenError::UnsupportedStruct(ty) => {
+                write!(f, "locals of struct `{ty}` are not supported: only integer fields are")
+            }
+            CodegenError::AssignToConst(name) => write!(f, "cannot assign to const `{name}`"),
+            CodegenError::AssignToImmutable(name) => {
+                write!(f, "cannot assign to immutable `{name}`; declare it `var` (or `mut` for a parameter)")
+            }
+            CodegenError::NonConstantGlobal(name) => {
+                write!(f, "global `{name}` must be initialized with an integer literal")
+            }
+            CodegenError::NonConstantConst(name) => {
+                write!(f, "const `{name}` must be initialized with a constant expression")
+            }
+            CodegenError::NonConstantDefault { func, param } => {
+                write!(f, "default of parameter `{param}` of `{func}` must be a constant expression")
+            }
+            CodegenError::NonConstantArrayLength(name) => {
+                write!(f, "length of array `{name}` must be a constant expression")
+            }
+            CodegenError::StructGlobal(name) => write!(f, "global `{name}` cannot be a struct"),
+            CodegenError::StructCycle(path) => {
+                let path: Vec<_> = path.iter().map(|s| s.as_str()).collect();
+                write!(f, "struct `{}` contains itself by value: {}", path[0], path.join(" -> "))
+            }
+            CodegenError::NotAnArray(name) => write!(f, "`{name}` is not an array and cannot be indexed"),
+            CodegenError::ArrayAsValue(name) => {
+                write!(f, "array `{name}` cannot be used as a value; index one of its elements")
+            }
+            CodegenError::UnsupportedArray(ty) => {
+                write!(f, "arrays of `{ty}` are not supported: only integer elements are")
+            }
+            CodegenError::ArrayLength { name, len } => write!(f, "array `{name}` cannot have {len} elements"),
+            CodegenError::IndexOutOfRange { name, index, len } => {
+                write!(f, "index {index} is out of range for `{name}` of length {len}")
+            }
+            CodegenError::InitializerCount { name, ty, expected, got } => {
+                write!(f, "initializer of `{name}` has {got} value(s) but struct `{ty}` has {expected} field(s)")
+            }
+            CodegenError::InitializerNotStruct(name) => {
+                write!(f, "`{name}` is not a struct and cannot take a brace initializer")
+            }
+            CodegenError::LocalConstInitializer(name) => {
+                write!(f, "const `{name}` has a brace initializer, which only a top-level const may have")
+            }
+            CodegenError::NoHandler(name) => {
+                write!(f, "effect `{name}` has no handler; define a function `{name}`")
+            }
+            CodegenError::EmptyProgram => write!(f, "the program is empty; it needs a `main` function"),
+            CodegenError::NoMain => write!(f, "no `main` function defined"),
+        }
+    }
+}
+
+pub struct Codegen {
+    pub warnings: Vec<String>,
+    pub trace: Option<TimeTrace>, // records a span per compiled function
+    // Evaluate the operand of a binary operation that needs the deeper
+    // stack first (Sethi–Ullman), where that can't change what the program
+    // does; off, operands are always evaluated left to right.
+    pub reorder: bool,
+    // Lower loops with the condition at the bottom as well as before the
+    // first iteration, so each iteration takes one conditional branch
+    // instead of a conditional and an unconditional one.
+    pub rotate_loops: bool,
+    next_label: u32,
+    // name -> (index in ProgramIR::funcs, parameter defaults)
+    funcs: HashMap<Sym, (usize, Vec<Option<i128>>)>,
+    // struct name -> field names in slot order, or None if a field isn't an integer
+    layouts: HashMap<Sym, Option<Vec<Sym>>>,
+    // (continue, break) labels of the enclosing loops, innermost last, and
+    // how many `handle` scopes were open at each
+    loops: Vec<(u32, u32, usize)>,
+    // `handle` scopes open where code is being emitted
+    handles: usize,
+    // top-level consts, which are globals that can't be assigned, and their values
+    consts: HashSet<Sym>,
+    const_values: HashMap<Sym, i128>,
+    // top-level const arrays, by index in ProgramIR::rodata with their
+    // elements, and const structs, by type with their fields in slot
+    // order; both are folded wherever the element or field is constant
+    const_arrays: HashMap<Sym, (usize, Vec<i64>)>,
+    const_structs: HashMap<Sym, (Sym, Vec<i128>)>,
+    // declared effects, whose `perform` calls the function of the same name
+    effects: HashSet<Sym>,
+    // what holds an i64, whose arithmetic keeps all 64 bits: globals,
+    // functions' results and (struct, field)s; locals are in LocalEnv
+    i64_globals: HashSet<Sym>,
+    i64_funcs: HashSet<Sym>,
+    i64_fields: HashSet<(Sym, Sym)>,
+    // (function, index)s of i64 parameters, and whether the function being
+    // compiled returns an i64; an i64 value stored, passed or returned as
+    // an i32 keeps only its low 32 bits
+    i64_params: HashSet<(Sym, usize)>,
+    returns_i64: bool,
+    // source position of the code being emitted, and of each instruction of
+    // the function so far, for runtime errors to point at
+    span: Span,
+    spans: Vec<Span>,
+}
+
+impl Default for Codegen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Codegen {
-    pub fn new() -> Self { Self }
+    pub fn new() -> Self {
+        Self { warnings: Vec::new(), trace: None, reorder: true, rotate_loops: true, next_label: 0, funcs: HashMap::new(), layouts: HashMap::new(), loops: Vec::new(), handles: 0, consts: HashSet::new(), const_values: HashMap::new(),
+               const_arrays: HashMap::new(), const_structs: HashMap::new(), effects: HashSet::new(),
+               i64_globals: HashSet::new(), i64_funcs: HashSet::new(), i64_fields: HashSet::new(),
+               i64_params: HashSet::new(), returns_i64: false,
+               span: Span { line: 1, col: 1 }, spans: Vec::new() }
+    }
+
+    fn new_label(&mut self) -> u32 {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    pub fn compile(&mut self, program: &Program) -> Result<ProgramIR, CodegenError> {
+        // Structs may refer to ones declared later, so check only after
+        // every declaration is known.
+        let structs: HashMap<&str, &StructDecl> = program.decls.iter()
+            .filter_map(|d| match d {
+                TopDecl::Struct(s) => Some((s.name.as_str(), s)),
+                _ => None,
+            })
+            .collect();
+        check_struct_cycles(program, &structs)?;
+        for s in structs.values() {
+            self.layouts.insert(s.name, struct_layout(s));
+            self.i64_fields.extend(s.fields.iter().filter(|f| is_i64(&f.ty)).map(|f| (s.name, f.name)));
+        }
+
+        // Top-level consts and variables become globals, stored once and
+        // accessed by index, except const arrays, which are read-only data,
+        // and const structs, whose fields are folded; functions follow. We
+        // require a `main` function.
+        if program.decls.is_empty() {
+            return Err(CodegenError::EmptyProgram);
+        }
+        let mut globals: HashMap<Sym, usize> = HashMap::new();
+        let mut pool = Vec::new();
+        let mut rodata = Vec::new();
+        let mut const_values = HashMap::new();
+        for d in &program.decls {
+            let (name, value) = match d {
+                TopDecl::Const(c) if c.len.is_some() || c.init.is_some() => {
+                    self.const_aggregate(c, &const_values, &mut rodata)?;
+                    continue;
+                }
+                TopDecl::Const(c) => {
+                    let value = c.value.as_ref().expect("a const without an initializer list has a value");
+                    let n = const_value(value, &const_values)
+                        .ok_or(CodegenError::NonConstantConst(c.name))?;
+                    let n = if is_i64(&c.ty) { n } else { wrap_i32(n) };
+                    self.consts.insert(c.name);
+                    self.const_values.insert(c.name, n);
+                    const_values.insert(c.name.as_str(), n);
+                    (&c.name, n)
+                }
+                TopDecl::Var(v) => {
+                    if self.layouts.contains_key(&v.ty.name) {
+                        return Err(CodegenError::StructGlobal(v.name));
+                    }
+                    let value = match &v.value {
+                        None => 0,
+                        Some(e) => literal(e).ok_or(CodegenError::NonConstantGlobal(v.name))?,
+                    };
+                    (&v.name, if is_i64(&v.ty) { value } else { wrap_i32(value) })
+                }
+                _ => continue,
+            };
+            if let TopDecl::Const(ConstDecl { ty, .. }) | TopDecl::Var(VarDecl { ty, .. }) = d
+                && is_i64(ty) {
+                self.i64_globals.insert(*name);
+            }
+            verbose!("global `{}` = {}", name, value);
+            let value = i64::try_from(value).map_err(|_| CodegenError::IntegerLiteralTooLarge(value))?;
+            globals.insert(*name, pool.len());
+            pool.push(Global { name: name.to_string(), value });
+        }
 
-    pub fn compile(&mut self, program: &Program) -> ProgramIR {
-        // Compile top-level consts (ignored for now) and functions.
-        // We’ll require a `main` function.
-        let mut globals: HashMap<String, i32> = HashMap::new();
+        // Indices are assigned up front so calls may refer to functions
+        // defined later in the file. Defaults are evaluated here, like
+        // consts, so they can't see the caller's locals.
         for d in &program.decls {
-            if let TopDecl::Const(c) = d {
-                if let Expr::Number(n) = c.value {
-                    globals.insert(c.name.clone(), n as i32);
+            if let TopDecl::Func(f) = d {
+                let defaults = f.params.iter()
+                    .map(|p| p.default.as_ref()
+                        .map(|d| const_value(d, &const_values)
+                            .map(|n| if is_i64(&p.ty) { n } else { wrap_i32(n) })
+                            .ok_or(CodegenError::NonConstantDefault { func: f.name, param: p.name }))
+                        .transpose())
+                    .collect::<Result<_, _>>()?;
+                self.i64_params.extend(f.params.iter().enumerate()
+                    .filter(|(_, p)| is_i64(&p.ty))
+                    .map(|(i, _)| (f.name, i)));
+                let index = self.funcs.len();
+                if is_i64(&f.ret_type) {
+                    self.i64_funcs.insert(f.name);
+                }
+                if self.funcs.insert(f.name, (index, defaults)).is_some() {
+                    return Err(CodegenError::DuplicateFunction(f.name));
                 }
             }
         }
+        if !self.funcs.contains_key(&Sym::intern("main")) {
+            return Err(CodegenError::NoMain);
+        }
+        for d in &program.decls {
+            if let TopDecl::Effect(e) = d {
+                if !self.funcs.contains_key(&e.name) {
+                    return Err(CodegenError::NoHandler(e.name));
+                }
+                self.effects.insert(e.name);
+            }
+        }
 
         let mut funcs = Vec::new();
         for d in &program.decls {
             match d {
-                TopDecl::Func(f) => funcs.push(self.compile_func(f, &globals)),
-                TopDecl::Const(_) => { /* could store in a global pool later */ }
+                TopDecl::Func(f) => {
+                    if let Some(t) = &mut self.trace { t.begin("function", &f.name); }
+                    let func = self.compile_func(f, &globals);
+                    if let Some(t) = &mut self.trace { t.end("function", &f.name); }
+                    funcs.push(func?);
+                }
+                TopDecl::Const(_) => { /* in the global pool */ }
                 TopDecl::Struct(_) => { /* type-only, no code */ }
-                TopDecl::Var(_) => { /* top-level vars unsupported in this MVP */ }
-                TopDecl::Effect(_) => { /* placeholder */ }
+                TopDecl::Var(_) => { /* in the global pool */ }
+                TopDecl::Effect(_) => { /* handled by a function */ }
             }
         }
 
-        ProgramIR { funcs }
+        Ok(ProgramIR { funcs, globals: pool, rodata })
+    }
+
+    // A top-level const with a brace initializer: an array, added to
+    // `rodata`, or a struct, each value folded like a const's.
+    fn const_aggregate(&mut self, c: &ConstDecl, consts: &HashMap<&str, i128>, rodata: &mut Vec<ConstArray>) -> Result<(), CodegenError> {
+        let fold = |e: &Expr, wide: bool| {
+            let n = const_value(e, consts).ok_or(CodegenError::NonConstantConst(c.name))?;
+            Ok(if wide { n } else { wrap_i32(n) })
+        };
+        let values = c.init.as_ref().expect("typecheck requires a const array to have an initializer list");
+        if let Some(len) = &c.len {
+            if !matches!(c.ty.name.as_str(), "i32" | "i64") {
+                return Err(CodegenError::UnsupportedArray(c.ty.name));
+            }
+            let len = const_value(len, consts).ok_or(CodegenError::NonConstantArrayLength(c.name))?;
+            let n = usize::try_from(len).ok().filter(|&n| n > 0)
+                .ok_or(CodegenError::ArrayLength { name: c.name, len })?;
+            assert_eq!(values.len(), n, "typecheck checks the element count of `{}`", c.name);
+            let values = values.iter()
+                .map(|v| fold(v, is_i64(&c.ty))
+                    .and_then(|n| i64::try_from(n).map_err(|_| CodegenError::IntegerLiteralTooLarge(n))))
+                .collect::<Result<Vec<_>, _>>()?;
+            if is_i64(&c.ty) {
+                self.i64_globals.insert(c.name);
+            }
+            verbose!("const array `{}` = {:?}", c.name, values);
+            self.const_arrays.insert(c.name, (rodata.len(), values.clone()));
+            rodata.push(ConstArray { name: c.name.to_string(), values });
+        } else {
+            let fields = match self.layouts.get(&c.ty.name) {
+                Some(Some(fields)) => fields.clone(),
+                Some(None) => return Err(CodegenError::UnsupportedStruct(c.ty.name)),
+                None => return Err(CodegenError::InitializerNotStruct(c.name)),
+            };
+            if values.len() != fields.len() {
+                return Err(CodegenError::InitializerCount {
+                    name: c.name,
+                    ty: c.ty.name,
+                    expected: fields.len(),
+                    got: values.len(),
+                });
+            }
+            let values = values.iter().zip(&fields)
+                .map(|(v, field)| fold(v, self.i64_fields.contains(&(c.ty.name, *field))))
+                .collect::<Result<_, _>>()?;
+            self.const_structs.insert(c.name, (c.ty.name, values));
+        }
+        self.consts.insert(c.name);
+        Ok(())
     }
 
-    fn compile_func(&mut self, f: &FuncDef, globals: &HashMap<String, i32>) -> Func {
+    fn compile_func(&mut self, f: &FuncDef, globals: &HashMap<Sym, usize>) -> Result<Func, CodegenError> {
+        verbose!("compiling `{}` ({} params)", f.name, f.params.len());
         // Local env: name -> slot
-        let mut env = LocalEnv::default();
+        let mut env = LocalEnv::new();
+        self.returns_i64 = is_i64(&f.ret_type);
 
         // Allocate params first (left-to-right)
         for p in &f.params {
-            env.alloc(&p.name);
+            let idx = env.alloc(p.name);
+            if p.mutable {
+                env.mutable.insert(idx);
+            }
+            if is_i64(&p.ty) {
+                env.i64s.insert(idx);
+            }
         }
 
         let mut code = Vec::new();
-        self.emit_block(&f.body, &mut env, globals, &mut code);
+        self.spans.clear();
+        self.emit_block(&f.body, &mut env, globals, &mut code)?;
 
-        // Ensure a Ret exists
-        code.push(Instr::Ret);
+        // A body that returns on every path needs no trailing Ret; falling
+        // off the end of any other returns 0, not what's left on the stack.
+        if !always_returns(&f.body) {
+            code.push(Instr::PushI32(0));
+            code.push(Instr::Ret);
+        }
+        if let Some(last) = f.body.spans.last() {
+            self.span = *last;
+        }
+        self.mark(&code);
+        verbose!("`{}`: {} instrs, {} locals", f.name, code.len(), env.next);
 
-        Func {
-            name: f.name.clone(),
+        Ok(Func {
+            name: f.name.to_string(),
+            max_stack: ir::max_stack_depth(&code),
             code,
             n_locals: env.next,
+            n_params: f.params.len(),
             locals_dbg: env.reverse_names(),
-        }
+            spans: std::mem::take(&mut self.spans),
+        })
+    }
+
+    // Attributes the instructions emitted since the last mark to `self.span`.
+    fn mark(&mut self, code: &[Instr]) {
+        self.spans.resize(code.len(), self.span);
     }
 
-    fn emit_block(&mut self, b: &Block, env: &mut LocalEnv, globals: &HashMap<String, i32>, code: &mut Vec<Instr>) {
-        // Simple linear block
-        for s in &b.stmts {
-            self.emit_stmt(s, env, &globals, code);
+    fn emit_block(&mut self, b: &Block, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
+        env.push_scope();
+        for (s, span) in b.stmts.iter().zip(&b.spans) {
+            self.mark(code);
+            let outer = std::mem::replace(&mut self.span, *span);
+            self.emit_stmt(s, env, globals, code)?;
+            self.mark(code);
+            self.span = outer;
         }
+        env.pop_scope();
+        Ok(())
     }
 
-    fn emit_stmt(&mut self, s: &Stmt, env: &mut LocalEnv, globals: &HashMap<String, i32>, code: &mut Vec<Instr>) {
+    fn emit_stmt(&mut self, s: &Stmt, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
         match s {
+            // Initializers are emitted before the name is allocated, so
+            // `i32 a = a;` is a use of an undeclared variable rather than a
+            // read of the fresh, uninitialized slot.
+            Stmt::VarDecl(VarDecl { ty, name, len: Some(len), mutable, .. }) => {
+                if !matches!(ty.name.as_str(), "i32" | "i64") {
+                    return Err(CodegenError::UnsupportedArray(ty.name));
+                }
+                let len = const_value(len, &self.visible_consts(env))
+                    .ok_or(CodegenError::NonConstantArrayLength(*name))?;
+                let n = usize::try_from(len).ok().filter(|&n| n > 0)
+                    .ok_or(CodegenError::ArrayLength { name: *name, len })?;
+                // every element starts at 0, like an uninitialized i32
+                let base = env.alloc_array(*name, n);
+                if *mutable {
+                    env.mutable.insert(base);
+                }
+                if is_i64(ty) {
+                    env.i64s.insert(base);
+                }
+                for i in 0..n {
+                    code.push(Instr::PushI32(0));
+                    code.push(Instr::Store(base + i));
+                }
+            }
+            Stmt::VarDecl(v) if self.layouts.contains_key(&v.ty.name) => {
+                let Some(fields) = self.layouts[&v.ty.name].clone() else {
+                    return Err(CodegenError::UnsupportedStruct(v.ty.name));
+                };
+                if v.value.is_some() {
+                    return Err(CodegenError::StructAsValue(v.name));
+                }
+                // `{ a, b }` is pushed in field order and stored back to
+                // front; without one every field starts at 0, like an
+                // uninitialized i32
+                match &v.init {
+                    Some(values) if values.len() != fields.len() => {
+                        return Err(CodegenError::InitializerCount {
+                            name: v.name,
+                            ty: v.ty.name,
+                            expected: fields.len(),
+                            got: values.len(),
+                        });
+                    }
+                    Some(values) => {
+                        for (value, field) in values.iter().zip(&fields) {
+                            let wide = self.i64_fields.contains(&(v.ty.name, *field));
+                            self.emit_narrowed(value, wide, env, globals, code)?;
+                        }
+                    }
+                    None => code.extend(fields.iter().map(|_| Instr::PushI32(0))),
+                }
+                let base = env.alloc_struct(v.name, v.ty.name, &fields);
+                if v.mutable {
+                    env.mutable.insert(base);
+                }
+                for (i, field) in fields.iter().enumerate() {
+                    if self.i64_fields.contains(&(v.ty.name, *field)) {
+                        env.i64s.insert(base + i);
+                    }
+                }
+                for i in (0..fields.len()).rev() {
+                    code.push(Instr::Store(base + i));
+                }
+            }
             Stmt::VarDecl(v) => {
-                let idx = env.alloc(&v.name);
+                if v.init.is_some() {
+                    return Err(CodegenError::InitializerNotStruct(v.name));
+                }
                 if let Some(e) = &v.value {
-                    self.emit_expr(e, env, globals, code);
-                    code.push(Instr::Store(idx));
+                    self.emit_narrowed(e, is_i64(&v.ty), env, globals, code)?;
                 } else {
                     // default 0
                     code.push(Instr::PushI32(0));
-                    code.push(Instr::Store(idx));
                 }
+                let idx = env.alloc(v.name);
+                if v.mutable {
+                    env.mutable.insert(idx);
+                }
+                if is_i64(&v.ty) {
+                    env.i64s.insert(idx);
+                }
+                code.push(Instr::Store(idx));
             }
             Stmt::ConstDecl(c) => {
-                // Treat like immutable local in this MVP
-                let idx = env.alloc(&c.name);
-                self.emit_expr(&c.value, env, globals, code);
+                // An ordinary slot, stored once and then only read
+                let Some(value) = c.value.as_ref().filter(|_| c.len.is_none()) else {
+                    return Err(CodegenError::LocalConstInitializer(c.name));
+                };
+                self.emit_narrowed(value, is_i64(&c.ty), env, globals, code)?;
+                let idx = env.alloc(c.name);
+                env.consts.insert(idx);
+                if is_i64(&c.ty) {
+                    env.i64s.insert(idx);
+                }
                 code.push(Instr::Store(idx));
             }
+            Stmt::Assign(Assign { name, .. }) if env.lookup(*name).is_none() && self.consts.contains(name) => {
+                return Err(CodegenError::AssignToConst(*name));
+            }
+            Stmt::Assign(Assign { name, index: Some(index), value, .. }) => {
+                let element = self.element(*name, index, env, globals)?;
+                env.check_assign(*name)?;
+                let wide = env.i64s.contains(&element.0);
+                match element {
+                    (_, _, Some(slot)) => {
+                        self.emit_narrowed(value, wide, env, globals, code)?;
+                        code.push(Instr::Store(slot));
+                    }
+                    (base, len, None) => {
+                        self.emit_expr(index, env, globals, code)?;
+                        self.emit_narrowed(value, wide, env, globals, code)?;
+                        code.push(Instr::StoreIndex(base, len));
+                    }
+                }
+            }
+            Stmt::Assign(Assign { name, field: Some(field), value, .. }) => {
+                let idx = self.field_slot(*name, *field, env, globals)?;
+                env.check_assign(*name)?;
+                self.emit_narrowed(value, env.i64s.contains(&idx), env, globals, code)?;
+                code.push(Instr::Store(idx));
+            }
+            Stmt::Assign(a) if env.lookup(a.name).is_none() => {
+                let Some(&index) = globals.get(&a.name) else {
+                    return Err(CodegenError::AssignToUndeclared(a.name));
+                };
+                let wide = self.i64_globals.contains(&a.name);
+                self.emit_narrowed(&a.value, wide, env, globals, code)?;
+                code.push(Instr::StoreGlobal(index));
+            }
             Stmt::Assign(a) => {
                 // Minimal MVP: support only simple `name = expr;`
-                let idx = env.lookup(&a.name).unwrap_or_else(|| {
-                    panic!("assign to undeclared variable `{}`", a.name)
-                });
-                self.emit_expr(&a.value, env, globals, code);
+                let idx = env.lookup(a.name).expect("checked by the arm above");
+                if env.struct_type(idx).is_some() {
+                    return Err(CodegenError::StructAsValue(a.name));
+                }
+                if env.array_len(idx).is_some() {
+                    return Err(CodegenError::ArrayAsValue(a.name));
+                }
+                env.check_assign(a.name)?;
+                if matches!(&a.value, Expr::Ident(n) if *n == a.name) {
+                    // `x = x;` would just reload and restore the same slot
+                    verbose!("elided self-assignment of `{}`", a.name);
+                    self.warnings.push(format!("self-assignment of `{}` has no effect", a.name));
+                    return Ok(());
+                }
+                self.emit_narrowed(&a.value, env.i64s.contains(&idx), env, globals, code)?;
                 code.push(Instr::Store(idx));
             }
             Stmt::Expr(e) => {
-                self.emit_expr(e, env, globals, code);
-                code.push(Instr::Pop); // discard value of expr-stmt
+                self.emit_expr(e, env, globals, code)?;
+                if leaves_value(e) {
+                    code.push(Instr::Pop); // discard value of expr-stmt
+                }
             }
+            // Ret returns the top value, or 0 from an empty stack for a
+            // bare `return;`, so the value must be all the expression leaves.
             Stmt::Return(opt) => {
                 if let Some(e) = opt {
-                    self.emit_expr(e, env, globals, code);
+                    let start = code.len();
+                    self.emit_narrowed(e, self.returns_i64, env, globals, code)?;
+                    debug_assert_eq!(ir::net_depth(&code[start..]), 1, "`return` of {e:?} must leave exactly its value");
                 }
                 code.push(Instr::Ret);
             }
-            Stmt::If(_) | Stmt::While(_) => {
-                // Not yet (your parser accepts them; we’ll add control flow later)
-                panic!("if/while not implemented in codegen MVP");
+            Stmt::If(i) => {
+                // cond; JumpIfZero else; then; [Jump end; else: else-block;] end:
+                let else_label = self.new_label();
+                self.emit_expr(&i.cond, env, globals, code)?;
+                code.push(Instr::JumpIfZero(else_label));
+                self.emit_block(&i.then_block, env, globals, code)?;
+                if let Some(else_block) = &i.else_block {
+                    let end_label = self.new_label();
+                    code.push(Instr::Jump(end_label));
+                    code.push(Instr::Label(else_label));
+                    self.emit_block(else_block, env, globals, code)?;
+                    code.push(Instr::Label(end_label));
+                } else {
+                    code.push(Instr::Label(else_label));
+                }
+            }
+            Stmt::While(w) => {
+                let top = self.new_label();
+                let exit = self.new_label();
+                if self.rotate_loops {
+                    // cond; JumpIfZero exit; top: body; next: cond; JumpIfNonZero top; exit:
+                    let next = self.new_label();
+                    self.emit_loop_entry(Some(&w.cond), exit, env, globals, code)?;
+                    code.push(Instr::Label(top));
+                    let body = code.len();
+                    self.loops.push((next, exit, self.handles));
+                    self.emit_block(&w.body, env, globals, code)?;
+                    self.loops.pop();
+                    // only a `continue` jumps here
+                    if code[body..].contains(&Instr::Jump(next)) {
+                        code.push(Instr::Label(next));
+                    }
+                    self.emit_loop_back_edge(Some(&w.cond), top, env, globals, code)?;
+                } else {
+                    // top: cond; JumpIfZero exit; body; Jump top; exit:
+                    code.push(Instr::Label(top));
+                    self.emit_expr(&w.cond, env, globals, code)?;
+                    code.push(Instr::JumpIfZero(exit));
+                    self.loops.push((top, exit, self.handles));
+                    self.emit_block(&w.body, env, globals, code)?;
+                    self.loops.pop();
+                    code.push(Instr::Jump(top));
+                }
+                code.push(Instr::Label(exit));
+            }
+            Stmt::For(f) => {
+                // init; top: cond; JumpIfZero exit; body; next: step; Jump top; exit:
+                // or rotated,
+                // init; cond; JumpIfZero exit; top: body; next: step; cond; JumpIfNonZero top; exit:
+                // in a scope of its own, so a variable declared by init ends
+                // with the loop
+                env.push_scope();
+                if let Some(init) = &f.init {
+                    self.emit_stmt(init, env, globals, code)?;
+                }
+                let top = self.new_label();
+                let next = self.new_label();
+                let exit = self.new_label();
+                if self.rotate_loops {
+                    self.emit_loop_entry(f.cond.as_ref(), exit, env, globals, code)?;
+                    code.push(Instr::Label(top));
+                } else {
+                    code.push(Instr::Label(top));
+                    if let Some(cond) = &f.cond {
+                        self.emit_expr(cond, env, globals, code)?;
+                        code.push(Instr::JumpIfZero(exit));
+                    }
+                }
+                self.loops.push((next, exit, self.handles));
+                self.emit_block(&f.body, env, globals, code)?;
+                self.loops.pop();
+                code.push(Instr::Label(next));
+                if let Some(step) = &f.step {
+                    self.emit_stmt(step, env, globals, code)?;
+                }
+                if self.rotate_loops {
+                    self.emit_loop_back_edge(f.cond.as_ref(), top, env, globals, code)?;
+                } else {
+                    code.push(Instr::Jump(top));
+                }
+                code.push(Instr::Label(exit));
+                env.pop_scope();
+            }
+            Stmt::Break => {
+                let &(_, exit, handles) = self.loops.last().ok_or(CodegenError::BreakOutsideLoop)?;
+                code.extend((handles..self.handles).map(|_| Instr::PopHandler));
+                code.push(Instr::Jump(exit));
+            }
+            Stmt::Continue => {
+                let &(next, _, handles) = self.loops.last().ok_or(CodegenError::ContinueOutsideLoop)?;
+                code.extend((handles..self.handles).map(|_| Instr::PopHandler));
+                code.push(Instr::Jump(next));
+            }
+            Stmt::Handle(h) => {
+                // PushHandler per handler; body; PopHandler per handler. A
+                // `return` leaves the handlers to Ret, and a `break` or
+                // `continue` pops them itself.
+                for (effect, handler) in &h.handlers {
+                    let (index, _) = self.funcs[handler];
+                    code.push(Instr::PushHandler(effect.to_string(), index));
+                }
+                self.handles += h.handlers.len();
+                self.emit_block(&h.body, env, globals, code)?;
+                self.handles -= h.handlers.len();
+                code.extend(h.handlers.iter().map(|_| Instr::PopHandler));
             }
         }
+        Ok(())
     }
 
-    fn emit_expr(&mut self, e: &Expr, env: &mut LocalEnv, globals: &HashMap<String, i32>, code: &mut Vec<Instr>) {
+    // The test before the first iteration of a rotated loop: none when
+    // the condition is missing or a nonzero literal.
+    fn emit_loop_entry(&mut self, cond: Option<&Expr>, exit: u32, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
+        match cond {
+            Some(cond) if !always_true(cond) => {
+                self.emit_expr(cond, env, globals, code)?;
+                code.push(Instr::JumpIfZero(exit));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The branch back to `top` at the bottom of a rotated loop: the
+    // condition again, or an unconditional jump when it can't be false.
+    fn emit_loop_back_edge(&mut self, cond: Option<&Expr>, top: u32, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
+        match cond {
+            Some(cond) if !always_true(cond) => {
+                self.emit_expr(cond, env, globals, code)?;
+                code.push(Instr::JumpIfNonZero(top));
+            }
+            _ => code.push(Instr::Jump(top)),
+        }
+        Ok(())
+    }
+
+    fn emit_expr(&mut self, e: &Expr, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
         match e {
-            Expr::Number(n) => code.push(Instr::PushI32(*n as i32)),
+            Expr::Number(n) => code.push(push_int(*n)?),
+            Expr::Str(_) | Expr::TypeOf { .. } => panic!("string outside `print`; typecheck rejects these"),
             Expr::Ident(name) => {
-                if let Some(idx) = env.lookup(name) {
+                if let Some(idx) = env.lookup(*name) {
+                    if env.struct_type(idx).is_some() {
+                        return Err(CodegenError::StructAsValue(*name));
+                    }
+                    if env.array_len(idx).is_some() {
+                        return Err(CodegenError::ArrayAsValue(*name));
+                    }
                     code.push(Instr::Load(idx))
-                } else if let Some(value) = globals.get(name) {
-                    code.push(Instr::PushI32(*value));
+                } else if let Some(&index) = globals.get(name) {
+                    code.push(Instr::LoadGlobal(index));
+                } else if self.const_arrays.contains_key(name) {
+                    return Err(CodegenError::ArrayAsValue(*name));
+                } else if self.const_structs.contains_key(name) {
+                    return Err(CodegenError::StructAsValue(*name));
                 } else {
-                    panic!("use of undeclared variable `{}`", name);
+                    return Err(CodegenError::UndeclaredVariable(*name));
                 }
             }
             Expr::Builtin(b) => match b {
-                Builtin::Print(arg) => {
-                    self.emit_expr(arg, env, globals, code);
-                    code.push(Instr::Print);
+                Builtin::Print(args) | Builtin::PrintUnsigned(args) => {
+                    let print = match b {
+                        Builtin::PrintUnsigned(_) => Instr::PrintUnsigned,
+                        _ => Instr::Print,
+                    };
+                    let strings = args.iter().any(|a| matches!(a, Expr::Str(_) | Expr::TypeOf { .. }));
+                    for arg in args {
+                        if let Expr::Str(bytes) = arg {
+                            code.push(Instr::PrintStr(bytes.clone()));
+                        } else if let Expr::TypeOf { expr, name } = arg {
+                            let Some(name) = name.get() else {
+                                // typecheck couldn't type it: a name that doesn't resolve
+                                self.emit_expr(expr, env, globals, &mut Vec::new())?;
+                                unreachable!("typecheck types every expression codegen accepts");
+                            };
+                            code.push(Instr::PrintStr(name.as_bytes().to_vec()));
+                        } else {
+                            self.emit_expr(arg, env, globals, code)?;
+                            code.push(print.clone());
+                            if !strings {
+                                code.push(Instr::PrintNewline);
+                            }
+                        }
+                    }
                     // Print consumes its argument, pushes nothing
                     // (so expr value is "unit"; caller often Pop's it if needed)
                 }
-                Builtin::Input => {
-                    // MVP: just push 0; real impl could read from stdin later
-                    code.push(Instr::PushI32(0));
+                Builtin::Input => code.push(Instr::Input),
+                Builtin::Perform(name, args) if self.effects.contains(name) => {
+                    self.emit_call(*name, args, env, globals, code)?;
                 }
-                Builtin::Perform(_, _) => {
-                    panic!("perform not implemented in codegen MVP");
+                // An undeclared effect is left to the handlers installed at
+                // run time.
+                Builtin::Perform(name, args) => {
+                    for a in args {
+                        self.emit_expr(a, env, globals, code)?;
+                    }
+                    code.push(Instr::Perform(name.to_string(), args.len()));
                 }
             },
 
-            // If you’ve already added Binary/Unary variants, handle them here.
-            // For the MVP from Step 6, we only had simple literals/idents/print.
-            Expr::Unary { .. } | Expr::Binary { .. } | Expr::Call { .. } => {
-                panic!("complex expr not implemented in codegen MVP");
+            // left; JumpIfZero short; right; JumpIfZero short;
+            //   Push !short_value; Jump end; short: Push short_value; end:
+            // where `a || b` is `!(!a && !b)`, so its tests are on the negations.
+            Expr::Binary { op, left, right, .. } if op == "&&" || op == "||" => {
+                let or = op == "||";
+                let short = self.new_label();
+                let end = self.new_label();
+                for operand in [left, right] {
+                    self.emit_expr(operand, env, globals, code)?;
+                    if !leaves_value(operand) {
+                        code.push(Instr::PushI32(0)); // `print` counts as 0
+                    }
+                    if or {
+                        code.push(Instr::Not);
+                    }
+                    code.push(Instr::JumpIfZero(short));
+                }
+                code.push(Instr::PushI32(!or as i32));
+                code.push(Instr::Jump(end));
+                code.push(Instr::Label(short));
+                code.push(Instr::PushI32(or as i32));
+                code.push(Instr::Label(end));
+            }
+            // Left operand is pushed first, so Sub/Div compute `left op right`;
+            // if the right one goes first, a Swap restores that order, or a
+            // comparison is mirrored. The operator's own position marks the
+            // one instruction that can fail, e.g. on division by zero.
+            // Arithmetic is on i64 if either operand is one.
+            Expr::Binary { op, left, right, span } => {
+                let wide = self.yields_i64(e, env);
+                let right_first = self.right_first(left, right);
+                let op = if right_first {
+                    self.emit_expr(right, env, globals, code)?;
+                    self.emit_expr(left, env, globals, code)?;
+                    match op.as_str() {
+                        "-" | "/" | "%" => {
+                            code.push(Instr::Swap);
+                            op.as_str()
+                        }
+                        "<" => ">",
+                        ">" => "<",
+                        "<=" => ">=",
+                        ">=" => "<=",
+                        op => op,
+                    }
+                } else {
+                    self.emit_expr(left, env, globals, code)?;
+                    self.emit_expr(right, env, globals, code)?;
+                    op.as_str()
+                };
+                self.mark(code);
+                let outer = std::mem::replace(&mut self.span, *span);
+                code.push(match op {
+                    "+" if wide => Instr::AddI64,
+                    "-" if wide => Instr::SubI64,
+                    "*" if wide => Instr::MulI64,
+                    "/" if wide => Instr::DivI64,
+                    "%" if wide => Instr::ModI64,
+                    "+" => Instr::Add,
+                    "-" => Instr::Sub,
+                    "*" => Instr::Mul,
+                    "/" => Instr::Div,
+                    "%" => Instr::Mod,
+                    "<" => Instr::CmpLt,
+                    ">" => Instr::CmpGt,
+                    "<=" => Instr::CmpLe,
+                    ">=" => Instr::CmpGe,
+                    "==" => Instr::CmpEq,
+                    "!=" => Instr::CmpNe,
+                    _ => panic!("binary operator `{}` not implemented in codegen MVP", op),
+                });
+                self.mark(code);
+                self.span = outer;
+            }
+            Expr::Call { name, args } => self.emit_call(*name, args, env, globals, code)?,
+            // Fields are integers only, so the base is always a struct local,
+            // or a const struct, whose field is its value.
+            Expr::Field { base, field } => {
+                let Expr::Ident(name) = &**base else {
+                    return Err(CodegenError::NotAStruct(describe(base)));
+                };
+                if env.lookup(*name).is_none()
+                    && let Some((ty, values)) = self.const_structs.get(name) {
+                    let fields = self.layouts[ty].as_ref().expect("const structs have a layout");
+                    let index = fields.iter().position(|f| f == field)
+                        .ok_or(CodegenError::UnknownField { ty: *ty, field: *field })?;
+                    code.push(push_int(values[index])?);
+                    return Ok(());
+                }
+                let idx = self.field_slot(*name, *field, env, globals)?;
+                code.push(Instr::Load(idx));
+            }
+            // A constant index is resolved here; any other is checked against
+            // the length and added to the base slot at run time. An element
+            // of a const array at a constant index is its value.
+            Expr::Index { base, index } => {
+                let Expr::Ident(name) = &**base else {
+                    return Err(CodegenError::NotAnArray(describe(base)));
+                };
+                if env.lookup(*name).is_none()
+                    && let Some((array, values)) = self.const_arrays.get(name) {
+                    let (array, len) = (*array, values.len());
+                    match const_value(index, &self.visible_consts(env)) {
+                        Some(i) if (0..len as i128).contains(&i) => code.push(push_int(values[i as usize] as i128)?),
+                        Some(i) => return Err(CodegenError::IndexOutOfRange { name: *name, index: i, len }),
+                        None => {
+                            self.emit_expr(index, env, globals, code)?;
+                            code.push(Instr::LoadConstIndex(array, len));
+                        }
+                    }
+                    return Ok(());
+                }
+                match self.element(*name, index, env, globals)? {
+                    (_, _, Some(slot)) => code.push(Instr::Load(slot)),
+                    (base, len, None) => {
+                        self.emit_expr(index, env, globals, code)?;
+                        code.push(Instr::LoadIndex(base, len));
+                    }
+                }
+            }
+            Expr::Unary { op, expr } => {
+                let wide = self.yields_i64(e, env);
+                self.emit_expr(expr, env, globals, code)?;
+                code.push(match op.as_str() {
+                    "-" if wide => Instr::NegI64,
+                    "-" => Instr::Neg,
+                    "!" => Instr::Not,
+                    _ => panic!("unsupported unary operator `{}`", op),
+                });
             }
         }
+        Ok(())
+    }
+
+    // Whether to evaluate `right` before `left`: it needs more stack, and
+    // going first changes nothing else. Neither may have side effects, and
+    // at most one may trap, so the same error is reported either way.
+    fn right_first(&self, left: &Expr, right: &Expr) -> bool {
+        self.reorder && pure(left) && pure(right) && swaps(left, right, stack_need(left), stack_need(right))
+    }
+
+    // Whether `e` is an i64, so arithmetic on it keeps all 64 bits: a
+    // literal beyond i32, something declared i64, or arithmetic on one.
+    fn yields_i64(&self, e: &Expr, env: &LocalEnv) -> bool {
+        let slot = |base: &Expr| match base {
+            Expr::Ident(name) => env.lookup(*name),
+            _ => None,
+        };
+        match e {
+            Expr::Number(n) => i32::try_from(*n).is_err(),
+            Expr::Ident(name) => match env.lookup(*name) {
+                Some(slot) => env.i64s.contains(&slot),
+                None => self.i64_globals.contains(name),
+            },
+            Expr::Unary { op, expr } => op == "-" && self.yields_i64(expr, env),
+            Expr::Binary { op, left, right, .. } => {
+                matches!(op.as_str(), "+" | "-" | "*" | "/" | "%")
+                    && (self.yields_i64(left, env) || self.yields_i64(right, env))
+            }
+            Expr::Call { name, .. } | Expr::Builtin(Builtin::Perform(name, _)) => self.i64_funcs.contains(name),
+            Expr::Field { base, field } => match (slot(base), &**base) {
+                (Some(base), _) => env.struct_type(base).is_some_and(|ty| self.i64_fields.contains(&(ty, *field))),
+                (None, Expr::Ident(name)) => self.const_structs.get(name)
+                    .is_some_and(|(ty, _)| self.i64_fields.contains(&(*ty, *field))),
+                _ => false,
+            },
+            Expr::Index { base, .. } => match (slot(base), &**base) {
+                (Some(base), _) => env.i64s.contains(&base),
+                (None, Expr::Ident(name)) => self.i64_globals.contains(name),
+                _ => false,
+            },
+            Expr::Builtin(_) | Expr::Str(_) | Expr::TypeOf { .. } => false,
+        }
+    }
+
+    // `e`, for a slot that holds an i64 if `wide` and an i32 otherwise,
+    // which keeps only the low 32 bits of an i64.
+    fn emit_narrowed(&mut self, e: &Expr, wide: bool, env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
+        self.emit_expr(e, env, globals, code)?;
+        if !wide && self.yields_i64(e, env) {
+            code.push(Instr::Narrow);
+        }
+        Ok(())
+    }
+
+    // A call, or the `perform` of a declared effect, whose handler is the
+    // function of the same name.
+    fn emit_call(&mut self, name: Sym, args: &[Expr], env: &mut LocalEnv, globals: &HashMap<Sym, usize>, code: &mut Vec<Instr>) -> Result<(), CodegenError> {
+        let (index, defaults) = self.funcs.get(&name).cloned()
+            .ok_or(CodegenError::UnknownFunction(name))?;
+        let arity = CodegenError::ArgumentCount { name, expected: defaults.len(), got: args.len() };
+        if args.len() > defaults.len() {
+            return Err(arity);
+        }
+        for (i, a) in args.iter().enumerate() {
+            let wide = self.i64_params.contains(&(name, i));
+            self.emit_narrowed(a, wide, env, globals, code)?;
+        }
+        // omitted trailing arguments take their declared defaults
+        for default in &defaults[args.len()..] {
+            match default {
+                Some(d) => code.push(push_int(*d)?),
+                None => return Err(arity),
+            }
+        }
+        code.push(Instr::Call(index, defaults.len()));
+        Ok(())
+    }
+
+    // Slot of `name.field`: the struct local's base slot plus the field's index.
+    fn field_slot(&self, name: Sym, field: Sym, env: &LocalEnv, globals: &HashMap<Sym, usize>) -> Result<usize, CodegenError> {
+        let Some(base) = env.lookup(name) else {
+            return Err(if globals.contains_key(&name) {
+                CodegenError::NotAStruct(name.to_string())
+            } else {
+                CodegenError::UndeclaredVariable(name)
+            });
+        };
+        let ty = env.struct_type(base).ok_or_else(|| CodegenError::NotAStruct(name.to_string()))?;
+        let fields = self.layouts[&ty].as_ref().expect("struct locals have a layout");
+        let index = fields.iter().position(|f| *f == field)
+            .ok_or(CodegenError::UnknownField { ty, field })?;
+        Ok(base + index)
+    }
+
+    // The values of the top-level consts no local shadows, for folding
+    // constant expressions.
+    fn visible_consts(&self, env: &LocalEnv) -> HashMap<&str, i128> {
+        self.const_values.iter()
+            .filter(|(name, _)| env.lookup(**name).is_none())
+            .map(|(name, n)| (name.as_str(), *n))
+            .collect()
+    }
+
+    // `name[index]`, for the array local `name`: its base slot and length,
+    // and the element's own slot if the index is a constant expression (of
+    // literals and top-level consts no local shadows), which must be in
+    // range. Other indices are checked when the access runs.
+    fn element(&self, name: Sym, index: &Expr, env: &LocalEnv, globals: &HashMap<Sym, usize>) -> Result<(usize, usize, Option<usize>), CodegenError> {
+        let Some(base) = env.lookup(name) else {
+            return Err(if globals.contains_key(&name) {
+                CodegenError::NotAnArray(name.to_string())
+            } else {
+                CodegenError::UndeclaredVariable(name)
+            });
+        };
+        let len = env.array_len(base).ok_or_else(|| CodegenError::NotAnArray(name.to_string()))?;
+        let slot = match const_value(index, &self.visible_consts(env)) {
+            Some(i) if (0..len as i128).contains(&i) => Some(base + i as usize),
+            Some(i) => return Err(CodegenError::IndexOutOfRange { name, index: i, len }),
+            None => None,
+        };
+        Ok((base, len, slot))
+    }
+}
+
+// Constants outside i32 need the wide push; literals are never wrapped.
+fn push_int(n: i128) -> Result<Instr, CodegenError> {
+    if let Ok(n) = i32::try_from(n) {
+        Ok(Instr::PushI32(n))
+    } else if let Ok(n) = i64::try_from(n) {
+        Ok(Instr::PushI64(n))
+    } else {
+        Err(CodegenError::IntegerLiteralTooLarge(n))
     }
 }
 
-#[derive(Default)]
+// Value of a global's initializer: an integer literal, maybe negated.
+fn literal(e: &Expr) -> Option<i128> {
+    match e {
+        Expr::Number(n) => Some(*n),
+        Expr::Unary { op, expr } if op == "-" => literal(expr).map(|n| -n),
+        _ => None,
+    }
+}
+
+// A loop condition that is a nonzero literal, like `while (1)`.
+fn always_true(e: &Expr) -> bool {
+    literal(e).is_some_and(|n| n != 0)
+}
+
+// Value of a const's initializer: integer literals and consts declared
+// before it, combined with the integer operators. None if it needs run
+// time, divides by zero or overflows.
+pub(crate) fn const_value(e: &Expr, consts: &HashMap<&str, i128>) -> Option<i128> {
+    match e {
+        Expr::Number(n) => Some(*n),
+        Expr::Ident(name) => consts.get(name.as_str()).copied(),
+        Expr::Unary { op, expr } => {
+            let v = const_value(expr, consts)?;
+            match op.as_str() {
+                "-" => v.checked_neg(),
+                "!" => Some((v == 0) as i128),
+                _ => None,
+            }
+        }
+        Expr::Binary { op, left, right, .. } => {
+            let (a, b) = (const_value(left, consts)?, const_value(right, consts)?);
+            match op.as_str() {
+                "+" => a.checked_add(b),
+                "-" => a.checked_sub(b),
+                "*" => a.checked_mul(b),
+                "/" => a.checked_div(b),
+                "%" => a.checked_rem(b),
+                "<" => Some((a < b) as i128),
+                ">" => Some((a > b) as i128),
+                "<=" => Some((a <= b) as i128),
+                ">=" => Some((a >= b) as i128),
+                "==" => Some((a == b) as i128),
+                "!=" => Some((a != b) as i128),
+                "&&" => Some((a != 0 && b != 0) as i128),
+                "||" => Some((a != 0 || b != 0) as i128),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// The i32 an i64 constant becomes in an i32 slot: its low 32 bits. Values
+// beyond i64 are left for the caller to reject.
+fn wrap_i32(n: i128) -> i128 {
+    i64::try_from(n).map_or(n, |n| n as i32 as i128)
+}
+
+fn is_i64(ty: &Type) -> bool {
+    ty.name == "i64"
+}
+
+// Each field takes one slot, in declaration order.
+fn struct_layout(s: &StructDecl) -> Option<Vec<Sym>> {
+    s.fields.iter()
+        .map(|f| matches!(f.ty.name.as_str(), "i32" | "i64").then(|| f.name))
+        .collect()
+}
+
+// Whether evaluating `e` only reads: no call, input, output or effect.
+fn pure(e: &Expr) -> bool {
+    match e {
+        Expr::Number(_) | Expr::Ident(_) | Expr::Field { .. } => true,
+        Expr::Index { index, .. } | Expr::Unary { expr: index, .. } => pure(index),
+        Expr::Binary { left, right, .. } => pure(left) && pure(right),
+        Expr::Call { .. } | Expr::Builtin(_) | Expr::Str(_) | Expr::TypeOf { .. } => false,
+    }
+}
+
+// Whether evaluating `e` can stop the program: arithmetic may overflow (in
+// checked mode) or divide by zero, and an index may be out of range.
+fn may_trap(e: &Expr) -> bool {
+    match e {
+        Expr::Number(_) | Expr::Ident(_) | Expr::Field { .. } => false,
+        Expr::Index { .. } => true,
+        Expr::Unary { op, expr } => op == "-" || may_trap(expr),
+        Expr::Binary { op, left, right, .. } => {
+            matches!(op.as_str(), "+" | "-" | "*" | "/" | "%") || may_trap(left) || may_trap(right)
+        }
+        Expr::Call { .. } | Expr::Builtin(_) | Expr::Str(_) | Expr::TypeOf { .. } => true,
+    }
+}
+
+// Whether the pure operands `left` and `right`, needing `l` and `r` slots,
+// are better evaluated right first: it needs more, and they don't both trap.
+fn swaps(left: &Expr, right: &Expr, l: usize, r: usize) -> bool {
+    r > l && !(may_trap(left) && may_trap(right))
+}
+
+// Operand-stack slots evaluating the pure `e` takes, its Sethi–Ullman
+// number: an operand waits under the other, which needs one more slot
+// unless the other goes first.
+fn stack_need(e: &Expr) -> usize {
+    match e {
+        Expr::Index { index, .. } | Expr::Unary { expr: index, .. } => stack_need(index),
+        Expr::Binary { left, right, .. } => {
+            let (l, r) = (stack_need(left), stack_need(right));
+            if swaps(left, right, l, r) { r.max(l + 1) } else { l.max(r + 1) }
+        }
+        _ => 1,
+    }
+}
+
+// How `e` is named in an error about using it as a struct.
+fn describe(e: &Expr) -> String {
+    match e {
+        Expr::Ident(name) => name.to_string(),
+        Expr::Field { base, field } => format!("{}.{}", describe(base), field),
+        Expr::Index { base, .. } => format!("{}[..]", describe(base)),
+        _ => "expression".to_string(),
+    }
+}
+
+// Whether evaluating `e` leaves exactly one value on the operand stack.
+// `print` and `print_unsigned` consume their arguments and push nothing.
+fn leaves_value(e: &Expr) -> bool {
+    !matches!(e, Expr::Builtin(Builtin::Print(_) | Builtin::PrintUnsigned(_)))
+}
+
+// A struct that contains itself by value (directly or through other
+// structs) has no finite size.
+fn check_struct_cycles(program: &Program, structs: &HashMap<&str, &StructDecl>) -> Result<(), CodegenError> {
+    fn visit(
+        name: Sym,
+        structs: &HashMap<&str, &StructDecl>,
+        path: &mut Vec<Sym>,
+        done: &mut HashSet<Sym>,
+    ) -> Result<(), CodegenError> {
+        if done.contains(&name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Err(CodegenError::StructCycle(cycle));
+        }
+        if let Some(s) = structs.get(name.as_str()) {
+            path.push(name);
+            for f in &s.fields {
+                visit(f.ty.name, structs, path, done)?;
+            }
+            path.pop();
+        }
+        done.insert(name);
+        Ok(())
+    }
+
+    // in declaration order, so the cycle reported is always the same one
+    let mut done = HashSet::new();
+    for d in &program.decls {
+        if let TopDecl::Struct(s) = d {
+            visit(s.name, structs, &mut Vec::new(), &mut done)?;
+        }
+    }
+    Ok(())
+}
+
+// One map per open block, innermost last; parameters live in the
+// outermost. Every declaration gets a fresh slot, so redeclaring a name
+// shadows the earlier variable instead of reusing it. `next` only grows:
+// slots of closed blocks are never handed out again within a function.
+// A struct local takes one slot per field, starting at its base slot, and
+// an array one per element. Locals are immutable after their declaration
+// unless declared `var`, parameters unless declared `mut`; `mutable` holds
+// the base slots of those that aren't.
 struct LocalEnv {
-    map: HashMap<String, usize>,
-    names: Vec<String>,
+    scopes: Vec<HashMap<Sym, usize>>,
+    names: Vec<String>, // slot -> name
+    structs: HashMap<usize, Sym>,   // base slot -> struct type
+    arrays: HashMap<usize, usize>,   // base slot -> length
+    consts: HashSet<usize>,
+    mutable: HashSet<usize>,
+    i64s: HashSet<usize>, // slots of i64s; an array's or struct's by element
     next: usize,
 }
 
 impl LocalEnv {
-    fn alloc(&mut self, name: &str) -> usize {
-        if let Some(&i) = self.map.get(name) {
-            return i;
-        }
+    fn new() -> Self {
+        LocalEnv { scopes: vec![HashMap::new()], names: Vec::new(), structs: HashMap::new(), arrays: HashMap::new(), consts: HashSet::new(), mutable: HashSet::new(), i64s: HashSet::new(), next: 0 }
+    }
+    fn alloc(&mut self, name: Sym) -> usize {
         let idx = self.next;
         self.next += 1;
-        self.map.insert(name.to_string(), idx);
+        verbose!("slot {} <- `{}`", idx, name);
+        self.scopes.last_mut().unwrap().insert(name, idx);
         self.names.push(name.to_string());
         idx
     }
-    fn lookup(&self, name: &str) -> Option<usize> {
-        self.map.get(name).copied()
+    // Slots are named `p.x`, `p.y`, ... for the debug listing. A struct
+    // without fields still takes a slot, so its base is its own.
+    fn alloc_struct(&mut self, name: Sym, ty: Sym, fields: &[Sym]) -> usize {
+        let base = self.next;
+        self.next += fields.len().max(1);
+        verbose!("slots {}..{} <- `{}`: {}", base, self.next, name, ty);
+        self.scopes.last_mut().unwrap().insert(name, base);
+        if fields.is_empty() {
+            self.names.push(name.to_string());
+        }
+        self.names.extend(fields.iter().map(|f| format!("{name}.{f}")));
+        self.structs.insert(base, ty);
+        base
     }
-    fn reverse_names(&self) -> Vec<String> {
-        let mut v = vec!["".to_string(); self.next];
-        for (n, &i) in &self.map {
-            v[i] = n.clone();
+    // Slots are named `a[0]`, `a[1]`, ...
+    fn alloc_array(&mut self, name: Sym, len: usize) -> usize {
+        let base = self.next;
+        self.next += len;
+        verbose!("slots {}..{} <- `{}`[{}]", base, self.next, name, len);
+        self.scopes.last_mut().unwrap().insert(name, base);
+        self.names.extend((0..len).map(|i| format!("{name}[{i}]")));
+        self.arrays.insert(base, len);
+        base
+    }
+    fn array_len(&self, slot: usize) -> Option<usize> {
+        self.arrays.get(&slot).copied()
+    }
+    fn struct_type(&self, slot: usize) -> Option<Sym> {
+        self.structs.get(&slot).copied()
+    }
+    fn lookup(&self, name: Sym) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(&name)).copied()
+    }
+    // Whether the local `name` may be assigned; globals are checked by the caller.
+    fn check_assign(&self, name: Sym) -> Result<(), CodegenError> {
+        match self.lookup(name) {
+            Some(slot) if self.consts.contains(&slot) => Err(CodegenError::AssignToConst(name)),
+            Some(slot) if !self.mutable.contains(&slot) => Err(CodegenError::AssignToImmutable(name)),
+            _ => Ok(()),
         }
-        v
+    }
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+    fn reverse_names(&self) -> Vec<String> {
+        self.names.clone()
     }
 }
@@ -0,0 +1,323 @@
+// src/disasm.rs
+//
+// Disassembler for the machine code elfgen.rs emits, to check its
+// encodings without an external tool. It is not a general x86-64 decoder:
+// it knows the instruction forms `compile_instr`, `compile_fused` and the
+// prologue, return, print and input routines produce, and shows anything
+// else as `.byte`. Mnemonics are AT&T syntax, as in `--emit=asm`.
+use std::collections::HashMap;
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+    "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+];
+// with any REX prefix, 4..=7 are spl..dil rather than ah..bh
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "ah", "ch", "dh", "bh",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+const REGS8_REX: [&str; 4] = ["spl", "bpl", "sil", "dil"];
+
+// Condition codes, by the low nibble of jcc/setcc
+const CONDITIONS: [&str; 16] = ["o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g"];
+// 0x80/0x81/0x83 by the ModRM reg field
+const GROUP1: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Dword,
+    Qword,
+}
+
+// One decoded instruction: `len` bytes at `offset` into the code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insn {
+    pub offset: usize,
+    pub len: usize,
+    pub text: String, // e.g. `mov -8(%rbp), %rax`
+}
+
+// Decodes `code` loaded at `addr`, an instruction per `next`. Jump and
+// call targets are shown as addresses, with the name from `symbols` if
+// one starts there.
+pub struct Disassembler<'a> {
+    code: &'a [u8],
+    addr: u64,
+    symbols: HashMap<u64, String>,
+    at: usize,
+    // per instruction
+    rex: u8,
+    rip_disp: Option<i32>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(code: &'a [u8], addr: u64) -> Self {
+        Disassembler { code, addr, symbols: HashMap::new(), at: 0, rex: 0, rip_disp: None }
+    }
+
+    pub fn with_symbols(mut self, symbols: HashMap<u64, String>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // `offset: bytes  text` per instruction, each symbol introducing its
+    // code like a label.
+    pub fn listing(self) -> String {
+        let (addr, code, symbols) = (self.addr, self.code, self.symbols.clone());
+        let mut out = String::new();
+        for insn in self {
+            let at = addr + insn.offset as u64;
+            if let Some(name) = symbols.get(&at) {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&format!("{name}:\n"));
+            }
+            let bytes: Vec<_> = code[insn.offset..insn.offset + insn.len].iter().map(|b| format!("{b:02x}")).collect();
+            out.push_str(&format!("{at:8x}:  {:<32} {}\n", bytes.join(" "), insn.text));
+        }
+        out
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let b = *self.code.get(self.at)?;
+        self.at += 1;
+        Some(b)
+    }
+
+    fn imm8(&mut self) -> Option<i8> {
+        self.byte().map(|b| b as i8)
+    }
+
+    fn imm32(&mut self) -> Option<i32> {
+        let bytes = self.code.get(self.at..self.at + 4)?;
+        self.at += 4;
+        Some(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn imm64(&mut self) -> Option<i64> {
+        let bytes = self.code.get(self.at..self.at + 8)?;
+        self.at += 8;
+        Some(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn reg(&self, n: u8, size: Size) -> String {
+        let n = n as usize;
+        let name = match size {
+            Size::Qword => REGS64[n],
+            Size::Dword => REGS32[n],
+            Size::Byte if self.rex != 0 && (4..8).contains(&n) => REGS8_REX[n - 4],
+            Size::Byte => REGS8[n],
+        };
+        format!("%{name}")
+    }
+
+    // Decodes a ModRM byte (and any SIB and displacement): the reg field,
+    // extended by REX.R, and the r/m operand as text. A RIP-relative
+    // operand's target is only known at the end of the instruction, so it
+    // is noted in `rip_disp`.
+    fn modrm(&mut self, size: Size) -> Option<(u8, String)> {
+        let modrm = self.byte()?;
+        let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 7 | (self.rex & 4) << 1, modrm & 7);
+        if md == 3 {
+            return Some((reg, self.reg(rm | (self.rex & 1) << 3, size)));
+        }
+        if md == 0 && rm == 5 {
+            let disp = self.imm32()?;
+            self.rip_disp = Some(disp);
+            let sign = if disp < 0 { "-" } else { "" };
+            return Some((reg, format!("{sign}{:#x}(%rip)", disp.unsigned_abs())));
+        }
+        let (base, index) = if rm == 4 {
+            // SIB: a base register, and an index unless it is 4 (rsp)
+            let sib = self.byte()?;
+            let index = (sib >> 3) & 7 | (self.rex & 2) << 2;
+            if md == 0 && sib & 7 == 5 {
+                return None;
+            }
+            let index = (index != 4).then(|| format!(",{},{}", self.reg(index, Size::Qword), 1 << (sib >> 6)));
+            (sib & 7, index.unwrap_or_default())
+        } else {
+            (rm, String::new())
+        };
+        let base = format!("{}{index}", self.reg(base | (self.rex & 1) << 3, Size::Qword));
+        let operand = match md {
+            0 => format!("({base})"),
+            1 => format!("{}({base})", self.imm8()?),
+            _ => format!("{}({base})", self.imm32()?),
+        };
+        Some((reg, operand))
+    }
+
+    fn target(&self, rel: i64) -> String {
+        let target = (self.addr as i64 + self.at as i64 + rel) as u64;
+        match self.symbols.get(&target) {
+            Some(name) => format!("{target:#x} <{name}>"),
+            None => format!("{target:#x}"),
+        }
+    }
+
+    fn decode(&mut self) -> Option<String> {
+        self.rex = 0;
+        self.rip_disp = None;
+        let mut op = self.byte()?;
+        if (0x40..=0x4F).contains(&op) {
+            self.rex = op;
+            op = self.byte()?;
+        }
+        let size = if self.rex & 8 != 0 { Size::Qword } else { Size::Dword };
+        let b = (self.rex & 1) << 3;
+
+        let text = match op {
+            // op r/m, reg
+            0x01 | 0x09 | 0x29 | 0x31 | 0x39 | 0x85 | 0x88 | 0x89 => {
+                let size = if op == 0x88 { Size::Byte } else { size };
+                let (reg, rm) = self.modrm(size)?;
+                let name = match op {
+                    0x01 => "add",
+                    0x09 => "or",
+                    0x29 => "sub",
+                    0x31 => "xor",
+                    0x39 => "cmp",
+                    0x85 => "test",
+                    _ => "mov",
+                };
+                format!("{name} {}, {rm}", self.reg(reg, size))
+            }
+            // op reg, r/m
+            0x8B | 0x8D => {
+                let (reg, rm) = self.modrm(size)?;
+                format!("{} {rm}, {}", if op == 0x8B { "mov" } else { "lea" }, self.reg(reg, size))
+            }
+            0x63 if size == Size::Qword => {
+                let (reg, rm) = self.modrm(Size::Dword)?;
+                format!("movslq {rm}, {}", self.reg(reg, size))
+            }
+            0x05 | 0x2D => {
+                let imm = self.imm32()?;
+                format!("{} ${imm}, {}", if op == 0x05 { "add" } else { "sub" }, self.reg(0, size))
+            }
+            0x3C => format!("cmp ${}, %al", self.imm8()?),
+            0x50..=0x57 => format!("push {}", self.reg((op - 0x50) | b, Size::Qword)),
+            0x58..=0x5F => format!("pop {}", self.reg((op - 0x58) | b, Size::Qword)),
+            0x68 => format!("push ${}", self.imm32()?),
+            0x6A => format!("push ${}", self.imm8()?),
+            0x69 | 0x6B => {
+                let (reg, rm) = self.modrm(size)?;
+                let imm = if op == 0x69 { self.imm32()? } else { self.imm8()? as i32 };
+                format!("imul ${imm}, {rm}, {}", self.reg(reg, size))
+            }
+            0x70..=0x7F => {
+                let rel = self.imm8()?;
+                format!("j{} {}", CONDITIONS[(op & 0xF) as usize], self.target(rel as i64))
+            }
+            0x80 | 0x81 | 0x83 => {
+                let size = if op == 0x80 { Size::Byte } else { size };
+                let (reg, rm) = self.modrm(size)?;
+                let imm = if op == 0x81 { self.imm32()? } else { self.imm8()? as i32 };
+                format!("{} ${imm}, {rm}", GROUP1[(reg & 7) as usize])
+            }
+            0xC1 => {
+                let (reg, rm) = self.modrm(size)?;
+                let name = ["rol", "ror", "rcl", "rcr", "shl", "shr", "", "sar"][(reg & 7) as usize];
+                if name.is_empty() {
+                    return None;
+                }
+                format!("{name} ${}, {rm}", self.imm8()? as u8)
+            }
+            0x99 => (if size == Size::Qword { "cqto" } else { "cltd" }).to_string(),
+            0xB8..=0xBF if size == Size::Qword => format!("movabs ${}, {}", self.imm64()?, self.reg((op - 0xB8) | b, size)),
+            0xB8..=0xBF => format!("mov ${}, {}", self.imm32()?, self.reg((op - 0xB8) | b, size)),
+            0xC3 => "ret".to_string(),
+            0xC6 | 0xC7 => {
+                let size = if op == 0xC6 { Size::Byte } else { size };
+                let (reg, rm) = self.modrm(size)?;
+                if reg & 7 != 0 {
+                    return None;
+                }
+                let imm = if op == 0xC6 { self.imm8()? as i32 } else { self.imm32()? };
+                let suffix = match size {
+                    Size::Byte => 'b',
+                    Size::Dword => 'l',
+                    Size::Qword => 'q',
+                };
+                format!("mov{suffix} ${imm}, {rm}")
+            }
+            0xE8 | 0xE9 => {
+                let rel = self.imm32()?;
+                format!("{} {}", if op == 0xE8 { "call" } else { "jmp" }, self.target(rel as i64))
+            }
+            0xEB => {
+                let rel = self.imm8()?;
+                format!("jmp {}", self.target(rel as i64))
+            }
+            0xF7 => {
+                let (reg, rm) = self.modrm(size)?;
+                let name = ["", "", "not", "neg", "mul", "imul", "div", "idiv"][(reg & 7) as usize];
+                if name.is_empty() {
+                    return None;
+                }
+                format!("{name} {rm}")
+            }
+            0xFF => {
+                // push r/m is always 64-bit
+                let (reg, rm) = self.modrm(size)?;
+                match reg & 7 {
+                    0 => format!("inc {rm}"),
+                    1 => format!("dec {rm}"),
+                    6 => format!("pushq {rm}"),
+                    _ => return None,
+                }
+            }
+            0x0F => match self.byte()? {
+                0x05 => "syscall".to_string(),
+                0x0B => "ud2".to_string(),
+                op @ 0x80..=0x8F => {
+                    let rel = self.imm32()?;
+                    format!("j{} {}", CONDITIONS[(op & 0xF) as usize], self.target(rel as i64))
+                }
+                op @ 0x90..=0x9F => format!("set{} {}", CONDITIONS[(op & 0xF) as usize], self.modrm(Size::Byte)?.1),
+                0xAF => {
+                    let (reg, rm) = self.modrm(size)?;
+                    format!("imul {rm}, {}", self.reg(reg, size))
+                }
+                0xB6 => {
+                    let (reg, rm) = self.modrm(Size::Byte)?;
+                    let suffix = if size == Size::Qword { 'q' } else { 'l' };
+                    format!("movzb{suffix} {rm}, {}", self.reg(reg, size))
+                }
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(match self.rip_disp {
+            Some(disp) => format!("{text}  # {:#x}", self.addr + (self.at as i64 + disp as i64) as u64),
+            None => text,
+        })
+    }
+}
+
+impl Iterator for Disassembler<'_> {
+    type Item = Insn;
+
+    fn next(&mut self) -> Option<Insn> {
+        let offset = self.at;
+        if offset >= self.code.len() {
+            return None;
+        }
+        let text = match self.decode() {
+            Some(text) => text,
+            None => {
+                // not a form this backend emits; resynchronize a byte on
+                self.at = offset + 1;
+                format!(".byte {:#04x}", self.code[offset])
+            }
+        };
+        Some(Insn { offset, len: self.at - offset, text })
+    }
+}
@@ -1,144 +1,2172 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Write, Seek, SeekFrom};
+// src/elfgen.rs
+//
+// Native x86-64 backend: compiles stack IR to machine code that keeps the
+// operand stack on the machine stack, and wraps it in a Linux ELF64 image.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt; // for mode()
 use std::path::Path;
 
-/// Writes a native ELF64 Linux executable at `out_path` that prints "Hello\n" and exits(0).
-pub fn emit_min_elf_hello<P: AsRef<Path>>(out_path: P) -> std::io::Result<()> {
-    // ---- ELF layout plan ----------------------------------------------------
-    // File offsets (hex):
-    //   0x0000  ELF header (64 bytes)
-    //   0x0040  Program header (56 bytes)
-    //   0x1000  Code (.text) + Data (.rodata)
+use crate::disasm::Disassembler;
+use crate::ir::{Func, Instr, ProgramIR};
+use crate::span::Span;
+
+// Virtual addresses mirror file offsets with base 0x400000; code starts at
+// the first page after the headers. The data segment follows the code in the
+// file (page-aligned) but always loads at DATA_VADDR, so a label's address
+// is known as soon as its bytes are added. A PIE uses the same layout from
+// base 0, and the kernel picks where it actually loads.
+pub const BASE_VADDR: u64 = 0x400000;
+pub const OFF_CODE: u64 = 0x1000;
+pub const DATA_VADDR: u64 = 0x600000;
+const OFF_PROG_HDR: u64 = 0x0040;
+const PAGE: u64 = 0x1000;
+
+// The most machine code that fits between OFF_CODE and the data segment.
+pub const MAX_CODE_BYTES: usize = (DATA_VADDR - BASE_VADDR - OFF_CODE) as usize;
+
+// Bytes `emit_print` reserves for the digits it writes. The longest text
+// is `-9223372036854775808`: `neg` leaves i64::MIN as is, and the unsigned
+// division reads that as 2^63, 19 digits, plus the sign makes 20 (a u32
+// has at most 10 digits). No newline goes in the buffer; PrintNewline writes
+// its own. Rounded up to a multiple of 16 to keep rsp aligned, and encoded
+// as a signed 8-bit immediate and displacement.
+const PRINT_BUFFER: u8 = 32;
+const _: () = assert!(PRINT_BUFFER >= 20 && PRINT_BUFFER.is_multiple_of(16) && PRINT_BUFFER < 128);
+
+// The largest stack frame a function may have: Linux's default stack
+// limit (`ulimit -s`), which a bigger frame overruns on its first call.
+// Well within the disp32 of a slot's `[rbp - disp]`.
+pub const MAX_FRAME_BYTES: usize = 8 << 20;
+const _: () = assert!(MAX_FRAME_BYTES < i32::MAX as usize);
+
+// What `stack_protector` stores below a function's locals. With no libc
+// there is no random `__stack_chk_guard`, so it is fixed, and starts with
+// the bytes that end strings and lines (NUL, LF, CR, 0xFF in little-endian
+// order) so that most string copies can't write it back intact.
+pub const CANARY: u64 = 0x5AFE_C0DE_FF0D_0A00;
+
+// A runtime error native code can stop with: a jump to the trap's stub
+// writes its message to stderr and exits with 70, as `cosplae --run` does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trap {
+    Overflow, // checked arithmetic; see `emit_overflow_check`
+    Bounds,   // an array index outside the array
+}
+
+impl Trap {
+    const ALL: [Trap; 2] = [Trap::Overflow, Trap::Bounds];
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Trap::Overflow => "__overflow",
+            Trap::Bounds => "__bounds",
+        }
+    }
+
+    // data label of the message
+    fn label(self) -> &'static str {
+        match self {
+            Trap::Overflow => ".Loverflow_msg",
+            Trap::Bounds => ".Lbounds_msg",
+        }
+    }
+
+    fn message(self) -> &'static [u8] {
+        match self {
+            Trap::Overflow => b"runtime error: integer overflow\n",
+            Trap::Bounds => b"runtime error: array index out of range\n",
+        }
+    }
+}
+
+// What a function's locals are addressed from: rbp, which the prologue
+// points just above them, or in a leaf compiled without a frame pointer
+// rsp, which moves with the operand stack, so that a slot's displacement
+// depends on the `depth` of operand values pushed below the locals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frame {
+    Rbp,
+    Rsp { n_locals: usize },
+}
+
+impl Frame {
+    // displacement of local `slot` from the frame's register
+    fn disp(self, slot: usize, depth: usize) -> Result<i32, BackendError> {
+        let below_rbp = rbp_disp(slot)?;
+        match self {
+            Frame::Rbp => Ok(below_rbp),
+            Frame::Rsp { n_locals } => {
+                let above = n_locals.checked_sub(slot + 1)
+                    .ok_or(BackendError::OutOfRange { what: "local slot", value: slot as i64 })?;
+                frame_bytes("stack depth", above + depth)
+            }
+        }
+    }
+
+    // displacement of argument `i` of `n_params` in the prologue, above
+    // the return address (and the saved rbp)
+    fn arg_disp(self, i: usize, n_params: usize) -> Result<i32, BackendError> {
+        let above = frame_bytes("parameter count", n_params - 1 - i)?;
+        match self {
+            Frame::Rbp => Ok(16 + above),
+            Frame::Rsp { n_locals } => Ok(8 + frame_bytes("local count", n_locals)? + above),
+        }
+    }
+
+    // AT&T operand of local `slot`, or with `indexed` of the element of
+    // the array there at index -%rax
+    fn operand(self, slot: usize, depth: usize, indexed: bool) -> String {
+        let (disp, reg) = match self {
+            Frame::Rbp => (-slot_offset(slot), "%rbp"),
+            Frame::Rsp { n_locals } => (8 * (n_locals - 1 - slot + depth) as i32, "%rsp"),
+        };
+        if indexed { format!("{disp}({reg},%rax,8)") } else { format!("{disp}({reg})") }
+    }
+}
+
+// Why the IR couldn't be compiled to machine code. Codegen's IR shouldn't
+// produce these; they stop the backend instead of writing a corrupt binary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendError {
+    NoMain,
+    UndefinedLabel { func: String, label: u32 },
+    Unsupported(String),                          // an instruction with no native code
+    OutOfRange { what: &'static str, value: i64 }, // too large for its encoding
+    FrameTooLarge { func: String, n_locals: usize }, // more than MAX_FRAME_BYTES of locals
+    CodeTooLarge(usize),                          // bytes of code, more than MAX_CODE_BYTES
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::NoMain => write!(f, "no `main` function found"),
+            BackendError::UndefinedLabel { func, label } => {
+                write!(f, "jump to undefined label L{label} in `{func}`")
+            }
+            BackendError::Unsupported(what) => write!(f, "{what} is not supported by the native backend"),
+            BackendError::OutOfRange { what, value } => write!(f, "{what} {value} does not fit its encoding"),
+            BackendError::FrameTooLarge { func, n_locals } => {
+                write!(f, "`{func}` has {n_locals} local slots, more than fit in a stack frame of {MAX_FRAME_BYTES} bytes")
+            }
+            BackendError::CodeTooLarge(bytes) => {
+                write!(f, "the program compiles to {bytes} bytes of code, more than the {MAX_CODE_BYTES} that fit below its data")
+            }
+        }
+    }
+}
+
+// Calling convention (internal, not System V): the caller pushes arguments
+// left to right and `call`s; the callee copies them into its first locals,
+// returns its value in rax, and the caller drops the arguments and pushes
+// rax. Locals live below rbp at [rbp - 8*(slot+1)].
+pub struct Compiler {
+    pub code: Vec<u8>,
+    pub data: Vec<u8>,                     // read-write, loaded at DATA_VADDR
+    pub data_labels: Vec<(usize, String)>, // (offset into data, label)
+    pub pie: bool, // emit an ET_DYN with addresses relative to the load base
+    pub checked: bool, // trap on i32 overflow instead of wrapping; see `emit_overflow_check`
+    simple_print: bool,               // print with `emit_simple_print`; see `simple_print`
+    omit_frame_pointer: bool,         // see `omit_frame_pointer`
+    stack_protector: bool,            // see `stack_protector`
+    in_process: bool,                 // see `in_process`
+    build_id: bool,                   // see `with_build_id`
+    relocatable: bool,                // see `relocatable`
+    write_source_map: bool,           // see `with_source_map`
+    globals: Vec<u64>,                // address of each global, by index
+    func_offsets: Vec<usize>,         // code offset of each function, by index
+    call_fixups: Vec<(usize, usize)>, // (offset of a call's rel32, callee index)
+    data_fixups: Vec<(usize, usize)>, // (offset of a RIP-relative rel32, offset into data it reaches)
+    rodata_start: usize,              // code offset of the const arrays, which follow the code
+    rodata_labels: Vec<(usize, String)>, // (offset of each const array from rodata_start, its name), by index
+    rodata_fixups: Vec<(usize, usize)>,  // (offset of a RIP-relative rel32, offset from rodata_start it reaches)
+    lines: Vec<(usize, Span)>,        // (code offset, source position of the code from there on)
+    trap_fixups: Vec<(usize, Trap)>,  // (offset of the rel32 of a jump to a trap's stub, trap)
+    strings: HashMap<Vec<u8>, String>, // bytes of each string literal -> its data label
+    short_jumps: HashSet<(usize, usize)>, // (function index, jump number) of the jumps to emit as rel8
+    relaxable: Vec<(usize, usize)>,       // the same, for rel32 jumps of the last layout that fit a rel8
+    // per function being compiled
+    labels: HashMap<u32, usize>,      // label id -> code offset
+    jump_fixups: Vec<(usize, u32, bool)>, // (offset of a jump's rel32 or rel8, label id, is rel8)
+    label_depths: HashMap<u32, usize>, // label id -> depth on the jumps to it
+    func_index: usize,
+    is_main: bool,
+    frame: Frame,
+    canary: Option<usize>, // the slot holding CANARY, below the locals
+    depth: usize, // operand values pushed by the function at this point
+    listing: Vec<Listed>, // what was compiled where, for `emit_asm`
+}
+
+// A function entry or an instruction, as `compile_func`/`compile_instr` saw
+// it; `Fused` is a `PushI32(imm)` that `compile_fused` folded into `instr`.
+enum Listed {
+    Func { index: usize, name: String, n_locals: usize, n_params: usize, is_main: bool, frame: Frame, canary: Option<usize> },
+    Instr { offset: usize, instr: Instr, depth: usize },
+    Fused { offset: usize, imm: i32, instr: Instr, depth: usize },
+    TrapStub { offset: usize, trap: Trap },
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            code: Vec::new(),
+            data: Vec::new(),
+            data_labels: Vec::new(),
+            pie: false,
+            checked: false,
+            simple_print: false,
+            omit_frame_pointer: false,
+            stack_protector: false,
+            in_process: false,
+            build_id: false,
+            relocatable: false,
+            write_source_map: false,
+            globals: Vec::new(),
+            func_offsets: Vec::new(),
+            call_fixups: Vec::new(),
+            data_fixups: Vec::new(),
+            rodata_start: 0,
+            rodata_labels: Vec::new(),
+            rodata_fixups: Vec::new(),
+            lines: Vec::new(),
+            trap_fixups: Vec::new(),
+            strings: HashMap::new(),
+            short_jumps: HashSet::new(),
+            relaxable: Vec::new(),
+            labels: HashMap::new(),
+            jump_fixups: Vec::new(),
+            label_depths: HashMap::new(),
+            func_index: 0,
+            is_main: false,
+            frame: Frame::Rbp,
+            canary: None,
+            depth: 0,
+            listing: Vec::new(),
+        }
+    }
+
+    // Prints integers with `emit_simple_print` instead of `emit_print`:
+    // slower, but simple enough to check by eye, as a fallback for when
+    // `print` output is in doubt.
+    pub fn simple_print(mut self, on: bool) -> Self {
+        self.simple_print = on;
+        self
+    }
+
+    // Leaf functions (those that call nothing) address their locals from
+    // rsp, without saving rbp or pointing it at their frame.
+    pub fn omit_frame_pointer(mut self, on: bool) -> Self {
+        self.omit_frame_pointer = on;
+        self
+    }
+
+    // Each function stores CANARY in an extra slot below its locals on
+    // entry, and executes `ud2` if it has changed by the time it returns:
+    // array elements run downward from their base slot, so writing past
+    // the end of the lowest array reaches it first.
+    pub fn stack_protector(mut self, on: bool) -> Self {
+        self.stack_protector = on;
+        self
+    }
+
+    // The executable gets a `.note.gnu.build-id`, a hash of its code and
+    // data, so tools can tell builds apart; see `build_id_note`.
+    pub fn with_build_id(mut self, on: bool) -> Self {
+        self.build_id = on;
+        self
+    }
+
+    // `generate_elf` also writes `source_map` next to the executable, as
+    // `<executable>.map`.
+    pub fn with_source_map(mut self, on: bool) -> Self {
+        self.write_source_map = on;
+        self
+    }
+
+    // `generate_elf` writes an object file (ET_REL) for `ld` to link with
+    // others, rather than an executable; see `generate_object`.
+    pub fn relocatable(mut self, on: bool) -> Self {
+        self.relocatable = on;
+        self
+    }
+
+    // Compiles for `map_executable` rather than an ELF file: position
+    // independent, and main returns its value like any other function
+    // instead of exiting. A runtime error still exits.
+    pub fn in_process(mut self, on: bool) -> Self {
+        self.in_process = on;
+        self.pie |= on;
+        self
+    }
+
+    // Lays out every function, main first so it sits at the entry point,
+    // and the globals in the data segment, then the stub of each trap the
+    // code can jump to and the const arrays, read-only after the code. Set `pie` and `checked` before calling this.
     //
-    // Virtual addresses mirror file offsets with base 0x400000:
-    const BASE_VADDR: u64 = 0x400000;
-    const OFF_ELF_HDR: u64 = 0x0000;
-    const OFF_PROG_HDR: u64 = 0x0040;
-    const OFF_SEG: u64 = 0x1000;
-    const VADDR_SEG: u64 = BASE_VADDR + OFF_SEG;
-
-    // ---------- Machine code -------------------------------------------------
-    // _start:
-    //   mov rax, 1          ; sys_write
-    //   mov rdi, 1          ; fd = 1 (stdout)
-    //   lea rsi, [rip+msg]  ; buffer addr
-    //   mov rdx, 6          ; length
-    //   syscall
-    //   mov rax, 60         ; sys_exit
-    //   xor rdi, rdi        ; status = 0
-    //   syscall
+    // Jumps between IR labels start out as rel32s. Each layout finds those
+    // whose target would be in reach of a rel8, and the program is laid out
+    // again with them shortened, which only brings other targets nearer,
+    // until no jump is left to shrink.
+    pub fn compile_program(&mut self, prog: &ProgramIR) -> Result<(), BackendError> {
+        loop {
+            self.layout(prog)?;
+            if self.relaxable.is_empty() {
+                break;
+            }
+            let shortened = std::mem::take(&mut self.relaxable);
+            self.short_jumps.extend(shortened);
+        }
+        if self.code.len() > MAX_CODE_BYTES {
+            return Err(BackendError::CodeTooLarge(self.code.len()));
+        }
+        Ok(())
+    }
+
+    // One layout of the program from scratch, with the jumps in
+    // `short_jumps` as rel8s.
+    fn layout(&mut self, prog: &ProgramIR) -> Result<(), BackendError> {
+        self.code.clear();
+        self.data.clear();
+        self.data_labels.clear();
+        self.call_fixups.clear();
+        self.data_fixups.clear();
+        self.rodata_fixups.clear();
+        self.lines.clear();
+        self.trap_fixups.clear();
+        self.strings.clear();
+        self.listing.clear();
+        let main_idx = prog.main_index().ok_or(BackendError::NoMain)?;
+        self.func_offsets = vec![0; prog.funcs.len()];
+        self.globals = prog.globals.iter()
+            .map(|g| self.add_data(&g.name, &g.value.to_le_bytes()))
+            .collect();
+        let mut rodata = Vec::new();
+        self.rodata_labels = prog.rodata.iter()
+            .map(|array| {
+                let offset = rodata.len();
+                rodata.extend(array.values.iter().flat_map(|v| v.to_le_bytes()));
+                (offset, array.name.clone())
+            })
+            .collect();
+
+        self.compile_func(main_idx, &prog.funcs[main_idx], !self.in_process)?;
+        for (i, f) in prog.funcs.iter().enumerate() {
+            if i != main_idx {
+                self.compile_func(i, f, false)?;
+            }
+        }
+
+        for &(at, callee) in &self.call_fixups {
+            let target = *self.func_offsets.get(callee)
+                .ok_or(BackendError::OutOfRange { what: "function index", value: callee as i64 })?;
+            self.code[at..at + 4].copy_from_slice(&rel32("call", at, target)?);
+        }
+        let fixups = std::mem::take(&mut self.trap_fixups);
+        for trap in Trap::ALL {
+            if !fixups.iter().any(|&(_, t)| t == trap) {
+                continue;
+            }
+            let target = self.code.len();
+            self.emit_trap_stub(trap)?;
+            for &(at, _) in fixups.iter().filter(|&&(_, t)| t == trap) {
+                self.code[at..at + 4].copy_from_slice(&rel32("trap", at, target)?);
+            }
+        }
+        if !rodata.is_empty() {
+            self.code.resize(self.code.len().next_multiple_of(8), 0xCC); // int3
+        }
+        self.rodata_start = self.code.len();
+        for &(at, offset) in &self.rodata_fixups {
+            self.code[at..at + 4].copy_from_slice(&rel32("const array", at, self.rodata_start + offset)?);
+        }
+        self.code.extend_from_slice(&rodata);
+        Ok(())
+    }
+
+    fn compile_func(&mut self, index: usize, func: &Func, is_main: bool) -> Result<(), BackendError> {
+        self.func_offsets[index] = self.code.len();
+        self.func_index = index;
+        self.is_main = is_main;
+        let n_slots = func.n_locals + self.stack_protector as usize;
+        self.canary = self.stack_protector.then_some(func.n_locals);
+        self.frame = if self.omit_frame_pointer && !func.code.iter().any(|i| matches!(i, Instr::Call(..))) {
+            Frame::Rsp { n_locals: n_slots }
+        } else {
+            Frame::Rbp
+        };
+        self.depth = 0;
+        self.labels.clear();
+        self.jump_fixups.clear();
+        self.label_depths.clear();
+        self.listing.push(Listed::Func {
+            index,
+            name: func.name.clone(),
+            n_locals: n_slots,
+            n_params: func.n_params,
+            is_main,
+            frame: self.frame,
+            canary: self.canary,
+        });
+
+        self.emit_prologue(func, n_slots)?;
+        let mut code = func.code.iter().enumerate().peekable();
+        while let Some((at, instr)) = code.next() {
+            if let Instr::PushI32(imm) = instr
+                && let Some((at, next)) = code.next_if(|(_, i)| {
+                    matches!(i, Instr::Store(_) | Instr::Add | Instr::Sub | Instr::Mul)
+                        || (**i == Instr::Div && shift(*imm).is_some())
+                })
+            {
+                self.mark_line(func, at);
+                self.compile_fused(*imm, next)?;
+            } else {
+                self.mark_line(func, at);
+                self.compile_instr(instr)?;
+            }
+        }
+
+        for (number, &(at, label, short)) in self.jump_fixups.iter().enumerate() {
+            let target = *self.labels.get(&label)
+                .ok_or_else(|| BackendError::UndefinedLabel { func: func.name.clone(), label })?;
+            if short {
+                self.code[at] = rel8(target as i64 - (at + 1) as i64)? as u8;
+                continue;
+            }
+            self.code[at..at + 4].copy_from_slice(&rel32("jump", at, target)?);
+            // As a rel8 the jump ends 3 (jmp) or 4 (je) bytes sooner: a
+            // forward target moves back as far, a backward one stays put.
+            let rel = target as i64 - (at + 4) as i64;
+            let saved = if self.code[at - 1] == 0xE9 { 3 } else { 4 };
+            if i8::try_from(if rel < 0 { rel + saved } else { rel }).is_ok() {
+                self.relaxable.push((index, number));
+            }
+        }
+        Ok(())
+    }
+
+    // Notes that the code about to be emitted is for `func.code[at]`, when
+    // codegen gave it a position and it differs from the last one's.
+    fn mark_line(&mut self, func: &Func, at: usize) {
+        if let Some(&span) = func.spans.get(at)
+            && self.lines.last().is_none_or(|&(_, last)| last != span)
+        {
+            self.lines.push((self.code.len(), span));
+        }
+    }
+
+    fn compile_instr(&mut self, instr: &Instr) -> Result<(), BackendError> {
+        let (pops, pushes) = instr.stack_effect();
+        self.listing.push(Listed::Instr { offset: self.code.len(), instr: instr.clone(), depth: self.depth });
+        match instr {
+            Instr::PushI32(v) => self.emit_push_i32(*v),
+            Instr::PushI64(v) => self.emit_push_i64(*v),
+            Instr::Pop => self.emit(&[0x58]), // pop rax
+            Instr::Dup => self.emit(&[0xFF, 0x34, 0x24]), // push qword [rsp]
+            Instr::Swap => self.emit(&[
+                0x58, // pop rax
+                0x5B, // pop rbx
+                0x50, // push rax
+                0x53, // push rbx
+            ]),
+            Instr::Load(slot) => self.emit_load(self.frame.disp(*slot, self.depth)?),
+            Instr::Store(slot) => self.emit_store(self.frame.disp(*slot, self.depth - 1)?),
+            // Element i of an array at `base` is slot base + i, which is
+            // 8*i bytes further below rbp (or nearer rsp): the index is
+            // negated for the scaled addressing.
+            Instr::LoadIndex(base, len) => {
+                self.emit(&[0x58]); // pop rax
+                self.emit_bounds_check(*len)?;
+                self.emit(&[0x48, 0xF7, 0xD8]); // neg rax
+                self.emit_element(0x8B, 0, self.frame.disp(*base, self.depth - 1)?); // mov rax, [rbp + rax*8 + disp]
+                self.emit(&[0x50]); // push rax
+            }
+            Instr::StoreIndex(base, len) => {
+                self.emit(&[
+                    0x5B, // pop rbx
+                    0x58, // pop rax
+                ]);
+                self.emit_bounds_check(*len)?;
+                self.emit(&[0x48, 0xF7, 0xD8]); // neg rax
+                self.emit_element(0x89, 3, self.frame.disp(*base, self.depth - 2)?); // mov [rbp + rax*8 + disp], rbx
+            }
+            Instr::LoadGlobal(index) => {
+                self.emit_rip_mem(0x8B, self.global(*index)?)?; // mov rax, [rip + rel32]
+                self.emit(&[0x50]); // push rax
+            }
+            Instr::StoreGlobal(index) => {
+                self.emit(&[0x58]); // pop rax
+                self.emit_rip_mem(0x89, self.global(*index)?)?; // mov [rip + rel32], rax
+            }
+            // A const array's rel32 is patched once the code before it is
+            // laid out.
+            Instr::LoadConstIndex(array, len) => {
+                let offset = self.rodata_labels.get(*array)
+                    .ok_or(BackendError::OutOfRange { what: "const array index", value: *array as i64 })?.0;
+                self.emit(&[0x58]); // pop rax
+                self.emit_bounds_check(*len)?;
+                self.emit(&[0x48, 0x8D, 0x1D]); // lea rbx, [rip + rel32]
+                self.rodata_fixups.push((self.code.len(), offset));
+                self.emit(&[0, 0, 0, 0]);
+                self.emit(&[
+                    0x48, 0x8B, 0x04, 0xC3, // mov rax, [rbx + rax*8]
+                    0x50,                   // push rax
+                ]);
+            }
+
+            // Values are kept sign-extended from 32 bits; re-extending after
+            // each op wraps results the same way the VM does.
+            Instr::Add => self.emit_binop(&[0x48, 0x01, 0xD8]),       // add rax, rbx
+            Instr::Sub => self.emit_binop(&[0x48, 0x29, 0xD8]),       // sub rax, rbx
+            Instr::Mul => self.emit_binop(&[0x48, 0x0F, 0xAF, 0xC3]), // imul rax, rbx
+            Instr::Div => self.emit_div(false),
+            Instr::Mod => self.emit_div(true),
+            Instr::Neg => {
+                self.emit(&[
+                    0x58,             // pop rax
+                    0x48, 0xF7, 0xD8, // neg rax
+                ]);
+                self.emit_overflow_check(true);
+                self.emit(&[0x48, 0x63, 0xC0, 0x50]); // movsxd rax, eax; push rax
+            }
+            // i64 operations keep all 64 bits, so only `jo` can tell of
+            // an overflow.
+            Instr::AddI64 => self.emit_binop_i64(&[0x48, 0x01, 0xD8]),       // add rax, rbx
+            Instr::SubI64 => self.emit_binop_i64(&[0x48, 0x29, 0xD8]),       // sub rax, rbx
+            Instr::MulI64 => self.emit_binop_i64(&[0x48, 0x0F, 0xAF, 0xC3]), // imul rax, rbx
+            Instr::DivI64 => self.emit_div_i64(false),
+            Instr::ModI64 => self.emit_div_i64(true),
+            Instr::NegI64 => {
+                self.emit(&[
+                    0x58,             // pop rax
+                    0x48, 0xF7, 0xD8, // neg rax
+                ]);
+                self.emit_i64_overflow_check();
+                self.emit(&[0x50]); // push rax
+            }
+            Instr::Narrow => self.emit(&[
+                0x58,             // pop rax
+                0x48, 0x63, 0xC0, // movsxd rax, eax
+                0x50,             // push rax
+            ]),
+            Instr::Not => self.emit(&[
+                0x58,             // pop rax
+                0x48, 0x85, 0xC0, // test rax, rax
+                0x0F, 0x94, 0xC0, // setz al
+                0x0F, 0xB6, 0xC0, // movzx eax, al
+                0x50,             // push rax
+            ]),
+            Instr::CmpLt => self.emit_cmp(0x9C), // setl
+            Instr::CmpGt => self.emit_cmp(0x9F), // setg
+            Instr::CmpLe => self.emit_cmp(0x9E), // setle
+            Instr::CmpGe => self.emit_cmp(0x9D), // setge
+            Instr::CmpEq => self.emit_cmp(0x94), // sete
+            Instr::CmpNe => self.emit_cmp(0x95), // setne
+
+            Instr::Label(id) => {
+                self.labels.insert(*id, self.code.len());
+            }
+            Instr::Jump(id) => self.emit_jump(&[0xEB], &[0xE9], *id), // jmp rel8 / rel32
+            Instr::JumpIfZero(id) => {
+                self.emit(&[
+                    0x58,             // pop rax
+                    0x48, 0x85, 0xC0, // test rax, rax
+                ]);
+                self.emit_jump(&[0x74], &[0x0F, 0x84], *id); // je rel8 / rel32
+            }
+            Instr::JumpIfNonZero(id) => {
+                self.emit(&[
+                    0x58,             // pop rax
+                    0x48, 0x85, 0xC0, // test rax, rax
+                ]);
+                self.emit_jump(&[0x75], &[0x0F, 0x85], *id); // jne rel8 / rel32
+            }
+
+            Instr::Print if self.simple_print => self.emit_simple_print(false)?,
+            Instr::PrintUnsigned if self.simple_print => self.emit_simple_print(true)?,
+            Instr::Print => self.emit_print(false)?,
+            Instr::PrintUnsigned => self.emit_print(true)?,
+            Instr::PrintNewline => self.emit(&[
+                0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (sys_write)
+                0xBF, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+                0x6A, 0x0A,                   // push '\n'
+                0x48, 0x89, 0xE6,             // mov rsi, rsp
+                0xBA, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+                0x0F, 0x05,                   // syscall
+                0x58,                         // pop rax
+            ]),
+            Instr::PrintStr(bytes) => {
+                let addr = self.add_string(bytes);
+                self.emit(&[
+                    0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (sys_write)
+                    0xBF, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+                    0x48, 0x8D, 0x35,             // lea rsi, [rip + rel32]
+                ]);
+                self.emit_rip_rel32(addr)?;
+                self.emit(&[0xBA]); // mov edx, len
+                let len = u32::try_from(bytes.len())
+                    .map_err(|_| BackendError::OutOfRange { what: "string length", value: bytes.len() as i64 })?;
+                self.emit(&len.to_le_bytes());
+                self.emit(&[0x0F, 0x05]); // syscall
+            }
+            Instr::Input => self.emit_input()?,
+            Instr::Perform(name, _) => return Err(BackendError::Unsupported(format!("effect `{name}`"))),
+            Instr::PushHandler(name, _) => return Err(BackendError::Unsupported(format!("a handler of effect `{name}`"))),
+            Instr::PopHandler => return Err(BackendError::Unsupported("a `handle` scope".to_string())),
+
+            Instr::Call(callee, argc) => {
+                self.emit(&[0xE8]); // call rel32
+                self.call_fixups.push((self.code.len(), *callee));
+                self.emit(&[0, 0, 0, 0]);
+                if *argc > 0 {
+                    self.emit_rsp_adjust(0xC4, frame_bytes("argument count", *argc)?); // add rsp, argc*8
+                }
+                self.emit(&[0x50]); // push rax
+            }
+            Instr::Ret => self.emit_return()?,
+        }
+
+        // Control never falls through a jump or return. A label is reached
+        // at the depth its jumps left behind, which inside `&&`/`||` still
+        // includes the operands of the enclosing expression.
+        self.depth = match instr {
+            Instr::Ret => 0,
+            Instr::Jump(id) => {
+                self.label_depths.insert(*id, self.depth);
+                0
+            }
+            Instr::JumpIfZero(id) | Instr::JumpIfNonZero(id) => {
+                self.label_depths.insert(*id, self.depth - 1);
+                self.depth - 1
+            }
+            Instr::Label(id) => self.label_depths.get(id).copied().unwrap_or(self.depth),
+            _ => self.depth.saturating_sub(pops) + pushes,
+        };
+        Ok(())
+    }
+
+    // Peephole for a constant feeding `instr` (a Store or Add/Sub/Mul, or a
+    // Div by a power of two): the immediate goes straight into the store or
+    // the arithmetic instead of through the stack. Neither changes the
+    // depth, a pushed value for a popped one.
+    fn compile_fused(&mut self, imm: i32, instr: &Instr) -> Result<(), BackendError> {
+        self.listing.push(Listed::Fused { offset: self.code.len(), imm, instr: instr.clone(), depth: self.depth });
+        let op: &[u8] = match instr {
+            Instr::Store(slot) => {
+                // mov qword [rbp - offset], imm32
+                self.emit_local_mem(0xC7, self.frame.disp(*slot, self.depth)?);
+                self.emit(&imm.to_le_bytes());
+                return Ok(());
+            }
+            // An i32 shifted by at most 30 stays well inside i64, so only
+            // the result leaving i32 can overflow.
+            Instr::Mul if let Some(k) = shift(imm) => {
+                self.emit(&[
+                    0x58,                // pop rax
+                    0x48, 0xC1, 0xE0, k, // shl rax, k
+                ]);
+                self.emit_overflow_check(false);
+                self.emit(&[0x48, 0x63, 0xC0, 0x50]); // movsxd rax, eax; push rax
+                return Ok(());
+            }
+            // `sar` rounds toward negative infinity and idiv toward zero,
+            // so a negative dividend is first biased by 2^k - 1. The
+            // quotient of an i32 by 2 or more can't overflow.
+            Instr::Div => {
+                let k = shift(imm).expect("only powers of two are fused into a Div");
+                self.emit(&[
+                    0x58,                     // pop rax
+                    0x48, 0x89, 0xC1,         // mov rcx, rax
+                    0x48, 0xC1, 0xF9, 0x3F,   // sar rcx, 63    ; -1 if negative, else 0
+                    0x48, 0xC1, 0xE9, 64 - k, // shr rcx, 64-k  ; 2^k - 1 if negative
+                    0x48, 0x01, 0xC8,         // add rax, rcx
+                    0x48, 0xC1, 0xF8, k,      // sar rax, k
+                    0x50,                     // push rax
+                ]);
+                return Ok(());
+            }
+            Instr::Add => &[0x48, 0x05],       // add rax, imm32
+            Instr::Sub => &[0x48, 0x2D],       // sub rax, imm32
+            Instr::Mul => &[0x48, 0x69, 0xC0], // imul rax, rax, imm32
+            _ => unreachable!("{instr:?} does not take an immediate"),
+        };
+        self.emit(&[0x58]); // pop rax
+        self.emit(op);
+        self.emit(&imm.to_le_bytes());
+        self.emit_overflow_check(true);
+        self.emit(&[0x48, 0x63, 0xC0, 0x50]); // movsxd rax, eax; push rax
+        Ok(())
+    }
+
+    // Address the image is linked at: BASE_VADDR, or 0 for a PIE.
+    fn base(&self) -> u64 {
+        if self.pie { 0 } else { BASE_VADDR }
+    }
+
+    fn data_vaddr(&self) -> u64 {
+        DATA_VADDR - BASE_VADDR + self.base()
+    }
+
+    fn global(&self, index: usize) -> Result<u64, BackendError> {
+        self.globals.get(index).copied()
+            .ok_or(BackendError::OutOfRange { what: "global index", value: index as i64 })
+    }
+
+    // Appends `bytes` to the data segment under `label`, 8-byte aligned,
+    // and returns their address. In a PIE that is relative to the load
+    // base, so code must reach it RIP-relative (see `emit_rip_mem`).
+    pub fn add_data(&mut self, label: &str, bytes: &[u8]) -> u64 {
+        self.data.resize(self.data.len().next_multiple_of(8), 0);
+        let offset = self.data.len();
+        self.data_labels.push((offset, label.to_string()));
+        self.data.extend_from_slice(bytes);
+        self.data_vaddr() + offset as u64
+    }
+
+    // Address of the string literal `bytes`, added to the data segment the
+    // first time it is printed; every later copy shares that one.
+    fn add_string(&mut self, bytes: &[u8]) -> u64 {
+        if let Some(label) = self.strings.get(bytes) {
+            return self.data_addr(label).expect("every string has a data label");
+        }
+        let label = string_label(self.code.len());
+        self.strings.insert(bytes.to_vec(), label.clone());
+        self.add_data(&label, bytes)
+    }
+
+    pub fn data_addr(&self, label: &str) -> Option<u64> {
+        self.data_labels.iter()
+            .find(|(_, l)| l == label)
+            .map(|(offset, _)| self.data_vaddr() + *offset as u64)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    // push rbp; mov rbp, rsp; sub rsp, n_locals*8; then copy the arguments
+    // (above the return address) into their local slots, and store the
+    // canary if there is one. Without a frame pointer, no push or mov.
+    fn emit_prologue(&mut self, func: &Func, n_locals: usize) -> Result<(), BackendError> {
+        let n_params = func.n_params;
+        if n_locals > MAX_FRAME_BYTES / 8 {
+            return Err(BackendError::FrameTooLarge { func: func.name.clone(), n_locals });
+        }
+        if self.frame == Frame::Rbp {
+            self.emit(&[0x55, 0x48, 0x89, 0xE5]);
+        }
+        if n_locals > 0 {
+            self.emit_rsp_adjust(0xEC, frame_bytes("local count", n_locals)?);
+        }
+        for i in 0..n_params {
+            let arg = self.frame.arg_disp(i, n_params)?;
+            self.emit_local_mem(0x8B, arg); // mov rax, [rbp + arg]
+            self.emit_local_mem(0x89, self.frame.disp(i, 0)?); // mov [rbp - slot], rax
+        }
+        if let Some(slot) = self.canary {
+            self.emit(&[0x48, 0xB8]); // movabs rax, CANARY
+            self.emit(&CANARY.to_le_bytes());
+            self.emit_local_mem(0x89, self.frame.disp(slot, 0)?); // mov [rbp - slot], rax
+        }
+        Ok(())
+    }
+
+    // Before a return, with the value still on the operand stack: stops at
+    // `ud2` if the canary was overwritten.
+    fn emit_canary_check(&mut self) -> Result<(), BackendError> {
+        let Some(slot) = self.canary else { return Ok(()) };
+        self.emit_local_mem(0x8B, self.frame.disp(slot, self.depth)?); // mov rax, [rbp - slot]
+        self.emit(&[0x48, 0xB9]); // movabs rcx, CANARY
+        self.emit(&CANARY.to_le_bytes());
+        self.emit(&[
+            0x48, 0x39, 0xC8, // cmp rax, rcx
+            0x74, 0x02,       // je +2
+            0x0F, 0x0B,       // ud2
+        ]);
+        Ok(())
+    }
+
+    // mov rsp, rbp; pop rbp; ret. Without a frame pointer, rsp is moved
+    // back above the locals and the `depth` operand values left below them.
+    fn emit_epilogue(&mut self, depth: usize) -> Result<(), BackendError> {
+        match self.frame {
+            Frame::Rbp => self.emit(&[0x48, 0x89, 0xEC, 0x5D]),
+            Frame::Rsp { n_locals } => {
+                let bytes = frame_bytes("stack depth", n_locals + depth)?;
+                if bytes > 0 {
+                    self.emit_rsp_adjust(0xC4, bytes); // add rsp, bytes
+                }
+            }
+        }
+        self.emit(&[0xC3]);
+        Ok(())
+    }
+
+    // main exits the process with its return value; other functions return
+    // it in rax. A bare `return;` yields 0.
+    fn emit_return(&mut self) -> Result<(), BackendError> {
+        self.emit_canary_check()?;
+        if self.is_main {
+            if self.depth > 0 {
+                self.emit(&[0x5F]); // pop rdi
+            } else {
+                self.emit(&[0x31, 0xFF]); // xor edi, edi
+            }
+            self.emit(&[
+                0xB8, 0x3C, 0x00, 0x00, 0x00, // mov eax, 60 (sys_exit)
+                0x0F, 0x05,                   // syscall
+            ]);
+        } else {
+            if self.depth > 0 {
+                self.emit(&[0x58]); // pop rax
+            } else {
+                self.emit(&[0x31, 0xC0]); // xor eax, eax
+            }
+            self.emit_epilogue(self.depth.saturating_sub(1))?;
+        }
+        Ok(())
+    }
+
+    // push imm32 (sign-extended to 64 bits)
+    fn emit_push_i32(&mut self, v: i32) {
+        self.emit(&[0x68]);
+        self.emit(&v.to_le_bytes());
+    }
+
+    // `push imm32` sign-extends, so only constants outside i32 need
+    // movabs rax, imm64; push rax
+    fn emit_push_i64(&mut self, v: i64) {
+        match i32::try_from(v) {
+            Ok(v) => self.emit_push_i32(v),
+            Err(_) => {
+                self.emit(&[0x48, 0xB8]);
+                self.emit(&v.to_le_bytes());
+                self.emit(&[0x50]);
+            }
+        }
+    }
+
+    // mov rax, [rbp + disp]; push rax
+    fn emit_load(&mut self, disp: i32) {
+        self.emit_local_mem(0x8B, disp);
+        self.emit(&[0x50]);
+    }
+
+    // pop rax; mov [rbp + disp], rax
+    fn emit_store(&mut self, disp: i32) {
+        self.emit(&[0x58]);
+        self.emit_local_mem(0x89, disp);
+    }
+
+    // `emit_rbp_mem`, or [rsp + disp] without a frame pointer, which needs
+    // a SIB byte to name rsp as the base.
+    fn emit_local_mem(&mut self, opcode: u8, disp: i32) {
+        if self.frame == Frame::Rbp {
+            return self.emit_rbp_mem(opcode, disp);
+        }
+        match i8::try_from(disp) {
+            Ok(d) => self.emit(&[0x48, opcode, 0x44, 0x24, d as u8]),
+            Err(_) => {
+                self.emit(&[0x48, opcode, 0x84, 0x24]);
+                self.emit(&disp.to_le_bytes());
+            }
+        }
+    }
+
+    // `opcode` rax <-> [rbp + disp] (0x8B load, 0x89 store; 0xC7 stores the
+    // imm32 the caller emits next, as its /0 reg field is rax's). A disp8 is
+    // sign-extended, so it reaches locals 1..=128 bytes below rbp (slots
+    // 0..=15); anything further needs a disp32.
+    fn emit_rbp_mem(&mut self, opcode: u8, disp: i32) {
+        match i8::try_from(disp) {
+            Ok(d) => self.emit(&[0x48, opcode, 0x45, d as u8]),
+            Err(_) => {
+                self.emit(&[0x48, opcode, 0x85]);
+                self.emit(&disp.to_le_bytes());
+            }
+        }
+    }
+
+    // `opcode` reg <-> [rbp + rax*8 + disp], an array element, in one
+    // instruction through the SIB byte (scale 8, index rax, base rbp, or
+    // rsp without a frame pointer). As in `emit_rbp_mem`, a disp8 reaches
+    // arrays based in slots 0..=15.
+    fn emit_element(&mut self, opcode: u8, reg: u8, disp: i32) {
+        let sib = if self.frame == Frame::Rbp { 0xC5 } else { 0xC4 };
+        match i8::try_from(disp) {
+            Ok(d) => self.emit(&[0x48, opcode, 0x44 | reg << 3, sib, d as u8]),
+            Err(_) => {
+                self.emit(&[0x48, opcode, 0x84 | reg << 3, sib]);
+                self.emit(&disp.to_le_bytes());
+            }
+        }
+    }
+
+    // `opcode` rax <-> [rip + rel32] addressing `addr`. The data segment
+    // sits at a fixed distance from the code, so this works in a PIE too.
+    fn emit_rip_mem(&mut self, opcode: u8, addr: u64) -> Result<(), BackendError> {
+        self.emit(&[0x48, opcode, 0x05]);
+        self.emit_rip_rel32(addr)
+    }
+
+    // The rel32 ending an instruction, from its end to `addr`.
+    fn emit_rip_rel32(&mut self, addr: u64) -> Result<(), BackendError> {
+        self.data_fixups.push((self.code.len(), (addr - self.data_vaddr()) as usize));
+        let next = self.base() + OFF_CODE + self.code.len() as u64 + 4;
+        let rel = i32::try_from(addr as i64 - next as i64)
+            .map_err(|_| BackendError::OutOfRange { what: "RIP-relative displacement", value: addr as i64 - next as i64 })?;
+        self.emit(&rel.to_le_bytes());
+        Ok(())
+    }
+
+    // sub rsp, n (modrm 0xEC) / add rsp, n (modrm 0xC4)
+    fn emit_rsp_adjust(&mut self, modrm: u8, n: i32) {
+        match i8::try_from(n) {
+            Ok(b) => self.emit(&[0x48, 0x83, modrm, b as u8]),
+            Err(_) => {
+                self.emit(&[0x48, 0x81, modrm]);
+                self.emit(&n.to_le_bytes());
+            }
+        }
+    }
+
+    // pop rbx; pop rax; <op rax, rbx>; movsxd rax, eax; push rax
+    fn emit_binop(&mut self, op: &[u8]) {
+        self.emit(&[0x5B, 0x58]);
+        self.emit(op);
+        self.emit_overflow_check(true);
+        self.emit(&[0x48, 0x63, 0xC0, 0x50]);
+    }
+
+    fn emit_binop_i64(&mut self, op: &[u8]) {
+        self.emit(&[0x5B, 0x58]); // pop rbx; pop rax
+        self.emit(op);
+        self.emit_i64_overflow_check();
+        self.emit(&[0x50]); // push rax
+    }
+
+    // In checked mode, jo __overflow
+    fn emit_i64_overflow_check(&mut self) {
+        if self.checked {
+            self.emit_trap_jump(0x80, Trap::Overflow);
+        }
+    }
+
+    // In checked mode, traps unless the 64-bit result in rax is an i32, as
+    // the VM's checked mode does: `jo` catches the operation itself
+    // overflowing (possible only with i64 constants as operands, and not
+    // set meaningfully by idiv), the compare a result outside i32.
+    fn emit_overflow_check(&mut self, jo: bool) {
+        if !self.checked {
+            return;
+        }
+        if jo {
+            self.emit_trap_jump(0x80, Trap::Overflow); // jo __overflow
+        }
+        self.emit(&[
+            0x48, 0x63, 0xC8, // movsxd rcx, eax
+            0x48, 0x39, 0xC1, // cmp rcx, rax
+        ]);
+        self.emit_trap_jump(0x85, Trap::Overflow); // jne __overflow
+    }
+
+    // jcc rel32 to the stub of `trap`, patched once it is placed
+    fn emit_trap_jump(&mut self, jcc: u8, trap: Trap) {
+        self.emit(&[0x0F, jcc]);
+        self.trap_fixups.push((self.code.len(), trap));
+        self.emit(&[0, 0, 0, 0]);
+    }
+
+    // Traps unless the index in rax is in 0..len; a negative index reads
+    // as a huge unsigned one.
+    fn emit_bounds_check(&mut self, len: usize) -> Result<(), BackendError> {
+        let len = i32::try_from(len).map_err(|_| BackendError::OutOfRange { what: "array length", value: len as i64 })?;
+        self.emit(&[0x48, 0x81, 0xF8]); // cmp rax, imm32
+        self.emit(&len.to_le_bytes());
+        self.emit_trap_jump(0x83, Trap::Bounds); // jae __bounds
+        Ok(())
+    }
+
+    // Shared by every jump to `trap`: reports it on stderr and exits with
+    // status 70, as `cosplae --run` does on a runtime error.
+    fn emit_trap_stub(&mut self, trap: Trap) -> Result<(), BackendError> {
+        self.listing.push(Listed::TrapStub { offset: self.code.len(), trap });
+        let addr = self.add_data(trap.label(), trap.message());
+        self.emit(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (sys_write)
+            0xBF, 0x02, 0x00, 0x00, 0x00, // mov edi, 2 (stderr)
+            0x48, 0x8D, 0x35,             // lea rsi, [rip + rel32]
+        ]);
+        self.emit_rip_rel32(addr)?;
+        self.emit(&[0xBA]); // mov edx, len
+        self.emit(&(trap.message().len() as u32).to_le_bytes());
+        self.emit(&[
+            0x0F, 0x05,                   // syscall
+            0xB8, 0x3C, 0x00, 0x00, 0x00, // mov eax, 60 (sys_exit)
+            0xBF, 0x46, 0x00, 0x00, 0x00, // mov edi, 70
+            0x0F, 0x05,                   // syscall
+        ]);
+        Ok(())
+    }
+
+    // idiv leaves the quotient in rax and the remainder in rdx
+    fn emit_div(&mut self, remainder: bool) {
+        self.emit(&[
+            0x5B,             // pop rbx
+            0x58,             // pop rax
+            0x48, 0x99,       // cqo
+            0x48, 0xF7, 0xFB, // idiv rbx
+        ]);
+        if remainder {
+            self.emit(&[0x52]); // push rdx
+        } else {
+            self.emit_overflow_check(false); // i32::MIN / -1
+            self.emit(&[0x48, 0x63, 0xC0, 0x50]); // movsxd rax, eax; push rax
+        }
+    }
+
+    // idiv faults on i64::MIN / -1, so division by -1 is a negation, which
+    // wraps (or overflows, when checked) like the VM, with remainder 0.
+    fn emit_div_i64(&mut self, remainder: bool) {
+        self.emit(&[
+            0x5B,                   // pop rbx
+            0x58,                   // pop rax
+            0x48, 0x83, 0xFB, 0xFF, // cmp rbx, -1
+        ]);
+        if remainder {
+            self.emit(&[
+                0x75, 0x04, // jne .idiv
+                0x31, 0xD2, // xor edx, edx
+            ]);
+        } else {
+            let skip = if self.checked { 3 + 6 + 2 } else { 3 + 2 };
+            self.emit(&[
+                0x75, skip,       // jne .idiv
+                0x48, 0xF7, 0xD8, // neg rax
+            ]);
+            self.emit_i64_overflow_check();
+        }
+        self.emit(&[
+            0xEB, 0x05,       // jmp .done
+            0x48, 0x99,       // .idiv: cqo
+            0x48, 0xF7, 0xFB, // idiv rbx
+            if remainder { 0x52 } else { 0x50 }, // .done: push rdx or rax
+        ]);
+    }
+
+    // pop rbx; pop rax; cmp rax, rbx; setcc al; movzx eax, al; push rax
+    fn emit_cmp(&mut self, setcc: u8) {
+        self.emit(&[
+            0x5B, 0x58,
+            0x48, 0x39, 0xD8,
+            0x0F, setcc, 0xC0,
+            0x0F, 0xB6, 0xC0,
+            0x50,
+        ]);
+    }
+
+    // A jump to `label`: the `short` opcode and a rel8 if an earlier layout
+    // found it in reach, else the `long` one and a rel32.
+    fn emit_jump(&mut self, short: &[u8], long: &[u8], label: u32) {
+        let is_short = self.short_jumps.contains(&(self.func_index, self.jump_fixups.len()));
+        self.emit(if is_short { short } else { long });
+        self.jump_fixups.push((self.code.len(), label, is_short));
+        self.emit(if is_short { &[0] } else { &[0, 0, 0, 0] });
+    }
+
+    // Pops a value and writes it in decimal to stdout: signed, or if
+    // `unsigned` its low 32 bits as a u32. Digits are produced least
+    // significant first, right to left into a stack buffer.
+    //
+    // Invariant: rsp is 16-byte aligned at the syscall. The operand stack
+    // leaves rsp at any multiple of 8, so the routine saves it in r8 (which
+    // syscall preserves), rounds down, and reserves the PRINT_BUFFER bytes
+    // [rsp, rsp+PRINT_BUFFER) before restoring it at the end.
+    fn emit_print(&mut self, unsigned: bool) -> Result<(), BackendError> {
+        self.emit(&[
+            0x58,                         // pop rax
+            0x49, 0x89, 0xE0,             // mov r8, rsp
+            0x48, 0x83, 0xE4, 0xF0,       // and rsp, -16
+            0x48, 0x83, 0xEC, PRINT_BUFFER, // sub rsp, PRINT_BUFFER
+            0x48, 0x8D, 0x74, 0x24, PRINT_BUFFER, // lea rsi, [rsp+PRINT_BUFFER] ; one past the buffer
+        ]);
+        if unsigned {
+            self.emit(&[0x89, 0xC0]);     // mov eax, eax        ; zero-extends
+        } else {
+            self.emit(&[
+                0x48, 0x89, 0xC1,         // mov rcx, rax        ; keep the sign
+                0x48, 0x85, 0xC0,         // test rax, rax
+                0x79, 0x03,               // jns +3
+                0x48, 0xF7, 0xD8,         // neg rax             ; 64-bit, so even i32::MIN has a magnitude
+            ]);
+        }
+        self.emit(&[0xBB, 0x0A, 0x00, 0x00, 0x00]); // mov ebx, 10
+        let loop_start = self.code.len();
+        self.emit(&[
+            0x31, 0xD2,                   // .loop: xor edx, edx
+            0x48, 0xF7, 0xF3,             // div rbx
+            0x80, 0xC2, 0x30,             // add dl, '0'
+            0x48, 0xFF, 0xCE,             // dec rsi
+            0x88, 0x16,                   // mov [rsi], dl
+            0x48, 0x85, 0xC0,             // test rax, rax
+        ]);
+        self.emit_jump8_back(0x75, loop_start)?; // jnz .loop
+        if !unsigned {
+            self.emit(&[
+                0x48, 0x85, 0xC9,         // test rcx, rcx
+                0x79, 0x06,               // jns +6
+                0x48, 0xFF, 0xCE,         // dec rsi
+                0xC6, 0x06, 0x2D,         // mov byte [rsi], '-'
+            ]);
+        }
+        self.emit(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (sys_write)
+            0xBF, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+            0x48, 0x8D, 0x54, 0x24, PRINT_BUFFER, // lea rdx, [rsp+PRINT_BUFFER]
+            0x48, 0x29, 0xF2,             // sub rdx, rsi        ; length
+            0x0F, 0x05,                   // syscall
+            0x4C, 0x89, 0xC4,             // mov rsp, r8
+        ]);
+        Ok(())
+    }
+
+    // `emit_print` without its tricks: the digits are written left to
+    // right, each the quotient by the next lower power of ten from 10^18
+    // (i64::MIN's magnitude has 19 digits), skipping leading zeros but
+    // never the units. r10 counts the bytes in the buffer at rsp, r9 is
+    // nonzero once a digit has been, and rsi holds what is left to print.
+    fn emit_simple_print(&mut self, unsigned: bool) -> Result<(), BackendError> {
+        self.emit(&[
+            0x58,                         // pop rax
+            0x49, 0x89, 0xE0,             // mov r8, rsp
+            0x48, 0x83, 0xE4, 0xF0,       // and rsp, -16
+            0x48, 0x83, 0xEC, PRINT_BUFFER, // sub rsp, PRINT_BUFFER
+            0x45, 0x31, 0xD2,             // xor r10d, r10d
+            0x45, 0x31, 0xC9,             // xor r9d, r9d
+        ]);
+        if unsigned {
+            self.emit(&[0x89, 0xC0]);     // mov eax, eax        ; zero-extends
+        } else {
+            self.emit(&[
+                0x48, 0x85, 0xC0,         // test rax, rax
+                0x79, 0x0D,               // jns +13
+                0xC6, 0x04, 0x24, 0x2D,   // mov byte [rsp], '-'
+                0x41, 0xBA, 0x01, 0x00, 0x00, 0x00, // mov r10d, 1
+                0x48, 0xF7, 0xD8,         // neg rax
+            ]);
+        }
+        self.emit(&[0x48, 0xBB]);         // movabs rbx, 10^18
+        self.emit(&1_000_000_000_000_000_000u64.to_le_bytes());
+        let digit = self.code.len();
+        self.emit(&[
+            0x31, 0xD2,                   // .digit: xor edx, edx
+            0x48, 0xF7, 0xF3,             // div rbx             ; rax = the digit
+            0x48, 0x89, 0xD6,             // mov rsi, rdx
+            0x48, 0x89, 0xC1,             // mov rcx, rax
+            0x49, 0x09, 0xC1,             // or r9, rax
+            0x48, 0x83, 0xFB, 0x01,       // cmp rbx, 1
+        ]);
+        let units = self.emit_jump8(0x74); // je .write
+        self.emit(&[0x4D, 0x85, 0xC9]);   // test r9, r9
+        let leading_zero = self.emit_jump8(0x74); // jz .next
+        self.patch_jump8(units)?;
+        self.emit(&[
+            0x80, 0xC1, 0x30,             // .write: add cl, '0'
+            0x42, 0x88, 0x0C, 0x14,       // mov [rsp + r10], cl
+            0x49, 0xFF, 0xC2,             // inc r10
+        ]);
+        self.patch_jump8(leading_zero)?;
+        self.emit(&[
+            0x48, 0x89, 0xD8,             // .next: mov rax, rbx
+            0x31, 0xD2,                   // xor edx, edx
+            0xB9, 0x0A, 0x00, 0x00, 0x00, // mov ecx, 10
+            0x48, 0xF7, 0xF1,             // div rcx
+            0x48, 0x89, 0xC3,             // mov rbx, rax
+            0x48, 0x89, 0xF0,             // mov rax, rsi
+            0x48, 0x85, 0xDB,             // test rbx, rbx
+        ]);
+        self.emit_jump8_back(0x75, digit)?; // jnz .digit
+        self.emit(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (sys_write)
+            0xBF, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+            0x48, 0x89, 0xE6,             // mov rsi, rsp
+            0x4C, 0x89, 0xD2,             // mov rdx, r10
+            0x0F, 0x05,                   // syscall
+            0x4C, 0x89, 0xC4,             // mov rsp, r8
+        ]);
+        Ok(())
+    }
+
+    // input(): reads stdin one byte at a time up to '\n' or EOF, so later
+    // calls see the following lines. An optional leading '-', then digits
+    // up to the first other character; the rest of the line is discarded.
+    // rbx is the state: 0 at line start, 1 in the number, 2 discarding.
+    fn emit_input(&mut self) -> Result<(), BackendError> {
+        self.emit(&[
+            0x49, 0x89, 0xE0,             // mov r8, rsp
+            0x48, 0x83, 0xE4, 0xF0,       // and rsp, -16
+            0x48, 0x83, 0xEC, 0x10,       // sub rsp, 16         ; one-byte buffer at [rsp]
+            0x45, 0x31, 0xC9,             // xor r9d, r9d        ; value
+            0x45, 0x31, 0xD2,             // xor r10d, r10d      ; 1 if negative
+            0x31, 0xDB,                   // xor ebx, ebx
+        ]);
+        let read = self.code.len();
+        self.emit(&[
+            0x31, 0xC0,                   // .read: xor eax, eax (sys_read)
+            0x31, 0xFF,                   // xor edi, edi (stdin)
+            0x48, 0x89, 0xE6,             // mov rsi, rsp
+            0xBA, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+            0x0F, 0x05,                   // syscall
+            0x48, 0x85, 0xC0,             // test rax, rax
+        ]);
+        let eof = self.emit_jump8(0x7E);  // jle .done
+        self.emit(&[
+            0x0F, 0xB6, 0x04, 0x24,       // movzx eax, byte [rsp]
+            0x3C, 0x0A,                   // cmp al, '\n'
+        ]);
+        let newline = self.emit_jump8(0x74); // je .done
+        self.emit(&[0x83, 0xFB, 0x02]);   // cmp ebx, 2
+        self.emit_jump8_back(0x74, read)?; // je .read
+        self.emit(&[0x85, 0xDB]);         // test ebx, ebx
+        let in_number = self.emit_jump8(0x75); // jnz .digit
+        self.emit(&[
+            0xBB, 0x01, 0x00, 0x00, 0x00, // mov ebx, 1
+            0x3C, 0x2D,                   // cmp al, '-'
+        ]);
+        let not_minus = self.emit_jump8(0x75); // jne .digit
+        self.emit(&[0x41, 0xBA, 0x01, 0x00, 0x00, 0x00]); // mov r10d, 1
+        self.emit_jump8_back(0xEB, read)?; // jmp .read
+        self.patch_jump8(in_number)?;
+        self.patch_jump8(not_minus)?;
+        self.emit(&[
+            0x83, 0xE8, 0x30,             // .digit: sub eax, '0'
+            0x83, 0xF8, 0x09,             // cmp eax, 9
+        ]);
+        let not_digit = self.emit_jump8(0x77); // ja .stop
+        self.emit(&[
+            0x4D, 0x6B, 0xC9, 0x0A,       // imul r9, r9, 10
+            0x49, 0x01, 0xC1,             // add r9, rax
+        ]);
+        self.emit_jump8_back(0xEB, read)?; // jmp .read
+        self.patch_jump8(not_digit)?;
+        self.emit(&[0xBB, 0x02, 0x00, 0x00, 0x00]); // .stop: mov ebx, 2
+        self.emit_jump8_back(0xEB, read)?; // jmp .read
+        self.patch_jump8(eof)?;
+        self.patch_jump8(newline)?;
+        self.emit(&[
+            0x4C, 0x89, 0xC8,             // .done: mov rax, r9
+            0x45, 0x85, 0xD2,             // test r10d, r10d
+            0x74, 0x03,                   // jz +3
+            0x48, 0xF7, 0xD8,             // neg rax
+            0x48, 0x63, 0xC0,             // movsxd rax, eax
+            0x4C, 0x89, 0xC4,             // mov rsp, r8
+            0x50,                         // push rax
+        ]);
+        Ok(())
+    }
+
+    // Short jumps inside the builtins: `opcode rel8` forward to a
+    // `patch_jump8`, or back to an earlier position.
+    fn emit_jump8(&mut self, opcode: u8) -> usize {
+        self.emit(&[opcode, 0]);
+        self.code.len() - 1
+    }
+
+    fn patch_jump8(&mut self, at: usize) -> Result<(), BackendError> {
+        let rel = (self.code.len() - (at + 1)) as i64;
+        self.code[at] = rel8(rel)? as u8;
+        Ok(())
+    }
+
+    fn emit_jump8_back(&mut self, opcode: u8, target: usize) -> Result<(), BackendError> {
+        let rel = rel8(target as i64 - (self.code.len() + 2) as i64)?;
+        self.emit(&[opcode, rel as u8]);
+        Ok(())
+    }
+
+    // Writes a Linux ELF64 executable with two PT_LOAD segments: `code`
+    // (R|X) at OFF_CODE, entered at main (the start of the code), and `data`
+    // (R|W) at DATA_VADDR. A section header table naming them `.text` and
+    // `.data`, with a symbol per function, follows for objdump, readelf and
+    // gdb; the loader ignores it.
     //
-    // Encodings (RIP-rel for lea will be patched):
-    let mut code: Vec<u8> = vec![
-        0x48, 0xC7, 0xC0, 0x01, 0x00, 0x00, 0x00,       // mov rax, 1
-        0x48, 0xC7, 0xC7, 0x01, 0x00, 0x00, 0x00,       // mov rdi, 1
-        0x48, 0x8D, 0x35, 0, 0, 0, 0,                   // lea rsi, [rip+disp32]  <-- patch
-        0x48, 0xC7, 0xC2, 0x06, 0x00, 0x00, 0x00,       // mov rdx, 6
-        0x0F, 0x05,                                     // syscall
-        0x48, 0xC7, 0xC0, 0x3C, 0x00, 0x00, 0x00,       // mov rax, 60
-        0x48, 0x31, 0xFF,                               // xor rdi, rdi
-        0x0F, 0x05,                                     // syscall
-    ];
-    let lea_disp32_offset_in_code = 12 + 3; // index where disp32 bytes start in the code vec
-
-    // The message in .rodata (right after code)
-    let msg = b"Hello\n";
-
-    // Compute RIP-relative displacement for LEA:
-    // disp32 = (addr(msg) - addr(next_instruction))
-    // file layout: [code][msg]
-    let code_start_file_off = OFF_SEG as usize;
-    let msg_file_off = code_start_file_off + code.len();
-    let lea_next_ip_file_off = code_start_file_off + (12 + 7); // at end of LEA instruction
-    let disp = (msg_file_off as i64) - (lea_next_ip_file_off as i64);
-    let disp_bytes = (disp as i32).to_le_bytes();
-    code[lea_disp32_offset_in_code + 0] = disp_bytes[0];
-    code[lea_disp32_offset_in_code + 1] = disp_bytes[1];
-    code[lea_disp32_offset_in_code + 2] = disp_bytes[2];
-    code[lea_disp32_offset_in_code + 3] = disp_bytes[3];
-
-    // Concatenate text+rodata blob
-    let mut seg: Vec<u8> = Vec::with_capacity(code.len() + msg.len());
-    seg.extend_from_slice(&code);
-    seg.extend_from_slice(msg);
-
-    // ---- ELF header (64 bytes) ---------------------------------------------
-    // e_ident
-    let mut elf: Vec<u8> = Vec::with_capacity(0x1000 + seg.len());
+    // A PIE is an ET_DYN without an interpreter (a static PIE), so nothing
+    // is relocated: the code only uses relative jumps and calls. Its data
+    // segment ends with a `.dynamic` holding just DF_1_PIE, which is how
+    // `file` tells it from a shared library.
+    //
+    // With `with_build_id`, a PT_NOTE points at the build id, written right
+    // after the program headers in the header page. No PT_LOAD maps that
+    // page, but tools (`readelf -n`, `file`) read notes from the file.
+    //
+    // `compile_program` rejects code that would overlap the data segment;
+    // code set by hand is refused here, with ErrorKind::InvalidInput.
+    pub fn generate_elf<P: AsRef<Path>>(&self, out_path: P) -> std::io::Result<()> {
+        if self.relocatable {
+            return self.generate_object(out_path);
+        }
+        if self.code.len() > MAX_CODE_BYTES {
+            let error = BackendError::CodeTooLarge(self.code.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, error.to_string()));
+        }
+        let vaddr_code = self.base() + OFF_CODE;
+        let vaddr_data = self.data_vaddr();
+        let off_data = (OFF_CODE + self.code.len() as u64).next_multiple_of(PAGE);
+        let off_dynamic = off_data + self.data.len().next_multiple_of(8) as u64;
+        let dynamic: &[u64] = if self.pie {
+            &[DT_FLAGS_1, DF_1_PIE, DT_NULL, 0]
+        } else {
+            &[]
+        };
+        let data_size = if self.pie {
+            (off_dynamic - off_data) as usize + 8 * dynamic.len()
+        } else {
+            self.data.len()
+        };
+        let note = self.build_id.then(|| build_id_note(&self.code, &self.data));
+        let phnum = 2 + self.pie as u16 + note.is_some() as u16;
+        let off_note = OFF_PROG_HDR + 56 * phnum as u64;
+        let mut elf: Vec<u8> = Vec::with_capacity(off_data as usize + data_size);
+
+        // ---- ELF header (64 bytes) -----------------------------------------
+        let e_type = if self.pie { ET_DYN } else { ET_EXEC };
+        elf_header(&mut elf, e_type, vaddr_code, phnum);
+
+        // ---- Program headers (56 bytes each) --------------------------------
+        program_header(&mut elf, PT_LOAD, 5, OFF_CODE, vaddr_code, self.code.len(), PAGE); // R | X
+        program_header(&mut elf, PT_LOAD, 6, off_data, vaddr_data, data_size, PAGE);        // R | W
+        let vaddr_dynamic = vaddr_data + (off_dynamic - off_data);
+        if self.pie {
+            program_header(&mut elf, PT_DYNAMIC, 6, off_dynamic, vaddr_dynamic, 8 * dynamic.len(), 8);
+        }
+        if let Some(note) = &note {
+            program_header(&mut elf, PT_NOTE, 4, off_note, self.base() + off_note, note.len(), 4); // R
+            elf.extend_from_slice(note);
+        }
+
+        // ---- Segments ------------------------------------------------------
+        elf.resize(OFF_CODE as usize, 0);
+        elf.extend_from_slice(&self.code);
+        elf.resize(off_data as usize, 0);
+        elf.extend_from_slice(&self.data);
+        elf.resize(off_dynamic as usize, 0);
+        for entry in dynamic {
+            elf.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        // ---- Sections ------------------------------------------------------
+        let mut sections = vec![
+            Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, vaddr_code, OFF_CODE, self.rodata_start, 16),
+        ];
+        if self.rodata_start < self.code.len() {
+            let (vaddr, offset) = (vaddr_code + self.rodata_start as u64, OFF_CODE + self.rodata_start as u64);
+            sections.push(Section::new(".rodata", SHT_PROGBITS, SHF_ALLOC, vaddr, offset, self.code.len() - self.rodata_start, 8));
+        }
+        if !self.data.is_empty() {
+            sections.push(Section::new(".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, vaddr_data, off_data, self.data.len(), 8));
+        }
+        if let Some(note) = &note {
+            sections.push(Section::new(".note.gnu.build-id", SHT_NOTE, 0, 0, off_note, note.len(), 4));
+        }
+        if self.pie {
+            let mut dynamic_section = Section::new(".dynamic", SHT_DYNAMIC, SHF_ALLOC | SHF_WRITE, vaddr_dynamic, off_dynamic, 8 * dynamic.len(), 8);
+            dynamic_section.link = sections.len() as u32 + 3; // .strtab, after .dynamic and .symtab
+            dynamic_section.entsize = 16;
+            sections.push(dynamic_section);
+        }
+
+        let (symtab, strtab, n_local, _) = self.symbols(vaddr_code, &[]);
+        elf.resize(elf.len().next_multiple_of(8), 0);
+        let mut symtab_section = Section::new(".symtab", SHT_SYMTAB, 0, 0, elf.len() as u64, symtab.len(), 8);
+        symtab_section.link = sections.len() as u32 + 2; // .strtab, after the null section and .symtab
+        symtab_section.info = n_local as u32;             // index of the first global
+        symtab_section.entsize = 24;
+        elf.extend_from_slice(&symtab);
+        sections.push(symtab_section);
+        sections.push(Section::new(".strtab", SHT_STRTAB, 0, 0, elf.len() as u64, strtab.len(), 1));
+        elf.extend_from_slice(&strtab);
+        section_headers(&mut elf, sections);
+
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o755); // rwxr-xr-x
+        let mut f = options.open(&out_path)?;
+        f.write_all(&elf)?;
+        f.flush()?;
+        if self.write_source_map {
+            let mut map = out_path.as_ref().as_os_str().to_owned();
+            map.push(".map");
+            std::fs::write(map, self.source_map())?;
+        }
+        Ok(())
+    }
+
+    // Copies the code and data into an anonymous mapping laid out as a PIE
+    // is loaded, code R|X at OFF_CODE and data R|W at DATA_VADDR from its
+    // start, so the program can be called without writing a file. After
+    // the code goes an entry stub that keeps rbx, which the code uses but
+    // the C calling convention preserves, and enters main with rsp aligned
+    // as at a process's entry point.
+    //
+    // Code compiled without `in_process` is refused, with
+    // ErrorKind::InvalidInput: its main would exit the caller.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn map_executable(&self) -> std::io::Result<Executable> {
+        let entry = OFF_CODE as usize + self.code.len();
+        let mut stub = vec![
+            0x53,                   // push rbx
+            0x48, 0x83, 0xEC, 0x08, // sub rsp, 8
+            0xE8,                   // call main
+        ];
+        stub.extend_from_slice(&(OFF_CODE as i32 - (entry + 10) as i32).to_le_bytes());
+        stub.extend_from_slice(&[
+            0x48, 0x83, 0xC4, 0x08, // add rsp, 8
+            0x5B,                   // pop rbx
+            0xC3,                   // ret
+        ]);
+        if !self.in_process || self.code.len() + stub.len() > MAX_CODE_BYTES {
+            let why = if self.in_process {
+                BackendError::CodeTooLarge(self.code.len()).to_string()
+            } else {
+                "compiled without `in_process`, main would exit".to_string()
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, why));
+        }
+
+        let off_data = (DATA_VADDR - BASE_VADDR) as usize;
+        let len = off_data + self.data.len().next_multiple_of(PAGE as usize);
+        // SAFETY: a fresh private mapping of `len` bytes, written only within
+        // its bounds, then made executable where the code is.
+        unsafe {
+            let base = mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if base as isize == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mapped = Executable { base: base.cast(), len, entry };
+            let at = |offset: usize| mapped.base.add(offset);
+            std::ptr::copy_nonoverlapping(self.code.as_ptr(), at(OFF_CODE as usize), self.code.len());
+            std::ptr::copy_nonoverlapping(stub.as_ptr(), at(entry), stub.len());
+            std::ptr::copy_nonoverlapping(self.data.as_ptr(), at(off_data), self.data.len());
+            if mprotect(base, off_data, PROT_READ | PROT_EXEC) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(mapped)
+        }
+    }
+
+    // Writes a relocatable object file (ET_REL): `.text` with a symbol per
+    // function, `main` global and the rest local, `.data`, and in
+    // `.rela.text` a relocation for each call between functions and each
+    // RIP-relative reference to data. Jumps within a function and to the
+    // trap stubs after the code stay relative inside `.text` and need
+    // none, as do loads from the const arrays, which `.text` ends with. There is no `_start`: link with `ld -e main`, and main exits
+    // the process as it does in an executable. No build id is written;
+    // `ld --build-id` adds one.
+    pub fn generate_object<P: AsRef<Path>>(&self, out_path: P) -> std::io::Result<()> {
+        const TEXT: u16 = 1;
+        const DATA: u16 = 2;
+        let mut elf = Vec::new();
+        elf_header(&mut elf, ET_REL, 0, 0);
+
+        elf.resize(elf.len().next_multiple_of(16), 0);
+        let mut sections = vec![Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 0, elf.len() as u64, self.code.len(), 16)];
+        elf.extend_from_slice(&self.code);
+        elf.resize(elf.len().next_multiple_of(8), 0);
+        sections.push(Section::new(".data", SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, 0, elf.len() as u64, self.data.len(), 8));
+        elf.extend_from_slice(&self.data);
+
+        // section symbols 1 and 2 stand for .text and .data
+        let (symtab, strtab, n_local, func_symbols) = self.symbols(0, &[TEXT, DATA]);
+        let calls = self.call_fixups.iter()
+            .map(|&(at, callee)| (at, func_symbols[callee], R_X86_64_PLT32, 0));
+        let data = self.data_fixups.iter()
+            .map(|&(at, offset)| (at, DATA as u32, R_X86_64_PC32, offset as i64));
+        let mut relocations: Vec<_> = calls.chain(data).collect();
+        relocations.sort_unstable();
+        elf.resize(elf.len().next_multiple_of(8), 0);
+        let mut rela = Section::new(".rela.text", SHT_RELA, SHF_INFO_LINK, 0, elf.len() as u64, 24 * relocations.len(), 8);
+        rela.link = 4;              // .symtab
+        rela.info = TEXT as u32;    // relocates .text
+        rela.entsize = 24;
+        sections.push(rela);
+        for (at, symbol, kind, addend) in relocations {
+            elf.extend_from_slice(&u64::to_le_bytes(at as u64));                       // r_offset
+            elf.extend_from_slice(&u64::to_le_bytes((symbol as u64) << 32 | kind as u64)); // r_info
+            elf.extend_from_slice(&i64::to_le_bytes(addend - 4)); // r_addend: a rel32 counts from its end
+        }
+
+        let mut symtab_section = Section::new(".symtab", SHT_SYMTAB, 0, 0, elf.len() as u64, symtab.len(), 8);
+        symtab_section.link = 5;            // .strtab
+        symtab_section.info = n_local as u32; // index of the first global
+        symtab_section.entsize = 24;
+        elf.extend_from_slice(&symtab);
+        sections.push(symtab_section);
+        sections.push(Section::new(".strtab", SHT_STRTAB, 0, 0, elf.len() as u64, strtab.len(), 1));
+        elf.extend_from_slice(&strtab);
+        section_headers(&mut elf, sections);
+        std::fs::write(out_path, elf)
+    }
+
+    // The .symtab entries (24 bytes each) and their .strtab names: an
+    // STT_SECTION symbol for each of `sections`, then an STT_FUNC symbol
+    // per function, spanning its code in .text (section 1), which starts
+    // at `text_addr`. Locals must come first, so `main`, the only global,
+    // is last; also returns the number of locals, counting the null
+    // symbol, and the symbol index of each function, by function index.
+    fn symbols(&self, text_addr: u64, sections: &[u16]) -> (Vec<u8>, Vec<u8>, usize, Vec<u32>) {
+        let mut funcs: Vec<(&str, usize, usize)> = self.listing.iter()
+            .filter_map(|l| match l {
+                Listed::Func { index, name, .. } => Some((name.as_str(), *index, self.func_offsets[*index])),
+                _ => None,
+            })
+            .collect();
+        funcs.sort_by_key(|&(name, _, offset)| (name == "main", offset));
+
+        let mut symtab = vec![0u8; 24];
+        let mut strtab = vec![0u8];
+        for &section in sections {
+            symtab.extend_from_slice(&u32::to_le_bytes(0));              // st_name
+            symtab.push(STB_LOCAL << 4 | STT_SECTION);                   // st_info
+            symtab.push(0);                                              // st_other
+            symtab.extend_from_slice(&u16::to_le_bytes(section));        // st_shndx
+            symtab.extend_from_slice(&[0; 16]);                          // st_value, st_size
+        }
+        let mut func_symbols = vec![0; self.func_offsets.len()];
+        for (i, &(name, index, offset)) in funcs.iter().enumerate() {
+            func_symbols[index] = (1 + sections.len() + i) as u32;
+            let end = self.func_offsets.iter().copied().filter(|&o| o > offset).min().unwrap_or(self.code.len());
+            let bind = if name == "main" { STB_GLOBAL } else { STB_LOCAL };
+            symtab.extend_from_slice(&u32::to_le_bytes(strtab.len() as u32)); // st_name
+            symtab.push(bind << 4 | STT_FUNC);                                 // st_info
+            symtab.push(0);                                                    // st_other
+            symtab.extend_from_slice(&u16::to_le_bytes(1));                    // st_shndx = .text
+            symtab.extend_from_slice(&u64::to_le_bytes(text_addr + offset as u64)); // st_value
+            symtab.extend_from_slice(&u64::to_le_bytes((end - offset) as u64)); // st_size
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let n_local = 1 + sections.len() + funcs.iter().filter(|&&(name, ..)| name != "main").count();
+        (symtab, strtab, n_local, func_symbols)
+    }
+
+    // Where in the source each piece of code comes from, for turning an
+    // address (a fault's, say) back into a line: one `address line:column`
+    // line per run of code from the same statement or operator, in address
+    // order, each covering the code up to the next. Addresses are as
+    // linked, so relative to the load base in a PIE.
+    pub fn source_map(&self) -> String {
+        self.lines.iter()
+            .map(|(offset, span)| format!("{:x} {}:{}\n", self.base() + OFF_CODE + *offset as u64, span.line, span.col))
+            .collect()
+    }
+
+    // The machine code decoded back by `Disassembler`, one line per
+    // instruction with its address and bytes, under each function's name.
+    pub fn disassemble(&self) -> String {
+        let symbols = self.listing.iter()
+            .filter_map(|l| match l {
+                Listed::Func { index, name, .. } => {
+                    Some((self.base() + OFF_CODE + self.func_offsets[*index] as u64, name.clone()))
+                }
+                Listed::TrapStub { offset, trap } => Some((self.base() + OFF_CODE + *offset as u64, trap.symbol().to_string())),
+                _ => None,
+            })
+            .collect();
+        Disassembler::new(&self.code[..self.rodata_start], self.base() + OFF_CODE).with_symbols(symbols).listing()
+    }
+
+    // AT&T-syntax listing of the compiled code, instruction for instruction
+    // what `compile_instr` encoded, for comparing with `objdump -d`. Each IR
+    // instruction is introduced by a comment with its code offset. The
+    // const arrays close it, in `.rodata`.
+    pub fn emit_asm(&self) -> String {
+        let names: HashMap<usize, &str> = self.listing.iter()
+            .filter_map(|l| match l {
+                Listed::Func { index, name, .. } => Some((*index, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        let mut out = String::new();
+        let mut func = 0;
+        let mut is_main = false;
+        let mut frame = Frame::Rbp;
+        let mut canary = None;
+        for l in &self.listing {
+            let lines = match l {
+                Listed::Func { index, name, n_locals, n_params, is_main: main, frame: f, canary: c } => {
+                    func = *index;
+                    is_main = *main;
+                    frame = *f;
+                    if !out.is_empty() {
+                        out.push('\n');
+                    }
+                    canary = *c;
+                    out.push_str(&format!("{name}:\n"));
+                    let mut lines = prologue_asm(frame, *n_locals, *n_params);
+                    if let Some(slot) = canary {
+                        lines.push(format!("movabs ${CANARY}, %rax"));
+                        lines.push(format!("mov %rax, {}", frame.operand(slot, 0, false)));
+                    }
+                    lines
+                }
+                Listed::Instr { offset, instr, depth } => {
+                    out.push_str(&format!("    # {:#x}: {:?}\n", self.base() + OFF_CODE + *offset as u64, instr));
+                    let mut lines = match instr {
+                        Instr::Print | Instr::PrintUnsigned if self.simple_print => {
+                            simple_print_asm(*instr == Instr::PrintUnsigned)
+                        }
+                        _ => instr_asm(instr, &self.strings, func, &names, &self.data_labels, &self.rodata_labels, (*depth, is_main, frame)),
+                    };
+                    if let (Instr::Ret, Some(slot)) = (instr, canary) {
+                        let check = [
+                            format!("mov {}, %rax", frame.operand(slot, *depth, false)),
+                            format!("movabs ${CANARY}, %rcx"),
+                            "cmp %rcx, %rax".into(), "je 1f".into(), "ud2".into(), "1:".into(),
+                        ];
+                        lines.splice(0..0, check);
+                    }
+                    if self.checked { with_overflow_check(instr, lines) } else { lines }
+                }
+                Listed::Fused { offset, imm, instr, depth } => {
+                    out.push_str(&format!("    # {:#x}: PushI32({imm}), {:?}\n", self.base() + OFF_CODE + *offset as u64, instr));
+                    let lines = fused_asm(*imm, instr, frame, *depth);
+                    // a shift sets no meaningful OF, and needs no `jo`
+                    let checked_as = if shift(*imm).is_some() && *instr == Instr::Mul { &Instr::Div } else { instr };
+                    if self.checked && *instr != Instr::Div { with_overflow_check(checked_as, lines) } else { lines }
+                }
+                Listed::TrapStub { trap, .. } => {
+                    out.push_str(&format!("\n{}:\n", trap.symbol()));
+                    vec![
+                        "mov $1, %eax".into(),
+                        "mov $2, %edi".into(),
+                        format!("lea {}(%rip), %rsi", trap.label()),
+                        format!("mov ${}, %edx", trap.message().len()),
+                        "syscall".into(),
+                        "mov $60, %eax".into(),
+                        "mov $70, %edi".into(),
+                        "syscall".into(),
+                    ]
+                }
+            };
+            for line in lines {
+                if line.ends_with(':') {
+                    out.push_str(&format!("{line}\n"));
+                } else {
+                    out.push_str(&format!("    {line}\n"));
+                }
+            }
+        }
+        let rodata = &self.code[self.rodata_start..];
+        for (i, (offset, name)) in self.rodata_labels.iter().enumerate() {
+            if i == 0 {
+                out.push_str("\n.section .rodata\n");
+            }
+            let end = self.rodata_labels.get(i + 1).map_or(rodata.len(), |(next, _)| *next);
+            let values: Vec<String> = rodata[*offset..end].chunks(8)
+                .map(|v| i64::from_le_bytes(v.try_into().unwrap()).to_string())
+                .collect();
+            out.push_str(&format!("{name}:\n    .quad {}\n", values.join(", ")));
+        }
+        out
+    }
+}
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_FLAGS_1: u64 = 0x6FFF_FFFB;
+const DF_1_PIE: u64 = 0x0800_0000;
+
+// Program header for `size` bytes at file offset `offset`, mapped at `vaddr`
+// The 64-byte ELF header of an x86-64 file of type `e_type`, with `phnum`
+// program headers right after it. The section header table's offset,
+// count and name table are filled in by `section_headers`.
+fn elf_header(elf: &mut Vec<u8>, e_type: u16, entry: u64, phnum: u16) {
     elf.extend_from_slice(&[
         0x7F, b'E', b'L', b'F',   // EI_MAG
         0x02,                     // EI_CLASS = ELFCLASS64
         0x01,                     // EI_DATA = little-endian
         0x01,                     // EI_VERSION
         0x00,                     // EI_OSABI = System V
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // EI_PAD (7 bytes)
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // EI_PAD
     ]);
-    // e_type (ET_EXEC), e_machine (EM_X86_64), e_version
-    elf.extend_from_slice(&u16::to_le_bytes(2));       // e_type = 2 (ET_EXEC)
-    elf.extend_from_slice(&u16::to_le_bytes(0x3E));    // e_machine = 62 (x86-64)
-    elf.extend_from_slice(&u32::to_le_bytes(1));       // e_version = EV_CURRENT
-
-    // e_entry
-    let entry = VADDR_SEG; // entry at start of segment (code begins there)
-    elf.extend_from_slice(&u64::to_le_bytes(entry));
-
-    // e_phoff (program header offset), e_shoff (no sections -> 0)
-    elf.extend_from_slice(&u64::to_le_bytes(OFF_PROG_HDR));
-    elf.extend_from_slice(&u64::to_le_bytes(0)); // e_shoff
-
-    // e_flags
-    elf.extend_from_slice(&u32::to_le_bytes(0));
-
-    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
-    elf.extend_from_slice(&u16::to_le_bytes(64));  // e_ehsize
-    elf.extend_from_slice(&u16::to_le_bytes(56));  // e_phentsize
-    elf.extend_from_slice(&u16::to_le_bytes(1));   // e_phnum (1 segment)
-    elf.extend_from_slice(&u16::to_le_bytes(0));   // e_shentsize
-    elf.extend_from_slice(&u16::to_le_bytes(0));   // e_shnum
-    elf.extend_from_slice(&u16::to_le_bytes(0));   // e_shstrndx
-
-    // Pad to program header offset (0x40)
-    while elf.len() < OFF_PROG_HDR as usize {
-        elf.push(0);
-    }
-
-    // ---- Program header (56 bytes) -----------------------------------------
-    let p_type   = 1u32; // PT_LOAD
-    let p_flags  = 5u32; // R | X  (read + execute)
-    let p_offset = OFF_SEG;
-    let p_vaddr  = VADDR_SEG;
-    let p_paddr  = VADDR_SEG;
-    let p_filesz = seg.len() as u64;
-    let p_memsz  = p_filesz; // no bss
-    let p_align  = 0x1000u64;
-
-    elf.extend_from_slice(&u32::to_le_bytes(p_type));
-    elf.extend_from_slice(&u32::to_le_bytes(p_flags));
-    elf.extend_from_slice(&u64::to_le_bytes(p_offset));
-    elf.extend_from_slice(&u64::to_le_bytes(p_vaddr));
-    elf.extend_from_slice(&u64::to_le_bytes(p_paddr));
-    elf.extend_from_slice(&u64::to_le_bytes(p_filesz));
-    elf.extend_from_slice(&u64::to_le_bytes(p_memsz));
-    elf.extend_from_slice(&u64::to_le_bytes(p_align));
-
-    // ---- Pad to segment start (0x1000) -------------------------------------
-    while elf.len() < OFF_SEG as usize {
-        elf.push(0);
-    }
-
-    // ---- Append code+data segment ------------------------------------------
-    elf.extend_from_slice(&seg);
-
-    // ---- Write file and mark executable ------------------------------------
-    let mut f = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .mode(0o755) // rwxr-xr-x
-        .open(out_path)?;
-    f.write_all(&elf)?;
-    f.flush()?;
-    f.seek(SeekFrom::Start(0))?;
-    Ok(())
+    let phoff = if phnum > 0 { OFF_PROG_HDR } else { 0 };
+    elf.extend_from_slice(&u16::to_le_bytes(e_type));     // e_type
+    elf.extend_from_slice(&u16::to_le_bytes(0x3E));       // e_machine = x86-64
+    elf.extend_from_slice(&u32::to_le_bytes(1));          // e_version
+    elf.extend_from_slice(&u64::to_le_bytes(entry));      // e_entry
+    elf.extend_from_slice(&u64::to_le_bytes(phoff));      // e_phoff
+    elf.extend_from_slice(&u64::to_le_bytes(0));          // e_shoff, patched by `section_headers`
+    elf.extend_from_slice(&u32::to_le_bytes(0));          // e_flags
+    elf.extend_from_slice(&u16::to_le_bytes(64));         // e_ehsize
+    elf.extend_from_slice(&u16::to_le_bytes(if phnum > 0 { 56 } else { 0 })); // e_phentsize
+    elf.extend_from_slice(&u16::to_le_bytes(phnum));      // e_phnum
+    elf.extend_from_slice(&u16::to_le_bytes(64));         // e_shentsize
+    elf.extend_from_slice(&u16::to_le_bytes(0));          // e_shnum, patched by `section_headers`
+    elf.extend_from_slice(&u16::to_le_bytes(0));          // e_shstrndx, patched by `section_headers`
+}
+
+// Appends `.shstrtab` naming `sections` and itself, then the section
+// header table (64 bytes each, index 0 is SHN_UNDEF), and points the ELF
+// header at them.
+fn section_headers(elf: &mut Vec<u8>, mut sections: Vec<Section>) {
+    let mut shstrtab = vec![0u8];
+    for s in &mut sections {
+        s.name = shstrtab.len() as u32;
+        shstrtab.extend_from_slice(s.label.as_bytes());
+        shstrtab.push(0);
+    }
+    let mut strtab_section = Section::new(".shstrtab", SHT_STRTAB, 0, 0, elf.len() as u64, 0, 1);
+    strtab_section.name = shstrtab.len() as u32;
+    shstrtab.extend_from_slice(b".shstrtab\0");
+    strtab_section.size = shstrtab.len() as u64;
+    elf.extend_from_slice(&shstrtab);
+    sections.push(strtab_section);
+
+    elf.resize(elf.len().next_multiple_of(8), 0);
+    let shoff = elf.len() as u64;
+    elf.extend_from_slice(&[0; 64]);
+    for s in &sections {
+        s.write_header(elf);
+    }
+    elf[0x28..0x30].copy_from_slice(&shoff.to_le_bytes());                         // e_shoff
+    elf[0x3C..0x3E].copy_from_slice(&(sections.len() as u16 + 1).to_le_bytes());  // e_shnum
+    elf[0x3E..0x40].copy_from_slice(&(sections.len() as u16).to_le_bytes());      // e_shstrndx
+}
+
+fn program_header(elf: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, vaddr: u64, size: usize, align: u64) {
+    elf.extend_from_slice(&u32::to_le_bytes(kind));         // p_type
+    elf.extend_from_slice(&u32::to_le_bytes(flags));        // p_flags
+    elf.extend_from_slice(&u64::to_le_bytes(offset));       // p_offset
+    elf.extend_from_slice(&u64::to_le_bytes(vaddr));        // p_vaddr
+    elf.extend_from_slice(&u64::to_le_bytes(vaddr));        // p_paddr
+    elf.extend_from_slice(&u64::to_le_bytes(size as u64));  // p_filesz
+    elf.extend_from_slice(&u64::to_le_bytes(size as u64));  // p_memsz
+    elf.extend_from_slice(&u64::to_le_bytes(align));        // p_align
+}
+
+// An ELF note holding the build id: its name and descriptor sizes, its
+// type, the name "GNU" and, as the descriptor, the 64-bit FNV-1a hash of
+// `code` followed by `data`. Every field is 4-byte aligned already.
+fn build_id_note(code: &[u8], data: &[u8]) -> Vec<u8> {
+    let hash = code.iter().chain(data).fold(0xCBF2_9CE4_8422_2325u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01B3)
+    });
+    let mut note = Vec::with_capacity(24);
+    note.extend_from_slice(&u32::to_le_bytes(4));               // n_namesz
+    note.extend_from_slice(&u32::to_le_bytes(8));               // n_descsz
+    note.extend_from_slice(&u32::to_le_bytes(NT_GNU_BUILD_ID)); // n_type
+    note.extend_from_slice(b"GNU\0");
+    note.extend_from_slice(&hash.to_be_bytes());
+    note
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_DYNAMIC: u32 = 6;
+const SHT_RELA: u32 = 4;
+const SHT_NOTE: u32 = 7;
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+// A section header table entry; `name` is the label's offset in .shstrtab.
+struct Section {
+    label: &'static str,
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl Section {
+    fn new(label: &'static str, kind: u32, flags: u64, addr: u64, offset: u64, size: usize, align: u64) -> Self {
+        Section { label, name: 0, kind, flags, addr, offset, size: size as u64, link: 0, info: 0, align, entsize: 0 }
+    }
+
+    fn write_header(&self, elf: &mut Vec<u8>) {
+        elf.extend_from_slice(&u32::to_le_bytes(self.name));   // sh_name
+        elf.extend_from_slice(&u32::to_le_bytes(self.kind));   // sh_type
+        elf.extend_from_slice(&u64::to_le_bytes(self.flags));  // sh_flags
+        elf.extend_from_slice(&u64::to_le_bytes(self.addr));   // sh_addr
+        elf.extend_from_slice(&u64::to_le_bytes(self.offset)); // sh_offset
+        elf.extend_from_slice(&u64::to_le_bytes(self.size));   // sh_size
+        elf.extend_from_slice(&u32::to_le_bytes(self.link));   // sh_link
+        elf.extend_from_slice(&u32::to_le_bytes(self.info));   // sh_info
+        elf.extend_from_slice(&u64::to_le_bytes(self.align));  // sh_addralign
+        elf.extend_from_slice(&u64::to_le_bytes(self.entsize)); // sh_entsize
+    }
+}
+
+// The AT&T counterparts of `emit_prologue` and `compile_instr`; IR labels
+// become `.L<function>_<id>`, and the builtins use numeric local labels.
+fn prologue_asm(frame: Frame, n_locals: usize, n_params: usize) -> Vec<String> {
+    let (mut lines, base) = match frame {
+        Frame::Rbp => (strs(&["push %rbp", "mov %rsp, %rbp"]), "%rbp"),
+        Frame::Rsp { .. } => (Vec::new(), "%rsp"),
+    };
+    if n_locals > 0 {
+        lines.push(format!("sub ${}, %rsp", n_locals * 8));
+    }
+    for i in 0..n_params {
+        let arg = frame.arg_disp(i, n_params).expect("compiled, so in range");
+        lines.push(format!("mov {arg}({base}), %rax"));
+        lines.push(format!("mov %rax, {}", frame.operand(i, 0, false)));
+    }
+    lines
+}
+
+// `globals` are the data labels, which start with one per global in order,
+// and `arrays` those of the const arrays; `strings` gives the label of each
+// string literal. The instruction runs at operand-stack `depth`, in main or
+// not, with locals in `frame`.
+fn instr_asm(
+    instr: &Instr,
+    strings: &HashMap<Vec<u8>, String>,
+    func: usize,
+    names: &HashMap<usize, &str>,
+    globals: &[(usize, String)],
+    arrays: &[(usize, String)],
+    (depth, is_main, frame): (usize, bool, Frame),
+) -> Vec<String> {
+    match instr {
+        Instr::PushI32(v) => vec![format!("push ${v}")],
+        Instr::PushI64(v) if i32::try_from(*v).is_ok() => vec![format!("push ${v}")],
+        Instr::PushI64(v) => vec![format!("movabs ${v}, %rax"), "push %rax".into()],
+        Instr::Pop => vec!["pop %rax".into()],
+        Instr::Dup => vec!["pushq (%rsp)".into()],
+        Instr::Swap => strs(&["pop %rax", "pop %rbx", "push %rax", "push %rbx"]),
+        Instr::Load(slot) => vec![format!("mov {}, %rax", frame.operand(*slot, depth, false)), "push %rax".into()],
+        Instr::Store(slot) => vec!["pop %rax".into(), format!("mov %rax, {}", frame.operand(*slot, depth - 1, false))],
+        Instr::LoadIndex(base, len) => vec![
+            "pop %rax".into(), format!("cmp ${len}, %rax"), "jae __bounds".into(), "neg %rax".into(),
+            format!("mov {}, %rax", frame.operand(*base, depth - 1, true)), "push %rax".into(),
+        ],
+        Instr::StoreIndex(base, len) => vec![
+            "pop %rbx".into(), "pop %rax".into(), format!("cmp ${len}, %rax"), "jae __bounds".into(), "neg %rax".into(),
+            format!("mov %rbx, {}", frame.operand(*base, depth - 2, true)),
+        ],
+        Instr::LoadGlobal(index) => vec![format!("mov {}(%rip), %rax", globals[*index].1), "push %rax".into()],
+        Instr::StoreGlobal(index) => vec!["pop %rax".into(), format!("mov %rax, {}(%rip)", globals[*index].1)],
+        Instr::LoadConstIndex(array, len) => vec![
+            "pop %rax".into(), format!("cmp ${len}, %rax"), "jae __bounds".into(),
+            format!("lea {}(%rip), %rbx", arrays[*array].1), "mov (%rbx,%rax,8), %rax".into(), "push %rax".into(),
+        ],
+
+        Instr::Add => binop_asm("add %rbx, %rax"),
+        Instr::Sub => binop_asm("sub %rbx, %rax"),
+        Instr::Mul => binop_asm("imul %rbx, %rax"),
+        Instr::Div | Instr::Mod => {
+            let mut lines = strs(&["pop %rbx", "pop %rax", "cqto", "idiv %rbx"]);
+            if matches!(instr, Instr::Mod) {
+                lines.push("push %rdx".into());
+            } else {
+                lines.extend(strs(&["movslq %eax, %rax", "push %rax"]));
+            }
+            lines
+        }
+        Instr::Neg => strs(&["pop %rax", "neg %rax", "movslq %eax, %rax", "push %rax"]),
+        Instr::AddI64 => strs(&["pop %rbx", "pop %rax", "add %rbx, %rax", "push %rax"]),
+        Instr::SubI64 => strs(&["pop %rbx", "pop %rax", "sub %rbx, %rax", "push %rax"]),
+        Instr::MulI64 => strs(&["pop %rbx", "pop %rax", "imul %rbx, %rax", "push %rax"]),
+        Instr::DivI64 => strs(&[
+            "pop %rbx", "pop %rax", "cmp $-1, %rbx", "jne 1f", "neg %rax", "jmp 2f",
+            "1:", "cqto", "idiv %rbx", "2:", "push %rax",
+        ]),
+        Instr::ModI64 => strs(&[
+            "pop %rbx", "pop %rax", "cmp $-1, %rbx", "jne 1f", "xor %edx, %edx", "jmp 2f",
+            "1:", "cqto", "idiv %rbx", "2:", "push %rdx",
+        ]),
+        Instr::NegI64 => strs(&["pop %rax", "neg %rax", "push %rax"]),
+        Instr::Narrow => strs(&["pop %rax", "movslq %eax, %rax", "push %rax"]),
+        Instr::Not => strs(&["pop %rax", "test %rax, %rax", "sete %al", "movzbl %al, %eax", "push %rax"]),
+        Instr::CmpLt => cmp_asm("setl"),
+        Instr::CmpGt => cmp_asm("setg"),
+        Instr::CmpLe => cmp_asm("setle"),
+        Instr::CmpGe => cmp_asm("setge"),
+        Instr::CmpEq => cmp_asm("sete"),
+        Instr::CmpNe => cmp_asm("setne"),
+
+        Instr::Label(id) => vec![format!(".L{func}_{id}:")],
+        Instr::Jump(id) => vec![format!("jmp .L{func}_{id}")],
+        Instr::JumpIfZero(id) => vec!["pop %rax".into(), "test %rax, %rax".into(), format!("je .L{func}_{id}")],
+        Instr::JumpIfNonZero(id) => vec!["pop %rax".into(), "test %rax, %rax".into(), format!("jne .L{func}_{id}")],
+
+        Instr::Print => print_asm(false),
+        Instr::PrintUnsigned => print_asm(true),
+        Instr::PrintNewline => strs(&[
+            "mov $1, %eax", "mov $1, %edi", "push $10", "mov %rsp, %rsi", "mov $1, %edx", "syscall", "pop %rax",
+        ]),
+        Instr::PrintStr(bytes) => vec![
+            "mov $1, %eax".into(),
+            "mov $1, %edi".into(),
+            format!("lea {}(%rip), %rsi", strings[bytes]),
+            format!("mov ${}, %edx", bytes.len()),
+            "syscall".into(),
+        ],
+        Instr::Input => strs(INPUT_ASM),
+        Instr::Perform(name, _) => vec![format!("# perform {name}: not supported natively")],
+        Instr::PushHandler(name, _) => vec![format!("# handle {name}: not supported natively")],
+        Instr::PopHandler => vec!["# end of handle: not supported natively".into()],
+
+        Instr::Call(callee, argc) => {
+            let mut lines = vec![format!("call {}", names[callee])];
+            if *argc > 0 {
+                lines.push(format!("add ${}, %rsp", argc * 8));
+            }
+            lines.push("push %rax".into());
+            lines
+        }
+        Instr::Ret => {
+            let (reg, zero) = if is_main { ("%rdi", "%edi") } else { ("%rax", "%eax") };
+            let mut lines = vec![if depth > 0 { format!("pop {reg}") } else { format!("xor {zero}, {zero}") }];
+            match frame {
+                _ if is_main => lines.extend(strs(&["mov $60, %eax", "syscall"])),
+                Frame::Rbp => lines.extend(strs(&["mov %rbp, %rsp", "pop %rbp", "ret"])),
+                Frame::Rsp { n_locals } => {
+                    let bytes = 8 * (n_locals + depth.saturating_sub(1));
+                    if bytes > 0 {
+                        lines.push(format!("add ${bytes}, %rsp"));
+                    }
+                    lines.push("ret".into());
+                }
+            }
+            lines
+        }
+    }
+}
+
+fn strs(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|l| l.to_string()).collect()
+}
+
+// Data label of a string first printed by the `PrintStr` compiled at code
+// offset `offset`
+fn string_label(offset: usize) -> String {
+    format!(".Lstr{offset:x}")
+}
+
+// log2 of a power of two in 2..=2^30, the constants a Mul or Div by which
+// `compile_fused` turns into a shift.
+fn shift(imm: i32) -> Option<u8> {
+    (imm > 1 && imm.count_ones() == 1).then(|| imm.trailing_zeros() as u8)
+}
+
+fn fused_asm(imm: i32, instr: &Instr, frame: Frame, depth: usize) -> Vec<String> {
+    let op = match instr {
+        Instr::Store(slot) => return vec![format!("movq ${imm}, {}", frame.operand(*slot, depth, false))],
+        Instr::Mul if let Some(k) = shift(imm) => format!("shl ${k}, %rax"),
+        Instr::Div => {
+            let k = shift(imm).expect("only powers of two are fused into a Div");
+            return vec![
+                "pop %rax".into(), "mov %rax, %rcx".into(), "sar $63, %rcx".into(), format!("shr ${}, %rcx", 64 - k),
+                "add %rcx, %rax".into(), format!("sar ${k}, %rax"), "push %rax".into(),
+            ];
+        }
+        Instr::Add => format!("add ${imm}, %rax"),
+        Instr::Sub => format!("sub ${imm}, %rax"),
+        Instr::Mul => format!("imul ${imm}, %rax, %rax"),
+        _ => unreachable!(),
+    };
+    vec!["pop %rax".into(), op, "movslq %eax, %rax".into(), "push %rax".into()]
+}
+
+// `emit_overflow_check`'s lines, ahead of the narrowing `movslq` of an
+// operation that can overflow
+fn with_overflow_check(instr: &Instr, mut lines: Vec<String>) -> Vec<String> {
+    let check: &[&str] = match instr {
+        Instr::Add | Instr::Sub | Instr::Mul | Instr::Neg => {
+            &["jo __overflow", "movslq %eax, %rcx", "cmp %rax, %rcx", "jne __overflow"]
+        }
+        Instr::Div => &["movslq %eax, %rcx", "cmp %rax, %rcx", "jne __overflow"],
+        // after the operation, or the negation by which DivI64 divides by -1
+        Instr::AddI64 | Instr::SubI64 | Instr::MulI64 | Instr::NegI64 | Instr::DivI64 => {
+            let at = lines.iter().position(|l| ["add", "sub", "imul", "neg"].iter().any(|op| l.starts_with(op)))
+                .expect("an operation that sets OF");
+            lines.insert(at + 1, "jo __overflow".into());
+            return lines;
+        }
+        _ => return lines,
+    };
+    let at = lines.iter().position(|l| l == "movslq %eax, %rax").expect("narrowed after the operation");
+    lines.splice(at..at, strs(check));
+    lines
+}
+
+fn binop_asm(op: &str) -> Vec<String> {
+    strs(&["pop %rbx", "pop %rax", op, "movslq %eax, %rax", "push %rax"])
+}
+
+fn cmp_asm(setcc: &str) -> Vec<String> {
+    vec![
+        "pop %rbx".into(), "pop %rax".into(), "cmp %rbx, %rax".into(),
+        format!("{setcc} %al"), "movzbl %al, %eax".into(), "push %rax".into(),
+    ]
+}
+
+fn print_asm(unsigned: bool) -> Vec<String> {
+    let mut lines = strs(&["pop %rax", "mov %rsp, %r8", "and $-16, %rsp"]);
+    lines.extend([format!("sub ${PRINT_BUFFER}, %rsp"), format!("lea {PRINT_BUFFER}(%rsp), %rsi")]);
+    if unsigned {
+        lines.push("mov %eax, %eax".into());
+    } else {
+        lines.extend(strs(&["mov %rax, %rcx", "test %rax, %rax", "jns 1f", "neg %rax", "1:"]));
+    }
+    lines.extend(strs(&[
+        "mov $10, %ebx",
+        "2:", "xor %edx, %edx", "div %rbx", "add $48, %dl", "dec %rsi", "mov %dl, (%rsi)",
+        "test %rax, %rax", "jnz 2b",
+    ]));
+    if !unsigned {
+        lines.extend(strs(&["test %rcx, %rcx", "jns 3f", "dec %rsi", "movb $45, (%rsi)", "3:"]));
+    }
+    lines.extend(strs(&["mov $1, %eax", "mov $1, %edi"]));
+    lines.push(format!("lea {PRINT_BUFFER}(%rsp), %rdx"));
+    lines.extend(strs(&["sub %rsi, %rdx", "syscall", "mov %r8, %rsp"]));
+    lines
+}
+
+fn simple_print_asm(unsigned: bool) -> Vec<String> {
+    let mut lines = strs(&["pop %rax", "mov %rsp, %r8", "and $-16, %rsp"]);
+    lines.push(format!("sub ${PRINT_BUFFER}, %rsp"));
+    lines.extend(strs(&["xor %r10d, %r10d", "xor %r9d, %r9d"]));
+    if unsigned {
+        lines.push("mov %eax, %eax".into());
+    } else {
+        lines.extend(strs(&["test %rax, %rax", "jns 1f", "movb $45, (%rsp)", "mov $1, %r10d", "neg %rax", "1:"]));
+    }
+    lines.extend(strs(&[
+        "movabs $1000000000000000000, %rbx",
+        "2:", "xor %edx, %edx", "div %rbx", "mov %rdx, %rsi", "mov %rax, %rcx", "or %rax, %r9",
+        "cmp $1, %rbx", "je 3f", "test %r9, %r9", "jz 4f",
+        "3:", "add $48, %cl", "mov %cl, (%rsp,%r10,1)", "inc %r10",
+        "4:", "mov %rbx, %rax", "xor %edx, %edx", "mov $10, %ecx", "div %rcx", "mov %rax, %rbx", "mov %rsi, %rax",
+        "test %rbx, %rbx", "jnz 2b",
+        "mov $1, %eax", "mov $1, %edi", "mov %rsp, %rsi", "mov %r10, %rdx", "syscall", "mov %r8, %rsp",
+    ]));
+    lines
+}
+
+const INPUT_ASM: &[&str] = &[
+    "mov %rsp, %r8", "and $-16, %rsp", "sub $16, %rsp",
+    "xor %r9d, %r9d", "xor %r10d, %r10d", "xor %ebx, %ebx",
+    "1:", "xor %eax, %eax", "xor %edi, %edi", "mov %rsp, %rsi", "mov $1, %edx", "syscall",
+    "test %rax, %rax", "jle 4f",
+    "movzbl (%rsp), %eax", "cmp $10, %al", "je 4f",
+    "cmp $2, %ebx", "je 1b",
+    "test %ebx, %ebx", "jnz 2f",
+    "mov $1, %ebx", "cmp $45, %al", "jne 2f", "mov $1, %r10d", "jmp 1b",
+    "2:", "sub $48, %eax", "cmp $9, %eax", "ja 3f", "imul $10, %r9, %r9", "add %rax, %r9", "jmp 1b",
+    "3:", "mov $2, %ebx", "jmp 1b",
+    "4:", "mov %r9, %rax", "test %r10d, %r10d", "jz 5f", "neg %rax",
+    "5:", "movslq %eax, %rax", "mov %r8, %rsp", "push %rax",
+];
+
+// [rbp + disp] for a local slot, which must lie in a frame of at most
+// MAX_FRAME_BYTES.
+fn rbp_disp(slot: usize) -> Result<i32, BackendError> {
+    if slot >= MAX_FRAME_BYTES / 8 {
+        return Err(BackendError::OutOfRange { what: "local slot", value: slot as i64 });
+    }
+    Ok(-8 * (slot as i32 + 1))
+}
+
+// `n` stack slots in bytes, for an imm32.
+fn frame_bytes(what: &'static str, n: usize) -> Result<i32, BackendError> {
+    i32::try_from(n).ok()
+        .and_then(|n| n.checked_mul(8))
+        .ok_or(BackendError::OutOfRange { what, value: n as i64 })
+}
+
+// A rel32 written at `at`, relative to the end of the field, to `target`.
+fn rel32(what: &'static str, at: usize, target: usize) -> Result<[u8; 4], BackendError> {
+    let rel = target as i64 - (at + 4) as i64;
+    i32::try_from(rel)
+        .map(i32::to_le_bytes)
+        .map_err(|_| BackendError::OutOfRange { what, value: rel })
+}
+
+fn rel8(rel: i64) -> Result<i8, BackendError> {
+    i8::try_from(rel).map_err(|_| BackendError::OutOfRange { what: "short jump", value: rel })
+}
+
+// Distance below rbp of a local slot
+fn slot_offset(slot: usize) -> i32 {
+    8 * (slot as i32 + 1)
+}
+
+// A program `map_executable` put in memory, unmapped when dropped.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub struct Executable {
+    base: *mut u8,
+    len: usize,
+    entry: usize, // offset of the entry stub
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+impl Executable {
+    // main, called through the entry stub: it returns main's value. Only
+    // valid while `self` is alive.
+    pub fn main(&self) -> unsafe extern "C" fn() -> i64 {
+        // SAFETY: the stub at `entry` is code that follows the C calling
+        // convention, in an executable page of the mapping.
+        unsafe { std::mem::transmute::<*mut u8, unsafe extern "C" fn() -> i64>(self.base.add(self.entry)) }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+impl Drop for Executable {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is ours and nothing can call into it after this.
+        unsafe {
+            munmap(self.base.cast(), self.len);
+        }
+    }
+}
+
+// The libc calls behind `map_executable`; std links libc on Linux.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const PROT_READ: i32 = 1;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const PROT_WRITE: i32 = 2;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const PROT_EXEC: i32 = 4;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const MAP_PRIVATE: i32 = 0x02;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const MAP_ANONYMOUS: i32 = 0x20;
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe extern "C" {
+    fn mmap(addr: *mut std::ffi::c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut std::ffi::c_void;
+    fn mprotect(addr: *mut std::ffi::c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut std::ffi::c_void, len: usize) -> i32;
 }
@@ -0,0 +1,82 @@
+// src/intern.rs
+// Interned identifiers. Each distinct name is stored once for the life of
+// the process and referred to by a `Sym`, so tokens and AST nodes copy names
+// without allocating, and lookups hash a u32 instead of the text.
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{LazyLock, Mutex};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Sym(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Sym>,
+    names: Vec<&'static str>, // Sym(i) -> its name
+}
+
+static INTERNER: LazyLock<Mutex<Interner>> = LazyLock::new(Default::default);
+
+impl Sym {
+    pub fn intern(name: &str) -> Sym {
+        let mut interner = INTERNER.lock().unwrap();
+        if let Some(&sym) = interner.ids.get(name) {
+            return sym;
+        }
+        // Names are never freed, so they can be handed out as 'static.
+        let name: &'static str = Box::leak(name.into());
+        let sym = Sym(interner.names.len() as u32);
+        interner.names.push(name);
+        interner.ids.insert(name, sym);
+        sym
+    }
+
+    pub fn as_str(self) -> &'static str {
+        INTERNER.lock().unwrap().names[self.0 as usize]
+    }
+
+    // How many distinct names have been interned so far.
+    pub fn count() -> usize {
+        INTERNER.lock().unwrap().names.len()
+    }
+}
+
+impl Deref for Sym {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Sym {
+    fn from(name: &str) -> Sym {
+        Sym::intern(name)
+    }
+}
+
+impl PartialEq<str> for Sym {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Sym {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// As the name's text, so `Ident("x")` reads as it did with a String.
+impl fmt::Debug for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
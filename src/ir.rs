@@ -1,37 +1,225 @@
 // src/ir.rs
-#[derive(Debug, Clone)]
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::span::Span;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instr {
     // stack ops
     PushI32(i32),
+    PushI64(i64), // a constant that doesn't fit in i32
     Pop,
+    Dup,  // push a copy of the top value
+    Swap, // exchange the top two values
 
     // locals
     Load(usize),   // push locals[idx]
     Store(usize),  // pop -> locals[idx]
+    LoadIndex(usize, usize),  // (base, len): pop i, push locals[base + i]; i must be in 0..len
+    StoreIndex(usize, usize), // (base, len): pop v, pop i, locals[base + i] = v
+
+    // globals, shared by every function
+    LoadGlobal(usize),  // push globals[idx]
+    StoreGlobal(usize), // pop -> globals[idx]
+
+    // read-only arrays, shared by every function
+    LoadConstIndex(usize, usize), // (array, len): pop i, push rodata[array][i]; i must be in 0..len
+
+    // arithmetic on i32, wrapping to its 32 bits
+    Add, Sub, Mul, Div, Mod,
+    Neg,
+    // and on i64, when either operand is one
+    AddI64, SubI64, MulI64, DivI64, ModI64,
+    NegI64,
+    Narrow, // keep the low 32 bits of an i64, sign-extended, for an i32 slot
+    Not, // push 1 if the operand is 0, else 0
 
-    // arithmetic
-    Add, Sub, Mul, Div,
+    // comparison (signed): pop b, pop a, push 1 if `a op b` else 0
+    CmpLt, CmpGt, CmpLe, CmpGe, CmpEq, CmpNe,
 
     // builtins
-    Print,         // pop & print as i32
+    Print,         // pop & print as i32, with no newline
+    PrintUnsigned, // pop & print the low 32 bits as u32, with no newline
+    PrintNewline,  // print '\n'
+    PrintStr(Vec<u8>), // write the bytes as they are
+    Input,         // read a line from stdin, push it parsed as i32 (0 if empty)
+
+    // effects
+    Perform(String, usize), // pop argc args, push the innermost handler's result
+    PushHandler(String, usize), // (effect, func index): the innermost handler of the effect calls the function
+    PopHandler,    // remove the innermost handler
+
+    // control flow; label ids are unique within a function
+    Label(u32),
+    Jump(u32),
+    JumpIfZero(u32), // pop; jump if it was 0
+    JumpIfNonZero(u32), // pop; jump if it wasn't 0
+
+    // calls
+    Call(usize, usize), // (func index, argc): pop argc args into the callee's first locals
+    Ret,           // pop the return value (0 if none) and push it for the caller
+}
+
+impl Instr {
+    // (values popped, values pushed)
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Instr::PushI32(_) | Instr::PushI64(_) | Instr::Load(_) | Instr::LoadGlobal(_) | Instr::Input => (0, 1),
+            Instr::Label(_) | Instr::Jump(_) | Instr::PrintNewline | Instr::PrintStr(_) => (0, 0),
+            Instr::PushHandler(..) | Instr::PopHandler => (0, 0),
+            Instr::JumpIfZero(_) | Instr::JumpIfNonZero(_) => (1, 0),
+            Instr::Neg | Instr::NegI64 | Instr::Narrow | Instr::Not | Instr::LoadIndex(..) | Instr::LoadConstIndex(..) => (1, 1),
+            Instr::StoreIndex(..) => (2, 0),
+            Instr::Dup => (1, 2),
+            Instr::Swap => (2, 2),
+            Instr::Pop | Instr::Store(_) | Instr::StoreGlobal(_) | Instr::Print | Instr::PrintUnsigned | Instr::Ret => (1, 0),
+            Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod
+            | Instr::AddI64 | Instr::SubI64 | Instr::MulI64 | Instr::DivI64 | Instr::ModI64
+            | Instr::CmpLt | Instr::CmpGt | Instr::CmpLe | Instr::CmpGe
+            | Instr::CmpEq | Instr::CmpNe => (2, 1),
+            Instr::Perform(_, argc) | Instr::Call(_, argc) => (*argc, 1),
+        }
+    }
+}
+
+// The operand-stack depth after `instr`, given the depth before it, as the
+// native backend sees it: code may jump forward or back to its own labels,
+// and a label resumes at the depth its jumps leave, recorded in `labels`,
+// rather than that of the code falling into it. Ret leaves the function, so
+// code after it starts from an empty stack.
+fn step_depth(instr: &Instr, depth: isize, labels: &mut HashMap<u32, isize>) -> isize {
+    let (pops, pushes) = instr.stack_effect();
+    match instr {
+        Instr::Jump(id) => {
+            labels.entry(*id).or_insert(depth);
+            depth
+        }
+        Instr::JumpIfZero(id) | Instr::JumpIfNonZero(id) => {
+            labels.entry(*id).or_insert(depth - 1);
+            depth - 1
+        }
+        Instr::Label(id) => *labels.entry(*id).or_insert(depth),
+        Instr::Ret => 0,
+        _ => depth - pops as isize + pushes as isize,
+    }
+}
+
+// Values `code` leaves on the operand stack, negative if it pops ones that
+// were there before.
+pub fn net_depth(code: &[Instr]) -> isize {
+    let mut labels = HashMap::new();
+    code.iter().fold(0, |depth, instr| step_depth(instr, depth, &mut labels))
+}
+
+// Peak operand-stack depth reached on any path.
+pub fn max_stack_depth(code: &[Instr]) -> usize {
+    let mut labels = HashMap::new();
+    let mut depth = 0;
+    let mut max = 0;
+    for instr in code {
+        depth = step_depth(instr, depth, &mut labels);
+        max = max.max(depth);
+    }
+    max as usize
+}
 
-    // control/return
-    Ret,           // pop as function return (or 0 if stack empty)
+// Code the VM refuses to run: its operand stack would go below the
+// function's own values, or two paths would meet at a label with different
+// depths, or it would grow past the recorded `max_stack`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    Underflow { func: String, at: usize },
+    Unbalanced { func: String, label: u32, depths: (isize, isize) },
+    MaxStack { func: String, recorded: usize, needed: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Underflow { func, at } => {
+                write!(f, "`{func}` pops an empty operand stack at instruction {at}")
+            }
+            VerifyError::Unbalanced { func, label, depths: (a, b) } => {
+                write!(f, "`{func}` reaches label L{label} with {a} and with {b} values on the operand stack")
+            }
+            VerifyError::MaxStack { func, recorded, needed } => {
+                write!(f, "`{func}` records a max stack of {recorded} but needs {needed}")
+            }
+        }
+    }
+}
+
+// Checks `func`'s stack discipline along the same walk as `max_stack_depth`.
+// A bare `return;` may pop nothing; it returns 0.
+pub fn verify(func: &Func) -> Result<(), VerifyError> {
+    let mut labels = HashMap::new();
+    let mut depth = 0;
+    let mut max = 0;
+    // whether the previous instruction can fall into the next
+    let mut falls_through = true;
+    for (at, instr) in func.code.iter().enumerate() {
+        let (pops, _) = instr.stack_effect();
+        if depth < pops as isize && !matches!(instr, Instr::Ret) {
+            return Err(VerifyError::Underflow { func: func.name.clone(), at });
+        }
+        let arriving = match instr {
+            Instr::Jump(id) => Some((*id, depth)),
+            Instr::JumpIfZero(id) | Instr::JumpIfNonZero(id) => Some((*id, depth - 1)),
+            Instr::Label(id) if falls_through => Some((*id, depth)),
+            _ => None,
+        };
+        if let Some((label, arriving)) = arriving
+            && let Some(&recorded) = labels.get(&label)
+            && recorded != arriving {
+            return Err(VerifyError::Unbalanced { func: func.name.clone(), label, depths: (recorded, arriving) });
+        }
+        depth = step_depth(instr, depth, &mut labels);
+        max = max.max(depth);
+        falls_through = !matches!(instr, Instr::Jump(_) | Instr::Ret);
+    }
+    let needed = max as usize;
+    if func.max_stack < needed {
+        return Err(VerifyError::MaxStack { func: func.name.clone(), recorded: func.max_stack, needed });
+    }
+    Ok(())
 }
 
 // One function's code + its local layout
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Func {
     pub name: String,
     pub code: Vec<Instr>,
     pub n_locals: usize,
+    pub n_params: usize,  // params occupy locals 0..n_params
+    pub max_stack: usize, // peak operand-stack depth
     // optional: map variable index → name for debugging
     pub locals_dbg: Vec<String>,
+    // optional: source position of each instruction, for runtime errors
+    pub spans: Vec<Span>,
 }
 
-#[derive(Debug, Clone)]
+// A top-level `const` or variable, stored once rather than inlined at each
+// use; `value` is its initial value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub value: i64,
+}
+
+// A top-level const array, `const i32[3] primes = { 2, 3, 5 };`, its
+// elements folded at compile time; nothing can store to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstArray {
+    pub name: String,
+    pub values: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ProgramIR {
     pub funcs: Vec<Func>, // index 0 must be "main"
+    pub globals: Vec<Global>,
+    pub rodata: Vec<ConstArray>,
 }
 
 impl ProgramIR {
@@ -39,3 +227,42 @@ impl ProgramIR {
         self.funcs.iter().position(|f| f.name == "main")
     }
 }
+
+// The listing printed by `--emit=ir`: the globals and const arrays, if any,
+// then each function with its layout and its instructions numbered by index. Loads and
+// stores are annotated with the variable's name and calls with the callee's,
+// e.g. `3: Store(0)  ; x`.
+impl fmt::Display for ProgramIR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, global) in self.globals.iter().enumerate() {
+            writeln!(f, "global #{i} {} = {}", global.name, global.value)?;
+        }
+        for (i, array) in self.rodata.iter().enumerate() {
+            writeln!(f, "const #{i} {} = {:?}", array.name, array.values)?;
+        }
+        for (i, func) in self.funcs.iter().enumerate() {
+            if i > 0 || !self.globals.is_empty() || !self.rodata.is_empty() {
+                writeln!(f)?;
+            }
+            writeln!(f, "func #{i} {}: {} params, {} locals, max stack {}",
+                     func.name, func.n_params, func.n_locals, func.max_stack)?;
+            for (n, instr) in func.code.iter().enumerate() {
+                let note = match instr {
+                    Instr::Load(slot) | Instr::Store(slot) => func.locals_dbg.get(*slot).map(String::as_str),
+                    Instr::LoadIndex(base, _) | Instr::StoreIndex(base, _) => {
+                        func.locals_dbg.get(*base).map(|n| n.strip_suffix("[0]").unwrap_or(n))
+                    }
+                    Instr::LoadGlobal(index) | Instr::StoreGlobal(index) => self.globals.get(*index).map(|g| g.name.as_str()),
+                    Instr::LoadConstIndex(array, _) => self.rodata.get(*array).map(|a| a.name.as_str()),
+                    Instr::Call(callee, _) | Instr::PushHandler(_, callee) => self.funcs.get(*callee).map(|c| c.name.as_str()),
+                    _ => None,
+                };
+                match note {
+                    Some(note) if !note.is_empty() => writeln!(f, "{n}: {instr:?}  ; {note}")?,
+                    _ => writeln!(f, "{n}: {instr:?}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+// src/irbytes.rs
+// A compact binary form of `ProgramIR`, so a program can be compiled once
+// and handed to either backend later.
+//
+// Layout: the magic `CPIR` and a version byte, then the globals, the const
+// arrays and the functions, each list prefixed by its u32 length. Integers are little
+// endian; `usize`s are written as u64 and strings as a u32 length and
+// their UTF-8 bytes. An instruction is an opcode byte and its operands.
+use std::fmt;
+
+use crate::ir::{ConstArray, Func, Global, Instr, ProgramIR};
+use crate::span::Span;
+
+const MAGIC: &[u8; 4] = b"CPIR";
+pub const FORMAT_VERSION: u8 = 4; // 2 added each instruction's source position, 3 array lengths, 4 const arrays
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    NotIr,                 // no `CPIR` magic
+    UnsupportedVersion(u8),
+    Truncated,
+    UnknownOpcode(u8),
+    InvalidUtf8,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::NotIr => write!(f, "not serialized IR: missing the `CPIR` header"),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "IR format version {v} is not supported (expected {FORMAT_VERSION})")
+            }
+            DecodeError::Truncated => write!(f, "serialized IR ends unexpectedly"),
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown instruction opcode {op:#04x}"),
+            DecodeError::InvalidUtf8 => write!(f, "a name in the serialized IR is not UTF-8"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} unexpected byte(s) after the IR"),
+        }
+    }
+}
+
+impl ProgramIR {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend_from_slice(MAGIC);
+        w.0.push(FORMAT_VERSION);
+        w.len(self.globals.len());
+        for g in &self.globals {
+            w.str(&g.name);
+            w.0.extend_from_slice(&g.value.to_le_bytes());
+        }
+        w.len(self.rodata.len());
+        for array in &self.rodata {
+            w.str(&array.name);
+            w.len(array.values.len());
+            for v in &array.values {
+                w.0.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        w.len(self.funcs.len());
+        for f in &self.funcs {
+            w.str(&f.name);
+            w.usize(f.n_locals);
+            w.usize(f.n_params);
+            w.usize(f.max_stack);
+            w.len(f.locals_dbg.len());
+            for name in &f.locals_dbg {
+                w.str(name);
+            }
+            w.len(f.code.len());
+            for instr in &f.code {
+                w.instr(instr);
+            }
+            w.len(f.spans.len());
+            for span in &f.spans {
+                w.usize(span.line);
+                w.usize(span.col);
+            }
+        }
+        w.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ProgramIR, DecodeError> {
+        let mut r = Reader { bytes, at: 0 };
+        if r.take(4).ok() != Some(MAGIC.as_slice()) {
+            return Err(DecodeError::NotIr);
+        }
+        match r.u8()? {
+            FORMAT_VERSION => {}
+            v => return Err(DecodeError::UnsupportedVersion(v)),
+        }
+        let globals = (0..r.len()?)
+            .map(|_| Ok(Global { name: r.str()?, value: r.i64()? }))
+            .collect::<Result<_, DecodeError>>()?;
+        let rodata = (0..r.len()?)
+            .map(|_| Ok(ConstArray { name: r.str()?, values: (0..r.len()?).map(|_| r.i64()).collect::<Result<_, _>>()? }))
+            .collect::<Result<_, DecodeError>>()?;
+        let funcs = (0..r.len()?)
+            .map(|_| {
+                let name = r.str()?;
+                let n_locals = r.usize()?;
+                let n_params = r.usize()?;
+                let max_stack = r.usize()?;
+                let locals_dbg = (0..r.len()?).map(|_| r.str()).collect::<Result<_, _>>()?;
+                let code = (0..r.len()?).map(|_| r.instr()).collect::<Result<_, _>>()?;
+                let spans = (0..r.len()?)
+                    .map(|_| Ok(Span { line: r.usize()?, col: r.usize()? }))
+                    .collect::<Result<_, DecodeError>>()?;
+                Ok(Func { name, code, n_locals, n_params, max_stack, locals_dbg, spans })
+            })
+            .collect::<Result<_, DecodeError>>()?;
+        match bytes.len() - r.at {
+            0 => Ok(ProgramIR { funcs, globals, rodata }),
+            n => Err(DecodeError::TrailingBytes(n)),
+        }
+    }
+}
+
+// Opcodes, in the order `Instr` declares its variants, later additions last.
+mod op {
+    pub const PUSH_I32: u8 = 0x00;
+    pub const PUSH_I64: u8 = 0x01;
+    pub const POP: u8 = 0x02;
+    pub const DUP: u8 = 0x03;
+    pub const SWAP: u8 = 0x04;
+    pub const LOAD: u8 = 0x05;
+    pub const STORE: u8 = 0x06;
+    pub const LOAD_INDEX: u8 = 0x07;
+    pub const STORE_INDEX: u8 = 0x08;
+    pub const LOAD_GLOBAL: u8 = 0x09;
+    pub const STORE_GLOBAL: u8 = 0x0A;
+    pub const ADD: u8 = 0x0B;
+    pub const SUB: u8 = 0x0C;
+    pub const MUL: u8 = 0x0D;
+    pub const DIV: u8 = 0x0E;
+    pub const MOD: u8 = 0x0F;
+    pub const NEG: u8 = 0x10;
+    pub const NOT: u8 = 0x11;
+    pub const CMP_LT: u8 = 0x12;
+    pub const CMP_GT: u8 = 0x13;
+    pub const CMP_LE: u8 = 0x14;
+    pub const CMP_GE: u8 = 0x15;
+    pub const CMP_EQ: u8 = 0x16;
+    pub const CMP_NE: u8 = 0x17;
+    pub const PRINT: u8 = 0x18;
+    pub const PRINT_UNSIGNED: u8 = 0x19;
+    pub const PRINT_NEWLINE: u8 = 0x1A;
+    pub const PRINT_STR: u8 = 0x1B;
+    pub const INPUT: u8 = 0x1C;
+    pub const PERFORM: u8 = 0x1D;
+    pub const LABEL: u8 = 0x1E;
+    pub const JUMP: u8 = 0x1F;
+    pub const JUMP_IF_ZERO: u8 = 0x20;
+    pub const CALL: u8 = 0x21;
+    pub const RET: u8 = 0x22;
+    pub const ADD_I64: u8 = 0x23;
+    pub const SUB_I64: u8 = 0x24;
+    pub const MUL_I64: u8 = 0x25;
+    pub const DIV_I64: u8 = 0x26;
+    pub const MOD_I64: u8 = 0x27;
+    pub const NEG_I64: u8 = 0x28;
+    pub const NARROW: u8 = 0x29;
+    pub const LOAD_CONST_INDEX: u8 = 0x2A;
+    pub const JUMP_IF_NON_ZERO: u8 = 0x2B;
+    pub const PUSH_HANDLER: u8 = 0x2C;
+    pub const POP_HANDLER: u8 = 0x2D;
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn usize(&mut self, n: usize) {
+        self.0.extend_from_slice(&(n as u64).to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.0.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn instr(&mut self, instr: &Instr) {
+        let opcode = match instr {
+            Instr::PushI32(_) => op::PUSH_I32,
+            Instr::PushI64(_) => op::PUSH_I64,
+            Instr::Pop => op::POP,
+            Instr::Dup => op::DUP,
+            Instr::Swap => op::SWAP,
+            Instr::Load(_) => op::LOAD,
+            Instr::Store(_) => op::STORE,
+            Instr::LoadIndex(..) => op::LOAD_INDEX,
+            Instr::StoreIndex(..) => op::STORE_INDEX,
+            Instr::LoadGlobal(_) => op::LOAD_GLOBAL,
+            Instr::StoreGlobal(_) => op::STORE_GLOBAL,
+            Instr::LoadConstIndex(..) => op::LOAD_CONST_INDEX,
+            Instr::Add => op::ADD,
+            Instr::Sub => op::SUB,
+            Instr::Mul => op::MUL,
+            Instr::Div => op::DIV,
+            Instr::Mod => op::MOD,
+            Instr::Neg => op::NEG,
+            Instr::AddI64 => op::ADD_I64,
+            Instr::SubI64 => op::SUB_I64,
+            Instr::MulI64 => op::MUL_I64,
+            Instr::DivI64 => op::DIV_I64,
+            Instr::ModI64 => op::MOD_I64,
+            Instr::NegI64 => op::NEG_I64,
+            Instr::Narrow => op::NARROW,
+            Instr::Not => op::NOT,
+            Instr::CmpLt => op::CMP_LT,
+            Instr::CmpGt => op::CMP_GT,
+            Instr::CmpLe => op::CMP_LE,
+            Instr::CmpGe => op::CMP_GE,
+            Instr::CmpEq => op::CMP_EQ,
+            Instr::CmpNe => op::CMP_NE,
+            Instr::Print => op::PRINT,
+            Instr::PrintUnsigned => op::PRINT_UNSIGNED,
+            Instr::PrintNewline => op::PRINT_NEWLINE,
+            Instr::PrintStr(_) => op::PRINT_STR,
+            Instr::Input => op::INPUT,
+            Instr::Perform(..) => op::PERFORM,
+            Instr::PushHandler(..) => op::PUSH_HANDLER,
+            Instr::PopHandler => op::POP_HANDLER,
+            Instr::Label(_) => op::LABEL,
+            Instr::Jump(_) => op::JUMP,
+            Instr::JumpIfZero(_) => op::JUMP_IF_ZERO,
+            Instr::JumpIfNonZero(_) => op::JUMP_IF_NON_ZERO,
+            Instr::Call(..) => op::CALL,
+            Instr::Ret => op::RET,
+        };
+        self.0.push(opcode);
+        match instr {
+            Instr::PushI32(v) => self.0.extend_from_slice(&v.to_le_bytes()),
+            Instr::PushI64(v) => self.0.extend_from_slice(&v.to_le_bytes()),
+            Instr::Load(n) | Instr::Store(n) | Instr::LoadGlobal(n) | Instr::StoreGlobal(n) => self.usize(*n),
+            Instr::LoadIndex(base, len) | Instr::StoreIndex(base, len) | Instr::LoadConstIndex(base, len) => {
+                self.usize(*base);
+                self.usize(*len);
+            }
+            Instr::PrintStr(bytes) => self.bytes(bytes),
+            Instr::Perform(name, argc) => {
+                self.str(name);
+                self.usize(*argc);
+            }
+            Instr::PushHandler(effect, func) => {
+                self.str(effect);
+                self.usize(*func);
+            }
+            Instr::Label(id) | Instr::Jump(id) | Instr::JumpIfZero(id) | Instr::JumpIfNonZero(id) => self.0.extend_from_slice(&id.to_le_bytes()),
+            Instr::Call(callee, argc) => {
+                self.usize(*callee);
+                self.usize(*argc);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.at.checked_add(n).filter(|&end| end <= self.bytes.len()).ok_or(DecodeError::Truncated)?;
+        let taken = &self.bytes[self.at..end];
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, DecodeError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.i64()? as usize)
+    }
+
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let n = self.len()?;
+        Ok(self.take(n)?.to_vec())
+    }
+
+    fn str(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn instr(&mut self) -> Result<Instr, DecodeError> {
+        Ok(match self.u8()? {
+            op::PUSH_I32 => Instr::PushI32(self.i32()?),
+            op::PUSH_I64 => Instr::PushI64(self.i64()?),
+            op::POP => Instr::Pop,
+            op::DUP => Instr::Dup,
+            op::SWAP => Instr::Swap,
+            op::LOAD => Instr::Load(self.usize()?),
+            op::STORE => Instr::Store(self.usize()?),
+            op::LOAD_INDEX => Instr::LoadIndex(self.usize()?, self.usize()?),
+            op::STORE_INDEX => Instr::StoreIndex(self.usize()?, self.usize()?),
+            op::LOAD_GLOBAL => Instr::LoadGlobal(self.usize()?),
+            op::STORE_GLOBAL => Instr::StoreGlobal(self.usize()?),
+            op::LOAD_CONST_INDEX => Instr::LoadConstIndex(self.usize()?, self.usize()?),
+            op::ADD => Instr::Add,
+            op::SUB => Instr::Sub,
+            op::MUL => Instr::Mul,
+            op::DIV => Instr::Div,
+            op::MOD => Instr::Mod,
+            op::NEG => Instr::Neg,
+            op::ADD_I64 => Instr::AddI64,
+            op::SUB_I64 => Instr::SubI64,
+            op::MUL_I64 => Instr::MulI64,
+            op::DIV_I64 => Instr::DivI64,
+            op::MOD_I64 => Instr::ModI64,
+            op::NEG_I64 => Instr::NegI64,
+            op::NARROW => Instr::Narrow,
+            op::NOT => Instr::Not,
+            op::CMP_LT => Instr::CmpLt,
+            op::CMP_GT => Instr::CmpGt,
+            op::CMP_LE => Instr::CmpLe,
+            op::CMP_GE => Instr::CmpGe,
+            op::CMP_EQ => Instr::CmpEq,
+            op::CMP_NE => Instr::CmpNe,
+            op::PRINT => Instr::Print,
+            op::PRINT_UNSIGNED => Instr::PrintUnsigned,
+            op::PRINT_NEWLINE => Instr::PrintNewline,
+            op::PRINT_STR => Instr::PrintStr(self.bytes()?),
+            op::INPUT => Instr::Input,
+            op::PERFORM => Instr::Perform(self.str()?, self.usize()?),
+            op::PUSH_HANDLER => Instr::PushHandler(self.str()?, self.usize()?),
+            op::POP_HANDLER => Instr::PopHandler,
+            op::LABEL => Instr::Label(self.u32()?),
+            op::JUMP => Instr::Jump(self.u32()?),
+            op::JUMP_IF_ZERO => Instr::JumpIfZero(self.u32()?),
+            op::JUMP_IF_NON_ZERO => Instr::JumpIfNonZero(self.u32()?),
+            op::CALL => Instr::Call(self.usize()?, self.usize()?),
+            op::RET => Instr::Ret,
+            other => return Err(DecodeError::UnknownOpcode(other)),
+        })
+    }
+}
@@ -1,57 +1,258 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 
+use crate::intern::Sym;
+pub use crate::span::Span;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     // keywords
-    Struct, Effect, Const, Var, If, Else, While, Return,
-    Print, Input, Perform, Void, I32, Mut,
+    Struct, Effect, Const, Var, If, Else, Elif, While, For, Break, Continue, Return,
+    Print, PrintUnsigned, Input, Perform, Handle, With, TypeOf, Void, I32, I64, Mut,
 
     // symbols
     LBrace, RBrace, LParen, RParen, LBracket, RBracket,
     Comma, Semicolon, Colon, Arrow, Dot,
     Plus, Minus, Star, Slash, Percent,
     And, Or, Not, Eq, EqEq, Neq, Lt, Gt, Le, Ge,
+    PlusEq, MinusEq, StarEq, SlashEq,
 
     // literals / identifiers
-    Ident(String),
-    Number(i64),
+    Ident(Sym),
+    Number(i128), // wider than any value type, so codegen can reject what doesn't fit
+    Str(Vec<u8>), // decoded bytes of a string literal
 
     // end of file
     EOF,
 }
 
+impl Token {
+    // Source spelling of a keyword token, for diagnostics.
+    pub fn keyword(&self) -> Option<&'static str> {
+        Some(match self {
+            Token::Struct => "struct",
+            Token::Effect => "effect",
+            Token::Const => "const",
+            Token::Var => "var",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::Elif => "elif",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Break => "break",
+            Token::Continue => "continue",
+            Token::Return => "return",
+            Token::Print => "print",
+            Token::PrintUnsigned => "print_unsigned",
+            Token::Input => "input",
+            Token::Perform => "perform",
+            Token::Handle => "handle",
+            Token::With => "with",
+            Token::TypeOf => "typeof",
+            Token::Void => "void",
+            Token::I32 => "i32",
+            Token::I64 => "i64",
+            Token::Mut => "mut",
+            _ => return None,
+        })
+    }
+}
+
+// Malformed tokens; each carries the position where the token starts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    IntegerOutOfRange { span: Span },
+    MissingDigits { span: Span },         // `0x` or `0b` alone
+    UnknownEscape { escape: Option<char>, span: Span },
+    BadHexEscape { span: Span },          // `\x` without two hex digits
+    BadCharLiteral { span: Span },        // empty or unterminated
+    UnterminatedString { span: Span },
+    UnterminatedComment { span: Span },
+    FloatLiteral { span: Span },          // `3.14`: only integers exist
+    UnexpectedChar { ch: char, span: Span }, // `@`, or a lone `&` or `|`
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match self {
+            LexError::IntegerOutOfRange { span }
+            | LexError::MissingDigits { span }
+            | LexError::UnknownEscape { span, .. }
+            | LexError::BadHexEscape { span }
+            | LexError::BadCharLiteral { span }
+            | LexError::UnterminatedString { span }
+            | LexError::UnterminatedComment { span }
+            | LexError::FloatLiteral { span }
+            | LexError::UnexpectedChar { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::IntegerOutOfRange { .. } => write!(f, "integer literal out of range")?,
+            LexError::MissingDigits { .. } => write!(f, "integer literal has no digits after its base prefix")?,
+            LexError::UnknownEscape { escape: Some(c), .. } => write!(f, "unknown escape sequence `\\{c}`")?,
+            LexError::UnknownEscape { escape: None, .. } => write!(f, "escape sequence cut off by end of file")?,
+            LexError::BadHexEscape { .. } => write!(f, "`\\x` escape needs two hex digits")?,
+            LexError::BadCharLiteral { .. } => write!(f, "empty or unterminated char literal")?,
+            LexError::UnterminatedString { .. } => write!(f, "unterminated string literal")?,
+            LexError::UnterminatedComment { .. } => write!(f, "unterminated block comment")?,
+            LexError::FloatLiteral { .. } => {
+                write!(f, "floating-point literals are not supported; only integer types exist")?
+            }
+            LexError::UnexpectedChar { ch, .. } => write!(f, "unexpected character {ch:?}")?,
+        }
+        write!(f, " at {}", self.span())
+    }
+}
+
 pub struct Lexer<'a> {
     input: Peekable<Chars<'a>>,
+    at: Span,    // position of the next char
+    start: Span, // start of the token last returned by next_token
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source: &'a str) -> Self {
-        Lexer { input: source.chars().peekable() }
+        let at = Span { line: 1, col: 1 };
+        Lexer { input: source.chars().peekable(), at, start: at }
     }
 
     fn next_char(&mut self) -> Option<char> {
-        self.input.next()
+        let ch = self.input.next()?;
+        if ch == '\n' {
+            self.at.line += 1;
+            self.at.col = 1;
+        } else {
+            self.at.col += 1;
+        }
+        Some(ch)
     }
 
     fn peek_char(&mut self) -> Option<&char> {
         self.input.peek()
     }
 
-    fn skip_whitespace(&mut self) {
-        while matches!(self.peek_char(), Some(c) if c.is_whitespace()) {
+    // The char after the next one, for telling a comment from a `/`.
+    fn peek_second(&self) -> Option<char> {
+        self.input.clone().nth(1)
+    }
+
+    // Skips whitespace and comments, however many there are in a row.
+    fn skip_trivia(&mut self) -> Result<(), LexError> {
+        loop {
+            match (self.peek_char().copied(), self.peek_second()) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.next_char();
+                }
+                (Some('/'), Some('/')) => {
+                    while !matches!(self.next_char(), Some('\n') | None) {}
+                }
+                // block comments do not nest
+                (Some('/'), Some('*')) => {
+                    self.start = self.at;
+                    self.next_char();
+                    self.next_char();
+                    let mut prev = ' ';
+                    loop {
+                        match self.next_char() {
+                            Some('/') if prev == '*' => break,
+                            Some(ch) => prev = ch,
+                            None => return Err(LexError::UnterminatedComment { span: self.start }),
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // Decodes the escape sequence following a backslash
+    fn escape(&mut self) -> Result<u8, LexError> {
+        Ok(match self.next_char() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('\'') => b'\'',
+            Some('"') => b'"',
+            Some('x') => {
+                let mut hex = String::new();
+                for _ in 0..2 {
+                    match self.next_char() {
+                        Some(h) if h.is_ascii_hexdigit() => hex.push(h),
+                        _ => return Err(LexError::BadHexEscape { span: self.start }),
+                    }
+                }
+                u8::from_str_radix(&hex, 16).unwrap()
+            }
+            escape => return Err(LexError::UnknownEscape { escape, span: self.start }),
+        })
+    }
+
+    // Reads up to the closing quote; the opening quote is already consumed
+    fn string_body(&mut self, escapes: bool) -> Result<Vec<u8>, LexError> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.next_char() {
+                Some('"') => return Ok(bytes),
+                Some('\\') if escapes => bytes.push(self.escape()?),
+                Some(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                None => return Err(LexError::UnterminatedString { span: self.start }),
+            }
+        }
+    }
+
+    // The rest of an integer literal in `radix`, after any `0x`/`0b`
+    // prefix; `digits` holds those already consumed. `_` may separate
+    // digits anywhere, e.g. `1_000` or `0xFF_FF`.
+    fn number(&mut self, mut digits: String, radix: u32) -> Result<Token, LexError> {
+        while let Some(&c) = self.peek_char() {
+            if c.is_digit(radix) {
+                digits.push(c);
+            } else if c != '_' {
+                break;
+            }
             self.next_char();
         }
+        if digits.is_empty() {
+            return Err(LexError::MissingDigits { span: self.start });
+        }
+        // `3.14` would otherwise lex as `3`, `.`, `14` and fail to parse
+        // somewhere confusing
+        let mut ahead = self.input.clone();
+        if radix == 10 && ahead.next() == Some('.') && ahead.next().is_some_and(|c| c.is_ascii_digit()) {
+            return Err(LexError::FloatLiteral { span: self.start });
+        }
+        // codegen reports what doesn't fit the value types
+        i128::from_str_radix(&digits, radix)
+            .map(Token::Number)
+            .map_err(|_| LexError::IntegerOutOfRange { span: self.start })
+    }
+
+    // Two-char operator `long` if the next char is `second`, else `short`.
+    fn either(&mut self, second: char, long: Token, short: Token) -> Token {
+        if self.peek_char() == Some(&second) {
+            self.next_char();
+            long
+        } else {
+            short
+        }
     }
 
-    pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
+    pub fn next_token(&mut self) -> Result<Token, LexError> {
+        self.skip_trivia()?;
+        self.start = self.at;
         let c = match self.next_char() {
             Some(ch) => ch,
-            None => return Token::EOF,
+            None => return Ok(Token::EOF),
         };
 
-        match c {
+        Ok(match c {
             '{' => Token::LBrace,
             '}' => Token::RBrace,
             '(' => Token::LParen,
@@ -62,18 +263,45 @@ impl<'a> Lexer<'a> {
             ';' => Token::Semicolon,
             ':' => Token::Colon,
             '.' => Token::Dot,
-            '=' => Token::Eq,
-            '+' => Token::Plus,
-            '-' => Token::Minus,
-            '*' => Token::Star,
-            '/' => Token::Slash,
-            d if d.is_ascii_digit() => {
-                let mut num = d.to_string();
-                while matches!(self.peek_char(), Some(n) if n.is_ascii_digit()) {
-                    num.push(self.next_char().unwrap());
+            '=' => self.either('=', Token::EqEq, Token::Eq),
+            '!' => self.either('=', Token::Neq, Token::Not),
+            '<' => self.either('=', Token::Le, Token::Lt),
+            '>' => self.either('=', Token::Ge, Token::Gt),
+            '&' if self.peek_char() == Some(&'&') => { self.next_char(); Token::And }
+            '|' if self.peek_char() == Some(&'|') => { self.next_char(); Token::Or }
+            '+' => self.either('=', Token::PlusEq, Token::Plus),
+            '-' if self.peek_char() == Some(&'=') => { self.next_char(); Token::MinusEq }
+            '-' => self.either('>', Token::Arrow, Token::Minus),
+            '*' => self.either('=', Token::StarEq, Token::Star),
+            '/' => self.either('=', Token::SlashEq, Token::Slash),
+            '%' => Token::Percent,
+            '0' if matches!(self.peek_char(), Some('x' | 'X')) => {
+                self.next_char();
+                self.number(String::new(), 16)?
+            }
+            '0' if matches!(self.peek_char(), Some('b' | 'B')) => {
+                self.next_char();
+                self.number(String::new(), 2)?
+            }
+            '\'' => {
+                // char literal: its code point as a number, e.g. '\n' == 10
+                let value = match self.next_char() {
+                    Some('\\') => self.escape()? as i128,
+                    Some(ch) if ch != '\'' => ch as i128,
+                    _ => return Err(LexError::BadCharLiteral { span: self.start }),
+                };
+                if self.next_char() != Some('\'') {
+                    return Err(LexError::BadCharLiteral { span: self.start });
                 }
-                Token::Number(num.parse().unwrap())
+                Token::Number(value)
+            }
+            '"' => Token::Str(self.string_body(true)?),
+            // raw string: backslashes are kept as-is
+            'r' if self.peek_char() == Some(&'"') => {
+                self.next_char();
+                Token::Str(self.string_body(false)?)
             }
+            d if d.is_ascii_digit() => self.number(d.to_string(), 10)?,
             a if a.is_ascii_alphabetic() || a == '_' => {
                 let mut ident = a.to_string();
                 while matches!(self.peek_char(), Some(ch) if ch.is_ascii_alphanumeric() || *ch == '_') {
@@ -83,32 +311,247 @@ impl<'a> Lexer<'a> {
                     "struct" => Token::Struct,
                     "effect" => Token::Effect,
                     "const" => Token::Const,
+                    "var" => Token::Var,
+                    "mut" => Token::Mut,
                     "if" => Token::If,
                     "else" => Token::Else,
+                    "elif" => Token::Elif,
                     "while" => Token::While,
+                    "for" => Token::For,
+                    "break" => Token::Break,
+                    "continue" => Token::Continue,
                     "return" => Token::Return,
                     "print" => Token::Print,
+                    "print_unsigned" => Token::PrintUnsigned,
                     "input" => Token::Input,
                     "perform" => Token::Perform,
+                    "handle" => Token::Handle,
+                    "with" => Token::With,
+                    "typeof" => Token::TypeOf,
                     "i32" => Token::I32,
+                    "i64" => Token::I64,
                     "void" => Token::Void,
-                    _ => Token::Ident(ident),
+                    _ => Token::Ident(Sym::intern(&ident)),
                 }
             }
-            _ => Token::EOF,
-        }
+            ch => return Err(LexError::UnexpectedChar { ch, span: self.start }),
+        })
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    pub fn tokenize(&mut self) -> Result<Vec<(Token, Span)>, LexError> {
         let mut tokens = Vec::new();
         loop {
-            let tok = self.next_token();
+            let tok = self.next_token()?;
             if tok == Token::EOF {
-                tokens.push(Token::EOF);
+                tokens.push((Token::EOF, self.start));
                 break;
             }
-            tokens.push(tok);
+            tokens.push((tok, self.start));
         }
-        tokens
+        Ok(tokens)
     }
-}
\ No newline at end of file
+}
+// Round trip: random token sequences, written out with random whitespace
+// and comments between the tokens, lex back to the same tokens. A small
+// xorshift generator keeps it free of dependencies and reproducible; a
+// failing case is shrunk, by dropping tokens and simplifying the rest,
+// before it is reported.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+            &items[self.below(items.len() as u64) as usize]
+        }
+    }
+
+    const FIXED: &[Token] = &[
+        Token::Struct, Token::Effect, Token::Const, Token::Var, Token::If, Token::Else, Token::Elif,
+        Token::While, Token::For, Token::Break, Token::Continue, Token::Return, Token::Print,
+        Token::PrintUnsigned, Token::Input, Token::Perform, Token::Handle, Token::With, Token::TypeOf,
+        Token::Void, Token::I32, Token::I64, Token::Mut,
+        Token::LBrace, Token::RBrace, Token::LParen, Token::RParen, Token::LBracket, Token::RBracket,
+        Token::Comma, Token::Semicolon, Token::Colon, Token::Arrow, Token::Dot,
+        Token::Plus, Token::Minus, Token::Star, Token::Slash, Token::Percent,
+        Token::And, Token::Or, Token::Not, Token::Eq, Token::EqEq, Token::Neq, Token::Lt, Token::Gt,
+        Token::Le, Token::Ge, Token::PlusEq, Token::MinusEq, Token::StarEq, Token::SlashEq,
+    ];
+
+    // Identifiers and numbers grow with `size`, so early cases are small.
+    fn ident(rng: &mut Rng, size: u64) -> Token {
+        const FIRST: &[u8] = b"abcdefghijklmnopqrstuvwxyz_";
+        const REST: &[u8] = b"abcdefghijklmnopqrstuvwxyz_0123456789";
+        loop {
+            let mut name = vec![*rng.pick(FIRST)];
+            for _ in 0..rng.below(size.min(12) + 1) {
+                name.push(*rng.pick(REST));
+            }
+            let name = String::from_utf8(name).unwrap();
+            if Lexer::new(&name).next_token() == Ok(Token::Ident(Sym::intern(&name))) {
+                return Token::Ident(Sym::intern(&name));
+            }
+        }
+    }
+
+    fn number(rng: &mut Rng, size: u64) -> Token {
+        let bits = rng.below(size.min(63) + 1);
+        Token::Number((rng.next() & ((1u64 << bits) - 1)) as i128)
+    }
+
+    fn string(rng: &mut Rng, size: u64) -> Token {
+        const BYTES: &[u8] = b"ab Z09\"\\\n\t\r\0\x01\x7f\xff{}/*'";
+        Token::Str((0..rng.below(size.min(8) + 1)).map(|_| *rng.pick(BYTES)).collect())
+    }
+
+    fn token(rng: &mut Rng, size: u64) -> Token {
+        match rng.below(8) {
+            0 | 1 => ident(rng, size),
+            2 | 3 => number(rng, size),
+            4 => string(rng, size),
+            _ => rng.pick(FIXED).clone(),
+        }
+    }
+
+    // Source text for `tok`, in one of the ways it can be written.
+    fn render(tok: &Token, rng: &mut Rng) -> String {
+        match tok {
+            Token::Ident(name) => name.to_string(),
+            Token::Number(n) => match rng.below(5) {
+                0 => format!("{n:#x}"),
+                1 => format!("{n:#b}"),
+                2 if (32..127).contains(n) && *n != '\'' as i128 && *n != '\\' as i128 => {
+                    format!("'{}'", *n as u8 as char)
+                }
+                3 => {
+                    // `_` between every three digits
+                    let digits = n.to_string();
+                    let first = digits.len() % 3;
+                    let mut out = digits[..first].to_string();
+                    for (i, chunk) in digits.as_bytes()[first..].chunks(3).enumerate() {
+                        if i > 0 || first > 0 {
+                            out.push('_');
+                        }
+                        out.push_str(std::str::from_utf8(chunk).unwrap());
+                    }
+                    out
+                }
+                _ => n.to_string(),
+            },
+            Token::Str(bytes) => {
+                let mut out = String::from("\"");
+                for &b in bytes {
+                    match b {
+                        b'"' => out.push_str("\\\""),
+                        b'\\' => out.push_str("\\\\"),
+                        b'\n' => out.push_str("\\n"),
+                        b'\t' => out.push_str("\\t"),
+                        b'\r' => out.push_str("\\r"),
+                        0 => out.push_str("\\0"),
+                        b' '..=b'~' => out.push(b as char),
+                        _ => out.push_str(&format!("\\x{b:02x}")),
+                    }
+                }
+                out + "\""
+            }
+            _ => match tok {
+                Token::LBrace => "{", Token::RBrace => "}", Token::LParen => "(", Token::RParen => ")",
+                Token::LBracket => "[", Token::RBracket => "]", Token::Comma => ",", Token::Semicolon => ";",
+                Token::Colon => ":", Token::Arrow => "->", Token::Dot => ".",
+                Token::Plus => "+", Token::Minus => "-", Token::Star => "*", Token::Slash => "/",
+                Token::Percent => "%", Token::And => "&&", Token::Or => "||", Token::Not => "!",
+                Token::Eq => "=", Token::EqEq => "==", Token::Neq => "!=", Token::Lt => "<",
+                Token::Gt => ">", Token::Le => "<=", Token::Ge => ">=", Token::PlusEq => "+=",
+                Token::MinusEq => "-=", Token::StarEq => "*=", Token::SlashEq => "/=",
+                keyword => keyword.keyword().expect("every other token is a keyword"),
+            }
+            .to_string(),
+        }
+    }
+
+    // Whitespace always comes first, so a comment can't merge with a
+    // token's trailing `/`.
+    fn separator(rng: &mut Rng) -> &'static str {
+        const SEPARATORS: &[&str] = &[" ", "\n", "\t ", "  ", " /* c */ ", " /**/\n", " // c\n", "\r\n"];
+        SEPARATORS[rng.below(SEPARATORS.len() as u64) as usize]
+    }
+
+    fn source(tokens: &[Token], rng: &mut Rng) -> String {
+        let mut src = String::from(separator(rng));
+        for tok in tokens {
+            src += &render(tok, rng);
+            src += separator(rng);
+        }
+        src
+    }
+
+    fn round_trips(tokens: &[Token], seed: u64) -> Result<(), String> {
+        let src = source(tokens, &mut Rng(seed));
+        let lexed: Result<Vec<Token>, _> = Lexer::new(&src).tokenize()
+            .map(|t| t.into_iter().map(|(tok, _)| tok).collect());
+        let mut expected = tokens.to_vec();
+        expected.push(Token::EOF);
+        match lexed {
+            Ok(got) if got == expected => Ok(()),
+            got => Err(format!("{src:?} lexed to {got:?}")),
+        }
+    }
+
+    // The smallest failing sequence found by dropping one token at a time
+    // and replacing identifiers and numbers with the simplest ones.
+    fn shrink(mut tokens: Vec<Token>, seed: u64) -> (Vec<Token>, String) {
+        let simplest = |t: &Token| match t {
+            Token::Ident(_) => Some(Token::Ident(Sym::intern("a"))),
+            Token::Number(n) if *n != 0 => Some(Token::Number(0)),
+            Token::Str(s) if !s.is_empty() => Some(Token::Str(Vec::new())),
+            _ => None,
+        };
+        'smaller: loop {
+            for i in 0..tokens.len() {
+                let mut fewer = tokens.clone();
+                fewer.remove(i);
+                if round_trips(&fewer, seed).is_err() {
+                    tokens = fewer;
+                    continue 'smaller;
+                }
+                if let Some(simple) = simplest(&tokens[i]).filter(|s| *s != tokens[i]) {
+                    let mut simpler = tokens.clone();
+                    simpler[i] = simple;
+                    if round_trips(&simpler, seed).is_err() {
+                        tokens = simpler;
+                        continue 'smaller;
+                    }
+                }
+            }
+            let error = round_trips(&tokens, seed).unwrap_err();
+            return (tokens, error);
+        }
+    }
+
+    #[test]
+    fn random_tokens_round_trip() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for case in 0..500u64 {
+            let size = case / 10 + 1;
+            let tokens: Vec<Token> = (0..rng.below(size.min(40) + 1)).map(|_| token(&mut rng, size)).collect();
+            let seed = rng.next() | 1;
+            if round_trips(&tokens, seed).is_err() {
+                let (tokens, error) = shrink(tokens, seed);
+                panic!("case {case}: {tokens:?} does not round-trip: {error}");
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// src/lib.rs
+//
+// The compiler as a library: lex, parse (adding what the program uses of
+// the prelude), typecheck, lower to stack IR and
+// fold constants, then interpret the IR with `VM` or compile it to an ELF
+// executable with `Compiler`. `main.rs` is a command-line front end over
+// these.
+#[macro_use]
+pub mod verbose;
+pub mod intern;
+pub mod span;
+pub mod lexer;
+pub mod parser;
+pub mod ast;
+pub mod ir;
+pub mod codegen;
+pub mod typecheck;
+pub mod opt;
+pub mod vm;
+pub mod astjson;
+pub mod timetrace;
+pub mod elfgen;
+pub mod disasm;
+pub mod irbytes;
+pub mod prelude;
+
+pub use codegen::Codegen;
+pub use elfgen::Compiler;
+pub use lexer::Lexer;
+pub use parser::Parser;
+pub use vm::VM;
+
+use std::fmt;
+use std::path::Path;
+
+use ir::ProgramIR;
+
+// Whether executables written here can run here: they are x86-64 Linux
+// ELF files. Elsewhere the VM (`--run`) is the way to run a program.
+pub const NATIVE_HOST: bool = cfg!(all(target_os = "linux", target_arch = "x86_64"));
+
+// Why a program didn't compile. The parser and the type checker report
+// every error they find; the other phases stop at the first.
+#[derive(Debug)]
+pub enum CompileError {
+    Lex(lexer::LexError),
+    Parse(Vec<parser::ParseError>),
+    Type(Vec<typecheck::TypeError>),
+    Codegen(codegen::CodegenError),
+    Backend(elfgen::BackendError),
+    Internal(&'static str), // a phase panicked
+    DeniedWarnings(usize),  // codegen warnings, with `-W error`
+    UnsupportedHost,        // writing an executable off x86-64 Linux
+    Read { path: String, error: std::io::Error },
+    Write { path: String, error: std::io::Error },
+}
+
+// One line per error, each prefixed with its phase, e.g.
+// `parse error: expected Semicolon, got Return at line 4, column 5`.
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn lines<E: fmt::Display>(f: &mut fmt::Formatter, phase: &str, errors: &[E]) -> fmt::Result {
+            let lines: Vec<_> = errors.iter().map(|e| format!("{phase} error: {e}")).collect();
+            write!(f, "{}", lines.join("\n"))
+        }
+        match self {
+            CompileError::Lex(e) => write!(f, "lex error: {e}"),
+            CompileError::Parse(errors) => lines(f, "parse", errors),
+            CompileError::Type(errors) => lines(f, "type", errors),
+            CompileError::Codegen(e) => write!(f, "error: {e}"),
+            CompileError::Backend(e) => write!(f, "native backend error: {e}"),
+            CompileError::Internal(message) => write!(f, "{message}"),
+            CompileError::DeniedWarnings(n) => write!(f, "{n} warning(s) treated as errors."),
+            CompileError::UnsupportedHost => write!(
+                f,
+                "native executables only run on x86-64 Linux; use `cosplae --run` to interpret the program"
+            ),
+            CompileError::Read { path, error } => write!(f, "cannot read `{path}`: {error}"),
+            CompileError::Write { path, error } => write!(f, "cannot write `{path}`: {error}"),
+        }
+    }
+}
+
+// The program in `source`, with the prelude functions it calls.
+pub fn parse(source: &str) -> Result<ast::Program, CompileError> {
+    let tokens = Lexer::new(source).tokenize().map_err(CompileError::Lex)?;
+    let mut program = Parser::new(tokens).parse_program().map_err(CompileError::Parse)?;
+    prelude::link(&mut program);
+    Ok(program)
+}
+
+pub fn typecheck(program: &ast::Program) -> Result<(), CompileError> {
+    typecheck::check(program).map_err(CompileError::Type)
+}
+
+// Lowers a checked program with `cg`, which keeps the warnings.
+pub fn codegen(cg: &mut Codegen, program: &ast::Program) -> Result<ProgramIR, CompileError> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cg.compile(program)))
+        .map_err(|_| CompileError::Internal("Code generation failed."))?
+        .map_err(CompileError::Codegen)
+}
+
+// The IR the backends are given, constant-folded and with repeated
+// subexpressions computed once, and codegen's warnings.
+pub fn compile_with_warnings(source: &str) -> Result<(ProgramIR, Vec<String>), CompileError> {
+    let program = parse(source)?;
+    typecheck(&program)?;
+    let mut cg = Codegen::new();
+    let mut ir = codegen(&mut cg, &program)?;
+    opt::fold_constants(&mut ir);
+    opt::eliminate_common_subexpressions(&mut ir);
+    Ok((ir, cg.warnings))
+}
+
+pub fn compile_source(source: &str) -> Result<ProgramIR, CompileError> {
+    compile_with_warnings(source).map(|(ir, _)| ir)
+}
+
+// Native code for `ir`, position-independent if `pie`. Arithmetic wraps on
+// i32 overflow unless `checked`, when it exits with a runtime error instead.
+pub fn native(ir: &ProgramIR, pie: bool, checked: bool) -> Result<Compiler, CompileError> {
+    let mut compiler = Compiler::new();
+    compiler.pie = pie;
+    compiler.checked = checked;
+    native_with(ir, compiler)
+}
+
+// Native code for `ir` from a `compiler` whose options are already set.
+pub fn native_with(ir: &ProgramIR, mut compiler: Compiler) -> Result<Compiler, CompileError> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| compiler.compile_program(ir)))
+        .map_err(|_| CompileError::Internal("Native code generation failed."))?
+        .map_err(CompileError::Backend)?;
+    Ok(compiler)
+}
+
+// Writes an x86-64 Linux executable for `source` to `path`, on such a host.
+pub fn compile_to_binary(source: &str, path: &str) -> Result<(), CompileError> {
+    if !NATIVE_HOST {
+        return Err(CompileError::UnsupportedHost);
+    }
+    let compiler = native(&compile_source(source)?, false, false)?;
+    compiler.generate_elf(path).map_err(|error| CompileError::Write { path: path.to_string(), error })
+}
+
+// What `compile_file` writes: an executable, or the listing `--emit=asm`
+// or `--emit=ir` would print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Emit {
+    #[default]
+    Executable,
+    Asm,
+    Ir,
+}
+
+// How `compile_file` compiles: whether the IR is optimized, the native
+// options `native` takes, and what to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub optimize: bool,
+    pub pie: bool,
+    pub checked: bool,
+    pub emit: Emit,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions { optimize: true, pie: false, checked: false, emit: Emit::Executable }
+    }
+}
+
+// Compiles the program in `path` and writes what `opts.emit` asks for to
+// `out`. An executable needs an x86-64 Linux host; the listings don't.
+pub fn compile_file(path: &Path, out: &Path, opts: CompileOptions) -> Result<(), CompileError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|error| CompileError::Read { path: path.display().to_string(), error })?;
+    let program = parse(&source)?;
+    typecheck(&program)?;
+    let mut ir = codegen(&mut Codegen::new(), &program)?;
+    if opts.optimize {
+        opt::fold_constants(&mut ir);
+        opt::eliminate_common_subexpressions(&mut ir);
+    }
+    let written = match opts.emit {
+        Emit::Ir => std::fs::write(out, ir.to_string()),
+        Emit::Asm => std::fs::write(out, native(&ir, opts.pie, opts.checked)?.emit_asm()),
+        Emit::Executable if !NATIVE_HOST => return Err(CompileError::UnsupportedHost),
+        Emit::Executable => native(&ir, opts.pie, opts.checked)?.generate_elf(out),
+    };
+    written.map_err(|error| CompileError::Write { path: out.display().to_string(), error })
+}
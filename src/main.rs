@@ -1,66 +1,402 @@
 // src/main.rs
-mod lexer;
-mod parser;
-mod ast;
-mod ir;
-mod codegen;
-mod vm;
-
-use lexer::Lexer;
-use parser::Parser;
-use codegen::Codegen;
+//
+// The `cosplae` command line, over the compiler in lib.rs.
+use cosplae::{ast, astjson, ir, opt, verbose, vm, CompileError, Codegen, Compiler};
+use cosplae::timetrace::TimeTrace;
 
 mod samplegen;
 
+use std::io::Read;
+
+// Process exit statuses for failures, kept clear of the program's own codes
+const EXIT_COMPILE_ERROR: i32 = 65; // EX_DATAERR
+const EXIT_RUNTIME_ERROR: i32 = 70; // EX_SOFTWARE
+const EXIT_USAGE: i32 = 64;         // EX_USAGE
+
+const USAGE: &str = "\
+usage: cosplae FILE [-o OUT]       compile FILE to a native x86-64 Linux executable
+                                   (OUT defaults to FILE without its extension)
+       cosplae --run [FILE]        interpret FILE (default: stdin), exiting with main's value
+       cosplae --stdin-exit        interpret stdin, exiting with main's value
+       cosplae --emit=KIND [FILE]  KIND is json (the AST), ir (the stack IR), asm (the
+                                   native code), disasm (the machine code, decoded) or elf
+                                   (an executable, OUT defaults to ./output)
+       cosplae --demo              write the built-in hello-world executable ./hello
+options: -o OUT, --time-trace=FILE, -W error | --warnings-as-errors, --vm-trace (with --run),
+         --pie (position-independent executable), --verbose,
+         --overflow-checks (i32 overflow is a runtime error; by default arithmetic wraps),
+         --no-optimize-print (native code prints with a simpler, slower routine),
+         --omit-frame-pointer (functions that call nothing address their locals from rsp),
+         --stack-protector (native functions check a canary below their locals on return),
+         --build-id (the executable gets a .note.gnu.build-id hashing its code and data),
+         --relocatable (with --emit=elf, write an object file to link with `ld -e main`),
+         --source-map (also write OUT.map, the source line and column of each code address),
+         --keep-temps (also write OUT.tokens, OUT.ir and OUT.s: the tokens, IR and assembly)";
+
+// The input file and `-o` value; every other option is looked up where
+// it is used.
+struct Cli {
+    input: Option<String>,
+    output: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Cli, String> {
+    let mut cli = Cli { input: None, output: None };
+    let mut stdin_only = false;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stdin-exit" => stdin_only = true,
+            "--run" | "--emit=json" | "--emit=elf" | "--emit=asm" | "--emit=disasm" | "--emit=ir" | "--demo" | "--verbose"
+            | "--warnings-as-errors" | "--vm-trace" | "--pie" | "--overflow-checks"
+            | "--no-optimize-print" | "--omit-frame-pointer" | "--stack-protector" | "--build-id"
+            | "--relocatable" | "--source-map" | "--keep-temps" => {}
+            a if a.starts_with("--time-trace=") => {}
+            "-W" => match args.next().map(String::as_str) {
+                Some("error") => {}
+                Some(other) => return Err(format!("unknown warning option `-W {other}`; only `-W error` is supported")),
+                None => return Err("`-W` needs a value".into()),
+            },
+            "-o" => cli.output = Some(args.next().ok_or("`-o` needs a path")?.clone()),
+            a if a.starts_with('-') => return Err(format!("unknown option `{a}`")),
+            a if cli.input.is_some() => return Err(format!("unexpected argument `{a}`: one input file only")),
+            a => cli.input = Some(a.to_string()),
+        }
+    }
+    if let (Some(input), true) = (&cli.input, stdin_only) {
+        return Err(format!("unexpected argument `{input}`: --stdin-exit reads the program from stdin"));
+    }
+    Ok(cli)
+}
+
 fn main() -> Result<(), std::io::Error> {
-    // Example source (fits your Step 6 features)
-    
-    /*let source = r#"
-        struct Point {
-            i32 x;
-            i32 y;
+    let args: Vec<String> = std::env::args().collect();
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("❌ {e}\n{USAGE}");
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
+    if args.iter().any(|a| a == "--verbose") {
+        if cfg!(feature = "verbose-log") {
+            verbose::enable();
+        } else {
+            eprintln!("note: --verbose needs a build with `--features verbose-log`");
+        }
+    }
+
+    // Arithmetic wraps to i32 like two's-complement hardware unless
+    // `--overflow-checks` is given: then an overflowing Add, Sub, Mul, Neg or
+    // Div stops the program with a runtime error (status 70), in the VM and
+    // in native code alike.
+    let overflow_checks = args.iter().any(|a| a == "--overflow-checks");
+
+    // Whatever is being compiled, `--time-trace=FILE` records its phases as
+    // Chrome trace JSON, and `-W error` (or `--warnings-as-errors`) fails it
+    // on any warning.
+    let trace_path = args.iter()
+        .find_map(|a| a.strip_prefix("--time-trace=").map(String::from));
+    let mut session = Session {
+        trace: trace_path.as_ref().map(|_| TimeTrace::new()),
+        trace_path,
+        deny_warnings: args.iter().any(|a| a == "--warnings-as-errors")
+            || args.windows(2).any(|w| w[0] == "-W" && w[1] == "error"),
+    };
+
+    // `cosplae --run` interprets the program and exits with its `main`
+    // return value, e.g. `echo "..." | cosplae --run; echo $?`
+    // Add `--vm-trace` to print each instruction with the stack and locals.
+    // Given a source file instead (`cosplae --run prog.cpl`), stdin is left
+    // for the program's `input()`. `--stdin-exit` is `--run` that only
+    // takes its program from stdin, for scripts that pipe one in.
+    if args.iter().any(|a| a == "--run" || a == "--stdin-exit") {
+        let source = read_source(&cli)?;
+        let vm_trace = args.iter().any(|a| a == "--vm-trace");
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            compile_and_run(&source, &mut session, vm_trace, overflow_checks)
+        }));
+        session.write_trace()?;
+        let code = match result {
+            Ok(Ok(Ok(code))) => code,
+            Ok(Ok(Err(e))) => {
+                eprintln!("❌ runtime error: {e}");
+                EXIT_RUNTIME_ERROR
+            }
+            Ok(Err(e)) => {
+                eprintln!("❌ {e}");
+                EXIT_COMPILE_ERROR
+            }
+            Err(_) => EXIT_RUNTIME_ERROR,
         };
+        std::process::exit(code);
+    }
+
+    // `cosplae --emit=json` prints the AST of the program
+    if args.iter().any(|a| a == "--emit=json") {
+        let source = read_source(&cli)?;
+        let ast = session.parse(&source);
+        session.write_trace()?;
+        match ast {
+            Ok(ast) => println!("{}", astjson::program_to_json(&ast)),
+            Err(e) => {
+                eprintln!("❌ {e}");
+                std::process::exit(EXIT_COMPILE_ERROR);
+            }
+        }
+        return Ok(());
+    }
+
+    // `cosplae --emit=elf` compiles the program to a native x86-64 Linux
+    // executable, `./output` unless `-o` says otherwise. With `--pie` (here
+    // or compiling a file) it is position-independent, rather than fixed
+    // at 0x400000; `--no-optimize-print` swaps its `print` for a plainer
+    // routine, `--omit-frame-pointer` leaves rbp alone in leaf functions,
+    // `--stack-protector` guards each frame with a canary, `--build-id`
+    // adds a build id note, `--relocatable` makes it an object file, and
+    // `--source-map` writes where each address's code came from next to it.
+    let native_options = || {
+        let mut compiler = Compiler::new()
+            .simple_print(args.iter().any(|a| a == "--no-optimize-print"))
+            .omit_frame_pointer(args.iter().any(|a| a == "--omit-frame-pointer"))
+            .stack_protector(args.iter().any(|a| a == "--stack-protector"))
+            .with_build_id(args.iter().any(|a| a == "--build-id"))
+            .relocatable(args.iter().any(|a| a == "--relocatable"))
+            .with_source_map(args.iter().any(|a| a == "--source-map"));
+        compiler.pie = args.iter().any(|a| a == "--pie");
+        compiler.checked = overflow_checks;
+        compiler
+    };
+    // `--keep-temps` (here or compiling a file) leaves the tokens, IR and
+    // assembly listing next to the executable.
+    let keep_temps = args.iter().any(|a| a == "--keep-temps");
+    if args.iter().any(|a| a == "--emit=elf") {
+        let source = read_source(&cli)?;
+        build(&source, &mut session, cli.output.as_deref().unwrap_or("output"), native_options(), keep_temps)?;
+        return Ok(());
+    }
 
-        const i32 n = 5;
+    // `cosplae --emit=asm` prints the native code for the program as AT&T
+    // assembly instead of writing an executable
+    if args.iter().any(|a| a == "--emit=asm") {
+        let source = read_source(&cli)?;
+        let compiled = compile_native(&source, &mut session, native_options());
+        session.write_trace()?;
+        match compiled {
+            Ok(compiler) => print!("{}", compiler.emit_asm()),
+            Err(e) => {
+                eprintln!("❌ {e}");
+                std::process::exit(EXIT_COMPILE_ERROR);
+            }
+        }
+        return Ok(());
+    }
 
-        i32 main() {
-            i32 x = 10;
-            print(x);
-            print(n);
-            return 0;
+    // `cosplae --emit=disasm` decodes the machine code that would be
+    // written, showing each instruction's address and bytes
+    if args.iter().any(|a| a == "--emit=disasm") {
+        let source = read_source(&cli)?;
+        let compiled = compile_native(&source, &mut session, native_options());
+        session.write_trace()?;
+        match compiled {
+            Ok(compiler) => print!("{}", compiler.disassemble()),
+            Err(e) => {
+                eprintln!("❌ {e}");
+                std::process::exit(EXIT_COMPILE_ERROR);
+            }
         }
-    "#;
+        return Ok(());
+    }
 
-    match compile_and_run(source) {
-        Ok(code) => {
-            println!("(exit code: {code})");
+    // `cosplae --emit=ir` prints the IR handed to the backends
+    if args.iter().any(|a| a == "--emit=ir") {
+        let source = read_source(&cli)?;
+        let compiled = compile_ir(&source, &mut session);
+        session.write_trace()?;
+        match compiled {
+            Ok(ir) => print!("{ir}"),
+            Err(e) => {
+                eprintln!("❌ {e}");
+                std::process::exit(EXIT_COMPILE_ERROR);
+            }
         }
-        Err(e) => {
-            println!("❌ {e}");
+        return Ok(());
+    }
+
+    if args.iter().any(|a| a == "--demo") {
+        samplegen::emit_min_elf_hello("hello")?;
+        println!("✅ ELF file generated");
+        return Ok(());
+    }
+
+    // `cosplae prog.cosp [-o prog]` compiles a source file
+    let Some(input) = &cli.input else {
+        eprintln!("{USAGE}");
+        std::process::exit(EXIT_USAGE);
+    };
+    let source = std::fs::read_to_string(input)?;
+    let output = cli.output.clone().unwrap_or_else(|| default_output(input));
+    build(&source, &mut session, &output, native_options(), keep_temps)
+}
+
+// Writes the executable for `source` to `output`, and with `keep_temps`
+// its tokens, IR and assembly to `output.tokens`, `output.ir` and
+// `output.s`, exiting on compile errors or if it couldn't run on this host.
+fn build(
+    source: &str,
+    session: &mut Session,
+    output: &str,
+    compiler: Compiler,
+    keep_temps: bool,
+) -> Result<(), std::io::Error> {
+    if !cosplae::NATIVE_HOST {
+        eprintln!("❌ {}", CompileError::UnsupportedHost);
+        std::process::exit(EXIT_USAGE);
+    }
+    let written = compile_ir(source, session).and_then(|ir| {
+        let compiler = native_from_ir(&ir, session, compiler)?;
+        let write = |path: String, contents: String| {
+            std::fs::write(&path, contents).map_err(|error| CompileError::Write { path, error })
+        };
+        if keep_temps {
+            write(format!("{output}.tokens"), token_listing(source))?;
+            write(format!("{output}.ir"), ir.to_string())?;
+            write(format!("{output}.s"), compiler.emit_asm())?;
         }
+        compiler.generate_elf(output)
+            .map_err(|error| CompileError::Write { path: output.to_string(), error })
+    });
+    session.write_trace()?;
+    if let Err(e) = written {
+        eprintln!("❌ {e}");
+        std::process::exit(EXIT_COMPILE_ERROR);
     }
-    */
-    samplegen::emit_min_elf_hello("hello")?;
-    println!("✅ ELF file generated");
+    println!("✅ ELF file generated: {output}");
     Ok(())
+}
 
+// One token per line, after the line and column it starts at; the source
+// has already compiled, so it lexes.
+fn token_listing(source: &str) -> String {
+    let tokens = cosplae::Lexer::new(source).tokenize().unwrap_or_default();
+    tokens.iter().map(|(tok, span)| format!("{}:{} {tok:?}\n", span.line, span.col)).collect()
 }
 
-fn compile_and_run(source: &str) -> Result<i32, String> {
-    // 1) Lex + parse
-    let mut lexer = Lexer::new(source);
-    let tokens = lexer.tokenize();
+// `dir/prog.cosp` -> `dir/prog`; an input without an extension gets `.out`
+// rather than being overwritten.
+fn default_output(input: &str) -> String {
+    let stem = std::path::Path::new(input).with_extension("");
+    if stem.as_os_str() == input {
+        format!("{input}.out")
+    } else {
+        stem.to_string_lossy().into_owned()
+    }
+}
 
-    let mut parser = Parser::new(tokens);
-    let ast = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| parser.parse_program()))
-        .map_err(|_| "Parsing failed due to syntax error.".to_string())?;
+fn read_source(cli: &Cli) -> Result<String, std::io::Error> {
+    match &cli.input {
+        Some(path) => std::fs::read_to_string(path),
+        None => read_stdin(),
+    }
+}
+
+fn read_stdin() -> Result<String, std::io::Error> {
+    let mut source = String::new();
+    std::io::stdin().read_to_string(&mut source)?;
+    Ok(source)
+}
+
+// What every compile shares: the phases recorded with `--time-trace`, and
+// whether `-W error` makes warnings fatal.
+struct Session {
+    trace: Option<TimeTrace>,
+    trace_path: Option<String>,
+    deny_warnings: bool,
+}
+
+impl Session {
+    fn begin(&mut self, name: &str) {
+        if let Some(t) = &mut self.trace { t.begin("phase", name); }
+    }
+
+    fn end(&mut self, name: &str) {
+        if let Some(t) = &mut self.trace { t.end("phase", name); }
+    }
 
-    // 2) Codegen
+    fn parse(&mut self, source: &str) -> Result<ast::Program, CompileError> {
+        self.begin("parse");
+        let ast = cosplae::parse(source);
+        self.end("parse");
+        ast
+    }
+
+    // Writes the trace, if one was asked for, however the compile ended.
+    fn write_trace(&self) -> Result<(), std::io::Error> {
+        match (&self.trace_path, &self.trace) {
+            (Some(path), Some(trace)) => std::fs::write(path, trace.to_json()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Err: the program doesn't compile; Ok(Err): it failed while running.
+fn compile_and_run(
+    source: &str,
+    session: &mut Session,
+    vm_trace: bool,
+    overflow_checks: bool,
+) -> Result<Result<i32, vm::VmError>, CompileError> {
+    let ir = compile_ir(source, session)?;
+
+    verbose!("running `main` in the VM");
+    session.begin("run");
+    let result = vm::VmState::new(&ir).and_then(|mut state| {
+        state.trace = vm_trace;
+        state.checked = overflow_checks;
+        vm::VM::finish(state)
+    });
+    session.end("run");
+
+    Ok(result)
+}
+
+// The IR the backends are given, after printing codegen's warnings (as
+// errors, failing the compile, with `-W error`).
+fn compile_ir(source: &str, session: &mut Session) -> Result<ir::ProgramIR, CompileError> {
+    let ast = session.parse(source)?;
+    cosplae::typecheck(&ast)?;
+    verbose!("parsed {} top-level declarations", ast.decls.len());
+
+    // codegen adds an event per function
+    session.begin("codegen");
     let mut cg = Codegen::new();
-    let ir = cg.compile(&ast);
+    cg.trace = session.trace.take();
+    let ir = cosplae::codegen(&mut cg, &ast);
+    session.trace = cg.trace.take();
+    session.end("codegen");
+    let mut ir = ir?;
+    let severity = if session.deny_warnings { "error" } else { "warning" };
+    for w in &cg.warnings {
+        eprintln!("{severity}: {w}");
+    }
+    if session.deny_warnings && !cg.warnings.is_empty() {
+        return Err(CompileError::DeniedWarnings(cg.warnings.len()));
+    }
+
+    opt::fold_constants(&mut ir);
+    opt::eliminate_common_subexpressions(&mut ir);
+    Ok(ir)
+}
 
-    // 3) Run VM
-    let exit = vm::VM::run(&ir);
+fn compile_native(source: &str, session: &mut Session, compiler: Compiler) -> Result<Compiler, CompileError> {
+    let ir = compile_ir(source, session)?;
+    native_from_ir(&ir, session, compiler)
+}
 
-    Ok(exit)
+fn native_from_ir(ir: &ir::ProgramIR, session: &mut Session, compiler: Compiler) -> Result<Compiler, CompileError> {
+    session.begin("native");
+    let compiler = cosplae::native_with(ir, compiler);
+    session.end("native");
+    compiler
 }
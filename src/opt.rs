@@ -0,0 +1,219 @@
+// src/opt.rs
+// IR-to-IR optimizations, run between Codegen and the backends.
+use crate::ir::{self, Instr, ProgramIR};
+
+// Collapses `PushI32 a; PushI32 b; op` into `PushI32 (a op b)`,
+// `PushI32 a; Neg` into `PushI32 -a`, and `PushI32 a; PushI32 b; Swap`
+// into `PushI32 b; PushI32 a`. Folding at
+// the end of the code built so far lets each result feed the next fold, so
+// one pass reaches the fixpoint: `2 + 3 * 4` becomes `PushI32 14`.
+// Division by zero and results that overflow i32 are left for run time,
+// where they trap or are reported (checked mode) as before. Source
+// positions, if any, stay in step; a result takes its operator's.
+pub fn fold_constants(prog: &mut ProgramIR) {
+    for func in &mut prog.funcs {
+        let mut code = Vec::with_capacity(func.code.len());
+        let mut spans = Vec::with_capacity(func.spans.len());
+        let mut old_spans = func.spans.drain(..);
+        for instr in func.code.drain(..) {
+            code.push(instr);
+            spans.extend(old_spans.next());
+            loop {
+                if let [.., Instr::PushI32(a), Instr::Neg] = code.as_slice()
+                    && let Some(v) = a.checked_neg()
+                {
+                    code.truncate(code.len() - 2);
+                    code.push(Instr::PushI32(v));
+                    if let Some(&span) = spans.last() {
+                        spans.truncate(spans.len() - 2);
+                        spans.push(span);
+                    }
+                    continue;
+                }
+                let [.., Instr::PushI32(a), Instr::PushI32(b), op] = code.as_slice() else { break };
+                if *op == Instr::Swap {
+                    let (a, b) = (*a, *b);
+                    code.truncate(code.len() - 3);
+                    code.extend([Instr::PushI32(b), Instr::PushI32(a)]);
+                    spans.truncate(spans.len().saturating_sub(1));
+                    break;
+                }
+                let Some(v) = fold(op, *a, *b) else { break };
+                code.truncate(code.len() - 3);
+                code.push(Instr::PushI32(v));
+                if let Some(&span) = spans.last() {
+                    spans.truncate(spans.len() - 3);
+                    spans.push(span);
+                }
+            }
+        }
+        drop(old_spans);
+        func.max_stack = ir::max_stack_depth(&code);
+        func.code = code;
+        func.spans = spans;
+    }
+}
+
+fn fold(op: &Instr, a: i32, b: i32) -> Option<i32> {
+    let (a, b) = (a as i64, b as i64);
+    let v = match op {
+        Instr::Add => a + b,
+        Instr::Sub => a - b,
+        Instr::Mul => a * b,
+        Instr::Div if b != 0 => a / b,
+        Instr::Mod if b != 0 => a % b,
+        Instr::CmpLt => (a < b) as i64,
+        Instr::CmpGt => (a > b) as i64,
+        Instr::CmpLe => (a <= b) as i64,
+        Instr::CmpGe => (a >= b) as i64,
+        Instr::CmpEq => (a == b) as i64,
+        Instr::CmpNe => (a != b) as i64,
+        _ => return None,
+    };
+    i32::try_from(v).ok()
+}
+
+// A value's code: code[start..end] pushes it and does nothing else.
+type Range = (usize, usize);
+
+// Local common-subexpression elimination: within a basic block, a value
+// computed again by the same pure code, with nothing stored in between to
+// what that code reads, is loaded from a new local instead. The first
+// computation saves it there, so `X; ..; X` becomes
+// `X; Dup; Store(t); ..; Load(t)`. Pure code is constants, loads and the
+// arithmetic, comparison and indexing on them: no calls, input, output or
+// effects. Some of it traps (a division by zero, an index out of range,
+// overflow in checked mode), but a repeat only runs once the first
+// computation didn't. Repeats shorter than three instructions aren't worth
+// the Dup and Store.
+pub fn eliminate_common_subexpressions(prog: &mut ProgramIR) {
+    for func in &mut prog.funcs {
+        let repeats = find_repeats(&func.code);
+        if repeats.is_empty() {
+            continue;
+        }
+        let mut temps: Vec<(usize, usize)> = Vec::new(); // (end of the first computation, slot)
+        for &((_, end), _) in &repeats {
+            if !temps.iter().any(|&(e, _)| e == end) {
+                if func.locals_dbg.len() == func.n_locals {
+                    func.locals_dbg.push(format!("$t{}", temps.len()));
+                }
+                temps.push((end, func.n_locals));
+                func.n_locals += 1;
+            }
+        }
+        let temp = |end: usize| temps.iter().find(|&&(e, _)| e == end).map(|&(_, slot)| slot);
+        let spans = !func.spans.is_empty();
+        let mut code = Vec::with_capacity(func.code.len());
+        let mut new_spans = Vec::with_capacity(func.spans.len());
+        let mut at = 0;
+        while at < func.code.len() {
+            if let Some(&((_, first), (_, end))) = repeats.iter().find(|&&(_, (start, _))| start == at) {
+                code.push(Instr::Load(temp(first).unwrap()));
+                if spans {
+                    new_spans.push(func.spans[end - 1]);
+                }
+                at = end;
+            } else {
+                code.push(func.code[at].clone());
+                if spans {
+                    new_spans.push(func.spans[at]);
+                }
+                at += 1;
+            }
+            if let Some(slot) = temp(at) {
+                code.extend([Instr::Dup, Instr::Store(slot)]);
+                if spans {
+                    new_spans.extend([func.spans[at - 1]; 2]);
+                }
+            }
+        }
+        func.max_stack = ir::max_stack_depth(&code);
+        func.code = code;
+        func.spans = new_spans;
+    }
+}
+
+// (first computation, repeat) pairs, in code order. A repeat inside a
+// longer one isn't listed: the longer one is replaced as a whole. The
+// first computation is the earliest still valid, which is never inside a
+// repeat, so its value is there to save.
+fn find_repeats(code: &[Instr]) -> Vec<(Range, Range)> {
+    // the operand stack, as far as this block knows it: each value's code
+    // if it is pure
+    let mut stack: Vec<Option<Range>> = Vec::new();
+    let mut available: Vec<Range> = Vec::new();
+    let mut repeats: Vec<(Range, Range)> = Vec::new();
+    for (at, instr) in code.iter().enumerate() {
+        // where the code of the value `instr` pushes starts, if it is pure
+        let start = match instr {
+            Instr::PushI32(_) | Instr::PushI64(_) | Instr::Load(_) | Instr::LoadGlobal(_) => Some(at),
+            Instr::Neg | Instr::NegI64 | Instr::Narrow | Instr::Not | Instr::LoadIndex(..) | Instr::LoadConstIndex(..) => {
+                stack.pop().flatten().filter(|&(_, end)| end == at).map(|(start, _)| start)
+            }
+            Instr::Add | Instr::Sub | Instr::Mul | Instr::Div | Instr::Mod
+            | Instr::AddI64 | Instr::SubI64 | Instr::MulI64 | Instr::DivI64 | Instr::ModI64
+            | Instr::CmpLt | Instr::CmpGt | Instr::CmpLe | Instr::CmpGe | Instr::CmpEq | Instr::CmpNe => {
+                let b = stack.pop().flatten();
+                let a = stack.pop().flatten();
+                // the operands' code back to back, maybe swapped after
+                a.zip(b).and_then(|(a, b)| {
+                    let (x, y) = if a.0 < b.0 { (a, b) } else { (b, a) };
+                    let ends = y.1 == at || (y.1 + 1 == at && code[y.1] == Instr::Swap);
+                    (x.1 == y.0 && ends).then_some(x.0)
+                })
+            }
+            Instr::Swap => {
+                match stack.len() {
+                    n @ 2.. => stack.swap(n - 1, n - 2),
+                    _ => stack.clear(),
+                }
+                continue;
+            }
+            _ => {
+                match instr {
+                    // another block, or a callee that may store to any global
+                    Instr::Label(_) | Instr::Call(..) | Instr::Perform(..) => {
+                        available.clear();
+                        stack.iter_mut().for_each(|v| *v = None);
+                    }
+                    Instr::Store(_) | Instr::StoreIndex(..) | Instr::StoreGlobal(_) => {
+                        available.retain(|&(start, end)| !code[start..end].iter().any(|load| clobbers(instr, load)));
+                    }
+                    _ => {}
+                }
+                let (pops, pushes) = instr.stack_effect();
+                stack.truncate(stack.len().saturating_sub(pops));
+                stack.extend(std::iter::repeat_n(None, pushes));
+                continue;
+            }
+        };
+        let value = start.map(|start| (start, at + 1));
+        stack.push(value);
+        let Some((start, end)) = value else { continue };
+        if end - start < 3 {
+            continue;
+        }
+        match available.iter().find(|&&(s, e)| code[s..e] == code[start..end]) {
+            Some(&first) => {
+                repeats.retain(|&(_, (s, _))| s < start);
+                repeats.push((first, (start, end)));
+            }
+            None => available.push((start, end)),
+        }
+    }
+    repeats
+}
+
+// Whether `store` may change what `load` pushes.
+fn clobbers(store: &Instr, load: &Instr) -> bool {
+    let slots = |i: &Instr| match *i {
+        Instr::Load(slot) | Instr::Store(slot) => Some(slot..slot + 1),
+        Instr::LoadIndex(base, len) | Instr::StoreIndex(base, len) => Some(base..base + len),
+        _ => None,
+    };
+    match (store, load) {
+        (Instr::StoreGlobal(a), Instr::LoadGlobal(b)) => a == b,
+        _ => slots(store).zip(slots(load)).is_some_and(|(s, l)| s.start < l.end && l.start < s.end),
+    }
+}
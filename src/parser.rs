@@ -1,205 +1,933 @@
-use crate::lexer::Token;
+use std::fmt;
+
+use crate::lexer::{Span, Token};
 use crate::ast::*;
+use crate::intern::Sym;
+
+// Each error carries the position of the token it is about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken { expected: String, got: Token, span: Span },
+    UnexpectedEof { expected: String, span: Span },
+    // keyword used where a name is expected
+    ReservedKeyword { keyword: &'static str, span: Span },
+    // `x = y = ...`, at the second `=`
+    ChainedAssignment { name: Sym, span: Span },
+    // param without a default after one with
+    MissingDefault { name: Sym, span: Span },
+    // `a < b < c`, at the second comparison
+    ChainedComparison { op: &'static str, span: Span },
+    // a bracket whose closing one is missing, at the token found instead
+    Unclosed { open: Token, opened: Span, got: Option<Token>, span: Span },
+    // a nested function using a local of the one it is in, at its start
+    Capture { func: Sym, name: Sym, outer: Sym, span: Span },
+    // a second handler for an effect in one `with`, at its start
+    DuplicateHandler { effect: Sym, span: Span },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. }
+            | ParseError::UnexpectedEof { span, .. }
+            | ParseError::ReservedKeyword { span, .. }
+            | ParseError::ChainedAssignment { span, .. }
+            | ParseError::MissingDefault { span, .. }
+            | ParseError::ChainedComparison { span, .. }
+            | ParseError::Unclosed { span, .. }
+            | ParseError::Capture { span, .. }
+            | ParseError::DuplicateHandler { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken { expected, got, .. } => write!(f, "expected {expected}, got {got:?}")?,
+            ParseError::UnexpectedEof { expected, .. } => write!(f, "expected {expected}, got end of file")?,
+            ParseError::ReservedKeyword { keyword, .. } => {
+                write!(f, "`{keyword}` is a reserved keyword and cannot be used as a name")?
+            }
+            ParseError::ChainedAssignment { name, .. } => {
+                write!(f, "chained assignment to `{name}` is not supported; assign each name separately")?
+            }
+            ParseError::MissingDefault { name, .. } => {
+                write!(f, "parameter `{name}` without a default follows a defaulted parameter")?
+            }
+            ParseError::ChainedComparison { op, .. } => {
+                write!(f, "`{op}` compares the result of another comparison; parenthesize that one or join them with `&&`")?
+            }
+            ParseError::Unclosed { open, opened, got, .. } => {
+                let (open, close) = closing(open);
+                write!(f, "unclosed `{open}` opened at {opened}: expected {close:?}, got ")?;
+                match got {
+                    Some(got) => write!(f, "{got:?}")?,
+                    None => write!(f, "end of file")?,
+                }
+            }
+            ParseError::Capture { func, name, outer, .. } => {
+                // a `with` handler is named `handleN.effect` until it is hoisted
+                match func.split_once('.') {
+                    Some((_, effect)) => write!(f, "the `with` handler of `{effect}` uses `{name}`, a local of `{outer}`; handlers cannot capture locals either")?,
+                    None => write!(f, "nested function `{func}` uses `{name}`, a local of `{outer}`; nested functions cannot capture locals, pass it as a parameter")?,
+                }
+            }
+            ParseError::DuplicateHandler { effect, .. } => write!(f, "effect `{effect}` has more than one handler in this `with`")?,
+        }
+        write!(f, " at {}", self.span())
+    }
+}
 
 pub struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>,
     pos: usize,
+    // per function being parsed, innermost last: its name and the
+    // functions defined in its body so far, with where each starts
+    nested: Vec<(Sym, Vec<(FuncDef, Span)>)>,
+    hoisted: Vec<FuncDef>, // nested functions, renamed, for `parse_program` to add
+    handles: usize, // `handle` statements so far, numbering their handlers' names
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, pos: 0 }
+    pub fn new(tokens: Vec<(Token, Span)>) -> Self {
+        let (tokens, spans) = tokens.into_iter().unzip();
+        Parser { tokens, spans, pos: 0, nested: Vec::new(), hoisted: Vec::new(), handles: 0 }
+    }
+
+    // Position of the token at `pos`; past the end, that of the last one.
+    fn span_at(&self, pos: usize) -> Span {
+        self.spans.get(pos).or(self.spans.last()).copied()
+            .unwrap_or(Span { line: 1, col: 1 })
+    }
+
+    // Error for `got`, the token just consumed by `next`.
+    fn unexpected(&self, expected: String, got: Token) -> ParseError {
+        let span = self.span_at(self.pos - 1);
+        if got == Token::EOF {
+            ParseError::UnexpectedEof { expected, span }
+        } else {
+            ParseError::UnexpectedToken { expected, got, span }
+        }
     }
 
     pub fn peek(&self) -> &Token {
         self.tokens.get(self.pos).unwrap_or(&Token::EOF)
     }
 
+    // Clones the token; idents are a copyable `Sym`, so only string
+    // literals allocate. Use `advance` when the token was already inspected.
     fn next(&mut self) -> Token {
         let tok = self.peek().clone();
         self.pos += 1;
         tok
     }
 
-    fn expect(&mut self, expected: &Token) {
+    fn advance(&mut self) {
+        self.pos += 1;
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), ParseError> {
+        if self.peek() != expected {
+            let got = self.next();
+            return Err(self.unexpected(format!("{:?}", expected), got));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    // Consume the `)`, `]` or `}` closing the bracket at token `open`.
+    fn expect_close(&mut self, open: usize) -> Result<(), ParseError> {
+        let (_, close) = closing(&self.tokens[open]);
+        if *self.peek() == close {
+            self.advance();
+            return Ok(());
+        }
         let got = self.next();
-        if &got != expected {
-            panic!("Expected {:?}, got {:?}", expected, got);
+        Err(ParseError::Unclosed {
+            open: self.tokens[open].clone(),
+            opened: self.span_at(open),
+            got: (got != Token::EOF).then_some(got),
+            span: self.span_at(self.pos - 1),
+        })
+    }
+
+    // Consume an identifier in a naming position (`what` is e.g. "field name").
+    fn expect_ident(&mut self, what: &str) -> Result<Sym, ParseError> {
+        match self.next() {
+            Token::Ident(id) => Ok(id),
+            t => match t.keyword() {
+                Some(keyword) => Err(ParseError::ReservedKeyword { keyword, span: self.span_at(self.pos - 1) }),
+                None => Err(self.unexpected(what.to_string(), t)),
+            },
         }
     }
 
     // ---- program ----
-    pub fn parse_program(&mut self) -> Program {
+    // Collects an error per malformed declaration rather than stopping at
+    // the first, resuming at the next one that looks well-formed.
+    pub fn parse_program(&mut self) -> Result<Program, Vec<ParseError>> {
         let mut decls = Vec::new();
+        let mut errors = Vec::new();
         while *self.peek() != Token::EOF {
-            decls.push(self.parse_top_decl());
+            let start = self.pos;
+            match self.parse_top_decl() {
+                Ok(decl) => {
+                    decls.push(decl);
+                    decls.extend(self.hoisted.drain(..).map(TopDecl::Func));
+                }
+                Err(e) => {
+                    errors.push(e);
+                    self.nested.clear();
+                    self.hoisted.clear();
+                    self.synchronize(start);
+                }
+            }
+        }
+        if errors.is_empty() { Ok(Program { decls }) } else { Err(errors) }
+    }
+
+    // Skips from `start`, where a failed declaration began, to the next
+    // `struct`, `const` or type keyword outside any braces. At least one
+    // token is skipped, so a declaration that fails on its first token
+    // can't be retried forever.
+    fn synchronize(&mut self, start: usize) {
+        self.pos = start + 1;
+        let mut depth = matches!(self.tokens.get(start), Some(Token::LBrace)) as usize;
+        loop {
+            match self.peek() {
+                Token::EOF => return,
+                Token::Struct | Token::Effect | Token::Const | Token::I32 | Token::I64 | Token::Void if depth == 0 => return,
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            self.pos += 1;
         }
-        Program { decls }
     }
 
     // ---- top_decl ----
-    fn parse_top_decl(&mut self) -> TopDecl {
+    fn parse_top_decl(&mut self) -> Result<TopDecl, ParseError> {
         match self.peek() {
-            Token::Struct => TopDecl::Struct(self.parse_struct_decl()),
-            Token::Const  => TopDecl::Const(self.parse_const_decl()),
-            Token::I32 | Token::Ident(_) => {
-                // Could be a function definition
-                let ty = self.parse_type();
-                let name = match self.next() {
-                    Token::Ident(id) => id,
-                    t => panic!("Expected function name, got {:?}", t),
-                };
-                self.expect(&Token::LParen);
-                let params = self.parse_params();
-                self.expect(&Token::RParen);
-                let body = self.parse_block();
-                TopDecl::Func(FuncDef { ret_type: ty, name, params, body })
+            Token::Struct => Ok(TopDecl::Struct(self.parse_struct_decl()?)),
+            Token::Const  => Ok(TopDecl::Const(self.parse_const_decl()?)),
+            Token::Effect => Ok(TopDecl::Effect(self.parse_effect_decl()?)),
+            Token::I32 | Token::I64 | Token::Void | Token::Ident(_) => {
+                // A function definition, or a global variable if the name
+                // is followed by `=` or `;` rather than `(`
+                let ty = self.parse_type()?;
+                let name = self.expect_ident("function name")?;
+                match self.peek() {
+                    Token::Eq => {
+                        self.advance();
+                        let value = self.parse_expr()?;
+                        self.expect(&Token::Semicolon)?;
+                        return Ok(TopDecl::Var(VarDecl { ty, name, len: None, value: Some(value), init: None, mutable: true }));
+                    }
+                    Token::Semicolon => {
+                        self.advance();
+                        return Ok(TopDecl::Var(VarDecl { ty, name, len: None, value: None, init: None, mutable: true }));
+                    }
+                    _ => {}
+                }
+                Ok(TopDecl::Func(self.parse_func_rest(ty, name)?))
+            }
+            _ => {
+                let got = self.next();
+                Err(self.unexpected("a top-level declaration".to_string(), got))
             }
-            _ => panic!("Unexpected token in top_decl: {:?}", self.peek()),
         }
     }
 
-    // ---- struct_decl ----
-    fn parse_struct_decl(&mut self) -> StructDecl {
-        self.expect(&Token::Struct);
-        let name = match self.next() {
-            Token::Ident(id) => id,
-            t => panic!("expected struct name, got {:?}", t),
+    // The parameters and body of a function whose type and name were just
+    // parsed. Functions defined in the body are hoisted out by `hoist`.
+    fn parse_func_rest(&mut self, ret_type: Type, name: Sym) -> Result<FuncDef, ParseError> {
+        let open = self.pos;
+        self.expect(&Token::LParen)?;
+        let params = self.parse_params()?;
+        self.expect_close(open)?;
+        self.nested.push((name, Vec::new()));
+        let body = self.parse_block()?;
+        let (_, inner) = self.nested.pop().expect("pushed above");
+        let mut func = FuncDef { ret_type, name, params, body };
+        self.hoist(&mut func, inner)?;
+        Ok(func)
+    }
+
+    // Moves the functions defined in `func`'s body to `hoisted`, renamed
+    // `outer.inner` after the functions they are in (a name no source can
+    // spell, so unique), and points the calls to them in `func` and in each
+    // other at the new name. There are no closures: they may not use a
+    // parameter or local of `func`.
+    fn hoist(&mut self, func: &mut FuncDef, inner: Vec<(FuncDef, Span)>) -> Result<(), ParseError> {
+        if inner.is_empty() {
+            return Ok(());
+        }
+        let path: String = self.nested.iter().map(|(name, _)| *name).chain([func.name])
+            .map(|name| format!("{name}."))
+            .collect();
+        let renames: Vec<(Sym, Sym)> = inner.iter()
+            .map(|(f, _)| (f.name, Sym::intern(&format!("{path}{}", f.name))))
+            .collect();
+        let rename = &mut |e: &mut Expr| {
+            if let Expr::Call { name, .. } = e
+                && let Some(&(_, to)) = renames.iter().find(|(from, _)| from == name)
+            {
+                *name = to;
+            }
         };
-        self.expect(&Token::LBrace);
+        let rename_handlers = &mut |s: &mut Stmt| {
+            if let Stmt::Handle(h) = s {
+                for (_, handler) in &mut h.handlers {
+                    if let Some(&(_, to)) = renames.iter().find(|(from, _)| from == handler) {
+                        *handler = to;
+                    }
+                }
+            }
+        };
+        visit_block(&mut func.body, rename_handlers, rename);
+        let outer_locals = locals(func);
+
+        for (mut f, span) in inner {
+            let own = locals(&mut f);
+            let (mut assigned, mut read) = (Vec::new(), Vec::new());
+            visit_block(&mut f.body, &mut |stmt| {
+                if let Stmt::Assign(a) = stmt {
+                    assigned.push(a.name);
+                }
+            }, &mut |e| {
+                if let Expr::Ident(n) = e {
+                    read.push(*n);
+                }
+            });
+            let captured = assigned.into_iter().chain(read).find(|n| outer_locals.contains(n) && !own.contains(n));
+            if let Some(name) = captured {
+                return Err(ParseError::Capture { func: f.name, name, outer: func.name, span });
+            }
+            visit_block(&mut f.body, &mut |_| {}, rename);
+            f.name = renames.iter().find(|(from, _)| *from == f.name).expect("renamed above").1;
+            self.hoisted.push(f);
+        }
+        Ok(())
+    }
+
+    // Whether the tokens ahead start a function definition: a type, a
+    // name and `(`. No statement starts that way.
+    fn at_func(&self) -> bool {
+        let at = |n: usize| self.tokens.get(self.pos + n);
+        matches!(at(0), Some(Token::I32 | Token::I64 | Token::Void | Token::Ident(_)))
+            && matches!(at(1), Some(Token::Ident(_)))
+            && at(2) == Some(&Token::LParen)
+    }
+
+    // ---- struct_decl ----
+    fn parse_struct_decl(&mut self) -> Result<StructDecl, ParseError> {
+        self.expect(&Token::Struct)?;
+        let name = self.expect_ident("struct name")?;
+        let open = self.pos;
+        self.expect(&Token::LBrace)?;
         let mut fields = Vec::new();
         while *self.peek() != Token::RBrace {
-            fields.push(self.parse_field());
+            fields.push(self.parse_field()?);
         }
-        self.expect(&Token::RBrace);
-        self.expect(&Token::Semicolon);
-        StructDecl { name, fields }
+        self.expect_close(open)?;
+        self.expect(&Token::Semicolon)?;
+        Ok(StructDecl { name, fields })
     }
 
-    fn parse_field(&mut self) -> Field {
-        let ty = self.parse_type();
-        let name = match self.next() {
-            Token::Ident(id) => id,
-            t => panic!("expected field name, got {:?}", t),
-        };
-        self.expect(&Token::Semicolon);
-        Field { ty, name }
+    // ---- effect_decl ----
+    // `effect i32 ask(i32);`: a prototype, handled by the function of the
+    // same name.
+    fn parse_effect_decl(&mut self) -> Result<EffectDecl, ParseError> {
+        self.expect(&Token::Effect)?;
+        let ret = self.parse_type()?;
+        let name = self.expect_ident("effect name")?;
+        let open = self.pos;
+        self.expect(&Token::LParen)?;
+        let mut params = Vec::new();
+        while *self.peek() != Token::RParen {
+            if !params.is_empty() {
+                self.expect(&Token::Comma)?;
+            }
+            params.push(self.parse_type()?);
+        }
+        self.expect_close(open)?;
+        self.expect(&Token::Semicolon)?;
+        let ret = (ret.name.as_str() != "void").then_some(ret);
+        Ok(EffectDecl { name, params, ret })
+    }
+
+    fn parse_field(&mut self) -> Result<Field, ParseError> {
+        let ty = self.parse_type()?;
+        let name = self.expect_ident("field name")?;
+        self.expect(&Token::Semicolon)?;
+        Ok(Field { ty, name })
     }
 
-    fn parse_type(&mut self) -> Type {
+    fn parse_type(&mut self) -> Result<Type, ParseError> {
         match self.next() {
-            Token::I32 => Type { name: "i32".to_string() },
-            Token::Ident(id) => Type { name: id },
-            t => panic!("expected type, got {:?}", t),
+            Token::I32 => Ok(Type { name: Sym::intern("i32") }),
+            Token::I64 => Ok(Type { name: Sym::intern("i64") }),
+            Token::Void => Ok(Type { name: Sym::intern("void") }),
+            Token::Ident(id) => Ok(Type { name: id }),
+            t => Err(self.unexpected("type".to_string(), t)),
         }
     }
 
     // ---- parameters ----
-    fn parse_params(&mut self) -> Vec<Param> {
-        let mut params = Vec::new();
-        while let Token::I32 | Token::Ident(_) = self.peek() {
-            let ty = self.parse_type();
-            let name = match self.next() {
-                Token::Ident(id) => id,
-                t => panic!("expected param name, got {:?}", t),
+    fn parse_params(&mut self) -> Result<Vec<Param>, ParseError> {
+        let mut params: Vec<Param> = Vec::new();
+        // C-style `f(void)` means no parameters
+        if *self.peek() == Token::Void && self.tokens.get(self.pos + 1) == Some(&Token::RParen) {
+            self.advance();
+            return Ok(params);
+        }
+        while let Token::I32 | Token::I64 | Token::Ident(_) | Token::Mut = self.peek() {
+            let mutable = *self.peek() == Token::Mut;
+            if mutable {
+                self.advance();
+            }
+            let ty = self.parse_type()?;
+            let name = self.expect_ident("param name")?;
+            let default = if *self.peek() == Token::Eq {
+                self.advance();
+                Some(self.parse_expr()?)
+            } else {
+                if params.iter().any(|p| p.default.is_some()) {
+                    return Err(ParseError::MissingDefault { name, span: self.span_at(self.pos - 1) });
+                }
+                None
             };
-            params.push(Param { ty, name });
+            params.push(Param { ty, name, default, mutable });
             if *self.peek() == Token::Comma {
-                self.next();
+                self.advance();
             } else {
                 break;
             }
         }
-        params
+        Ok(params)
     }
 
     // ---- block ----
-    fn parse_block(&mut self) -> Block {
-        self.expect(&Token::LBrace);
-        let mut stmts = Vec::new();
-        while *self.peek() != Token::RBrace {
-            stmts.push(self.parse_stmt());
+    fn parse_block(&mut self) -> Result<Block, ParseError> {
+        let open = self.pos;
+        self.expect(&Token::LBrace)?;
+        let (mut stmts, mut spans) = (Vec::new(), Vec::new());
+        while !matches!(self.peek(), Token::RBrace | Token::EOF) {
+            let span = self.span_at(self.pos);
+            if self.at_func() && !self.nested.is_empty() {
+                // a function nested in the one being parsed, for `hoist`
+                let ty = self.parse_type()?;
+                let name = self.expect_ident("function name")?;
+                let func = self.parse_func_rest(ty, name)?;
+                self.nested.last_mut().expect("checked above").1.push((func, span));
+                continue;
+            }
+            spans.push(span);
+            stmts.push(self.parse_stmt()?);
         }
-        self.expect(&Token::RBrace);
-        Block { stmts }
+        self.expect_close(open)?;
+        Ok(Block { stmts, spans })
     }
 
     // ---- statement ----
-    fn parse_stmt(&mut self) -> Stmt {
+    fn parse_stmt(&mut self) -> Result<Stmt, ParseError> {
         match self.peek() {
-            Token::Const => Stmt::ConstDecl(self.parse_const_decl()),
-            Token::Return => Stmt::Return(self.parse_return_stmt()),
-            Token::I32 | Token::Ident(_) => {
+            Token::Const => Ok(Stmt::ConstDecl(self.parse_const_decl()?)),
+            Token::Return => Ok(Stmt::Return(self.parse_return_stmt()?)),
+            Token::If => Ok(Stmt::If(self.parse_if_stmt()?)),
+            Token::While => Ok(Stmt::While(self.parse_while_stmt()?)),
+            Token::For => Ok(Stmt::For(self.parse_for_stmt()?)),
+            Token::Handle => Ok(Stmt::Handle(self.parse_handle_stmt()?)),
+            Token::Break | Token::Continue => {
+                let stmt = if self.next() == Token::Break { Stmt::Break } else { Stmt::Continue };
+                self.expect(&Token::Semicolon)?;
+                Ok(stmt)
+            }
+            Token::Var => {
+                // `var i32 x = 0;`: a local that can be assigned again
+                self.advance();
+                let start = self.pos;
+                match self.parse_stmt()? {
+                    Stmt::VarDecl(v) => Ok(Stmt::VarDecl(VarDecl { mutable: true, ..v })),
+                    _ => {
+                        self.pos = start;
+                        let got = self.next();
+                        Err(self.unexpected("a variable declaration after `var`".to_string(), got))
+                    }
+                }
+            }
+            Token::Ident(_) if self.at_assign() => Ok(Stmt::Assign(self.parse_assign()?)),
+            Token::I32 | Token::I64 | Token::Void | Token::Ident(_) => {
                 // Could be var_decl or expr
                 // Look ahead to decide
                 let pos = self.pos;
-                let ty = self.parse_type();
-                if let Token::Ident(id) = self.next() {
+                let ty = self.parse_type()?;
+                let tok = self.next();
+                if let Token::Ident(id) = tok {
                     if *self.peek() == Token::Eq {
-                        self.next();
-                        let expr = self.parse_expr();
-                        self.expect(&Token::Semicolon);
-                        Stmt::VarDecl(VarDecl { ty, name: id, value: Some(expr) })
+                        self.advance();
+                        let (value, init) = if *self.peek() == Token::LBrace {
+                            (None, Some(self.parse_initializer()?))
+                        } else {
+                            (Some(self.parse_expr()?), None)
+                        };
+                        self.expect(&Token::Semicolon)?;
+                        Ok(Stmt::VarDecl(VarDecl { ty, name: id, len: None, value, init, mutable: false }))
                     } else if *self.peek() == Token::Semicolon {
-                        self.next();
-                        Stmt::VarDecl(VarDecl { ty, name: id, value: None })
+                        self.advance();
+                        Ok(Stmt::VarDecl(VarDecl { ty, name: id, len: None, value: None, init: None, mutable: false }))
+                    } else if *self.peek() == Token::LBracket {
+                        // `i32 arr[4];`: codegen folds the length, which
+                        // must be constant; the elements start at 0
+                        let open = self.pos;
+                        self.advance();
+                        let len = self.parse_expr()?;
+                        self.expect_close(open)?;
+                        self.expect(&Token::Semicolon)?;
+                        Ok(Stmt::VarDecl(VarDecl { ty, name: id, len: Some(len), value: None, init: None, mutable: false }))
                     } else {
                         // restore position → expression statement
                         self.pos = pos;
-                        let e = self.parse_expr();
-                        self.expect(&Token::Semicolon);
-                        Stmt::Expr(e)
+                        let e = self.parse_expr()?;
+                        self.expect(&Token::Semicolon)?;
+                        Ok(Stmt::Expr(e))
                     }
+                } else if let Some(keyword) = tok.keyword() {
+                    Err(ParseError::ReservedKeyword { keyword, span: self.span_at(self.pos - 1) })
                 } else {
-                    panic!("Expected identifier after type or expression");
+                    // not a declaration, e.g. `f(x);` → expression statement
+                    self.pos = pos;
+                    let e = self.parse_expr()?;
+                    self.expect(&Token::Semicolon)?;
+                    Ok(Stmt::Expr(e))
                 }
             }
             _ => {
-                let e = self.parse_expr();
-                self.expect(&Token::Semicolon);
-                Stmt::Expr(e)
+                let e = self.parse_expr()?;
+                self.expect(&Token::Semicolon)?;
+                Ok(Stmt::Expr(e))
+            }
+        }
+    }
+
+    // `{ 1, 2 }`, possibly empty, with an optional trailing comma
+    fn parse_initializer(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let open = self.pos;
+        self.expect(&Token::LBrace)?;
+        let mut values = Vec::new();
+        while *self.peek() != Token::RBrace {
+            values.push(self.parse_expr()?);
+            if *self.peek() == Token::Comma {
+                self.advance();
+            } else {
+                break;
             }
         }
+        self.expect_close(open)?;
+        Ok(values)
     }
 
-    fn parse_return_stmt(&mut self) -> Option<Expr> {
-        self.expect(&Token::Return);
+    fn parse_return_stmt(&mut self) -> Result<Option<Expr>, ParseError> {
+        self.expect(&Token::Return)?;
         let expr = if *self.peek() == Token::Semicolon {
             None
         } else {
-            Some(self.parse_expr())
+            Some(self.parse_expr()?)
         };
-        self.expect(&Token::Semicolon);
-        expr
+        self.expect(&Token::Semicolon)?;
+        Ok(expr)
     }
 
+    fn parse_if_stmt(&mut self) -> Result<IfStmt, ParseError> {
+        self.expect(&Token::If)?;
+        self.parse_if_rest()
+    }
+
+    // What follows `if` or `elif`: the condition, the block and any else.
+    fn parse_if_rest(&mut self) -> Result<IfStmt, ParseError> {
+        let open = self.pos;
+        self.expect(&Token::LParen)?;
+        let cond = self.parse_expr()?;
+        self.expect_close(open)?;
+        let then_block = self.parse_block()?;
+        // `else if` is sugar for an else block holding just that `if`, and
+        // `elif` for `else if`
+        let else_block = match self.peek() {
+            Token::Else => {
+                self.advance();
+                if *self.peek() == Token::If {
+                    let span = self.span_at(self.pos);
+                    Some(Block { stmts: vec![Stmt::If(self.parse_if_stmt()?)], spans: vec![span] })
+                } else {
+                    Some(self.parse_block()?)
+                }
+            }
+            Token::Elif => {
+                let span = self.span_at(self.pos);
+                self.advance();
+                Some(Block { stmts: vec![Stmt::If(self.parse_if_rest()?)], spans: vec![span] })
+            }
+            _ => None,
+        };
+        Ok(IfStmt { cond, then_block, else_block })
+    }
+
+    // Whether the tokens ahead start `name = ...`, `name.field = ...` or
+    // `name[index] = ...` (`+=` and the like included)
+    fn at_assign(&self) -> bool {
+        let is_assign = |n: usize| self.tokens.get(self.pos + n)
+            .is_some_and(|t| *t == Token::Eq || compound_op(t).is_some());
+        if !matches!(self.peek(), Token::Ident(_)) {
+            return false;
+        }
+        match self.tokens.get(self.pos + 1) {
+            Some(Token::Dot) => is_assign(3),
+            Some(Token::LBracket) => {
+                // past the matching `]`
+                let mut depth = 0;
+                for (n, t) in self.tokens[self.pos + 1..].iter().enumerate() {
+                    match t {
+                        Token::LBracket => depth += 1,
+                        Token::RBracket if depth == 1 => return is_assign(n + 2),
+                        Token::RBracket => depth -= 1,
+                        Token::Semicolon | Token::EOF => return false,
+                        _ => {}
+                    }
+                }
+                false
+            }
+            _ => is_assign(1),
+        }
+    }
+
+    fn parse_assign(&mut self) -> Result<Assign, ParseError> {
+        let assign = self.parse_assign_expr()?;
+        self.expect(&Token::Semicolon)?;
+        Ok(assign)
+    }
+
+    // `name = value`, `name.field = value` or `name[index] = value`,
+    // without the `;`
+    fn parse_assign_expr(&mut self) -> Result<Assign, ParseError> {
+        let name = self.expect_ident("assignment target")?;
+        let mut field = None;
+        let mut index = None;
+        match self.peek() {
+            Token::Dot => {
+                self.advance();
+                field = Some(self.expect_ident("field name")?);
+            }
+            Token::LBracket => {
+                let open = self.pos;
+                self.advance();
+                index = Some(self.parse_expr()?);
+                self.expect_close(open)?;
+            }
+            _ => {}
+        }
+        // `x += e` is sugar for `x = x + e`
+        let op = compound_op(self.peek());
+        let op_span = self.span_at(self.pos);
+        if op.is_some() {
+            self.advance();
+        } else {
+            self.expect(&Token::Eq)?;
+        }
+        let mut value = self.parse_expr()?;
+        if *self.peek() == Token::Eq {
+            return Err(ParseError::ChainedAssignment { name, span: self.span_at(self.pos) });
+        }
+        if let Some(op) = op {
+            let base = Box::new(Expr::Ident(name));
+            let target = match (&field, &index) {
+                (Some(field), _) => Expr::Field { base, field: *field },
+                (_, Some(index)) => Expr::Index { base, index: Box::new(index.clone()) },
+                _ => Expr::Ident(name),
+            };
+            value = Expr::Binary { op: op.to_string(), left: Box::new(target), right: Box::new(value), span: op_span };
+        }
+        Ok(Assign { name, field, index, value })
+    }
+
+    fn parse_while_stmt(&mut self) -> Result<WhileStmt, ParseError> {
+        self.expect(&Token::While)?;
+        let open = self.pos;
+        self.expect(&Token::LParen)?;
+        let cond = self.parse_expr()?;
+        self.expect_close(open)?;
+        let body = self.parse_block()?;
+        Ok(WhileStmt { cond, body })
+    }
+
+    // `handle { ... } with { i32 get() { ... } ... }`: each handler is
+    // defined like a function named after its effect, and is nested in the
+    // one being parsed, to be hoisted with the rest. Its own name is
+    // `handleN.effect`, which no call can spell.
+    fn parse_handle_stmt(&mut self) -> Result<HandleStmt, ParseError> {
+        self.expect(&Token::Handle)?;
+        let body = self.parse_block()?;
+        self.expect(&Token::With)?;
+        self.handles += 1;
+        let open = self.pos;
+        self.expect(&Token::LBrace)?;
+        let mut handlers: Vec<(Sym, Sym)> = Vec::new();
+        while !matches!(self.peek(), Token::RBrace | Token::EOF) {
+            let span = self.span_at(self.pos);
+            let ty = self.parse_type()?;
+            let effect = self.expect_ident("effect name")?;
+            if handlers.iter().any(|&(e, _)| e == effect) {
+                return Err(ParseError::DuplicateHandler { effect, span });
+            }
+            let name = Sym::intern(&format!("handle{}.{effect}", self.handles));
+            let func = self.parse_func_rest(ty, name)?;
+            self.nested.last_mut().expect("statements are parsed in a function").1.push((func, span));
+            handlers.push((effect, name));
+        }
+        self.expect_close(open)?;
+        Ok(HandleStmt { body, handlers })
+    }
+
+    // The init is a whole statement, `;` included; the step is an
+    // assignment or expression without one.
+    fn parse_for_stmt(&mut self) -> Result<ForStmt, ParseError> {
+        self.expect(&Token::For)?;
+        let open = self.pos;
+        self.expect(&Token::LParen)?;
+        let init = if *self.peek() == Token::Semicolon {
+            self.advance();
+            None
+        } else {
+            Some(Box::new(self.parse_stmt()?))
+        };
+        let cond = if *self.peek() == Token::Semicolon {
+            None
+        } else {
+            Some(self.parse_expr()?)
+        };
+        self.expect(&Token::Semicolon)?;
+        let step = if *self.peek() == Token::RParen {
+            None
+        } else if self.at_assign() {
+            Some(Box::new(Stmt::Assign(self.parse_assign_expr()?)))
+        } else {
+            Some(Box::new(Stmt::Expr(self.parse_expr()?)))
+        };
+        self.expect_close(open)?;
+        let body = self.parse_block()?;
+        Ok(ForStmt { init, cond, step, body })
+    }
 
     // ---- const_decl ----
-    fn parse_const_decl(&mut self) -> ConstDecl {
-        self.expect(&Token::Const);
-        let ty = self.parse_type();
-        let name = match self.next() {
-            Token::Ident(id) => id,
-            t => panic!("expected identifier after type, got {:?}", t),
+    // `const i32 n = 3;`, or with a brace initializer an array,
+    // `const i32[3] primes = { 2, 3, 5 };`, or a struct,
+    // `const Point origin = { 0, 0 };`.
+    fn parse_const_decl(&mut self) -> Result<ConstDecl, ParseError> {
+        self.expect(&Token::Const)?;
+        let ty = self.parse_type()?;
+        let len = if *self.peek() == Token::LBracket {
+            let open = self.pos;
+            self.advance();
+            let len = self.parse_expr()?;
+            self.expect_close(open)?;
+            Some(len)
+        } else {
+            None
+        };
+        let name = self.expect_ident("identifier after type")?;
+        self.expect(&Token::Eq)?;
+        let (value, init) = if *self.peek() == Token::LBrace {
+            (None, Some(self.parse_initializer()?))
+        } else {
+            (Some(self.parse_expr()?), None)
         };
-        self.expect(&Token::Eq);
-        let value = self.parse_expr();
-        self.expect(&Token::Semicolon);
-        ConstDecl { ty, name, value }
+        self.expect(&Token::Semicolon)?;
+        Ok(ConstDecl { ty, name, len, value, init })
     }
 
     // ---- expr ----
-    fn parse_expr(&mut self) -> Expr {
+    pub fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        self.parse_expr_bp(0)
+    }
+
+    // Precedence climbing: operators bind left-to-right, and only those
+    // binding tighter than `min_bp` are taken at this level. Public so tests
+    // can parse an expression on its own. `a < b < c` would compare `a < b`'s
+    // 0 or 1 with `c`, which is never what was meant, so it is an error;
+    // `(a < b) < c` still parses.
+    pub fn parse_expr_bp(&mut self, min_bp: u8) -> Result<Expr, ParseError> {
+        let mut left = self.parse_unary()?;
+        let mut compared = false; // `left` is a comparison built at this level
+        while let Some((op, bp)) = binary_op(self.peek()) {
+            if bp <= min_bp {
+                break;
+            }
+            let relational = matches!(op, "<" | ">" | "<=" | ">=");
+            if relational && compared {
+                return Err(ParseError::ChainedComparison { op, span: self.span_at(self.pos) });
+            }
+            compared = relational;
+            let span = self.span_at(self.pos);
+            self.advance();
+            let right = self.parse_expr_bp(bp)?;
+            left = Expr::Binary { op: op.to_string(), left: Box::new(left), right: Box::new(right), span };
+        }
+        Ok(left)
+    }
+
+    // Prefix `-` and `!` bind tighter than any binary operator and nest,
+    // so `- -x` is `-(-x)`.
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek() {
+            Token::Minus => "-",
+            Token::Not => "!",
+            _ => return self.parse_postfix(),
+        };
+        self.advance();
+        let expr = self.parse_unary()?;
+        Ok(Expr::Unary { op: op.to_string(), expr: Box::new(expr) })
+    }
+
+    // Field access and indexing bind tighter than prefix operators: `-p.x`
+    // is `-(p.x)` and `!a[i]` is `!(a[i])`.
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut e = self.parse_primary()?;
+        loop {
+            match self.peek() {
+                Token::Dot => {
+                    self.advance();
+                    let field = self.expect_ident("field name")?;
+                    e = Expr::Field { base: Box::new(e), field };
+                }
+                Token::LBracket => {
+                    let open = self.pos;
+                    self.advance();
+                    let index = self.parse_expr()?;
+                    self.expect_close(open)?;
+                    e = Expr::Index { base: Box::new(e), index: Box::new(index) };
+                }
+                _ => return Ok(e),
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
         match self.next() {
-            Token::Number(n) => Expr::Number(n),
-            Token::Ident(id) => Expr::Ident(id),
-            Token::Print => {
-                self.expect(&Token::LParen);
-                let arg = self.parse_expr();
-                self.expect(&Token::RParen);
-                Expr::Builtin(Builtin::Print(Box::new(arg)))
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::Str(bytes) => Ok(Expr::Str(bytes)),
+            Token::Ident(name) if *self.peek() == Token::LParen => {
+                self.advance();
+                let args = self.parse_args()?;
+                Ok(Expr::Call { name, args })
+            }
+            Token::Ident(id) => Ok(Expr::Ident(id)),
+            Token::LParen => {
+                let open = self.pos - 1;
+                let e = self.parse_expr()?;
+                self.expect_close(open)?;
+                Ok(e)
+            }
+            tok @ (Token::Print | Token::PrintUnsigned) => {
+                let unsigned = tok == Token::PrintUnsigned;
+                let open = self.pos;
+                self.expect(&Token::LParen)?;
+                let mut args = vec![self.parse_expr()?];
+                while *self.peek() == Token::Comma {
+                    self.advance();
+                    args.push(self.parse_expr()?);
+                }
+                self.expect_close(open)?;
+                Ok(Expr::Builtin(if unsigned { Builtin::PrintUnsigned(args) } else { Builtin::Print(args) }))
+            }
+            Token::Input => {
+                let open = self.pos;
+                self.expect(&Token::LParen)?;
+                self.expect_close(open)?;
+                Ok(Expr::Builtin(Builtin::Input))
+            }
+            Token::TypeOf => {
+                let open = self.pos;
+                self.expect(&Token::LParen)?;
+                let expr = self.parse_expr()?;
+                self.expect_close(open)?;
+                Ok(Expr::TypeOf { expr: Box::new(expr), name: Default::default() })
+            }
+            Token::Perform => {
+                let name = self.expect_ident("effect name after perform")?;
+                self.expect(&Token::LParen)?;
+                let args = self.parse_args()?;
+                Ok(Expr::Builtin(Builtin::Perform(name, args)))
+            }
+            t => Err(self.unexpected("expression".to_string(), t)),
+        }
+    }
+
+    // Comma-separated arguments up to and including the closing `)`, the
+    // `(` having just been consumed.
+    fn parse_args(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let open = self.pos - 1;
+        let mut args = Vec::new();
+        while *self.peek() != Token::RParen {
+            args.push(self.parse_expr()?);
+            if *self.peek() == Token::Comma {
+                self.advance();
+            } else {
+                break;
             }
-            t => panic!("unexpected token in expr: {:?}", t),
         }
+        self.expect_close(open)?;
+        Ok(args)
+    }
+}
+
+// The parameters of `func` and every local declared in its body.
+fn locals(func: &mut FuncDef) -> Vec<Sym> {
+    let mut names: Vec<Sym> = func.params.iter().map(|p| p.name).collect();
+    visit_block(&mut func.body, &mut |stmt| match stmt {
+        Stmt::VarDecl(v) => names.push(v.name),
+        Stmt::ConstDecl(c) => names.push(c.name),
+        _ => {}
+    }, &mut |_| {});
+    names
+}
+
+// How the opening bracket `open` is spelled, and the token closing it.
+fn closing(open: &Token) -> (&'static str, Token) {
+    match open {
+        Token::LParen => ("(", Token::RParen),
+        Token::LBracket => ("[", Token::RBracket),
+        _ => ("{", Token::RBrace),
+    }
+}
+
+// The operator a compound assignment token applies, e.g. `+` for `+=`.
+fn compound_op(tok: &Token) -> Option<&'static str> {
+    match tok {
+        Token::PlusEq => Some("+"),
+        Token::MinusEq => Some("-"),
+        Token::StarEq => Some("*"),
+        Token::SlashEq => Some("/"),
+        _ => None,
+    }
+}
+
+// Spelling and precedence of a binary operator token (higher binds tighter).
+fn binary_op(tok: &Token) -> Option<(&'static str, u8)> {
+    match tok {
+        Token::Or => Some(("||", 1)),
+        Token::And => Some(("&&", 2)),
+        Token::EqEq => Some(("==", 3)),
+        Token::Neq => Some(("!=", 3)),
+        Token::Lt => Some(("<", 4)),
+        Token::Gt => Some((">", 4)),
+        Token::Le => Some(("<=", 4)),
+        Token::Ge => Some((">=", 4)),
+        Token::Plus => Some(("+", 5)),
+        Token::Minus => Some(("-", 5)),
+        Token::Star => Some(("*", 6)),
+        Token::Slash => Some(("/", 6)),
+        Token::Percent => Some(("%", 6)),
+        _ => None,
     }
 }
@@ -0,0 +1,76 @@
+// src/prelude.rs
+//
+// Functions every program can call without defining them, written in the
+// language itself. `link` adds the ones a program calls, and those they
+// call, to its declarations; a function the program defines itself takes
+// the place of the prelude's.
+use crate::ast::{visit_block, Expr, FuncDef, Program, TopDecl};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+
+pub const SOURCE: &str = "
+i32 pow(i32 base, i32 exp) {
+    var i32 result = 1;
+    var i32 i = 0;
+    while (i < exp) {
+        result = result * base;
+        i = i + 1;
+    }
+    return result;
+}
+
+i32 gcd(mut i32 a, mut i32 b) {
+    while (b != 0) {
+        i32 t = b;
+        b = a % b;
+        a = t;
+    }
+    if (a < 0) {
+        return -a;
+    }
+    return a;
+}
+";
+
+pub fn link(program: &mut Program) {
+    // parsed only once a program calls something it doesn't define
+    let mut available: Option<Vec<FuncDef>> = None;
+    // the declarations whose calls have been looked at
+    let mut checked = 0;
+    loop {
+        let mut called = Vec::new();
+        for decl in &mut program.decls[checked..] {
+            if let TopDecl::Func(f) = decl {
+                visit_block(&mut f.body, &mut |_| {}, &mut |e| {
+                    if let Expr::Call { name, .. } = e {
+                        called.push(*name);
+                    }
+                });
+            }
+        }
+        checked = program.decls.len();
+        called.retain(|&name| !program.decls.iter().any(|d| matches!(d, TopDecl::Func(f) if f.name == name)));
+        if called.is_empty() {
+            return;
+        }
+        let (linked, rest): (Vec<_>, Vec<_>) = available.take().unwrap_or_else(parse)
+            .into_iter()
+            .partition(|f| called.contains(&f.name));
+        available = Some(rest);
+        if linked.is_empty() {
+            return;
+        }
+        program.decls.extend(linked.into_iter().map(TopDecl::Func));
+    }
+}
+
+fn parse() -> Vec<FuncDef> {
+    let tokens = Lexer::new(SOURCE).tokenize().expect("the prelude lexes");
+    let prelude = Parser::new(tokens).parse_program().expect("the prelude parses");
+    prelude.decls.into_iter()
+        .filter_map(|d| match d {
+            TopDecl::Func(f) => Some(f),
+            _ => None,
+        })
+        .collect()
+}
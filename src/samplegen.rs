@@ -1,5 +1,6 @@
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::{Write, Seek, SeekFrom};
+#[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt; // for mode()
 use std::path::Path;
 
@@ -13,7 +14,6 @@ pub fn emit_min_elf_hello<P: AsRef<Path>>(out_path: P) -> std::io::Result<()> {
     //
     // Virtual addresses mirror file offsets with base 0x400000:
     const BASE_VADDR: u64 = 0x400000;
-    const OFF_ELF_HDR: u64 = 0x0000;
     const OFF_PROG_HDR: u64 = 0x0040;
     const OFF_SEG: u64 = 0x1000;
     const VADDR_SEG: u64 = BASE_VADDR + OFF_SEG;
@@ -53,10 +53,7 @@ pub fn emit_min_elf_hello<P: AsRef<Path>>(out_path: P) -> std::io::Result<()> {
     let lea_next_ip_file_off = code_start_file_off + (12 + 7); // at end of LEA instruction
     let disp = (msg_file_off as i64) - (lea_next_ip_file_off as i64);
     let disp_bytes = (disp as i32).to_le_bytes();
-    code[lea_disp32_offset_in_code + 0] = disp_bytes[0];
-    code[lea_disp32_offset_in_code + 1] = disp_bytes[1];
-    code[lea_disp32_offset_in_code + 2] = disp_bytes[2];
-    code[lea_disp32_offset_in_code + 3] = disp_bytes[3];
+    code[lea_disp32_offset_in_code..lea_disp32_offset_in_code + 4].copy_from_slice(&disp_bytes);
 
     // Concatenate text+rodata blob
     let mut seg: Vec<u8> = Vec::with_capacity(code.len() + msg.len());
@@ -74,8 +71,8 @@ pub fn emit_min_elf_hello<P: AsRef<Path>>(out_path: P) -> std::io::Result<()> {
     elf.extend_from_slice(&u16::to_le_bytes(2));    // e_type = ET_EXEC
     elf.extend_from_slice(&u16::to_le_bytes(0x3E)); // e_machine = EM_X86_64
     elf.extend_from_slice(&u32::to_le_bytes(1));    // e_version
-    elf.extend_from_slice(&u64::to_le_bytes(0x401000)); // e_entry
-    elf.extend_from_slice(&u64::to_le_bytes(0x40));     // e_phoff
+    elf.extend_from_slice(&u64::to_le_bytes(VADDR_SEG)); // e_entry
+    elf.extend_from_slice(&u64::to_le_bytes(OFF_PROG_HDR)); // e_phoff
     elf.extend_from_slice(&u64::to_le_bytes(0));        // e_shoff
     elf.extend_from_slice(&u32::to_le_bytes(0));        // e_flags
     elf.extend_from_slice(&u16::to_le_bytes(64));       // e_ehsize
@@ -86,31 +83,30 @@ pub fn emit_min_elf_hello<P: AsRef<Path>>(out_path: P) -> std::io::Result<()> {
     elf.extend_from_slice(&u16::to_le_bytes(0));        // e_shstrndx
 
     // 2. Pad to program header offset (0x40)
-    while elf.len() < 0x40 { elf.push(0); }
+    while elf.len() < OFF_PROG_HDR as usize { elf.push(0); }
 
     // 3. Program header (56 bytes)
     elf.extend_from_slice(&u32::to_le_bytes(1));        // PT_LOAD
     elf.extend_from_slice(&u32::to_le_bytes(5));        // R | X
-    elf.extend_from_slice(&u64::to_le_bytes(0x1000));   // p_offset
-    elf.extend_from_slice(&u64::to_le_bytes(0x401000)); // p_vaddr
-    elf.extend_from_slice(&u64::to_le_bytes(0x401000)); // p_paddr
+    elf.extend_from_slice(&u64::to_le_bytes(OFF_SEG));  // p_offset
+    elf.extend_from_slice(&u64::to_le_bytes(VADDR_SEG)); // p_vaddr
+    elf.extend_from_slice(&u64::to_le_bytes(VADDR_SEG)); // p_paddr
     elf.extend_from_slice(&u64::to_le_bytes(seg.len() as u64)); // p_filesz
     elf.extend_from_slice(&u64::to_le_bytes(seg.len() as u64)); // p_memsz
     elf.extend_from_slice(&u64::to_le_bytes(0x1000));   // p_align
 
     // 4. Pad to 0x1000 before writing code
-    while elf.len() < 0x1000 { elf.push(0); }
+    while elf.len() < OFF_SEG as usize { elf.push(0); }
 
     // 5. Append code+data
     elf.extend_from_slice(&seg);
 
     // ---- Write file and mark executable ------------------------------------
-    let mut f = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .mode(0o755) // rwxr-xr-x
-        .open(out_path)?;
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o755); // rwxr-xr-x
+    let mut f = options.open(out_path)?;
     f.write_all(&elf)?;
     f.flush()?;
     f.seek(SeekFrom::Start(0))?;
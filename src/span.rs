@@ -0,0 +1,18 @@
+// src/span.rs
+// Source positions, shared by the front end, which finds them, and the IR
+// and VM, which carry them to runtime errors.
+use std::fmt;
+
+// 1-based source position of a token's first character; a tab counts as
+// one column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
@@ -0,0 +1,41 @@
+// src/timetrace.rs
+// Records compilation phases as Chrome trace events, viewable in
+// chrome://tracing or https://ui.perfetto.dev.
+use std::time::Instant;
+
+pub struct TimeTrace {
+    start: Instant,
+    events: Vec<String>,
+}
+
+impl Default for TimeTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeTrace {
+    pub fn new() -> Self {
+        TimeTrace { start: Instant::now(), events: Vec::new() }
+    }
+
+    pub fn begin(&mut self, category: &str, name: &str) {
+        self.event(category, name, 'B');
+    }
+
+    pub fn end(&mut self, category: &str, name: &str) {
+        self.event(category, name, 'E');
+    }
+
+    fn event(&mut self, category: &str, name: &str, phase: char) {
+        // names are identifiers or fixed phase labels, so need no escaping
+        let ts = self.start.elapsed().as_micros();
+        self.events.push(format!(
+            "{{\"name\":\"{name}\",\"cat\":\"{category}\",\"ph\":\"{phase}\",\"ts\":{ts},\"pid\":1,\"tid\":1}}"
+        ));
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"traceEvents\":[{}]}}", self.events.join(","))
+    }
+}
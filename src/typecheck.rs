@@ -0,0 +1,611 @@
+// src/typecheck.rs
+// Gives every expression a type and rejects operations on the wrong kind
+// of value, between parsing and codegen. Names that don't resolve are left
+// for codegen to report; expressions involving them go unchecked.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::*;
+use crate::codegen::const_value;
+use crate::intern::Sym;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    I32,
+    I64,
+    Void,
+    Struct(String),
+    Array(Box<Ty>), // of any length
+}
+
+impl Ty {
+    fn is_int(&self) -> bool {
+        matches!(self, Ty::I32 | Ty::I64)
+    }
+
+    // Integers convert into each other, an i64 into an i32 by keeping its
+    // low 32 bits; structs only into the same struct.
+    fn accepts(&self, got: &Ty) -> bool {
+        self.is_int() && got.is_int() || self == got
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::I32 => write!(f, "i32"),
+            Ty::I64 => write!(f, "i64"),
+            Ty::Void => write!(f, "void"),
+            Ty::Struct(name) => write!(f, "{name}"),
+            Ty::Array(elem) => write!(f, "{elem}[]"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeError {
+    UnknownType(String),
+    VoidVariable(String),
+    BinaryOperands { op: String, left: Ty, right: Ty },
+    UnaryOperand { op: String, operand: Ty },
+    PrintArgument(Ty),
+    StringOutsidePrint,
+    TypeOfOutsidePrint,
+    NotIndexable(Ty),
+    Index(Ty), // an index that isn't an integer
+    Condition(Ty),
+    Assign { name: String, expected: Ty, got: Ty }, // also initializers
+    ElementCount { name: String, expected: usize, got: usize }, // `const i32[3] a = { 1, 2 };`
+    ReturnType { func: String, expected: Ty, got: Ty },
+    ReturnValueFromVoid(String),
+    MissingReturnValue { func: String, ty: Ty }, // `return;` outside a void function
+    MissingReturn { func: String, ty: Ty },      // a path falls off the end
+    Argument { func: String, index: usize, expected: Ty, got: Ty }, // index from 1
+    VoidCall(String), // a call of a `void` function used as a value
+    HandlerSignature { effect: String, expected: String }, // e.g. `i32 ask(i32)`
+    WithHandlerSignature { effect: String, expected: String }, // a `with` handler, e.g. `i32 get()`
+    HandledDeclaredEffect(String), // a `with` handler for an effect the function of its name handles
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeError::UnknownType(name) => write!(f, "unknown type `{name}`"),
+            TypeError::VoidVariable(name) => write!(f, "variable `{name}` cannot have type `void`"),
+            TypeError::BinaryOperands { op, left, right } => match binary_verb(op) {
+                Some(verb) => write!(f, "cannot {verb} `{left}` and `{right}`"),
+                None => write!(f, "cannot apply `{op}` to `{left}` and `{right}`"),
+            },
+            TypeError::UnaryOperand { op, operand } if op == "-" => write!(f, "cannot negate `{operand}`"),
+            TypeError::UnaryOperand { op, operand } => write!(f, "cannot apply `{op}` to `{operand}`"),
+            TypeError::PrintArgument(ty) => write!(f, "print expects i32, got `{ty}`"),
+            TypeError::StringOutsidePrint => write!(f, "string literals can only be arguments of `print`"),
+            TypeError::TypeOfOutsidePrint => write!(f, "`typeof` is a string, so it can only be an argument of `print`"),
+            TypeError::NotIndexable(ty) => write!(f, "cannot index `{ty}`: only arrays have elements"),
+            TypeError::Index(ty) => write!(f, "array index must be an integer, got `{ty}`"),
+            TypeError::Condition(ty) => write!(f, "condition must be i32, got `{ty}`"),
+            TypeError::Assign { name, expected, got } => {
+                write!(f, "cannot assign `{got}` to `{name}` of type `{expected}`")
+            }
+            TypeError::ElementCount { name, expected, got } => {
+                write!(f, "`{name}` has {expected} element(s) but its initializer gives {got}")
+            }
+            TypeError::ReturnType { func, expected, got } => {
+                write!(f, "`{func}` returns `{expected}`, but this returns `{got}`")
+            }
+            TypeError::ReturnValueFromVoid(func) => write!(f, "`void` function `{func}` cannot return a value"),
+            TypeError::MissingReturnValue { func, ty } => {
+                write!(f, "`return;` needs a value: `{func}` returns `{ty}`")
+            }
+            TypeError::MissingReturn { func, ty } => {
+                write!(f, "`{func}` can reach its end without returning a `{ty}`")
+            }
+            TypeError::Argument { func, index, expected, got } => {
+                write!(f, "argument {index} of `{func}` must be `{expected}`, got `{got}`")
+            }
+            TypeError::VoidCall(func) => write!(f, "`{func}` returns `void`, so its call has no value"),
+            TypeError::HandlerSignature { effect, expected } => {
+                write!(f, "the handler of effect `{effect}` must be declared as the effect is: `{expected}`")
+            }
+            TypeError::WithHandlerSignature { effect, expected } => {
+                write!(f, "a `with` handler of `{effect}` takes and returns `i32`: `{expected}`")
+            }
+            TypeError::HandledDeclaredEffect(effect) => {
+                write!(f, "effect `{effect}` is declared, so the function `{effect}` handles it; a `with` cannot")
+            }
+        }
+    }
+}
+
+fn binary_verb(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "+" => "add",
+        "-" => "subtract",
+        "*" => "multiply",
+        "/" => "divide",
+        "%" => "take the remainder of",
+        "<" | ">" | "<=" | ">=" | "==" | "!=" => "compare",
+        _ => return None,
+    })
+}
+
+// Whether running `b` can't get past its end: some statement returns on
+// every path (an `if (1)` takes its then branch), or loops forever
+// (`while (1)`, or `for` without a condition, with no `break` out).
+pub(crate) fn always_returns(b: &Block) -> bool {
+    b.stmts.iter().any(|s| match s {
+        Stmt::Return(_) => true,
+        Stmt::If(i) => always_returns(&i.then_block)
+            && (matches!(i.cond, Expr::Number(n) if n != 0) || i.else_block.as_ref().is_some_and(always_returns)),
+        Stmt::While(w) => matches!(w.cond, Expr::Number(n) if n != 0) && !breaks(&w.body),
+        Stmt::For(f) => f.cond.is_none() && !breaks(&f.body),
+        Stmt::Handle(h) => always_returns(&h.body),
+        _ => false,
+    })
+}
+
+// Whether `b` contains a `break` out of the loop it is the body of.
+fn breaks(b: &Block) -> bool {
+    b.stmts.iter().any(|s| match s {
+        Stmt::Break => true,
+        Stmt::If(i) => breaks(&i.then_block) || i.else_block.as_ref().is_some_and(breaks),
+        Stmt::Handle(h) => breaks(&h.body),
+        _ => false, // a nested loop's breaks are its own
+    })
+}
+
+// Checks the whole program, collecting every error rather than stopping
+// at the first.
+pub fn check(program: &Program) -> Result<(), Vec<TypeError>> {
+    let mut checker = Checker::new(program);
+    for d in &program.decls {
+        match d {
+            TopDecl::Struct(s) => {
+                for field in &s.fields {
+                    checker.resolve(&field.ty, &field.name);
+                }
+            }
+            TopDecl::Func(f) => checker.check_func(f),
+            TopDecl::Var(v) => checker.check_global(v),
+            TopDecl::Effect(e) => checker.check_effect(e),
+            TopDecl::Const(c) => checker.check_const(c),
+        }
+    }
+    if checker.errors.is_empty() { Ok(()) } else { Err(checker.errors) }
+}
+
+struct Checker<'a> {
+    structs: HashMap<&'a str, &'a StructDecl>,
+    funcs: HashMap<&'a str, &'a FuncDef>,
+    effects: HashMap<&'a str, &'a EffectDecl>,
+    globals: HashMap<&'a str, Ty>,
+    const_values: HashMap<&'a str, i128>, // of the top-level consts that fold, for array lengths
+    scopes: Vec<HashMap<Sym, Ty>>, // innermost last, like codegen's LocalEnv
+    func: Sym, // the function being checked
+    ret: Option<Ty>, // and its return type, if known
+    errors: Vec<TypeError>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(program: &'a Program) -> Self {
+        let mut checker = Checker {
+            structs: HashMap::new(),
+            funcs: HashMap::new(),
+            effects: HashMap::new(),
+            globals: HashMap::new(),
+            const_values: HashMap::new(),
+            scopes: Vec::new(),
+            func: Sym::intern(""),
+            ret: None,
+            errors: Vec::new(),
+        };
+        for d in &program.decls {
+            match d {
+                TopDecl::Struct(s) => { checker.structs.insert(&s.name, s); }
+                TopDecl::Func(f) => { checker.funcs.insert(&f.name, f); }
+                TopDecl::Effect(e) => { checker.effects.insert(&e.name, e); }
+                _ => {}
+            }
+        }
+        for d in &program.decls {
+            let (ty, name, len) = match d {
+                TopDecl::Const(c) => (&c.ty, &c.name, c.len.as_ref()),
+                TopDecl::Var(v) => (&v.ty, &v.name, None),
+                _ => continue,
+            };
+            if let Some(ty) = checker.resolve(ty, name) {
+                let ty = if len.is_some() { Ty::Array(Box::new(ty)) } else { ty };
+                checker.globals.insert(name, ty);
+            }
+            if let TopDecl::Const(ConstDecl { len: None, value: Some(value), .. }) = d
+                && let Some(n) = const_value(value, &checker.const_values) {
+                checker.const_values.insert(name, n);
+            }
+        }
+        checker
+    }
+
+    // The type named by `ty`, for a value named `name`; None if it's unknown.
+    fn resolve(&mut self, ty: &Type, name: &str) -> Option<Ty> {
+        match self.named(&ty.name) {
+            Some(Ty::Void) => {
+                self.errors.push(TypeError::VoidVariable(name.to_string()));
+                None
+            }
+            Some(ty) => Some(ty),
+            None => {
+                self.errors.push(TypeError::UnknownType(ty.name.to_string()));
+                None
+            }
+        }
+    }
+
+    fn named(&self, name: &str) -> Option<Ty> {
+        match name {
+            "i32" => Some(Ty::I32),
+            "i64" => Some(Ty::I64),
+            "void" => Some(Ty::Void),
+            s if self.structs.contains_key(s) => Some(Ty::Struct(s.to_string())),
+            _ => None,
+        }
+    }
+
+    // The initializer of a global variable, whose type `new` resolved.
+    fn check_global(&mut self, v: &VarDecl) {
+        self.scopes = Vec::new();
+        if let Some(e) = &v.value
+            && let Some(got) = self.expr(e)
+            && let Some(ty) = self.globals.get(v.name.as_str()).cloned() {
+            self.expect_assignable(&v.name, &ty, got);
+        }
+    }
+
+    // A top-level const: its value, or the elements of a const array, or the
+    // fields of a const struct. Codegen requires them to be constant.
+    fn check_const(&mut self, c: &ConstDecl) {
+        self.scopes = Vec::new();
+        let Some(ty) = self.globals.get(c.name.as_str()).cloned() else { return };
+        if let Some(got) = c.value.as_ref().and_then(|e| self.expr(e)) {
+            self.expect_assignable(&c.name, &ty, got);
+        }
+        if let Some(values) = &c.init {
+            let len = c.len.as_ref().and_then(|len| const_value(len, &self.const_values));
+            self.check_initializer(&c.name, &ty, values, len);
+        }
+    }
+
+    // The values of `name = { ... }`: the elements of an array, which must
+    // number `len` if that is known and positive, or the fields of a
+    // struct, whose count codegen checks.
+    fn check_initializer(&mut self, name: &str, ty: &Ty, values: &[Expr], len: Option<i128>) {
+        if let (Ty::Array(_), Some(len)) = (ty, len)
+            && len > 0
+            && len != values.len() as i128 {
+            let expected = len as usize;
+            self.errors.push(TypeError::ElementCount { name: name.to_string(), expected, got: values.len() });
+        }
+        for (i, value) in values.iter().enumerate() {
+            let got = self.expr(value);
+            let (target, expected) = match ty {
+                Ty::Array(elem) => (format!("{name}[{i}]"), Some((**elem).clone())),
+                Ty::Struct(s) => match self.structs[s.as_str()].fields.get(i) {
+                    Some(field) => (format!("{name}.{}", field.name), self.named(&field.ty.name)),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let (Some(expected), Some(got)) = (expected, got) {
+                self.expect_assignable(&target, &expected, got);
+            }
+        }
+    }
+
+    // A declared effect is handled by the function of the same name, which
+    // must take and return what the effect does; codegen reports a missing
+    // handler.
+    fn check_effect(&mut self, e: &EffectDecl) {
+        for ty in &e.params {
+            if self.named(&ty.name).is_none() {
+                self.errors.push(TypeError::UnknownType(ty.name.to_string()));
+            }
+        }
+        let Some(f) = self.funcs.get(e.name.as_str()) else { return };
+        let ret = e.ret.as_ref().map_or("void", |t| t.name.as_str());
+        let matches = f.ret_type.name == ret
+            && f.params.len() == e.params.len()
+            && f.params.iter().zip(&e.params).all(|(p, ty)| p.ty.name == ty.name);
+        if !matches {
+            let params: Vec<_> = e.params.iter().map(|t| t.name.as_str()).collect();
+            let expected = format!("{ret} {}({})", e.name, params.join(", "));
+            self.errors.push(TypeError::HandlerSignature { effect: e.name.to_string(), expected });
+        }
+    }
+
+    // Like C, `main` may fall off its end, which returns 0.
+    fn check_func(&mut self, f: &FuncDef) {
+        self.scopes = vec![HashMap::new()];
+        self.func = f.name;
+        self.ret = self.named(&f.ret_type.name);
+        if self.ret.is_none() {
+            self.errors.push(TypeError::UnknownType(f.ret_type.name.to_string()));
+        }
+        for p in &f.params {
+            // a default sees only globals; codegen requires it be constant
+            let default = p.default.as_ref().and_then(|d| {
+                let scopes = std::mem::take(&mut self.scopes);
+                let got = self.expr(d);
+                self.scopes = scopes;
+                got
+            });
+            if let Some(ty) = self.resolve(&p.ty, &p.name) {
+                if let Some(got) = default {
+                    self.expect_assignable(&p.name, &ty, got);
+                }
+                self.scopes[0].insert(p.name, ty);
+            }
+        }
+        self.check_block(&f.body);
+        if let Some(ty) = &self.ret
+            && *ty != Ty::Void
+            && f.name != "main"
+            && !always_returns(&f.body) {
+            self.errors.push(TypeError::MissingReturn { func: f.name.to_string(), ty: ty.clone() });
+        }
+    }
+
+    fn check_block(&mut self, b: &Block) {
+        self.scopes.push(HashMap::new());
+        for s in &b.stmts {
+            self.check_stmt(s);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_stmt(&mut self, s: &Stmt) {
+        match s {
+            Stmt::VarDecl(v) => {
+                let value = v.value.as_ref().and_then(|e| self.expr(e));
+                let Some(mut ty) = self.resolve(&v.ty, &v.name) else { return };
+                if let Some(len) = &v.len {
+                    self.expr(len);
+                    ty = Ty::Array(Box::new(ty));
+                }
+                if let Some(got) = value {
+                    self.expect_assignable(&v.name, &ty, got);
+                }
+                if let Some(values) = &v.init {
+                    self.check_initializer(&v.name, &ty, values, None);
+                }
+                self.scopes.last_mut().unwrap().insert(v.name, ty);
+            }
+            // codegen rejects a brace initializer here
+            Stmt::ConstDecl(c) => {
+                let value = c.value.as_ref().and_then(|e| self.expr(e));
+                let Some(ty) = self.resolve(&c.ty, &c.name) else { return };
+                if let Some(got) = value {
+                    self.expect_assignable(&c.name, &ty, got);
+                }
+                self.scopes.last_mut().unwrap().insert(c.name, ty);
+            }
+            Stmt::Assign(a) => {
+                let got = self.expr(&a.value);
+                let base = Expr::Ident(a.name);
+                let target = match (&a.field, &a.index) {
+                    (Some(field), _) => self.field(&base, field),
+                    (_, Some(index)) => self.index(&base, index),
+                    _ => self.lookup(a.name),
+                };
+                if let (Some(expected), Some(got)) = (target, got) {
+                    let name = match (&a.field, &a.index) {
+                        (Some(field), _) => format!("{}.{}", a.name, field),
+                        (_, Some(_)) => format!("{}[..]", a.name),
+                        _ => a.name.to_string(),
+                    };
+                    self.expect_assignable(&name, &expected, got);
+                }
+            }
+            // the one place a `void` call needs no value
+            Stmt::Expr(Expr::Call { name, args }) => { self.call(name, args); }
+            Stmt::Expr(Expr::Builtin(Builtin::Perform(name, args))) if self.effects.contains_key(name.as_str()) => {
+                self.call(name, args);
+            }
+            Stmt::Expr(e) => { self.expr(e); }
+            Stmt::Return(e) => {
+                let got = e.as_ref().and_then(|e| self.expr(e));
+                let func = self.func.to_string();
+                match (self.ret.clone(), e) {
+                    (Some(Ty::Void), Some(_)) => self.errors.push(TypeError::ReturnValueFromVoid(func)),
+                    (Some(Ty::Void), None) | (None, _) => {}
+                    (Some(ty), None) => self.errors.push(TypeError::MissingReturnValue { func, ty }),
+                    (Some(expected), Some(_)) => {
+                        if let Some(got) = got
+                            && !expected.accepts(&got) {
+                            self.errors.push(TypeError::ReturnType { func, expected, got });
+                        }
+                    }
+                }
+            }
+            Stmt::If(i) => {
+                self.condition(&i.cond);
+                self.check_block(&i.then_block);
+                if let Some(else_block) = &i.else_block {
+                    self.check_block(else_block);
+                }
+            }
+            Stmt::While(w) => {
+                self.condition(&w.cond);
+                self.check_block(&w.body);
+            }
+            Stmt::For(f) => {
+                self.scopes.push(HashMap::new());
+                if let Some(init) = &f.init {
+                    self.check_stmt(init);
+                }
+                if let Some(cond) = &f.cond {
+                    self.condition(cond);
+                }
+                self.check_block(&f.body);
+                if let Some(step) = &f.step {
+                    self.check_stmt(step);
+                }
+                self.scopes.pop();
+            }
+            Stmt::Break | Stmt::Continue => {}
+            Stmt::Handle(h) => {
+                for &(effect, handler) in &h.handlers {
+                    self.check_with_handler(effect, handler);
+                }
+                self.check_block(&h.body);
+            }
+        }
+    }
+
+    // `perform` of an undeclared effect takes and gives i32s, so a `with`
+    // handler of it must too; a declared one keeps the function of its
+    // name.
+    fn check_with_handler(&mut self, effect: Sym, handler: Sym) {
+        if self.effects.contains_key(effect.as_str()) {
+            self.errors.push(TypeError::HandledDeclaredEffect(effect.to_string()));
+            return;
+        }
+        let f = self.funcs[handler.as_str()];
+        if f.ret_type.name != "i32" || f.params.iter().any(|p| p.ty.name != "i32") {
+            let params = vec!["i32"; f.params.len()];
+            let expected = format!("i32 {effect}({})", params.join(", "));
+            self.errors.push(TypeError::WithHandlerSignature { effect: effect.to_string(), expected });
+        }
+    }
+
+    fn expect_assignable(&mut self, name: &str, expected: &Ty, got: Ty) {
+        if !expected.accepts(&got) {
+            self.errors.push(TypeError::Assign { name: name.to_string(), expected: expected.clone(), got });
+        }
+    }
+
+    fn condition(&mut self, cond: &Expr) {
+        if let Some(ty) = self.expr(cond)
+            && !ty.is_int() {
+            self.errors.push(TypeError::Condition(ty));
+        }
+    }
+
+    fn lookup(&self, name: Sym) -> Option<Ty> {
+        self.scopes.iter().rev()
+            .find_map(|scope| scope.get(&name))
+            .or_else(|| self.globals.get(name.as_str()))
+            .cloned()
+    }
+
+    // Type of `base.field`; codegen reports a field that doesn't exist.
+    fn field(&mut self, base: &Expr, field: &str) -> Option<Ty> {
+        let Ty::Struct(name) = self.expr(base)? else { return None };
+        let decl = self.structs[name.as_str()];
+        let f = decl.fields.iter().find(|f| f.name == field)?;
+        self.named(&f.ty.name)
+    }
+
+    // Type of an element of `base`; codegen reports constant indices out
+    // of range.
+    fn index(&mut self, base: &Expr, index: &Expr) -> Option<Ty> {
+        if let Some(ty) = self.expr(index)
+            && !ty.is_int() {
+            self.errors.push(TypeError::Index(ty));
+        }
+        match self.expr(base)? {
+            Ty::Array(elem) => Some(*elem),
+            ty => {
+                self.errors.push(TypeError::NotIndexable(ty));
+                None
+            }
+        }
+    }
+
+    // Return type of a call, after checking its arguments; codegen reports
+    // unknown functions and argument counts.
+    fn call(&mut self, name: &str, args: &[Expr]) -> Option<Ty> {
+        let got: Vec<_> = args.iter().map(|a| self.expr(a)).collect();
+        let f = *self.funcs.get(name)?;
+        for (i, (param, got)) in f.params.iter().zip(got).enumerate() {
+            if let (Some(expected), Some(got)) = (self.named(&param.ty.name), got)
+                && !expected.accepts(&got) {
+                self.errors.push(TypeError::Argument { func: name.to_string(), index: i + 1, expected, got });
+            }
+        }
+        self.named(&f.ret_type.name)
+    }
+
+    // A call whose value is used, which a `void` function has none of.
+    fn call_value(&mut self, name: &str, args: &[Expr]) -> Option<Ty> {
+        match self.call(name, args)? {
+            Ty::Void => {
+                self.errors.push(TypeError::VoidCall(name.to_string()));
+                None
+            }
+            ty => Some(ty),
+        }
+    }
+
+    // None when the type can't be known, e.g. for an undeclared variable.
+    fn expr(&mut self, e: &Expr) -> Option<Ty> {
+        match e {
+            Expr::Number(n) => Some(if i32::try_from(*n).is_ok() { Ty::I32 } else { Ty::I64 }),
+            Expr::Str(_) => {
+                self.errors.push(TypeError::StringOutsidePrint);
+                None
+            }
+            Expr::TypeOf { .. } => {
+                self.errors.push(TypeError::TypeOfOutsidePrint);
+                None
+            }
+            Expr::Ident(name) => self.lookup(*name),
+            Expr::Builtin(Builtin::Print(args) | Builtin::PrintUnsigned(args)) => {
+                for arg in args.iter().filter(|a| !matches!(a, Expr::Str(_))) {
+                    if let Expr::TypeOf { expr, name } = arg {
+                        // an unresolved name leaves it unset, for codegen to report
+                        name.set(self.expr(expr).map(|ty| Sym::intern(&ty.to_string())));
+                        continue;
+                    }
+                    if let Some(ty) = self.expr(arg)
+                        && !ty.is_int() {
+                        self.errors.push(TypeError::PrintArgument(ty));
+                    }
+                }
+                Some(Ty::Void)
+            }
+            Expr::Builtin(Builtin::Input) => Some(Ty::I32),
+            Expr::Builtin(Builtin::Perform(name, args)) if self.effects.contains_key(name.as_str()) => {
+                self.call_value(name, args)
+            }
+            Expr::Builtin(Builtin::Perform(_, args)) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                Some(Ty::I32)
+            }
+            Expr::Unary { op, expr } => {
+                let operand = self.expr(expr)?;
+                if !operand.is_int() {
+                    self.errors.push(TypeError::UnaryOperand { op: op.clone(), operand });
+                    return None;
+                }
+                Some(if op == "!" { Ty::I32 } else { operand })
+            }
+            Expr::Binary { op, left, right, .. } => {
+                let (left, right) = (self.expr(left), self.expr(right));
+                let (left, right) = (left?, right?);
+                // `print` counts as 0 in a condition
+                let logical = op == "&&" || op == "||";
+                let fits = |t: &Ty| t.is_int() || logical && *t == Ty::Void;
+                if !fits(&left) || !fits(&right) {
+                    self.errors.push(TypeError::BinaryOperands { op: op.clone(), left, right });
+                    return None;
+                }
+                let compares = logical || binary_verb(op) == Some("compare");
+                Some(if !compares && (left == Ty::I64 || right == Ty::I64) { Ty::I64 } else { Ty::I32 })
+            }
+            Expr::Call { name, args } => self.call_value(name, args),
+            Expr::Field { base, field } => self.field(base, field),
+            Expr::Index { base, index } => self.index(base, index),
+        }
+    }
+}
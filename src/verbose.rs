@@ -0,0 +1,29 @@
+// src/verbose.rs
+// Opt-in tracing of compiler internals (phase boundaries, slot
+// allocation, rewrites). `verbose!` compiles to nothing unless the crate
+// is built with `--features verbose-log`; `--verbose` then turns it on.
+#![cfg_attr(not(feature = "verbose-log"), allow(dead_code))]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "verbose-log")]
+        {
+            if $crate::verbose::enabled() {
+                eprintln!("[{}] {}", module_path!(), format_args!($($arg)*));
+            }
+        }
+    };
+}
@@ -1,53 +1,603 @@
 // src/vm.rs
-use crate::ir::{Instr, ProgramIR};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
 
-pub struct VM;
+use crate::ir::{self, Instr, ProgramIR, VerifyError};
+use crate::span::Span;
 
-impl VM {
-    pub fn run(prog: &ProgramIR) -> i32 {
-        let main_idx = prog.main_index().expect("no `main` function found");
-        let main = &prog.funcs[main_idx];
+// A handler receives the arguments of a `perform` and returns the value
+// the performing expression resumes with.
+pub type Handler = Box<dyn FnMut(&[i64]) -> i64>;
+
+// A handler pushed by the embedder, or by the program's own `handle`:
+// the index of the function that handles the effect.
+enum Installed {
+    Native(Handler),
+    Func(usize),
+}
+
+// Installed effect handlers; the innermost (last pushed) wins.
+#[derive(Default)]
+pub struct HandlerStack {
+    handlers: Vec<(String, Installed)>,
+}
+
+impl HandlerStack {
+    pub fn push(&mut self, effect: &str, handler: impl FnMut(&[i64]) -> i64 + 'static) {
+        self.handlers.push((effect.to_string(), Installed::Native(Box::new(handler))));
+    }
+
+    pub fn pop(&mut self) {
+        self.handlers.pop();
+    }
+
+    fn push_func(&mut self, effect: &str, func: usize) {
+        self.handlers.push((effect.to_string(), Installed::Func(func)));
+    }
+
+    // Position of the innermost handler of `effect`.
+    fn find(&self, effect: &str) -> Option<usize> {
+        self.handlers.iter().rposition(|(name, _)| name == effect)
+    }
+
+    // Removes the handlers from `at` on, to be put back by `restore`.
+    fn split_off(&mut self, at: usize) -> HandlerStack {
+        HandlerStack { handlers: self.handlers.split_off(at) }
+    }
+
+    // Drops the handlers above `len`, then puts `hidden` back on top.
+    fn restore(&mut self, len: usize, hidden: HandlerStack) {
+        self.handlers.truncate(len);
+        self.handlers.extend(hidden.handlers);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    StackUnderflow(&'static str), // instruction that found the stack empty
+    UnhandledEffect(String),
+    HandlerArguments { effect: String, params: usize, args: usize }, // a `perform` of a `with` handler
+    Overflow(&'static str),       // checked mode only
+    ForbiddenOperation(&'static str), // I/O attempted in a sandbox
+    StepBudgetExhausted,
+    MemoryBudgetExceeded,
+    UnknownLabel(u32),            // jump to a label the function doesn't define
+    DivisionByZero(&'static str), // Div or Mod, of either width
+    IndexOutOfRange { index: i64, len: usize }, // an array index outside 0..len
+    StackOverflow { func: String, depth: usize }, // `func` called past `VmState::max_depth` nested calls
+    NoMain,
+    Invalid(VerifyError), // code `ir::verify` rejects, refused before it runs
+    At { error: Box<VmError>, span: Span }, // raised by code from this source position
+}
+
+impl VmError {
+    // The error itself, without the source position `At` adds.
+    pub fn cause(&self) -> &VmError {
+        match self {
+            VmError::At { error, .. } => error.cause(),
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow(op) => write!(f, "stack underflow on {op}"),
+            VmError::UnhandledEffect(name) => write!(f, "unhandled effect `{name}`"),
+            VmError::HandlerArguments { effect, params, args } => {
+                write!(f, "the handler of effect `{effect}` takes {params} argument(s), but `perform` passes {args}")
+            }
+            VmError::Overflow(op) => write!(f, "integer overflow in {op}"),
+            VmError::ForbiddenOperation(op) => write!(f, "`{op}` is not allowed in the sandbox"),
+            VmError::StepBudgetExhausted => write!(f, "step budget exhausted"),
+            VmError::MemoryBudgetExceeded => write!(f, "memory budget exceeded"),
+            VmError::UnknownLabel(id) => write!(f, "jump to undefined label L{id}"),
+            VmError::DivisionByZero(op) => write!(f, "division by zero in {op}"),
+            VmError::IndexOutOfRange { index, len } => {
+                write!(f, "array index {index} is out of range for length {len}")
+            }
+            VmError::StackOverflow { func, depth } => {
+                write!(f, "stack overflow in `{func}`: more than {depth} nested calls")
+            }
+            VmError::NoMain => write!(f, "no `main` function found"),
+            VmError::Invalid(e) => write!(f, "invalid IR: {e}"),
+            VmError::At { error, span } => write!(f, "{error} at {span}"),
+        }
+    }
+}
 
-        let mut stack: Vec<i32> = Vec::new();
-        let mut locals: Vec<i32> = vec![0; main.n_locals];
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Continue,
+    Halted(i64), // main's return value
+    Error(VmError),
+}
+
+// Limits for evaluating untrusted programs: I/O builtins are refused and
+// execution stops after `max_steps` instructions or once the operand stack
+// holds more than `max_stack` values.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    pub max_steps: usize,
+    pub max_stack: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox { max_steps: 1_000_000, max_stack: 1 << 16 }
+    }
+}
+
+// How deep calls may nest before a run stops with VmError::StackOverflow.
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+// A suspended caller, resumed when its callee returns.
+struct Frame {
+    func: usize,
+    ip: usize,
+    locals: Vec<i64>,
+    base: usize,
+    // the caller's handlers: how many the callee may leave installed
+    // (a `return` from inside a `handle` doesn't pop them), and those a
+    // handler function runs without, which its `handle` installed
+    handlers: usize,
+    hidden: HandlerStack,
+}
+
+// Execution state of a program, advanced one instruction at a time by
+// `step`. Embedders and debuggers can inspect `ip`, `stack` and `locals`
+// (those of the running function) in between. Values are stored as i64;
+// those of i32 expressions are kept sign-extended.
+pub struct VmState<'p> {
+    prog: &'p ProgramIR,
+    labels: Vec<HashMap<u32, usize>>, // per function: label id -> index of its Label instr
+    func: usize,      // index of the running function
+    base: usize,      // stack height when it was entered; values below belong to callers
+    calls: Vec<Frame>,
+    pub ip: usize,
+    pub stack: Vec<i64>,
+    pub locals: Vec<i64>,
+    pub globals: Vec<i64>,
+    pub handlers: HandlerStack,
+    // Arithmetic wraps on overflow by default, like the native backend's
+    // 64-bit registers truncated to i32. Checked mode reports
+    // VmError::Overflow instead, so it can stop where native code wouldn't.
+    pub checked: bool,
+    pub sandbox: Option<Sandbox>,
+    pub max_depth: usize, // calls that may be in progress at once, main not counted
+    pub steps: usize, // instructions executed so far
+    pub trace: bool,  // print each instruction and the state it runs in to stderr
+    pub output: Option<Vec<u8>>, // if set, collects what the program prints instead of stdout
+}
+
+impl<'p> VmState<'p> {
+    pub fn new(prog: &'p ProgramIR) -> Result<Self, VmError> {
+        let main_idx = prog.main_index().ok_or(VmError::NoMain)?;
+        for func in &prog.funcs {
+            ir::verify(func).map_err(VmError::Invalid)?;
+        }
+        let labels = prog.funcs.iter()
+            .map(|f| {
+                f.code.iter().enumerate()
+                    .filter_map(|(i, instr)| match instr {
+                        Instr::Label(id) => Some((*id, i)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        Ok(VmState {
+            prog,
+            labels,
+            func: main_idx,
+            base: 0,
+            calls: Vec::new(),
+            ip: 0,
+            stack: Vec::with_capacity(prog.funcs[main_idx].max_stack),
+            locals: vec![0; prog.funcs[main_idx].n_locals],
+            globals: prog.globals.iter().map(|g| g.value).collect(),
+            handlers: HandlerStack::default(),
+            checked: false,
+            sandbox: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            steps: 0,
+            trace: false,
+            output: None,
+        })
+    }
+
+    pub fn step(&mut self) -> StepResult {
+        if self.trace {
+            self.print_trace();
+        }
+        match self.exec() {
+            Ok(result) => result,
+            Err(e) => StepResult::Error(self.locate(e)),
+        }
+    }
+
+    // `error` with the source position of the instruction that raised it,
+    // if the IR records positions.
+    fn locate(&self, error: VmError) -> VmError {
+        let func = &self.prog.funcs[self.func];
+        match self.ip.checked_sub(1).and_then(|i| func.spans.get(i)) {
+            Some(&span) => VmError::At { error: Box::new(error), span },
+            None => error,
+        }
+    }
+
+    // `main:3: Store(0)  stack=[10] locals=[0]`, before Store(0) runs
+    fn print_trace(&self) {
+        let func = &self.prog.funcs[self.func];
+        if let Some(instr) = func.code.get(self.ip) {
+            eprintln!("{}:{}: {:?}  stack={:?} locals={:?}", func.name, self.ip, instr, self.stack, self.locals);
+        }
+    }
 
-        let mut ip: usize = 0; // instruction pointer
+    // Jumps land on the Label itself, which is a no-op.
+    fn target(&self, label: u32) -> Result<usize, VmError> {
+        self.labels[self.func].get(&label).copied().ok_or(VmError::UnknownLabel(label))
+    }
 
-        while ip < main.code.len() {
-            match &main.code[ip] {
-                Instr::PushI32(n) => stack.push(*n),
-                Instr::Pop => { stack.pop(); }
+    fn exec(&mut self) -> Result<StepResult, VmError> {
+        // In case no explicit Ret got hit (we emit one anyway)
+        let prog = self.prog;
+        let Some(instr) = prog.funcs[self.func].code.get(self.ip) else {
+            return Ok(StepResult::Halted(0));
+        };
+        self.ip += 1;
+        self.steps += 1;
 
-                Instr::Load(i) => stack.push(locals[*i]),
-                Instr::Store(i) => {
-                    let v = stack.pop().expect("stack underflow on Store");
-                    locals[*i] = v;
+        if let Some(limits) = &self.sandbox {
+            if self.steps > limits.max_steps {
+                return Err(VmError::StepBudgetExhausted);
+            }
+            match instr {
+                Instr::Print | Instr::PrintUnsigned | Instr::PrintNewline | Instr::PrintStr(_) => {
+                    return Err(VmError::ForbiddenOperation("print"))
                 }
+                Instr::Input => return Err(VmError::ForbiddenOperation("input")),
+                Instr::Perform(..) => return Err(VmError::ForbiddenOperation("perform")),
+                _ => {}
+            }
+            if self.stack.len() >= limits.max_stack {
+                return Err(VmError::MemoryBudgetExceeded);
+            }
+        }
 
-                Instr::Add => bin(&mut stack, |a,b| a+b),
-                Instr::Sub => bin(&mut stack, |a,b| a-b),
-                Instr::Mul => bin(&mut stack, |a,b| a*b),
-                Instr::Div => bin(&mut stack, |a,b| a/b),
+        let stack = &mut self.stack;
+        match instr {
+            Instr::PushI32(n) => stack.push(*n as i64),
+            Instr::PushI64(n) => stack.push(*n),
+            Instr::Pop => { stack.pop(); }
+            Instr::Dup => {
+                let v = *stack.last().ok_or(VmError::StackUnderflow("Dup"))?;
+                stack.push(v);
+            }
+            Instr::Swap => {
+                let n = stack.len();
+                if n < 2 {
+                    return Err(VmError::StackUnderflow("Swap"));
+                }
+                stack.swap(n - 1, n - 2);
+            }
+
+            Instr::Load(i) => stack.push(self.locals[*i]),
+            Instr::Store(i) => {
+                let v = stack.pop().ok_or(VmError::StackUnderflow("Store"))?;
+                self.locals[*i] = v;
+            }
+            Instr::LoadIndex(base, len) => {
+                let i = stack.pop().ok_or(VmError::StackUnderflow("LoadIndex"))?;
+                let slot = local_slot(&self.locals, *base, *len, i)?;
+                stack.push(self.locals[slot]);
+            }
+            Instr::StoreIndex(base, len) => {
+                let v = stack.pop().ok_or(VmError::StackUnderflow("StoreIndex"))?;
+                let i = stack.pop().ok_or(VmError::StackUnderflow("StoreIndex"))?;
+                let slot = local_slot(&self.locals, *base, *len, i)?;
+                self.locals[slot] = v;
+            }
+            Instr::LoadGlobal(i) => stack.push(self.globals[*i]),
+            Instr::LoadConstIndex(array, len) => {
+                let i = stack.pop().ok_or(VmError::StackUnderflow("LoadConstIndex"))?;
+                let values = &self.prog.rodata[*array].values;
+                let slot = local_slot(values, 0, *len, i)?;
+                stack.push(values[slot]);
+            }
+            Instr::StoreGlobal(i) => {
+                let v = stack.pop().ok_or(VmError::StackUnderflow("StoreGlobal"))?;
+                self.globals[*i] = v;
+            }
+
+            Instr::Add => bin(stack, self.checked, Width::I32, "Add", |a,b| a+b)?,
+            Instr::Sub => bin(stack, self.checked, Width::I32, "Sub", |a,b| a-b)?,
+            Instr::Mul => bin(stack, self.checked, Width::I32, "Mul", |a,b| a*b)?,
+            Instr::Div if stack.last() == Some(&0) => return Err(VmError::DivisionByZero("Div")),
+            Instr::Mod if stack.last() == Some(&0) => return Err(VmError::DivisionByZero("Mod")),
+            Instr::Div => bin(stack, self.checked, Width::I32, "Div", |a,b| a/b)?,
+            Instr::Mod => bin(stack, self.checked, Width::I32, "Mod", |a,b| a%b)?,
+            Instr::Neg => un(stack, self.checked, Width::I32, "Neg", |a| -a)?,
+            Instr::AddI64 => bin(stack, self.checked, Width::I64, "AddI64", |a,b| a+b)?,
+            Instr::SubI64 => bin(stack, self.checked, Width::I64, "SubI64", |a,b| a-b)?,
+            Instr::MulI64 => bin(stack, self.checked, Width::I64, "MulI64", |a,b| a*b)?,
+            Instr::DivI64 if stack.last() == Some(&0) => return Err(VmError::DivisionByZero("DivI64")),
+            Instr::ModI64 if stack.last() == Some(&0) => return Err(VmError::DivisionByZero("ModI64")),
+            Instr::DivI64 => bin(stack, self.checked, Width::I64, "DivI64", |a,b| a/b)?,
+            Instr::ModI64 => bin(stack, self.checked, Width::I64, "ModI64", |a,b| a%b)?,
+            Instr::NegI64 => un(stack, self.checked, Width::I64, "NegI64", |a| -a)?,
+            // wraps even when checked: the language converts without a cast
+            Instr::Narrow => {
+                let a = stack.pop().ok_or(VmError::StackUnderflow("Narrow"))?;
+                stack.push(a as i32 as i64);
+            }
+            Instr::Not => un(stack, self.checked, Width::I32, "Not", |a| (a == 0) as i128)?,
+            Instr::CmpLt => bin(stack, self.checked, Width::I32, "CmpLt", |a,b| (a < b) as i128)?,
+            Instr::CmpGt => bin(stack, self.checked, Width::I32, "CmpGt", |a,b| (a > b) as i128)?,
+            Instr::CmpLe => bin(stack, self.checked, Width::I32, "CmpLe", |a,b| (a <= b) as i128)?,
+            Instr::CmpGe => bin(stack, self.checked, Width::I32, "CmpGe", |a,b| (a >= b) as i128)?,
+            Instr::CmpEq => bin(stack, self.checked, Width::I32, "CmpEq", |a,b| (a == b) as i128)?,
+            Instr::CmpNe => bin(stack, self.checked, Width::I32, "CmpNe", |a,b| (a != b) as i128)?,
 
-                Instr::Print => {
-                    let v = stack.pop().expect("stack underflow on Print");
-                    println!("{v}");
+            Instr::Label(_) => {}
+            Instr::Jump(id) => self.ip = self.target(*id)?,
+            Instr::JumpIfZero(id) => {
+                if stack.pop().ok_or(VmError::StackUnderflow("JumpIfZero"))? == 0 {
+                    self.ip = self.target(*id)?;
+                }
+            }
+            Instr::JumpIfNonZero(id) => {
+                if stack.pop().ok_or(VmError::StackUnderflow("JumpIfNonZero"))? != 0 {
+                    self.ip = self.target(*id)?;
                 }
+            }
+
+            Instr::Print => {
+                let v = stack.pop().ok_or(VmError::StackUnderflow("Print"))?;
+                write_out(&mut self.output, v.to_string().as_bytes());
+            }
+            Instr::PrintUnsigned => {
+                let v = stack.pop().ok_or(VmError::StackUnderflow("PrintUnsigned"))?;
+                write_out(&mut self.output, (v as u32).to_string().as_bytes());
+            }
+            Instr::PrintNewline => write_out(&mut self.output, b"\n"),
+            // the bytes needn't be UTF-8
+            Instr::PrintStr(bytes) => write_out(&mut self.output, bytes),
+            Instr::Input => {
+                let mut line = String::new();
+                // EOF or an unreadable stdin reads as an empty line
+                let _ = std::io::stdin().read_line(&mut line);
+                stack.push(parse_input(&line) as i64);
+            }
 
-                Instr::Ret => {
-                    return stack.pop().unwrap_or(0);
+            Instr::Perform(name, argc) => {
+                if stack.len() < *argc {
+                    return Err(VmError::StackUnderflow("Perform"));
                 }
+                let at = self.handlers.find(name).ok_or_else(|| VmError::UnhandledEffect(name.clone()))?;
+                match &mut self.handlers.handlers[at].1 {
+                    Installed::Native(handler) => {
+                        let args = stack.split_off(stack.len() - argc);
+                        stack.push(handler(&args));
+                    }
+                    // Called like a function, it resumes the `perform` by
+                    // returning. It runs where its `handle` is, outside the
+                    // handlers installed from it on.
+                    &mut Installed::Func(func) => {
+                        let params = prog.funcs[func].n_params;
+                        if params != *argc {
+                            return Err(VmError::HandlerArguments { effect: name.clone(), params, args: *argc });
+                        }
+                        let hidden = self.handlers.split_off(at);
+                        self.call(func, *argc, hidden)?;
+                    }
+                }
+            }
+            Instr::PushHandler(name, func) => self.handlers.push_func(name, *func),
+            Instr::PopHandler => self.handlers.pop(),
+
+            Instr::Call(callee, argc) => self.call(*callee, *argc, HandlerStack::default())?,
+
+            Instr::Ret => {
+                let value = if stack.len() > self.base { stack.pop().unwrap() } else { 0 };
+                let Some(caller) = self.calls.pop() else {
+                    return Ok(StepResult::Halted(value));
+                };
+                stack.truncate(self.base);
+                stack.push(value);
+                self.func = caller.func;
+                self.ip = caller.ip;
+                self.locals = caller.locals;
+                self.base = caller.base;
+                self.handlers.restore(caller.handlers, caller.hidden);
             }
-            ip += 1;
         }
+        Ok(StepResult::Continue)
+    }
 
-        // In case no explicit Ret got hit (we emit one anyway)
-        0
+    // Enters `callee` with the top `argc` values as its first locals;
+    // `hidden` are the handlers it runs without.
+    fn call(&mut self, callee: usize, argc: usize, hidden: HandlerStack) -> Result<(), VmError> {
+        let prog = self.prog;
+        let stack = &mut self.stack;
+        if stack.len() - self.base < argc {
+            return Err(VmError::StackUnderflow("Call"));
+        }
+        if self.calls.len() >= self.max_depth {
+            let func = prog.funcs[self.func].name.clone();
+            return Err(VmError::StackOverflow { func, depth: self.max_depth });
+        }
+        let args = stack.split_off(stack.len() - argc);
+        // verified to be all the callee's operands need
+        stack.reserve(prog.funcs[callee].max_stack);
+        let mut locals = vec![0; prog.funcs[callee].n_locals];
+        locals[..args.len()].copy_from_slice(&args);
+        self.calls.push(Frame {
+            func: self.func,
+            ip: self.ip,
+            locals: std::mem::replace(&mut self.locals, locals),
+            base: self.base,
+            handlers: self.handlers.handlers.len(),
+            hidden,
+        });
+        self.func = callee;
+        self.ip = 0;
+        self.base = stack.len();
+        Ok(())
     }
 }
 
-fn bin(stack: &mut Vec<i32>, f: impl Fn(i32, i32) -> i32) {
-    let b = stack.pop().expect("stack underflow (rhs)");
-    let a = stack.pop().expect("stack underflow (lhs)");
-    stack.push(f(a, b));
+// What `main` left behind, for tests that assert on variables directly.
+#[derive(Debug)]
+pub struct DebugRun {
+    pub exit: i64,
+    pub locals: Vec<i64>,
+    pub stack: Vec<i64>, // operand stack after the return value was popped
+}
+
+pub struct VM;
+
+impl VM {
+    // Returns main's value, which `--run` exits with just as native code
+    // passes it to sys_exit; falling off the end of main returns 0.
+    pub fn run(prog: &ProgramIR) -> Result<i32, VmError> {
+        Self::run_with_handlers(prog, HandlerStack::default())
+    }
+
+    pub fn run_with_handlers(prog: &ProgramIR, handlers: HandlerStack) -> Result<i32, VmError> {
+        let mut state = VmState::new(prog)?;
+        state.handlers = handlers;
+        Self::finish(state)
+    }
+
+    // Like `run`, printing every step to stderr; see `VmState::trace`.
+    pub fn run_traced(prog: &ProgramIR) -> Result<i32, VmError> {
+        let mut state = VmState::new(prog)?;
+        state.trace = true;
+        Self::finish(state)
+    }
+
+    // Like `run`, but arithmetic overflow is an error instead of wrapping.
+    pub fn run_checked(prog: &ProgramIR) -> Result<i32, VmError> {
+        let mut state = VmState::new(prog)?;
+        state.checked = true;
+        Self::finish(state)
+    }
+
+    // Runs an untrusted program within `limits`; see `Sandbox`.
+    pub fn run_sandboxed(prog: &ProgramIR, limits: Sandbox) -> Result<i32, VmError> {
+        let mut state = VmState::new(prog)?;
+        state.sandbox = Some(limits);
+        Self::finish(state)
+    }
+
+    // Runs like `run` (wrapping arithmetic), and hands back main's final
+    // locals and operand stack.
+    pub fn run_debug(prog: &ProgramIR) -> Result<DebugRun, VmError> {
+        let mut state = VmState::new(prog)?;
+        loop {
+            match state.step() {
+                StepResult::Continue => {}
+                StepResult::Halted(exit) => {
+                    return Ok(DebugRun { exit, locals: state.locals, stack: state.stack });
+                }
+                StepResult::Error(e) => return Err(e),
+            }
+        }
+    }
+
+    // Runs a state the caller has set up to the end, returning main's value.
+    pub fn finish(mut state: VmState) -> Result<i32, VmError> {
+        loop {
+            match state.step() {
+                StepResult::Continue => {}
+                StepResult::Halted(value) => return Ok(value as i32),
+                StepResult::Error(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Print's output goes to `VmState::output` if that's set, else stdout.
+fn write_out(output: &mut Option<Vec<u8>>, bytes: &[u8]) {
+    match output {
+        Some(buf) => buf.extend_from_slice(bytes),
+        None => { let _ = std::io::stdout().write_all(bytes); }
+    }
+}
+
+// Matches the native backend's `input()`: an optional leading `-`, then
+// digits up to the first other character; anything else on the line is
+// ignored. Wraps like the native 64-bit accumulator truncated to i32.
+fn parse_input(line: &str) -> i32 {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let (negative, digits) = match line.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let value = digits.bytes()
+        .take_while(u8::is_ascii_digit)
+        .fold(0i64, |acc, d| acc.wrapping_mul(10).wrapping_add((d - b'0') as i64));
+    (if negative { value.wrapping_neg() } else { value }) as i32
+}
+
+// Slot `base + i` of an indexed access to an array of `len` elements, if
+// `i` is in bounds and the slot one of `slots`: the locals, or a const
+// array's values.
+fn local_slot(slots: &[i64], base: usize, len: usize, i: i64) -> Result<usize, VmError> {
+    usize::try_from(i).ok()
+        .filter(|&i| i < len)
+        .map(|i| base + i)
+        .filter(|&slot| slot < slots.len())
+        .ok_or(VmError::IndexOutOfRange { index: i, len })
+}
+
+// The type an arithmetic instruction computes in.
+#[derive(Clone, Copy)]
+enum Width {
+    I32,
+    I64,
+}
+
+// Arithmetic computes in 128 bits (where no stored operands can overflow)
+// and narrows the result to the op's width, wrapping to its low bits as the
+// native backend does, or VmError::Overflow when `checked`.
+fn un(
+    stack: &mut Vec<i64>,
+    checked: bool,
+    width: Width,
+    name: &'static str,
+    op: impl Fn(i128) -> i128,
+) -> Result<(), VmError> {
+    let a = stack.pop().ok_or(VmError::StackUnderflow(name))?;
+    stack.push(narrow(op(a as i128), checked, width, name)?);
+    Ok(())
+}
+
+fn bin(
+    stack: &mut Vec<i64>,
+    checked: bool,
+    width: Width,
+    name: &'static str,
+    op: impl Fn(i128, i128) -> i128,
+) -> Result<(), VmError> {
+    let b = stack.pop().ok_or(VmError::StackUnderflow("rhs"))?;
+    let a = stack.pop().ok_or(VmError::StackUnderflow("lhs"))?;
+    stack.push(narrow(op(a as i128, b as i128), checked, width, name)?);
+    Ok(())
+}
+
+fn narrow(wide: i128, checked: bool, width: Width, name: &'static str) -> Result<i64, VmError> {
+    let fits = match width {
+        Width::I32 => i32::try_from(wide).is_ok(),
+        Width::I64 => i64::try_from(wide).is_ok(),
+    };
+    match width {
+        _ if fits => Ok(wide as i64),
+        _ if checked => Err(VmError::Overflow(name)),
+        Width::I32 => Ok(wide as i32 as i64),
+        Width::I64 => Ok(wide as i64),
+    }
 }
@@ -0,0 +1,79 @@
+// IR the native backend can't compile is reported as a `BackendError`
+// rather than written out as a broken executable.
+use cosplae::elfgen::{BackendError, MAX_CODE_BYTES, MAX_FRAME_BYTES};
+use cosplae::ir::{Func, Instr, ProgramIR};
+use cosplae::{native, CompileError, Compiler};
+
+fn program(code: Vec<Instr>) -> ProgramIR {
+    let main = Func {
+        name: "main".into(),
+        code,
+        n_locals: 0,
+        n_params: 0,
+        max_stack: 1,
+        locals_dbg: Vec::new(),
+        spans: Vec::new(),
+    };
+    ProgramIR { funcs: vec![main], globals: Vec::new(), rodata: Vec::new() }
+}
+
+fn backend_error(ir: &ProgramIR) -> BackendError {
+    Compiler::new().compile_program(ir).unwrap_err()
+}
+
+#[test]
+fn unresolvable_label() {
+    let ir = program(vec![Instr::Jump(99), Instr::Label(1), Instr::PushI32(0), Instr::Ret]);
+    assert_eq!(backend_error(&ir), BackendError::UndefinedLabel { func: "main".into(), label: 99 });
+    let Err(e @ CompileError::Backend(_)) = native(&ir, false, false) else { panic!() };
+    assert_eq!(e.to_string(), "native backend error: jump to undefined label L99 in `main`");
+}
+
+#[test]
+fn unsupported_and_out_of_range() {
+    let ir = program(vec![Instr::Perform("ask".into(), 0), Instr::Ret]);
+    assert_eq!(backend_error(&ir), BackendError::Unsupported("effect `ask`".into()));
+    let ir = program(vec![Instr::PushHandler("ask".into(), 0), Instr::PopHandler, Instr::PushI32(0), Instr::Ret]);
+    assert_eq!(backend_error(&ir), BackendError::Unsupported("a handler of effect `ask`".into()));
+
+    let ir = program(vec![Instr::Load(1 << 40), Instr::Ret]);
+    assert_eq!(backend_error(&ir), BackendError::OutOfRange { what: "local slot", value: 1 << 40 });
+
+    let mut ir = program(vec![Instr::PushI32(0), Instr::Ret]);
+    ir.funcs[0].name = "start".into();
+    assert_eq!(backend_error(&ir), BackendError::NoMain);
+}
+
+#[test]
+fn frame_too_large() {
+    let mut ir = program(vec![Instr::PushI32(0), Instr::Ret]);
+    ir.funcs[0].n_locals = 1 << 40;
+    assert_eq!(backend_error(&ir), BackendError::FrameTooLarge { func: "main".into(), n_locals: 1 << 40 });
+    let Err(e) = native(&ir, false, false) else { panic!() };
+    assert_eq!(e.to_string(), "native backend error: `main` has 1099511627776 local slots, more than fit in a stack frame of 8388608 bytes");
+
+    // the largest frame still compiles, but its slots end there
+    ir.funcs[0].n_locals = MAX_FRAME_BYTES / 8;
+    assert!(Compiler::new().compile_program(&ir).is_ok());
+    ir.funcs[0].code.insert(0, Instr::Load(MAX_FRAME_BYTES / 8));
+    assert_eq!(backend_error(&ir), BackendError::OutOfRange { what: "local slot", value: (MAX_FRAME_BYTES / 8) as i64 });
+}
+
+// Code and data are mapped at fixed addresses, so code that would run into
+// the data segment is refused, by the backend and by `generate_elf`.
+#[test]
+fn code_too_large() {
+    let pairs = MAX_CODE_BYTES / 6 + 1; // `push imm32; pop rax`
+    let mut code: Vec<_> = (0..pairs).flat_map(|_| [Instr::PushI32(1 << 20), Instr::Pop]).collect();
+    code.extend([Instr::PushI32(0), Instr::Ret]);
+    let BackendError::CodeTooLarge(bytes) = backend_error(&program(code)) else { panic!() };
+    assert!(bytes > MAX_CODE_BYTES);
+
+    let mut compiler = Compiler::new();
+    compiler.code = vec![0x90; MAX_CODE_BYTES + 1];
+    let path = std::env::temp_dir().join(format!("cosplae-{}-code-too-large", std::process::id()));
+    let error = compiler.generate_elf(&path).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(error.to_string().contains("more than the 2093056 that fit below its data"), "{error}");
+    assert!(!path.exists());
+}
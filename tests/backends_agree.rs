@@ -0,0 +1,367 @@
+// Runs inline programs through both the VM (`--run`) and a native build,
+// checking each against the expected stdout and exit status, so the two
+// backends can't drift apart unnoticed. Off x86-64 Linux only the VM runs.
+mod common;
+
+fn check(name: &str, source: &str, stdout: &str, exit: i32) {
+    let vm = common::cosplae(&["--run"], source);
+    assert_eq!(String::from_utf8_lossy(&vm.stdout), stdout, "{name}: VM stdout");
+    assert_eq!(vm.status.code(), Some(exit), "{name}: VM exit status");
+
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        let native = common::native(name, source);
+        assert_eq!(String::from_utf8_lossy(&native.stdout), stdout, "{name}: native stdout");
+        assert_eq!(native.status.code(), Some(exit), "{name}: native exit status");
+    }
+}
+
+#[test]
+fn arithmetic() {
+    check("agree-precedence", "i32 main() { print(2 + 3 * 4, (2 + 3) * 4, 10 - 4 - 3); return 0; }", "14\n20\n3\n", 0);
+    // division truncates toward zero; the remainder takes the dividend's sign
+    check("agree-division", "i32 main() { print(-7 / 2, -7 % 2, 7 % -2); return 0; }", "-3\n-1\n1\n", 0);
+    check("agree-wrapping", "i32 main() { i32 x = 2147483647; print(x + 1, x * 2); return 0; }",
+          "-2147483648\n-2\n", 0);
+}
+
+#[test]
+fn print() {
+    check("agree-print", "i32 main() { print(0, -5, 2147483647); return 0; }", "0\n-5\n2147483647\n", 0);
+    check("agree-print-strings", "i32 main() { print(\"a=\", 1, \", b=\", -2); return 0; }", "a=1, b=-2", 0);
+}
+
+// the high bit set reads as a large positive number, not a sign
+#[test]
+fn print_unsigned() {
+    check("agree-print-unsigned", "i32 main() { print_unsigned(-1, 0, 7, -2147483647 - 1); return 0; }",
+          "4294967295\n0\n7\n2147483648\n", 0);
+    check("agree-print-unsigned-strings", "i32 main() { print_unsigned(\"x=\", -16, \"\\n\"); return 0; }",
+          "x=4294967280\n", 0);
+}
+
+// Strings are written byte for byte: escapes decoded, UTF-8 as encoded in
+// the source, with the length in bytes rather than chars.
+#[test]
+fn string_bytes() {
+    let source = "i32 main() { print(\"a\\tb\\n\", \"café ☕\\n\"); return 0; }";
+    let want = b"a\tb\ncaf\xC3\xA9 \xE2\x98\x95\n";
+    assert_eq!(common::cosplae(&["--run"], source).stdout, want);
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        assert_eq!(common::native("agree-string-bytes", source).stdout, want);
+    }
+}
+
+// `\xNN` is any byte, UTF-8 or not; a raw string keeps its backslashes, so
+// r"\n" is two bytes where "\n" is one.
+#[test]
+fn hex_escapes_and_raw_strings() {
+    let source = r#"i32 main() { print("\x41\xff\x00|", r"\n", "\n", r"C:\x41\"); return 0; }"#;
+    let want = b"A\xff\0|\\n\nC:\\x41\\";
+    assert_eq!(common::cosplae(&["--run"], source).stdout, want);
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        assert_eq!(common::native("agree-hex-raw", source).stdout, want);
+    }
+}
+
+#[test]
+fn return_value_is_the_exit_status() {
+    check("agree-exit", "i32 main() { return 42; }", "", 42);
+    // statuses are taken mod 256
+    check("agree-exit-wraps", "i32 main() { return 300; }", "", 44);
+    check("agree-exit-negative", "i32 main() { return -1; }", "", 255);
+    check("agree-exit-call", "i32 f(i32 x) { return x * 3; } i32 main() { return f(5); }", "", 15);
+}
+
+// A returned expression leaves just its value, which is what is returned,
+// and a bare `return;` returns 0 without popping anything.
+#[test]
+fn returned_expressions() {
+    check("agree-return-expr", "i32 main() { return 2 + 3 * 4; }", "", 14);
+    check("agree-return-nested",
+          "i32 f(i32 x) { return x * 3 + 2 * (x - 1) - (x > 2 && x < 9); } i32 main() { print(f(4)); return f(1) + (f(2) || 0); }",
+          "17
+", 4);
+    check("agree-return-void",
+          "void f(i32 x) { if (x) { print(x); return; } print(0); } i32 main() { f(7); f(0); return (2 + 3) * 4 - 6; }",
+          "7
+0
+", 14);
+}
+
+// With `--overflow-checks` a result out of range of its type stops the
+// program with status 70, after the output so far, instead of wrapping.
+#[test]
+fn overflow_checks() {
+    let checked = |name: &str, source: &str, stdout: &str| {
+        let vm = common::cosplae(&["--run", "--overflow-checks"], source);
+        assert_eq!(String::from_utf8_lossy(&vm.stdout), stdout, "{name}: VM stdout");
+        assert_eq!(vm.status.code(), Some(70), "{name}: VM exit status");
+        assert!(String::from_utf8_lossy(&vm.stderr).contains("runtime error: integer overflow"), "{name}");
+        if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            let native = common::native_with_flags(name, &["--overflow-checks"], source, "");
+            assert_eq!(String::from_utf8_lossy(&native.stdout), stdout, "{name}: native stdout");
+            assert_eq!(native.status.code(), Some(70), "{name}: native exit status");
+            assert_eq!(String::from_utf8_lossy(&native.stderr), "runtime error: integer overflow\n", "{name}");
+        }
+    };
+    let program = |expr: &str| format!("i32 main() {{ i32 x = 2147483647; print(x - 1); print({expr}); return 0; }}");
+    for (name, expr) in [("add", "x + 1"), ("sub", "-x - 2"), ("mul", "x * 2"), ("neg", "-(-x - 1)"), ("div", "(-x - 1) / -1")] {
+        checked(&format!("agree-overflow-{name}"), &program(expr), "2147483646\n");
+    }
+    let program_i64 = |expr: &str| format!("i32 main() {{ i64 x = 9223372036854775807; print(x - 1); print({expr}); return 0; }}");
+    for (name, expr) in [("add", "x + 1"), ("sub", "-x - 2"), ("mul", "x * 2"), ("neg", "-(-x - 1)"), ("div", "(-x - 1) / -1")] {
+        checked(&format!("agree-overflow-i64-{name}"), &program_i64(expr), "9223372036854775806\n");
+    }
+    // the default wraps, and checked arithmetic that stays in range is unchanged
+    check("agree-overflow-wraps", &program("x + 1"), "2147483646\n-2147483648\n", 0);
+    let source = "i32 main() { i32 x = 2147483647; print(x - 1, -x - 1, (x - 7) / 3 * -2, x % 10); return 0; }";
+    let vm = common::cosplae(&["--run", "--overflow-checks"], source);
+    assert_eq!(String::from_utf8_lossy(&vm.stdout), "2147483646\n-2147483648\n-1431655760\n7\n");
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        assert_eq!(common::native_with_flags("agree-in-range", &["--overflow-checks"], source, "").stdout, vm.stdout);
+    }
+}
+
+// An index outside the array stops the program, whether it reads or
+// writes, rather than touching a neighbouring local.
+#[test]
+fn array_bounds() {
+    let program = |index: &str, access: &str| {
+        format!("i32 main() {{ var i32 a[4]; i32 b = 7; i32 i = {index}; print(1); {access} print(b); return 0; }}")
+    };
+    check("agree-bounds-in-range", &program("3", "a[i] = 2; print(a[i]);"), "1\n2\n7\n", 0);
+    for (name, index, access) in [("load", "4", "print(a[i]);"), ("store", "4", "a[i] = 2;"), ("negative", "-1", "a[i] = 2;")] {
+        let name = format!("agree-bounds-{name}");
+        let source = program(index, access);
+        let vm = common::cosplae(&["--run"], &source);
+        assert_eq!(String::from_utf8_lossy(&vm.stdout), "1\n", "{name}: VM stdout");
+        assert_eq!(vm.status.code(), Some(70), "{name}: VM exit status");
+        let message = format!("runtime error: array index {index} is out of range for length 4");
+        assert!(String::from_utf8_lossy(&vm.stderr).contains(&message), "{name}");
+        if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            let native = common::native(&name, &source);
+            assert_eq!(String::from_utf8_lossy(&native.stdout), "1\n", "{name}: native stdout");
+            assert_eq!(native.status.code(), Some(70), "{name}: native exit status");
+            assert_eq!(String::from_utf8_lossy(&native.stderr), "runtime error: array index out of range\n", "{name}");
+        }
+    }
+}
+
+// The longest numbers print exactly, with no digit lost or buffer overrun
+#[test]
+fn extreme_values_print_exactly() {
+    check("agree-print-extremes",
+          "i64 lo = -9223372036854775808; i32 main() { print(lo, 9223372036854775807, -2147483647 - 1); print_unsigned(-1); return 0; }",
+          "-9223372036854775808\n9223372036854775807\n-2147483648\n4294967295\n", 0);
+}
+
+// A default is evaluated where the function is declared, not in the caller.
+#[test]
+fn default_arguments() {
+    let src = "const i32 k = 5;\n\
+               i32 f(i32 x, i32 y = k * 2) { return x + y; }\n\
+               i32 main() { i32 k = 100; print(f(1), f(1, 2)); return 0; }";
+    check("agree-defaults", src, "11\n3\n", 0);
+}
+
+// Arithmetic on an i64 keeps all 64 bits, wrapping only at i64's bounds;
+// i32 arithmetic still wraps at 32.
+#[test]
+fn i64_arithmetic() {
+    let src = "i64 big() { return 4000000000; }\n\
+               i32 main() {\n\
+                   i64 x = 3000000000;\n\
+                   print(x + 1, x * 2, x - 4000000000, -x, x / 7, x % 7, big() + x);\n\
+                   i64 min = -9223372036854775807 - 1;\n\
+                   print(min - 1, min / -1, min % -1, -min);\n\
+                   i32 y = 2147483647;\n\
+                   print(y + 1, y + x);\n\
+                   return 0;\n\
+               }";
+    check("agree-i64", src, "3000000001\n6000000000\n-1000000000\n-3000000000\n428571428\n4\n7000000000\n\
+                             9223372036854775807\n-9223372036854775808\n0\n-9223372036854775808\n\
+                             -2147483648\n5147483647\n", 0);
+}
+
+// An i64 stored, passed or returned as an i32 keeps only its low 32 bits,
+// so the i32 reads back the same whichever way it's used.
+#[test]
+fn i64_narrows_into_i32() {
+    let program = |decls: &str, body: &str| format!("{decls}\ni32 main() {{ {body} print(x); print(x + 0); return 0; }}");
+    for (name, decls, body) in [
+        ("literal", "", "i32 x = 5000000000;"),
+        ("variable", "", "i64 wide = 5000000000; i32 x = wide;"),
+        ("call", "i64 big() { return 5000000000; }", "i32 x = big();"),
+        ("assign", "", "var i32 x = 0; i64 wide = 5000000000; x = wide;"),
+        ("global", "i32 x = 5000000000;", ""),
+        ("argument", "i32 id(i32 a) { return a; }", "i32 x = id(5000000000);"),
+        ("return", "i32 low() { i64 wide = 5000000000; return wide; }", "i32 x = low();"),
+        ("element", "", "var i32 a[1]; a[0] = 5000000000; i32 x = a[0];"),
+    ] {
+        check(&format!("agree-narrow-{name}"), &program(decls, body), "705032704\n705032704\n", 0);
+    }
+    check("agree-narrow-default", &program("i32 f(i32 a = 2147483648) { return a; }", "i32 x = f();"),
+          "-2147483648\n-2147483648\n", 0);
+}
+
+// Operands evaluated right first, for less stack, give the same values.
+#[test]
+fn reordered_operands() {
+    let src = "i32 main() {\n\
+                   i32 a = 1; i32 b = 2; i32 c = 4; i32 d = 8; i64 w = 5000000000;\n\
+                   print(a - (b - (c - d)), a / (b * (c + d)), 100 % (b * (c + d)));\n\
+                   print(a < (b * (c - d)), a >= (b + (c + d)), a <= (w - (b + c)));\n\
+                   print(a - (w * (b + c)));\n\
+                   return 0;\n\
+               }";
+    check("agree-reordered", src, "-5\n0\n4\n0\n0\n1\n-29999999999\n", 0);
+}
+
+// A constant power-of-two factor or divisor compiles to a shift natively;
+// it must give what imul and idiv do, which round toward zero, including
+// for negative dividends that aren't multiples of the divisor.
+#[test]
+fn power_of_two_shifts() {
+    let source = "i32 f(i32 x, i32 eight, i32 four) { print(x * 8, x * eight, x / 4, x / four, x / 2, x * 1073741824); return 0; }
+                  i32 main() { f(-7, 8, 4); f(-8, 8, 4); f(-1, 8, 4); f(5, 8, 4); f(-2147483647 - 1, 8, 4); return 0; }";
+    let mut want = String::new();
+    for x in [-7i32, -8, -1, 5, i32::MIN] {
+        let (m8, d4) = (x.wrapping_mul(8), x / 4);
+        want += &format!("{m8}\n{m8}\n{d4}\n{d4}\n{}\n{}\n", x / 2, x.wrapping_mul(1 << 30));
+    }
+    check("agree-shifts", source, &want, 0);
+    assert!(common::cosplae(&["--emit=asm"], source).stdout.windows(12).any(|w| w == b"sar $2, %rax"));
+}
+
+// A defaulted local is 0 each time its declaration runs, not just the
+// first time its slot is used.
+#[test]
+fn defaulted_locals_read_back_zero() {
+    check("agree-default-zero",
+          "i32 main() { var i32 i = 0; while (i < 3) { var i32 z; var i64 w; print(z + w); z = 5; w = 6; i = i + 1; } return 0; }",
+          "0\n0\n0\n", 0);
+}
+
+// Stores at a runtime index, into arrays reached with a disp8 and a disp32
+#[test]
+fn indexed_stores_read_back() {
+    check("agree-indexed-stores",
+          "i32 fill(i32 n) { var i32 a[3]; var i64 b[20]; var i32 i = 0;
+               while (i < n) { a[i] = i * 10 - 5; b[19 - i] = a[i] * 3000000000; i = i + 1; }
+               print(a[0] + a[1] + a[2], b[17], b[19]); return 0; }
+           i32 main() { return fill(3); }",
+          "15\n45000000000\n-15000000000\n", 0);
+}
+
+// `--no-optimize-print`'s routine prints what the usual one does, signed,
+// unsigned and at i64's extremes.
+#[test]
+fn simple_print_agrees() {
+    let values = ["0", "1", "-1", "9", "10", "-10", "1000000", "2147483647", "-2147483647 - 1",
+                  "9223372036854775807", "-9223372036854775807 - 1", "1000000000000000000"];
+    let source = format!("i32 main() {{ print({}); print_unsigned(0, -1, 10, -2147483647 - 1); return 0; }}", values.join(", "));
+    let vm = common::cosplae(&["--run"], &source);
+    assert!(vm.status.success());
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        for flags in [&[][..], &["--no-optimize-print"]] {
+            let name = format!("agree-print-routine{}", flags.len());
+            let native = common::native_with_flags(&name, flags, &source, "");
+            assert_eq!(String::from_utf8_lossy(&native.stdout), String::from_utf8_lossy(&vm.stdout), "{flags:?}");
+        }
+        let simple = common::cosplae(&["--emit=disasm", "--no-optimize-print"], &source);
+        let listing = String::from_utf8(simple.stdout).unwrap();
+        assert!(listing.contains("movabs $1000000000000000000, %rbx") && !listing.contains(".byte"), "{listing}");
+    }
+}
+
+// Guarded frames run as before, with and without a frame pointer.
+#[test]
+fn stack_protector_runs() {
+    let source = "i32 sum(i32 n) { var i32 a[4]; var i32 i = 0; var i32 s = 0; while (i < 4) { a[i] = n * i; s = s + a[i]; i = i + 1; } return s; }
+                  i32 main() { print(sum(1), sum(-3)); return sum(2); }";
+    check("agree-protected", source, "6\n-18\n", 12);
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        for flags in [&["--stack-protector"][..], &["--stack-protector", "--omit-frame-pointer"]] {
+            let native = common::native_with_flags(&format!("agree-protected{}", flags.len()), flags, source, "");
+            assert_eq!(String::from_utf8_lossy(&native.stdout), "6\n-18\n", "{flags:?}");
+            assert_eq!(native.status.code(), Some(12), "{flags:?}");
+        }
+    }
+}
+
+// Loops whose jumps were shortened to rel8 next to one whose body is too
+// long for it, so both forms run.
+#[test]
+fn relaxed_jumps_run() {
+    let body = "s = s + i * 3 - i / 2 + (s % 7); ".repeat(8);
+    let source = format!("i32 main() {{ var i32 s = 0; var i32 i = 0; while (i < 5) {{ if (i % 2) {{ s = s + 1; }} i = i + 1; }} print(s);
+                          i = 0; while (i < 3) {{ {body}i = i + 1; }} print(s); return i; }}");
+    let vm = common::cosplae(&["--run"], &source);
+    let out = String::from_utf8(vm.stdout).unwrap();
+    assert_eq!(out.lines().next(), Some("2"));
+    check("agree-relaxed", &source, &out, 3);
+}
+
+// Functions defined inside another are callable from it, recursively and
+// from each other, and hide a global function of the same name there.
+#[test]
+fn nested_helpers() {
+    let source = "i32 main() {
+                      i32 base = 10;
+                      i32 sq(i32 v) {
+                          i32 twice(i32 w) { return w + w; }
+                          return v * v + twice(1);
+                      }
+                      i32 fact(i32 n) { if (n < 2) { return 1; } return n * fact(n - 1); }
+                      print(sq(base), fact(5), other());
+                      return sq(3);
+                  }
+                  i32 sq(i32 v) { return 0 - v; }
+                  i32 other() { return sq(4); }";
+    check("agree-nested", source, "102\n120\n-4\n", 11);
+}
+
+// `pow` and `gcd` come from the prelude unless the program defines its own.
+#[test]
+fn prelude_functions() {
+    check("agree-prelude", "i32 main() { print(pow(3, 4), gcd(-4, 6)); return gcd(48, 36); }", "81\n2\n", 12);
+    check("agree-prelude-own", "i32 pow(i32 a, i32 b) { return a - b; } i32 main() { return pow(9, 2); }", "", 7);
+}
+
+// `typeof` prints the checker's name for a type, without evaluating what
+// it is given: the `input()` reads nothing.
+#[test]
+fn typeof_names_types() {
+    check("agree-typeof", "i32 main() { print(typeof(1 + 2)); return 0; }", "i32", 0);
+    check("agree-typeof-all", "struct P { i32 x; };
+                               i32 main() { i64 big = 1; P p = {1}; var i32 a[3];
+                                            print(typeof(big * 2), \" \", typeof(big < 1), \" \", typeof(p), \" \", typeof(a), \" \", typeof(input()), \"\\n\");
+                                            return 0; }", "i64 i32 P i32[] i32\n", 0);
+}
+
+// A const array is read-only data: an element at a constant index is
+// folded, any other loaded and bounds-checked. A const struct's fields fold.
+#[test]
+fn const_arrays() {
+    let source = "struct P { i32 x; i64 y; };
+                  const i32 N = 3;
+                  const i32[N] primes = { 2, 3, 5 };
+                  const i64[2] big = { 1, 5000000000 };
+                  const P p = { 7, 6000000000 };
+                  i32 main() { var i32 sum = 0;
+                               for (var i32 i = 0; i < N; i += 1) { sum += primes[i]; }
+                               print(sum, primes[2], big[sum - 9] + 1, p.x, p.y);
+                               return primes[sum]; }";
+    check("agree-const-array", source, "10\n5\n5000000001\n7\n6000000000\n", 70);
+}
+
+// `a*b` is computed once and reused; a store to `a` between two products
+// makes the second one count.
+#[test]
+fn repeated_subexpressions() {
+    let src = "i32 f(mut i32 a, i32 b) { print((a*b) + (a*b)); a += 1; return (a*b) - (a*b) + a*b; }\n\
+               i32 main() { return f(3, 4); }";
+    check("agree-cse", src, "24\n", 16);
+    let ir = String::from_utf8(common::cosplae(&["--emit=ir"], src).stdout).unwrap();
+    assert_eq!(ir.lines().filter(|l| l.ends_with(": Mul")).count(), 2, "{ir}");
+}
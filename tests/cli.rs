@@ -0,0 +1,181 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const EXIT_USAGE: i32 = 64;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cosplae-cli-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn compiles_a_file_next_to_it_by_default() {
+    let dir = scratch("default");
+    fs::write(dir.join("prog.cosp"), "i32 main() { print(7); return 3; }").unwrap();
+    let out = common::cosplae_in(&dir, &["prog.cosp"], "");
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        let run = Command::new(dir.join("prog")).output().unwrap();
+        assert_eq!(run.stdout, b"7\n");
+        assert_eq!(run.status.code(), Some(3));
+    } else {
+        assert_eq!(out.status.code(), Some(EXIT_USAGE));
+        assert!(!dir.join("prog").exists());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dash_o_names_the_output() {
+    let dir = scratch("dash-o");
+    fs::write(dir.join("prog"), "i32 main() { return 0; }").unwrap();
+    let out = common::cosplae_in(&dir, &["-o", "bin", "prog"], "");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(dir.join("bin").exists());
+    // without -o, a source without an extension isn't overwritten
+    let out = common::cosplae_in(&dir, &["prog"], "");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(dir.join("prog.out").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bad_arguments_print_usage() {
+    for args in [&[][..], &["--frobnicate", "x.cosp"], &["x.cosp", "-o"], &["a.cosp", "b.cosp"]] {
+        let out = common::cosplae(args, "");
+        assert_eq!(out.status.code(), Some(EXIT_USAGE), "{args:?}");
+        assert!(String::from_utf8_lossy(&out.stderr).contains("usage: cosplae"), "{args:?}");
+    }
+}
+
+#[test]
+fn missing_input_file_is_an_error() {
+    let out = common::cosplae(&["no-such-file.cosp"], "");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("No such file"));
+}
+
+// The VM is plain Rust, so `--run` works wherever cosplae builds
+#[test]
+fn the_vm_runs_on_any_host() {
+    let out = common::cosplae(&["--run"], "i32 main() { print(6 * 7); return 5; }");
+    assert_eq!(out.stdout, b"42\n");
+    assert_eq!(out.status.code(), Some(5));
+}
+
+// A piped program's return value is the exit status; a runtime error is 70,
+// apart from any code the program returns itself.
+#[test]
+fn stdin_exit_returns_the_programs_status() {
+    assert_eq!(common::cosplae(&["--stdin-exit"], "i32 main() { return 9; }").status.code(), Some(9));
+    let out = common::cosplae(&["--stdin-exit"], "i32 main() { i32 zero = 0; return 1 / zero; }");
+    assert_eq!(out.status.code(), Some(70));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("runtime error: division by zero"));
+    // the program comes from stdin only
+    let out = common::cosplae(&["--stdin-exit", "prog.cpl"], "");
+    assert_eq!(out.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--stdin-exit reads the program from stdin"));
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+#[test]
+fn no_executables_off_x86_64_linux() {
+    let dir = scratch("unsupported-host");
+    let out = common::cosplae_in(&dir, &["--emit=elf"], "i32 main() { return 0; }");
+    assert_eq!(out.status.code(), Some(EXIT_USAGE));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("use `cosplae --run`"));
+    assert!(!dir.join("output").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// `-W error` fails every kind of compile on a warning, not only `--run`.
+#[test]
+fn warnings_as_errors() {
+    let src = "i32 main() { var i32 x = 1; x = x; return x; }";
+    let out = common::cosplae(&["--emit=ir"], src);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("warning: self-assignment of `x` has no effect"));
+    let dir = scratch("warnings");
+    for args in [&["--run", "-W", "error"][..], &["--emit=ir", "-W", "error"], &["--emit=asm", "--warnings-as-errors"],
+                 &["--emit=disasm", "-W", "error"]] {
+        let out = common::cosplae_in(&dir, args, src);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert_eq!(out.status.code(), Some(65), "{args:?}: {stderr}");
+        assert!(stderr.contains("error: self-assignment of `x` has no effect"), "{args:?}: {stderr}");
+        assert!(stderr.contains("1 warning(s) treated as errors."), "{args:?}: {stderr}");
+        assert!(out.stdout.is_empty(), "{args:?}");
+    }
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        fs::write(dir.join("prog.cosp"), src).unwrap();
+        assert_eq!(common::cosplae_in(&dir, &["--emit=elf", "-W", "error"], src).status.code(), Some(65));
+        assert_eq!(common::cosplae_in(&dir, &["-W", "error", "prog.cosp"], "").status.code(), Some(65));
+        assert!(!dir.join("output").exists() && !dir.join("prog").exists());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+
+    let out = common::cosplae(&["--run", "-W", "all"], "");
+    assert_eq!(out.status.code(), Some(EXIT_USAGE));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown warning option `-W all`; only `-W error` is supported"));
+}
+
+// `--time-trace=FILE` writes the phases of whatever is compiled, even when
+// the compile fails.
+#[test]
+fn time_trace_on_every_path() {
+    let dir = scratch("time-trace");
+    let src = "i32 f() { return 1; }\ni32 main() { return f(); }";
+    let phases = |file: &str| {
+        let json = fs::read_to_string(dir.join(file)).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":["), "{json}");
+        ["parse", "codegen", "run", "native", "f", "main"].into_iter()
+            .filter(|name| json.contains(&format!("\"name\":\"{name}\",\"cat\":\"phase\",\"ph\":\"B\""))
+                || json.contains(&format!("\"name\":\"{name}\",\"cat\":\"function\",\"ph\":\"B\"")))
+            .collect::<Vec<_>>()
+    };
+    let mut cases = vec![
+        ("--run", vec!["parse", "codegen", "run", "f", "main"]),
+        ("--emit=ir", vec!["parse", "codegen", "f", "main"]),
+        ("--emit=asm", vec!["parse", "codegen", "native", "f", "main"]),
+        ("--emit=disasm", vec!["parse", "codegen", "native", "f", "main"]),
+        ("--emit=json", vec!["parse"]),
+    ];
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        cases.push(("--emit=elf", vec!["parse", "codegen", "native", "f", "main"]));
+    }
+    for (mode, expected) in cases {
+        let file = format!("{}.json", mode.trim_start_matches("--"));
+        let out = common::cosplae_in(&dir, &[mode, &format!("--time-trace={file}")], src);
+        assert!(out.status.code().is_some(), "{mode}");
+        assert_eq!(phases(&file), expected, "{mode}");
+    }
+    let out = common::cosplae_in(&dir, &["--emit=ir", "--time-trace=failed.json"], "i32 main() { return x; }");
+    assert_eq!(out.status.code(), Some(65));
+    // the events of a failed phase are still closed
+    assert_eq!(phases("failed.json"), ["parse", "codegen", "main"]);
+    let json = fs::read_to_string(dir.join("failed.json")).unwrap();
+    assert_eq!(json.matches("\"ph\":\"B\"").count(), json.matches("\"ph\":\"E\"").count(), "{json}");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn keep_temps_writes_the_listings() {
+    if !cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        return;
+    }
+    let dir = scratch("keep-temps");
+    fs::write(dir.join("prog.cosp"), "i32 main() { i32 x = 6; print(x * 7); return 0; }").unwrap();
+    let out = common::cosplae_in(&dir, &["--keep-temps", "-o", "out", "prog.cosp"], "");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    for file in ["out.tokens", "out.ir", "out.s"] {
+        let text = fs::read_to_string(dir.join(file)).unwrap();
+        assert!(!text.is_empty(), "{file} is empty");
+    }
+    assert!(fs::read_to_string(dir.join("out.tokens")).unwrap().starts_with("1:1 I32\n"));
+    assert!(fs::read_to_string(dir.join("out.s")).unwrap().contains("main:"));
+    assert_eq!(Command::new(dir.join("out")).output().unwrap().stdout, b"42\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,211 @@
+mod common;
+
+// Codegen reports errors as values: no panic output, exit status 65.
+fn codegen_error(source: &str) -> String {
+    let out = common::cosplae(&["--run"], source);
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(65), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    stderr
+}
+
+#[test]
+fn use_of_undeclared_variable() {
+    assert!(codegen_error("i32 main() { print(y); return 0; }").contains("error: use of undeclared variable `y`"));
+    // nested in an expression
+    assert!(codegen_error("i32 main() { i32 x = 1; return x + (2 * y); }").contains("undeclared variable `y`"));
+}
+
+// A local is in scope from after its initializer: it can't read itself or
+// a later declaration, but can read the ones before it.
+#[test]
+fn initializers_see_only_earlier_locals() {
+    assert!(codegen_error("i32 main() { i32 a = a; return a; }").contains("error: use of undeclared variable `a`"));
+    assert!(codegen_error("i32 main() { i32 a = b; i32 b = 1; return a; }").contains("error: use of undeclared variable `b`"));
+    let out = common::cosplae(&["--run"], "i32 main() { i32 a = 1; i32 b = a + 2; return b; }");
+    assert_eq!(out.status.code(), Some(3));
+}
+
+#[test]
+fn assignment_to_undeclared_variable() {
+    assert!(codegen_error("i32 main() { z = 1; return 0; }").contains("error: assignment to undeclared variable `z`"));
+    assert!(codegen_error("i32 main() { z += 1; return 0; }").contains("error: assignment to undeclared variable `z`"));
+}
+
+#[test]
+fn block_declarations_end_with_the_block() {
+    let src = "i32 main() { if (1) { i32 inner = 1; } return inner; }";
+    assert!(codegen_error(src).contains("use of undeclared variable `inner`"));
+}
+
+#[test]
+fn struct_field_errors() {
+    let point = "struct Point { i32 x; i32 y; };";
+    let err = |body: &str| codegen_error(&format!("{point} i32 main() {{ {body} }}"));
+    assert!(err("Point p; return p.z;").contains("error: struct `Point` has no field `z`"));
+    assert!(err("i32 q = 1; return q.x;").contains("error: `q` is not a struct and has no fields"));
+    assert!(err("Point p; Point q; p = q; return 0;").contains("error: struct `p` cannot be used as a value"));
+    let err_for = |init: &str| err(&format!("Point p = {init}; return p.x;"));
+    assert!(err_for("{ 1 }").contains("error: initializer of `p` has 1 value(s) but struct `Point` has 2 field(s)"));
+    assert!(err_for("{ 1, 2, 3 }").contains("error: initializer of `p` has 3 value(s) but struct `Point` has 2 field(s)"));
+    assert!(err_for("{}").contains("has 0 value(s)"));
+    assert!(err("i32 n = { 1 }; return n;").contains("error: `n` is not a struct and cannot take a brace initializer"));
+}
+
+#[test]
+fn integer_literal_beyond_i64() {
+    let err = codegen_error("i32 main() { print(99999999999999999999); return 0; }");
+    assert!(err.contains("error: integer literal `99999999999999999999` is too large"), "{err}");
+    assert!(codegen_error("i32 main() { return 0x10000000000000000; }").contains("too large"));
+    // i64::MAX itself still compiles
+    let out = common::cosplae(&["--run"], "i32 main() { print(9223372036854775807); return 0; }");
+    assert_eq!(out.stdout, b"9223372036854775807\n");
+}
+
+#[test]
+fn for_loop_variable_ends_with_the_loop() {
+    let src = "i32 main() { for (var i32 i = 0; i < 2; i = i + 1) { } return i; }";
+    assert!(codegen_error(src).contains("use of undeclared variable `i`"));
+}
+
+#[test]
+fn break_and_continue_outside_a_loop() {
+    assert!(codegen_error("i32 main() { break; return 0; }").contains("error: `break` outside of a loop"));
+    let src = "i32 main() { if (1) { continue; } return 0; }";
+    assert!(codegen_error(src).contains("error: `continue` outside of a loop"));
+}
+
+#[test]
+fn calls_check_the_callee_and_argument_count() {
+    assert!(codegen_error("i32 main() { return nope(1); }").contains("error: call to undefined function `nope`"));
+    let add = "i32 add(i32 a, i32 b) { return a + b; }";
+    let err = codegen_error(&format!("{add} i32 main() {{ return add(1); }}"));
+    assert!(err.contains("error: `add` takes 2 argument(s) but 1 were given"), "{err}");
+    let err = codegen_error(&format!("{add} i32 main() {{ return add(1, 2, 3); }}"));
+    assert!(err.contains("error: `add` takes 2 argument(s) but 3 were given"), "{err}");
+}
+
+#[test]
+fn global_errors() {
+    assert!(codegen_error("const i32 k = 1; i32 main() { k = 2; return 0; }").contains("error: cannot assign to const `k`"));
+    assert!(codegen_error("i32 x = input(); i32 main() { return x; }")
+        .contains("error: global `x` must be initialized with an integer literal"));
+    assert!(codegen_error("struct P { i32 x; }; P p; i32 main() { return 0; }").contains("error: global `p` cannot be a struct"));
+    assert!(codegen_error("i32 v = 2; const i32 c = v + 1; i32 main() { return c; }")
+        .contains("error: const `c` must be initialized with a constant expression"));
+    // only consts declared earlier are known
+    assert!(codegen_error("const i32 a = b; const i32 b = 1; i32 main() { return a; }")
+        .contains("error: const `a` must be initialized with a constant expression"));
+}
+
+#[test]
+fn local_consts_cannot_be_assigned() {
+    let err = |body: &str| codegen_error(&format!("i32 main() {{ const i32 k = 1; {body} return k; }}"));
+    assert!(err("k = 2;").contains("error: cannot assign to const `k`"));
+    assert!(err("k += 1;").contains("error: cannot assign to const `k`"));
+    assert!(err("while (k < 3) { k = k + 1; }").contains("error: cannot assign to const `k`"));
+    // a plain local, or one shadowing the const, can still be assigned
+    let out = common::cosplae(&["--run"], "i32 main() { const i32 k = 1; var i32 v = k; v = v + 4; if (1) { var i32 k = 2; k = 7; v = v + k; } return v + k; }");
+    assert_eq!(out.status.code(), Some(13), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn only_var_locals_and_mut_parameters_can_be_assigned() {
+    let err = codegen_error("i32 main() { i32 x = 1; x = 2; return x; }");
+    assert!(err.contains("error: cannot assign to immutable `x`; declare it `var` (or `mut` for a parameter)"), "{err}");
+    assert!(codegen_error("i32 main() { i32 x = 1; x += 1; return x; }").contains("cannot assign to immutable `x`"));
+    assert!(codegen_error("i32 main() { i32 a[2]; a[0] = 1; return 0; }").contains("cannot assign to immutable `a`"));
+    let src = "struct P { i32 x; }; i32 main() { P p; p.x = 1; return 0; }";
+    assert!(codegen_error(src).contains("cannot assign to immutable `p`"));
+    let src = "i32 dec(i32 n) { n = n - 1; return n; } i32 main() { return dec(3); }";
+    assert!(codegen_error(src).contains("cannot assign to immutable `n`"));
+    // a `var` shadowed by a plain declaration is immutable again
+    assert!(codegen_error("i32 main() { var i32 x = 1; if (1) { i32 x = 2; x = 3; } return x; }").contains("immutable `x`"));
+    let out = common::cosplae(&["--run"], "i32 dec(mut i32 n) { n = n - 1; return n; } i32 main() { var i32 x = 1; x += dec(3); return x; }");
+    assert_eq!(out.status.code(), Some(3), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn array_errors() {
+    let err = |body: &str| codegen_error(&format!("i32 main() {{ i32 a[4]; {body} }}"));
+    assert!(err("return a[4];").contains("error: index 4 is out of range for `a` of length 4"));
+    assert!(err("a[-1] = 2; return 0;").contains("error: index -1 is out of range for `a` of length 4"));
+    assert!(err("i32 b[4]; b = a; return 0;").contains("error: array `b` cannot be used as a value"));
+    assert!(err("i32 e[0]; return 0;").contains("error: array `e` cannot have 0 elements"));
+}
+
+// A length folds like a const: literals and top-level consts no local hides.
+#[test]
+fn constant_array_lengths() {
+    let err = |body: &str| codegen_error(&format!("const i32 N = 4; i32 main() {{ {body} return 0; }}"));
+    assert!(err("i32 n = 2; i32 a[n];").contains("error: length of array `a` must be a constant expression"));
+    assert!(err("i32 N = 2; i32 a[N];").contains("error: length of array `a` must be a constant expression"));
+    assert!(err("i32 a[N - 4];").contains("error: array `a` cannot have 0 elements"));
+    assert!(err("i32 a[2 - N];").contains("error: array `a` cannot have -2 elements"));
+    let out = common::cosplae(&["--emit=ir"], "const i32 N = 4; i32 main() { var i32 a[N + 1]; a[N] = 7; return a[4]; }");
+    let listing = String::from_utf8_lossy(&out.stdout);
+    assert!(listing.contains("func #0 main: 0 params, 5 locals"), "{listing}");
+    let out = common::cosplae(&["--run"], "const i32 N = 4; i32 main() { i32 a[2 + 3]; var i32 b[N + 1]; b[N] = 7; return b[4]; }");
+    assert_eq!(out.status.code(), Some(7), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+// An index that folds to a constant is checked when compiling; one that
+// needs run time is checked when it runs (see runtime_errors.rs).
+#[test]
+fn constant_indices_out_of_range() {
+    let err = |body: &str| codegen_error(&format!("const i32 N = 4; i32 main() {{ var i32 a[4]; {body} }}"));
+    assert!(err("return a[2 - 3];").contains("error: index -1 is out of range for `a` of length 4"));
+    assert!(err("a[N] = 1; return 0;").contains("error: index 4 is out of range for `a` of length 4"));
+    // in range, and a local `N` that hides the const
+    let run = |body: &str| common::cosplae(&["--run"], &format!("const i32 N = 4; i32 main() {{ var i32 a[4]; a[3] = 9; {body} }}"));
+    assert_eq!(run("a[N - 3] = 8; return a[N - 1] - a[1];").status.code(), Some(1));
+    assert_eq!(run("i32 N = 3; return a[N];").status.code(), Some(9));
+}
+
+#[test]
+fn missing_main() {
+    let stderr = codegen_error("struct P { i32 x; }; const i32 N = 3; i32 helper() { return N; }");
+    assert!(stderr.contains("error: no `main` function defined"), "{stderr}");
+    for empty in ["", "  \n// nothing here\n"] {
+        let stderr = codegen_error(empty);
+        assert!(stderr.contains("error: the program is empty; it needs a `main` function"), "{stderr}");
+    }
+    let out = common::cosplae(&["--emit=elf", "-o", "/dev/null"], "const i32 N = 3;");
+    assert_eq!(out.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("no `main` function defined"));
+}
+
+#[test]
+fn effect_without_a_handler() {
+    let stderr = codegen_error("effect i32 ask(); i32 main() { return perform ask(); }");
+    assert!(stderr.contains("error: effect `ask` has no handler; define a function `ask`"), "{stderr}");
+}
+
+#[test]
+fn non_constant_default() {
+    let src = "i32 f(i32 x, i32 y = x) { return x + y; }\ni32 main() { i32 x = 100; return f(1); }";
+    assert!(codegen_error(src).contains("error: default of parameter `y` of `f` must be a constant expression"));
+    assert!(codegen_error("i32 f(i32 y = input()) { return y; }\ni32 main() { return f(); }")
+        .contains("default of parameter `y`"));
+}
+
+#[test]
+fn duplicate_function() {
+    let src = "i32 f() { return 1; }\ni32 f() { return 2; }\ni32 main() { return f(); }";
+    assert!(codegen_error(src).contains("error: function `f` is defined more than once"));
+    let out = common::cosplae(&["--emit=ir"], src);
+    assert_eq!(out.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("function `f` is defined more than once"));
+}
+
+// Structs may name ones declared later; only containing themselves by value
+// (no finite size) is an error.
+#[test]
+fn struct_containing_itself() {
+    let ok = "struct Line { Point a; Point b; };\nstruct Point { i32 x; i32 y; };\ni32 main() { Point p = { 1, 2 }; return p.y; }";
+    assert_eq!(common::cosplae(&["--run"], ok).status.code(), Some(2));
+    let src = "struct A { i32 n; B b; };\nstruct B { A a; };\ni32 main() { return 0; }";
+    assert!(codegen_error(src).contains("error: struct `A` contains itself by value: A -> B -> A"));
+    let src = "struct Node { i32 v; Node next; };\ni32 main() { return 0; }";
+    assert!(codegen_error(src).contains("struct `Node` contains itself by value: Node -> Node"));
+}
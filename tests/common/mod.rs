@@ -0,0 +1,55 @@
+// Shared by several test crates, each of which uses only some helpers.
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+// Runs the compiler binary with `args`, feeding `source` on stdin.
+pub fn cosplae(args: &[&str], source: &str) -> Output {
+    cosplae_in(Path::new("."), args, source)
+}
+
+// Like `cosplae`, with `dir` as the working directory.
+pub fn cosplae_in(dir: &Path, args: &[&str], source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cosplae"))
+        .args(args)
+        .current_dir(dir)
+        .env("RUST_BACKTRACE", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(source.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Compiles `source` to a native binary in a scratch directory named after
+// `name` and runs it.
+pub fn native(name: &str, source: &str) -> Output {
+    native_with_stdin(name, source, "")
+}
+
+// Like `native`, feeding `stdin` to the compiled program.
+pub fn native_with_stdin(name: &str, source: &str, stdin: &str) -> Output {
+    native_with_flags(name, &[], source, stdin)
+}
+
+// Like `native_with_stdin`, passing `flags` to the compiler as well.
+pub fn native_with_flags(name: &str, flags: &[&str], source: &str, stdin: &str) -> Output {
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = cosplae_in(&dir, &[&["--emit=elf"], flags].concat(), source);
+    assert!(out.status.success(), "{name}: {}", String::from_utf8_lossy(&out.stderr));
+    let mut child = Command::new(dir.join("output"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let run = child.wait_with_output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    run
+}
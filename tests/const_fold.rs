@@ -0,0 +1,86 @@
+// Exercises the constant-folding pass directly on hand-written IR.
+#[path = "../src/span.rs"]
+mod span;
+#[path = "../src/ir.rs"]
+#[allow(dead_code)]
+mod ir;
+#[path = "../src/opt.rs"]
+#[allow(dead_code)]
+mod opt;
+
+use ir::{Func, Instr, ProgramIR};
+
+fn fold(code: Vec<Instr>) -> Vec<Instr> {
+    let func = Func {
+        name: "main".to_string(),
+        max_stack: ir::max_stack_depth(&code),
+        code,
+        n_locals: 0,
+        n_params: 0,
+        locals_dbg: Vec::new(),
+        spans: Vec::new(),
+    };
+    let mut prog = ProgramIR { funcs: vec![func], globals: Vec::new(), rodata: Vec::new() };
+    opt::fold_constants(&mut prog);
+    prog.funcs.remove(0).code
+}
+
+fn pushed(code: &[Instr]) -> Vec<Option<i32>> {
+    code.iter().map(|i| match i { Instr::PushI32(n) => Some(*n), _ => None }).collect()
+}
+
+#[test]
+fn nested_arithmetic_folds_to_one_push() {
+    // 2 + 3 * 4
+    let code = fold(vec![
+        Instr::PushI32(2), Instr::PushI32(3), Instr::PushI32(4), Instr::Mul, Instr::Add, Instr::Ret,
+    ]);
+    assert_eq!(code.len(), 2);
+    assert_eq!(pushed(&code), [Some(14), None]);
+}
+
+#[test]
+fn comparisons_fold() {
+    let code = fold(vec![Instr::PushI32(10), Instr::PushI32(7), Instr::Sub, Instr::PushI32(3), Instr::CmpEq, Instr::Print]);
+    assert_eq!(pushed(&code), [Some(1), None]);
+}
+
+// `1 - 2 * 3` with the product evaluated first: the swap folds away too.
+#[test]
+fn swapped_constants_fold() {
+    let code = fold(vec![
+        Instr::PushI32(2), Instr::PushI32(3), Instr::Mul, Instr::PushI32(1), Instr::Swap, Instr::Sub, Instr::Ret,
+    ]);
+    assert_eq!(pushed(&code), [Some(-5), None]);
+}
+
+#[test]
+fn non_constant_operands_are_left_alone() {
+    let code = fold(vec![Instr::Load(0), Instr::PushI32(1), Instr::Add, Instr::PushI32(2), Instr::Mul, Instr::Ret]);
+    assert_eq!(code.len(), 6);
+}
+
+#[test]
+fn traps_and_overflow_are_left_for_run_time() {
+    let code = fold(vec![Instr::PushI32(1), Instr::PushI32(0), Instr::Div, Instr::Pop]);
+    assert_eq!(code.len(), 4);
+    let code = fold(vec![Instr::PushI32(5), Instr::PushI32(0), Instr::Mod, Instr::Pop]);
+    assert_eq!(code.len(), 4);
+    let code = fold(vec![Instr::PushI32(i32::MAX), Instr::PushI32(1), Instr::Add, Instr::Pop]);
+    assert_eq!(code.len(), 4);
+}
+
+#[test]
+fn folding_does_not_reach_across_labels() {
+    let code = fold(vec![Instr::PushI32(1), Instr::Label(1), Instr::PushI32(2), Instr::Add, Instr::Ret]);
+    assert_eq!(code.len(), 5);
+}
+
+// `-3` is a constant too; negating i32::MIN overflows and is left alone.
+#[test]
+fn negation_folds() {
+    let code = fold(vec![Instr::PushI32(3), Instr::Neg, Instr::PushI32(4), Instr::Mul, Instr::Ret]);
+    assert_eq!(pushed(&code), [Some(-12), None]);
+    let code = fold(vec![Instr::PushI32(i32::MIN), Instr::Neg, Instr::Ret]);
+    assert_eq!(code.len(), 3);
+}
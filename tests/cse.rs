@@ -0,0 +1,77 @@
+// Exercises common-subexpression elimination directly on hand-written IR.
+#[path = "../src/span.rs"]
+mod span;
+#[path = "../src/ir.rs"]
+#[allow(dead_code)]
+mod ir;
+#[path = "../src/opt.rs"]
+#[allow(dead_code)]
+mod opt;
+
+use ir::{Func, Instr, ProgramIR};
+
+// `code` over locals 0 (a), 1 (b) and 2 (c), after the pass
+fn cse(code: Vec<Instr>) -> Func {
+    let func = Func {
+        name: "main".to_string(),
+        max_stack: ir::max_stack_depth(&code),
+        code,
+        n_locals: 3,
+        n_params: 3,
+        locals_dbg: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        spans: Vec::new(),
+    };
+    let mut prog = ProgramIR { funcs: vec![func], globals: Vec::new(), rodata: Vec::new() };
+    opt::eliminate_common_subexpressions(&mut prog);
+    prog.funcs.remove(0)
+}
+
+fn a_times_b() -> [Instr; 3] {
+    [Instr::Load(0), Instr::Load(1), Instr::Mul]
+}
+
+// (a*b) + (a*b)
+#[test]
+fn a_repeat_is_loaded_not_recomputed() {
+    let func = cse([&a_times_b()[..], &a_times_b(), &[Instr::Add, Instr::Ret]].concat());
+    assert_eq!(func.code, [&a_times_b()[..], &[Instr::Dup, Instr::Store(3), Instr::Load(3), Instr::Add, Instr::Ret]].concat());
+    assert_eq!((func.n_locals, func.max_stack), (4, 2));
+    assert_eq!(func.locals_dbg[3], "$t0");
+}
+
+// (a*b + c) * (a*b + c): the whole operand is the repeat, not just a*b
+#[test]
+fn the_longest_repeat_is_replaced() {
+    let operand = [&a_times_b()[..], &[Instr::Load(2), Instr::Add]].concat();
+    let func = cse([&operand[..], &operand, &[Instr::Mul, Instr::Ret]].concat());
+    assert_eq!(func.code, [&operand[..], &[Instr::Dup, Instr::Store(3), Instr::Load(3), Instr::Mul, Instr::Ret]].concat());
+    assert_eq!(func.n_locals, 4);
+}
+
+// `c - a*b` evaluated with its product first is still the same value
+#[test]
+fn swapped_operands_are_one_value() {
+    let operand = [&a_times_b()[..], &[Instr::Load(2), Instr::Swap, Instr::Sub]].concat();
+    let func = cse([&operand[..], &[Instr::Print], &operand, &[Instr::Ret]].concat());
+    assert_eq!(func.code, [&operand[..], &[Instr::Dup, Instr::Store(3), Instr::Print, Instr::Load(3), Instr::Ret]].concat());
+}
+
+#[test]
+fn a_store_to_an_operand_ends_the_reuse() {
+    let code = [&a_times_b()[..], &[Instr::Print, Instr::PushI32(2), Instr::Store(1)], &a_times_b(), &[Instr::Ret]].concat();
+    assert_eq!(cse(code.clone()).code, code);
+    let code = [&a_times_b()[..], &[Instr::Print, Instr::PushI32(2), Instr::Store(2)], &a_times_b(), &[Instr::Ret]].concat();
+    assert_eq!(cse(code).code.iter().filter(|i| **i == Instr::Mul).count(), 1);
+}
+
+// A label starts another block, which may be reached without the first
+// computation, and a call or input isn't pure.
+#[test]
+fn blocks_and_effects_are_left_alone() {
+    let code = [&a_times_b()[..], &[Instr::Print, Instr::Label(1)], &a_times_b(), &[Instr::Ret]].concat();
+    assert_eq!(cse(code.clone()).code, code);
+    let code = vec![Instr::Input, Instr::Load(0), Instr::Add, Instr::Print, Instr::Input, Instr::Load(0), Instr::Add, Instr::Ret];
+    assert_eq!(cse(code.clone()).code, code);
+    let code = [&a_times_b()[..], &[Instr::Print, Instr::Call(0, 0), Instr::Pop], &a_times_b(), &[Instr::Ret]].concat();
+    assert_eq!(cse(code.clone()).code, code);
+}
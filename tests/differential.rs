@@ -0,0 +1,492 @@
+// Differential testing of the two backends. Random IR programs, made of
+// instructions both implement without trapping (no input, effects, calls
+// that recurse, or division by zero), run on the VM and as native
+// executables, which must print the same bytes and exit with the same
+// status. A program on which they differ is shrunk to a smallest one that
+// still does before the test fails, so the report is a usable reproducer.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::path::Path;
+use std::process::Command;
+
+use cosplae::ir::{self, Func, Global, Instr, ProgramIR};
+use cosplae::vm::{StepResult, VmState};
+
+const LOCALS: usize = 4; // main's scalar locals
+const ARRAY: usize = LOCALS; // then an array of ARRAY_LEN
+const ARRAY_LEN: usize = 4;
+const GLOBALS: usize = 2;
+const HELPERS: usize = 3;
+
+#[derive(Clone, Debug)]
+enum Ex {
+    Const(i32),
+    Local(usize),
+    Global(usize),
+    Element(usize),                    // of main's array, at a constant index
+    Unary(Instr, Box<Ex>),             // Neg, Not
+    Binary(Instr, Box<Ex>, Box<Ex>),   // arithmetic and comparisons
+    Swapped(Instr, Box<Ex>, Box<Ex>),  // `b a Swap op`, the same value as Binary
+    Squared(Instr, Box<Ex>),           // `a Dup op`
+    Divide(Instr, Box<Ex>, i32),       // Div or Mod by a nonzero constant
+    Call(usize, Box<Ex>, Box<Ex>),     // a helper, which takes two parameters
+}
+
+#[derive(Clone, Debug)]
+enum St {
+    Print(Ex),
+    PrintUnsigned(Ex),
+    Newline,
+    Str(Vec<u8>),
+    Store(usize, Ex),
+    StoreGlobal(usize, Ex),
+    StoreElement(usize, Ex),
+    Discard(Ex), // evaluated, then popped
+    If(Ex, Vec<St>),
+    IfElse(Ex, Vec<St>, Vec<St>),
+}
+
+// `main` first sets its locals and array to `init`, runs `body` and returns
+// `ret`. Helper `i` returns `helpers[i]` of its two parameters and may call
+// only the helpers before it.
+#[derive(Clone, Debug)]
+struct Prog {
+    globals: [i32; GLOBALS],
+    helpers: Vec<Ex>,
+    init: [i32; LOCALS + ARRAY_LEN],
+    body: Vec<St>,
+    ret: Ex,
+}
+
+// xorshift64*, so a seed always makes the same programs.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // Mostly values at the edges of i32, where the backends' wrapping and
+    // sign handling can differ.
+    fn int(&mut self) -> i32 {
+        const EDGES: [i32; 12] = [0, 1, -1, 2, -2, 7, -7, 100, i32::MAX, i32::MIN, i32::MAX - 1, i32::MIN + 1];
+        match self.below(3) {
+            0 => self.next() as i32,
+            _ => EDGES[self.below(EDGES.len())],
+        }
+    }
+
+    fn pick(&mut self, instrs: &[Instr]) -> Instr {
+        instrs[self.below(instrs.len())].clone()
+    }
+}
+
+const BINARY: [Instr; 12] = [
+    Instr::Add, Instr::Sub, Instr::Mul, Instr::AddI64, Instr::SubI64, Instr::MulI64,
+    Instr::CmpLt, Instr::CmpGt, Instr::CmpLe, Instr::CmpGe, Instr::CmpEq, Instr::CmpNe,
+];
+
+// An expression over `locals` locals (the first of which are a helper's
+// parameters), which may call the first `callable` helpers.
+fn gen_ex(rng: &mut Rng, depth: usize, locals: usize, in_main: bool, callable: usize) -> Ex {
+    let sub = |rng: &mut Rng| Box::new(gen_ex(rng, depth - 1, locals, in_main, callable));
+    if depth == 0 || rng.below(4) == 0 {
+        return match rng.below(4) {
+            0 => Ex::Const(rng.int()),
+            1 => Ex::Local(rng.below(locals)),
+            2 => Ex::Global(rng.below(GLOBALS)),
+            _ if in_main => Ex::Element(rng.below(ARRAY_LEN)),
+            _ => Ex::Const(rng.int()),
+        };
+    }
+    match rng.below(7) {
+        0 => Ex::Unary(rng.pick(&[Instr::Neg, Instr::NegI64, Instr::Narrow, Instr::Not]), sub(rng)),
+        1 => Ex::Swapped(rng.pick(&BINARY), sub(rng), sub(rng)),
+        2 => Ex::Squared(rng.pick(&BINARY), sub(rng)),
+        3 => {
+            let op = rng.pick(&[Instr::Div, Instr::Mod, Instr::DivI64, Instr::ModI64]);
+            let divisor = match rng.int() { 0 => 3, d => d };
+            Ex::Divide(op, sub(rng), divisor)
+        }
+        4 if callable > 0 => Ex::Call(rng.below(callable), sub(rng), sub(rng)),
+        _ => Ex::Binary(rng.pick(&BINARY), sub(rng), sub(rng)),
+    }
+}
+
+// Up to `max_len` statements, nesting ifs `depth` deep.
+fn gen_block(rng: &mut Rng, depth: usize, max_len: usize) -> Vec<St> {
+    let ex = |rng: &mut Rng| gen_ex(rng, 3, LOCALS, true, HELPERS);
+    let len = 1 + rng.below(max_len);
+    (0..len)
+        .map(|_| match rng.below(10) {
+            0 | 1 => St::Print(ex(rng)),
+            2 => St::PrintUnsigned(ex(rng)),
+            3 => if rng.below(2) == 0 { St::Newline } else { St::Str(b"; ".to_vec()) },
+            4 => St::Store(rng.below(LOCALS), ex(rng)),
+            5 => St::StoreGlobal(rng.below(GLOBALS), ex(rng)),
+            6 => St::StoreElement(rng.below(ARRAY_LEN), ex(rng)),
+            7 => St::Discard(ex(rng)),
+            8 if depth > 0 => St::If(ex(rng), gen_block(rng, depth - 1, 3)),
+            9 if depth > 0 => {
+                let then = gen_block(rng, depth - 1, 3);
+                St::IfElse(ex(rng), then, gen_block(rng, depth - 1, 3))
+            }
+            _ => St::Print(ex(rng)),
+        })
+        .collect()
+}
+
+fn gen_prog(rng: &mut Rng) -> Prog {
+    Prog {
+        globals: std::array::from_fn(|_| rng.int()),
+        helpers: (0..HELPERS).map(|i| gen_ex(rng, 2, 2, false, i)).collect(),
+        init: std::array::from_fn(|_| rng.int()),
+        body: gen_block(rng, 2, 10),
+        ret: gen_ex(rng, 2, LOCALS, true, HELPERS),
+    }
+}
+
+struct Lower {
+    code: Vec<Instr>,
+    next_label: u32,
+}
+
+impl Lower {
+    fn label(&mut self) -> u32 {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    fn ex(&mut self, e: &Ex) {
+        match e {
+            Ex::Const(n) => self.code.push(Instr::PushI32(*n)),
+            Ex::Local(slot) => self.code.push(Instr::Load(*slot)),
+            Ex::Global(index) => self.code.push(Instr::LoadGlobal(*index)),
+            Ex::Element(i) => self.code.extend([Instr::PushI32(*i as i32), Instr::LoadIndex(ARRAY, ARRAY_LEN)]),
+            Ex::Unary(op, a) => {
+                self.ex(a);
+                self.code.push(op.clone());
+            }
+            Ex::Binary(op, a, b) => {
+                self.ex(a);
+                self.ex(b);
+                self.code.push(op.clone());
+            }
+            Ex::Swapped(op, a, b) => {
+                self.ex(b);
+                self.ex(a);
+                self.code.extend([Instr::Swap, op.clone()]);
+            }
+            Ex::Squared(op, a) => {
+                self.ex(a);
+                self.code.extend([Instr::Dup, op.clone()]);
+            }
+            Ex::Divide(op, a, divisor) => {
+                self.ex(a);
+                self.code.extend([Instr::PushI32(*divisor), op.clone()]);
+            }
+            // main is function 0
+            Ex::Call(helper, a, b) => {
+                self.ex(a);
+                self.ex(b);
+                self.code.push(Instr::Call(helper + 1, 2));
+            }
+        }
+    }
+
+    fn block(&mut self, block: &[St]) {
+        for s in block {
+            match s {
+                St::Print(e) => {
+                    self.ex(e);
+                    self.code.push(Instr::Print);
+                }
+                St::PrintUnsigned(e) => {
+                    self.ex(e);
+                    self.code.push(Instr::PrintUnsigned);
+                }
+                St::Newline => self.code.push(Instr::PrintNewline),
+                St::Str(bytes) => self.code.push(Instr::PrintStr(bytes.clone())),
+                St::Store(slot, e) => {
+                    self.ex(e);
+                    self.code.push(Instr::Store(*slot));
+                }
+                St::StoreGlobal(index, e) => {
+                    self.ex(e);
+                    self.code.push(Instr::StoreGlobal(*index));
+                }
+                St::StoreElement(i, e) => {
+                    self.code.push(Instr::PushI32(*i as i32));
+                    self.ex(e);
+                    self.code.push(Instr::StoreIndex(ARRAY, ARRAY_LEN));
+                }
+                St::Discard(e) => {
+                    self.ex(e);
+                    self.code.push(Instr::Pop);
+                }
+                St::If(cond, then) => {
+                    let end = self.label();
+                    self.ex(cond);
+                    self.code.push(Instr::JumpIfZero(end));
+                    self.block(then);
+                    self.code.push(Instr::Label(end));
+                }
+                St::IfElse(cond, then, els) => {
+                    let (other, end) = (self.label(), self.label());
+                    self.ex(cond);
+                    self.code.push(Instr::JumpIfZero(other));
+                    self.block(then);
+                    self.code.extend([Instr::Jump(end), Instr::Label(other)]);
+                    self.block(els);
+                    self.code.push(Instr::Label(end));
+                }
+            }
+        }
+    }
+}
+
+fn func(name: String, code: Vec<Instr>, n_locals: usize, n_params: usize) -> Func {
+    Func { name, max_stack: ir::max_stack_depth(&code), code, n_locals, n_params, locals_dbg: Vec::new(), spans: Vec::new() }
+}
+
+fn lower(prog: &Prog) -> ProgramIR {
+    let mut main = Lower { code: Vec::new(), next_label: 0 };
+    for (slot, value) in prog.init.iter().enumerate() {
+        main.code.extend([Instr::PushI32(*value), Instr::Store(slot)]);
+    }
+    main.block(&prog.body);
+    main.ex(&prog.ret);
+    main.code.push(Instr::Ret);
+
+    let mut funcs = vec![func("main".into(), main.code, LOCALS + ARRAY_LEN, 0)];
+    for (i, body) in prog.helpers.iter().enumerate() {
+        let mut helper = Lower { code: Vec::new(), next_label: 0 };
+        helper.ex(body);
+        helper.code.push(Instr::Ret);
+        funcs.push(func(format!("h{i}"), helper.code, 2, 2));
+    }
+    let globals = prog.globals.iter().enumerate()
+        .map(|(i, value)| Global { name: format!("g{i}"), value: *value as i64 })
+        .collect();
+    ProgramIR { funcs, globals, rodata: Vec::new() }
+}
+
+// Smaller variants of `e`: each operand on its own, a constant nearer 0,
+// and `e` with one operand made smaller.
+fn smaller_ex(e: &Ex) -> Vec<Ex> {
+    let b = |e: &Ex| Box::new(e.clone());
+    let mut out = Vec::new();
+    match e {
+        Ex::Const(0) => return out,
+        Ex::Const(n) => out.push(Ex::Const(n / 2)),
+        Ex::Local(_) | Ex::Global(_) | Ex::Element(_) => {}
+        Ex::Unary(op, a) | Ex::Squared(op, a) | Ex::Divide(op, a, _) => {
+            out.push((**a).clone());
+            for a in smaller_ex(a) {
+                out.push(match e {
+                    Ex::Unary(..) => Ex::Unary(op.clone(), Box::new(a)),
+                    Ex::Squared(..) => Ex::Squared(op.clone(), Box::new(a)),
+                    Ex::Divide(_, _, d) => Ex::Divide(op.clone(), Box::new(a), *d),
+                    _ => unreachable!(),
+                });
+            }
+        }
+        Ex::Binary(_, l, r) | Ex::Swapped(_, l, r) | Ex::Call(_, l, r) => {
+            out.extend([(**l).clone(), (**r).clone()]);
+            let with = |l: Box<Ex>, r: Box<Ex>| match e {
+                Ex::Binary(op, ..) => Ex::Binary(op.clone(), l, r),
+                Ex::Swapped(op, ..) => Ex::Swapped(op.clone(), l, r),
+                Ex::Call(h, ..) => Ex::Call(*h, l, r),
+                _ => unreachable!(),
+            };
+            out.extend(smaller_ex(l).iter().map(|l| with(b(l), r.clone())));
+            out.extend(smaller_ex(r).iter().map(|r| with(l.clone(), b(r))));
+        }
+    }
+    out.push(Ex::Const(0));
+    out
+}
+
+fn smaller_st(s: &St) -> Vec<Vec<St>> {
+    let one = |s: St| vec![s];
+    match s {
+        St::Newline | St::Str(_) => vec![],
+        St::Print(e) => smaller_ex(e).into_iter().map(|e| one(St::Print(e))).collect(),
+        St::PrintUnsigned(e) => smaller_ex(e).into_iter().map(|e| one(St::PrintUnsigned(e))).collect(),
+        St::Store(slot, e) => smaller_ex(e).into_iter().map(|e| one(St::Store(*slot, e))).collect(),
+        St::StoreGlobal(i, e) => smaller_ex(e).into_iter().map(|e| one(St::StoreGlobal(*i, e))).collect(),
+        St::StoreElement(i, e) => smaller_ex(e).into_iter().map(|e| one(St::StoreElement(*i, e))).collect(),
+        St::Discard(e) => smaller_ex(e).into_iter().map(|e| one(St::Discard(e))).collect(),
+        St::If(cond, then) => {
+            let mut out = vec![then.clone()];
+            out.extend(smaller_ex(cond).into_iter().map(|c| one(St::If(c, then.clone()))));
+            out.extend(smaller_block(then).into_iter().map(|t| one(St::If(cond.clone(), t))));
+            out
+        }
+        St::IfElse(cond, then, els) => {
+            let mut out = vec![then.clone(), els.clone(), one(St::If(cond.clone(), then.clone()))];
+            out.extend(smaller_ex(cond).into_iter().map(|c| one(St::IfElse(c, then.clone(), els.clone()))));
+            out.extend(smaller_block(then).into_iter().map(|t| one(St::IfElse(cond.clone(), t, els.clone()))));
+            out.extend(smaller_block(els).into_iter().map(|e| one(St::IfElse(cond.clone(), then.clone(), e))));
+            out
+        }
+    }
+}
+
+// `block` without one of its statements, or with one replaced by what
+// `smaller_st` makes of it.
+fn smaller_block(block: &[St]) -> Vec<Vec<St>> {
+    let mut out = Vec::new();
+    for (i, s) in block.iter().enumerate() {
+        let mut splice = |with: Vec<St>| {
+            let mut b = block[..i].to_vec();
+            b.extend(with);
+            b.extend_from_slice(&block[i + 1..]);
+            out.push(b);
+        };
+        splice(Vec::new());
+        for with in smaller_st(s) {
+            splice(with);
+        }
+    }
+    out
+}
+
+fn smaller(prog: &Prog) -> Vec<Prog> {
+    let mut out = Vec::new();
+    for body in smaller_block(&prog.body) {
+        out.push(Prog { body, ..prog.clone() });
+    }
+    for ret in smaller_ex(&prog.ret) {
+        out.push(Prog { ret, ..prog.clone() });
+    }
+    for (i, helper) in prog.helpers.iter().enumerate() {
+        for h in smaller_ex(helper) {
+            let mut p = prog.clone();
+            p.helpers[i] = h;
+            out.push(p);
+        }
+    }
+    for i in 0..prog.init.len() {
+        if prog.init[i] != 0 {
+            let mut p = prog.clone();
+            p.init[i] = 0;
+            out.push(p);
+        }
+    }
+    for i in 0..GLOBALS {
+        if prog.globals[i] != 0 {
+            let mut p = prog.clone();
+            p.globals[i] = 0;
+            out.push(p);
+        }
+    }
+    out
+}
+
+// What a run printed and its exit status.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout: Vec<u8>,
+    exit: i32,
+}
+
+fn run_vm(ir: &ProgramIR) -> Outcome {
+    let mut state = VmState::new(ir).unwrap();
+    state.output = Some(Vec::new());
+    loop {
+        match state.step() {
+            StepResult::Continue => {}
+            // the status the process would exit with
+            StepResult::Halted(value) => {
+                return Outcome { stdout: state.output.unwrap(), exit: value as i32 & 0xFF };
+            }
+            StepResult::Error(e) => panic!("VM error: {e}\n{ir}"),
+        }
+    }
+}
+
+fn run_native(ir: &ProgramIR, pie: bool, dir: &Path) -> Outcome {
+    let path = dir.join("output");
+    cosplae::native(ir, pie, false).unwrap_or_else(|e| panic!("{e}\n{ir}")).generate_elf(&path).unwrap();
+    // Another test thread forking while the file was open for writing
+    // leaves it busy until that child execs.
+    let run = loop {
+        match Command::new(&path).output() {
+            Err(e) if e.raw_os_error() == Some(26) => std::thread::yield_now(), // ETXTBSY
+            run => break run.unwrap(),
+        }
+    };
+    Outcome { stdout: run.stdout, exit: run.status.code().expect("killed by a signal") }
+}
+
+// The outcomes of `prog` on the VM and natively, if they differ.
+fn diverges(prog: &Prog, pie: bool, dir: &Path) -> Option<(Outcome, Outcome)> {
+    let ir = lower(prog);
+    let (vm, native) = (run_vm(&ir), run_native(&ir, pie, dir));
+    (vm != native).then_some((vm, native))
+}
+
+fn check(what: &str, prog: Prog, pie: bool) {
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-differential-{what}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    if diverges(&prog, pie, &dir).is_some() {
+        let mut prog = prog;
+        'shrink: loop {
+            for candidate in smaller(&prog) {
+                if diverges(&candidate, pie, &dir).is_some() {
+                    prog = candidate;
+                    continue 'shrink;
+                }
+            }
+            break;
+        }
+        let (vm, native) = diverges(&prog, pie, &dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        panic!("{what}: the backends diverge{}\nVM:     {vm:?}\nnative: {native:?}\nsmallest program:\n{}",
+               if pie { " (PIE)" } else { "" }, lower(&prog));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn random_programs_agree() {
+    let mut rng = Rng(0x5EED_C0DE_F00D_CAFE);
+    for case in 0..300 {
+        let prog = gen_prog(&mut rng);
+        check(&format!("case {case}"), prog, case % 3 == 0);
+    }
+}
+
+// Where the backends have differed before, kept as regressions.
+#[test]
+fn fixed_divergences_stay_fixed() {
+    let c = |n| Box::new(Ex::Const(n));
+    let prog = |body: Vec<St>, ret: Ex| Prog {
+        globals: [0; GLOBALS],
+        helpers: vec![Ex::Local(0); HELPERS],
+        init: [0; LOCALS + ARRAY_LEN],
+        body,
+        ret,
+    };
+    // negative numbers printed with their sign, and as unsigned
+    check("negative-print", prog(vec![
+        St::Print(Ex::Const(-5)), St::Newline,
+        St::Print(Ex::Unary(Instr::Neg, c(i32::MIN))), St::Newline,
+        St::PrintUnsigned(Ex::Const(-1)), St::Newline,
+    ], Ex::Const(-1)), false);
+    // the condition is popped on the path that falls through as well as on
+    // the one that jumps
+    check("fall-through-pop", prog(vec![
+        St::Store(0, Ex::Const(7)),
+        St::If(Ex::Const(1), vec![St::Print(Ex::Local(0))]),
+        St::IfElse(Ex::Const(0), vec![St::Print(Ex::Const(1))], vec![St::Print(Ex::Const(2))]),
+    ], Ex::Binary(Instr::Add, Box::new(Ex::Local(0)), c(1))), false);
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use cosplae::disasm::Disassembler;
+
+fn disasm(source: &str) -> String {
+    let out = common::cosplae(&["--emit=disasm"], source);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+// The instruction column of each line, e.g. `push %rbp`
+fn mnemonics(listing: &str) -> Vec<&str> {
+    listing.lines().filter_map(|l| l.split_once(":  ")).map(|(_, rest)| rest[32..].trim()).collect()
+}
+
+#[test]
+fn simple_program() {
+    let listing = disasm("i32 main() { i32 x = 5; return x + 2; }");
+    assert!(listing.starts_with("main:\n  401000:  55 "), "{listing}");
+    assert_eq!(mnemonics(&listing)[..13], [
+        "push %rbp", "mov %rsp, %rbp", "sub $8, %rsp",
+        "movq $5, -8(%rbp)",
+        "mov -8(%rbp), %rax", "push %rax",
+        "pop %rax", "add $2, %rax", "movslq %eax, %rax", "push %rax",
+        "pop %rdi", "mov $60, %eax", "syscall",
+    ]);
+}
+
+#[test]
+fn calls_and_jumps_show_their_targets() {
+    let listing = disasm("i32 f() { return 1; } i32 main() { var i32 i = 0; while (i < 3) { i += f(); } return i; }");
+    let f = listing.lines().find_map(|l| l.strip_suffix(" <f>")).unwrap();
+    let target = f.rsplit(' ').next().unwrap().trim_start_matches("0x");
+    assert!(listing.contains(&format!("\nf:\n  {target}:  55 ")), "{listing}");
+    assert!(mnemonics(&listing).iter().any(|m| m.starts_with("je 0x")), "{listing}");
+}
+
+// Every form the backend emits decodes, builtins included
+#[test]
+fn nothing_is_left_undecoded() {
+    let listing = disasm("i32 g = 1; i32 sq(i32 v) { return v * v; }\n\
+                          i32 main() { i32 x = input(); i64 big = 5000000000; g = g - x; var i32 a[2]; a[x] = a[x - 1];\n\
+                          print(sq(x) / 2 % 3, -x, !x, x >= 2, \"s\\n\"); return g; }");
+    assert!(!listing.contains(".byte"), "{listing}");
+    assert!(listing.contains("movabs $5000000000, %rax"), "{listing}");
+    assert!(listing.contains("(%rip), %rax  # 0x600000"), "{listing}");
+}
+
+// A local's rbp displacement is a disp8 down to -128 (slot 15), then a disp32
+#[test]
+fn displacement_boundary() {
+    let code = [
+        0x48, 0x8B, 0x45, 0x80,                   // slot 15
+        0x48, 0x8B, 0x85, 0x78, 0xFF, 0xFF, 0xFF, // slot 16
+        0x0F, 0xA2,                               // cpuid: not something the backend emits
+        0x58,
+    ];
+    let text: Vec<_> = Disassembler::new(&code, 0).map(|i| i.text).collect();
+    assert_eq!(text, ["mov -128(%rbp), %rax", "mov -136(%rbp), %rax", ".byte 0x0f", ".byte 0xa2", "pop %rax"]);
+}
+
+// The `if`'s je is emitted before its target and patched once the function
+// is done: it must skip exactly the `then` block, in a rel8.
+#[test]
+fn forward_jump_is_patched() {
+    let src = "i32 pick(mut i32 x) { if (x) { x = 5; } return x + 1; } i32 main() { return pick(0) * 10 + pick(3); }";
+    let listing = disasm(src);
+    let pick: Vec<_> = listing.split("\npick:\n").nth(1).unwrap().lines().collect();
+    let je = pick.iter().position(|l| l.contains(" je 0x")).unwrap();
+    let target = pick[je].rsplit("0x").next().unwrap();
+    assert!(pick[je].contains("74 08"), "{listing}");
+    assert!(pick[je + 1].ends_with("movq $5, -8(%rbp)"), "{listing}");
+    assert!(pick[je + 2].trim_start().starts_with(&format!("{target}:")), "{listing}");
+
+    assert_eq!(common::cosplae(&["--run"], src).status.code(), Some(16));
+    assert_eq!(common::native("forward_jump", src).status.code(), Some(16));
+}
+
+// Both of a small loop's jumps, the exit and the one back up, reach in a
+// rel8; rotated, the one back up is conditional too
+#[test]
+fn short_loops_use_rel8_jumps() {
+    let listing = disasm("i32 main() { var i32 i = 0; while (i < 10) { i += 1; } return i; }");
+    let je = listing.lines().find(|l| l.contains(" je 0x")).unwrap();
+    let jne = listing.lines().find(|l| l.contains(" jne 0x")).unwrap();
+    assert!(je.contains(":  74 "), "{listing}");
+    assert!(jne.contains(":  75 "), "{listing}");
+    assert!(!listing.contains(" 0f 84 ") && !listing.contains(" 0f 85 ") && !listing.contains(" jmp 0x"), "{listing}");
+}
+
+#[test]
+fn helpers_return_through_an_epilogue() {
+    let src = "i32 h(i32 a) { i32 b = a * 2; return b + 1; } i32 main() { return h(1) + h(2); }";
+    let listing = disasm(src);
+    let (main, h) = listing.split_once("\nh:\n").unwrap();
+    // only main exits the process; a helper unwinds its frame back to the caller
+    assert!(mnemonics(main).ends_with(&["pop %rdi", "mov $60, %eax", "syscall"]), "{listing}");
+    assert!(mnemonics(h).ends_with(&["pop %rax", "mov %rbp, %rsp", "pop %rbp", "ret"]), "{listing}");
+    assert!(!mnemonics(h).contains(&"syscall"), "{listing}");
+    assert_eq!(common::native("epilogue", src).status.code(), Some(8));
+}
+
+#[test]
+fn each_function_sizes_its_own_frame() {
+    let listing = disasm("i32 one(i32 a) { return a; } i32 three(i32 a) { i32 b = a; i32 c = b; return c; } i32 main() { return one(1) + three(2); }");
+    let (main, rest) = listing.split_once("\none:\n").unwrap();
+    let (one, three) = rest.split_once("\nthree:\n").unwrap();
+    // main has no locals; `one` has its parameter, `three` that and two more
+    assert_eq!(mnemonics(main)[2], "push $1", "{listing}");
+    assert_eq!(mnemonics(one)[..3], ["push %rbp", "mov %rsp, %rbp", "sub $8, %rsp"], "{listing}");
+    assert_eq!(mnemonics(three)[..3], ["push %rbp", "mov %rsp, %rbp", "sub $24, %rsp"], "{listing}");
+}
+
+// An element at a runtime index is one scaled-index access, with a disp8
+// while the array's base is near rbp.
+#[test]
+fn indexed_elements_use_scaled_addressing() {
+    let listing = disasm("i32 main() { i32 i = input(); var i32 a[20]; var i32 b[4]; b[i] = 7; a[i] = b[i]; return a[i]; }");
+    for (bytes, text) in [
+        ("48 89 9c c5 50 ff ff ff", "mov %rbx, -176(%rbp,%rax,8)"), // b at slot 21, past a
+        ("48 8b 84 c5 50 ff ff ff", "mov -176(%rbp,%rax,8), %rax"),
+        ("48 89 5c c5 f0", "mov %rbx, -16(%rbp,%rax,8)"),            // a at slot 1
+        ("48 8b 44 c5 f0", "mov -16(%rbp,%rax,8), %rax"),
+    ] {
+        assert!(listing.lines().any(|l| l.contains(bytes) && l.ends_with(text)), "{bytes} {text}\n{listing}");
+    }
+}
+
+// The canary is stored below the locals on entry and compared before each
+// return, trapping with ud2 on a mismatch.
+#[test]
+fn stack_protector_guards_each_frame() {
+    let src = "i32 f(i32 a) { var i32 t[2]; t[a] = 1; return t[0]; }\ni32 main() { return f(0); }";
+    let out = common::cosplae(&["--emit=disasm", "--stack-protector"], src);
+    let listing = String::from_utf8(out.stdout).unwrap();
+    let canary = cosplae::elfgen::CANARY.to_le_bytes().map(|b| format!("{b:02x}")).join(" ");
+    for (bytes, text) in [
+        (format!("48 b8 {canary}"), format!("movabs ${}, %rax", cosplae::elfgen::CANARY as i64)),
+        ("48 89 45 e0".into(), "mov %rax, -32(%rbp)".into()), // f: a, t[0], t[1], then the canary
+        ("48 8b 45 e0".into(), "mov -32(%rbp), %rax".into()),
+        (format!("48 b9 {canary}"), format!("movabs ${}, %rcx", cosplae::elfgen::CANARY as i64)),
+        ("48 39 c8".into(), "cmp %rcx, %rax".into()),
+        ("0f 0b".into(), "ud2".into()),
+    ] {
+        assert!(listing.lines().any(|l| l.contains(&bytes) && l.ends_with(&text)), "{bytes} {text}\n{listing}");
+    }
+    assert_eq!(mnemonics(&listing).iter().filter(|m| **m == "ud2").count(), 2, "{listing}"); // a return each
+    assert!(!disasm(src).contains("ud2"));
+}
+
+// `--source-map` writes `OUT.map` next to the executable; the run of code
+// holding the `idiv` maps to the division.
+#[test]
+fn source_map_finds_the_division() {
+    let src = "i32 main() {\n    i32 a = input();\n    print(a + 1);\n    return 100 / a;\n}\n";
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-source-map", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = common::cosplae_in(&dir, &["--emit=elf", "--source-map", "-o", "prog"], src);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let map = std::fs::read_to_string(dir.join("prog.map")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let listing = disasm(src);
+    let idiv = listing.lines().find(|l| l.ends_with("idiv %rbx")).unwrap();
+    let idiv = u64::from_str_radix(idiv.trim_start().split(':').next().unwrap(), 16).unwrap();
+    let entries: Vec<(u64, &str)> = map.lines()
+        .map(|l| l.split_once(' ').unwrap())
+        .map(|(addr, pos)| (u64::from_str_radix(addr, 16).unwrap(), pos))
+        .collect();
+    assert!(entries.is_sorted_by_key(|&(addr, _)| addr), "{map}");
+    let (_, pos) = entries.iter().rev().find(|&&(addr, _)| addr <= idiv).unwrap();
+    assert_eq!(*pos, "4:16", "the `/` on line 4\n{map}");
+}
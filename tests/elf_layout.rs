@@ -0,0 +1,192 @@
+mod common;
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+// The executable `--emit=elf` writes for `source`.
+fn elf(name: &str, source: &str) -> Vec<u8> {
+    elf_with(name, &[], source).0
+}
+
+// Like `elf`, with extra compiler `args`; also runs the executable.
+fn elf_with(name: &str, args: &[&str], source: &str) -> (Vec<u8>, std::process::Output) {
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = common::cosplae_in(&dir, &[&["--emit=elf"], args].concat(), source);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let elf = std::fs::read(dir.join("output")).unwrap();
+    let run = std::process::Command::new(dir.join("output")).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    (elf, run)
+}
+
+// NUL-terminated string at `at`.
+fn str_at(b: &[u8], at: usize) -> String {
+    let len = b[at..].iter().position(|&c| c == 0).unwrap();
+    String::from_utf8(b[at..at + len].to_vec()).unwrap()
+}
+
+// (name, sh_addr, sh_offset, sh_size) of each section header after the
+// null one.
+fn sections(elf: &[u8]) -> Vec<(String, u64, u64, u64)> {
+    let shoff = u64_at(elf, 0x28) as usize;
+    let shnum = u16_at(elf, 0x3C) as usize;
+    let header = |i: usize| shoff + 64 * i;
+    let strtab = u64_at(elf, header(u16_at(elf, 0x3E) as usize) + 24) as usize;
+    (1..shnum)
+        .map(|i| {
+            let h = header(i);
+            (str_at(elf, strtab + u32_at(elf, h) as usize), u64_at(elf, h + 16), u64_at(elf, h + 24), u64_at(elf, h + 32))
+        })
+        .collect()
+}
+
+#[test]
+fn code_and_data_are_separate_segments() {
+    let elf = elf("elf-layout", "i32 main() { print(1); return 0; }");
+
+    let phoff = u64_at(&elf, 0x20) as usize;
+    assert_eq!(u16_at(&elf, 0x38), 2, "e_phnum");
+    let (code, data) = (phoff, phoff + 56);
+    for ph in [code, data] {
+        assert_eq!(u32_at(&elf, ph), 1, "PT_LOAD");
+        assert_eq!(u64_at(&elf, ph + 8) % 0x1000, 0, "page-aligned offset");
+        assert_eq!(u64_at(&elf, ph + 16) % 0x1000, 0, "page-aligned vaddr");
+    }
+    assert_eq!(u32_at(&elf, code + 4), 5, "code is R|X");
+    assert_eq!(u32_at(&elf, data + 4), 6, "data is R|W");
+    assert_eq!(u64_at(&elf, 0x18), u64_at(&elf, code + 16), "entry at start of code");
+}
+
+#[test]
+fn text_section_covers_the_code_segment() {
+    let elf = elf("elf-sections", "i32 main() { print(1); return 0; }");
+    let sections = sections(&elf);
+    let names: Vec<_> = sections.iter().map(|s| s.0.as_str()).collect();
+    assert_eq!(names, [".text", ".symtab", ".strtab", ".shstrtab"]);
+
+    let code = u64_at(&elf, 0x20) as usize;
+    let (_, addr, offset, size) = &sections[0];
+    assert_eq!(*addr, u64_at(&elf, code + 16), "sh_addr");
+    assert_eq!(*offset, u64_at(&elf, code + 8), "sh_offset");
+    assert_eq!(*size, u64_at(&elf, code + 32), "sh_size");
+}
+
+#[test]
+fn functions_are_symbols_with_main_global() {
+    let elf = elf("elf-symbols", "i32 f() { return 1; }\ni32 main() { return f(); }");
+    let sections = sections(&elf);
+    let find = |name: &str| sections.iter().find(|s| s.0 == name).unwrap();
+    let (_, _, symtab, size) = find(".symtab");
+    let (_, _, strtab, _) = find(".strtab");
+    let (_, text, _, _) = find(".text");
+
+    // (name, st_info, st_value); entry 0 is the null symbol
+    let symbols: Vec<_> = (1..*size as usize / 24)
+        .map(|i| {
+            let sym = *symtab as usize + 24 * i;
+            (str_at(&elf, *strtab as usize + u32_at(&elf, sym) as usize), elf[sym + 4], u64_at(&elf, sym + 8))
+        })
+        .collect();
+    assert_eq!(symbols.len(), 2);
+    let (f, main) = (&symbols[0], &symbols[1]);
+    assert_eq!((f.0.as_str(), f.1), ("f", 0x02), "local STT_FUNC");
+    assert_eq!((main.0.as_str(), main.1), ("main", 0x12), "global STT_FUNC");
+    assert_eq!(main.2, *text, "main starts the code");
+    assert!(f.2 > *text);
+}
+
+// `PushI32(5), Store(0)` skips the stack: mov qword [rbp - 8], 5
+#[test]
+fn constant_initialization_is_a_single_store() {
+    let elf = elf("elf-peephole", "i32 main() { i32 x = 5; return x; }");
+    let (_, _, text, _) = sections(&elf).into_iter().find(|s| s.0 == ".text").unwrap();
+    let text = text as usize;
+    // after push rbp; mov rbp, rsp; sub rsp, 8
+    assert_eq!(elf[text + 8..text + 16], [0x48, 0xC7, 0x45, 0xF8, 0x05, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn pie_is_a_dyn_with_a_pie_flag_and_still_runs() {
+    let (elf, run) = elf_with("elf-pie", &["--pie"], "i32 f() { return 42; }\ni32 main() { print(f()); return 3; }");
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "42\n");
+    assert_eq!(run.status.code(), Some(3));
+
+    assert_eq!(u16_at(&elf, 0x10), 3, "ET_DYN");
+    let phoff = u64_at(&elf, 0x20) as usize;
+    let headers: Vec<_> = (0..u16_at(&elf, 0x38) as usize).map(|i| phoff + 56 * i).collect();
+    assert_eq!(u64_at(&elf, headers[0] + 16), 0x1000, "code linked from base 0");
+    let dynamic = headers.iter().find(|&&ph| u32_at(&elf, ph) == 2).expect("PT_DYNAMIC");
+    let at = u64_at(&elf, dynamic + 8) as usize;
+    assert_eq!((u64_at(&elf, at), u64_at(&elf, at + 8)), (0x6FFF_FFFB, 0x0800_0000), "DT_FLAGS_1 = DF_1_PIE");
+}
+
+#[test]
+fn identical_strings_share_their_data() {
+    let src = "i32 main() { print(\"-\\n\"); print(1); print(\"-\\n\"); print(\"=\\n\"); print(\"-\\n\"); return 0; }";
+    let (elf, run) = elf_with("elf-strings", &[], src);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "-\n1\n-\n=\n-\n");
+    let (_, _, offset, size) = sections(&elf).into_iter().find(|s| s.0 == ".data").unwrap();
+    let data = &elf[offset as usize..(offset + size) as usize];
+    assert_eq!(data.windows(2).filter(|w| *w == b"-\n").count(), 1, "{data:?}");
+    assert_eq!(data.windows(2).filter(|w| *w == b"=\n").count(), 1, "{data:?}");
+}
+
+// `--build-id` adds a PT_NOTE and a `.note.gnu.build-id` for a GNU note
+// with an 8-byte hash that changes with the code.
+#[test]
+fn build_id_is_a_well_formed_note() {
+    let note = |name: &str, source: &str| {
+        let (elf, run) = elf_with(name, &["--build-id"], source);
+        assert_eq!(run.status.code(), Some(7));
+        let phoff = u64_at(&elf, 0x20) as usize;
+        let headers: Vec<_> = (0..u16_at(&elf, 0x38) as usize).map(|i| phoff + 56 * i).collect();
+        let ph = *headers.iter().find(|&&ph| u32_at(&elf, ph) == 4).expect("PT_NOTE");
+        let (at, size) = (u64_at(&elf, ph + 8) as usize, u64_at(&elf, ph + 32) as usize);
+        let section = sections(&elf).into_iter().find(|s| s.0 == ".note.gnu.build-id").unwrap();
+        assert_eq!((section.2, section.3), (at as u64, size as u64), "the section is the segment");
+        assert_eq!(size, 24);
+        assert_eq!((u32_at(&elf, at), u32_at(&elf, at + 4), u32_at(&elf, at + 8)), (4, 8, 3), "namesz, descsz, NT_GNU_BUILD_ID");
+        assert_eq!(&elf[at + 12..at + 16], b"GNU\0");
+        elf[at + 16..at + 24].to_vec()
+    };
+    let seven = note("elf-build-id-a", "i32 main() { return 7; }");
+    assert_eq!(note("elf-build-id-b", "i32 main() { return 7; }"), seven);
+    assert_ne!(note("elf-build-id-c", "i32 main() { return 3 + 4 + 0 * input(); }"), seven);
+    assert!(!sections(&elf("elf-no-build-id", "i32 main() { return 7; }")).iter().any(|s| s.0.starts_with(".note")));
+}
+
+// `--relocatable` writes an object file, its calls and data references
+// left to relocations, that `ld` links into a program that runs.
+#[test]
+fn relocatable_objects_link_with_ld() {
+    let src = "i32 g = 5; i32 sq(i32 v) { return v * v; }\ni32 main() { print(\"sq \"); print(sq(g)); g = g + 1; return sq(g) - 30; }";
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-elf-rel", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = common::cosplae_in(&dir, &["--emit=elf", "--relocatable", "-o", "sq.o"], src);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let obj = std::fs::read(dir.join("sq.o")).unwrap();
+    assert_eq!(u16_at(&obj, 0x10), 1, "ET_REL");
+    assert_eq!(u16_at(&obj, 0x38), 0, "no program headers");
+    let names: Vec<_> = sections(&obj).into_iter().map(|s| s.0).collect();
+    assert_eq!(names, [".text", ".data", ".rela.text", ".symtab", ".strtab", ".shstrtab"]);
+
+    let has_ld = std::process::Command::new("ld").arg("--version").output().is_ok_and(|o| o.status.success());
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) && has_ld {
+        let ld = std::process::Command::new("ld").current_dir(&dir).args(["-e", "main", "-o", "sq", "sq.o"]).output().unwrap();
+        assert!(ld.status.success(), "{}", String::from_utf8_lossy(&ld.stderr));
+        let run = std::process::Command::new(dir.join("sq")).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&run.stdout), "sq 25\n");
+        assert_eq!(run.status.code(), Some(6));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
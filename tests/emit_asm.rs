@@ -0,0 +1,138 @@
+mod common;
+
+fn asm(source: &str) -> String {
+    let out = common::cosplae(&["--emit=asm"], source);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+fn lines(asm: &str) -> Vec<&str> {
+    asm.lines().map(str::trim).filter(|l| !l.starts_with('#')).collect()
+}
+
+#[test]
+fn main_comes_first_and_exits_with_its_value() {
+    let asm = asm("i32 main() { return 10; }");
+    // codegen's trailing Ret follows, unreachable
+    assert_eq!(lines(&asm)[..7], ["main:", "push %rbp", "mov %rsp, %rbp",
+                                  "push $10", "pop %rdi", "mov $60, %eax", "syscall"]);
+}
+
+#[test]
+fn calls_name_the_callee_and_drop_the_arguments() {
+    let asm = asm("i32 add(i32 a, i32 b) { return a + b; }\ni32 main() { return add(1, 2); }");
+    let lines = lines(&asm);
+    let call = lines.iter().position(|l| *l == "call add").unwrap();
+    assert_eq!(lines[call + 1..call + 3], ["add $16, %rsp", "push %rax"]);
+    let callee = lines.iter().position(|l| *l == "add:").unwrap();
+    assert_eq!(lines[callee + 3..callee + 8],
+               ["sub $16, %rsp", "mov 24(%rbp), %rax", "mov %rax, -8(%rbp)", "mov 16(%rbp), %rax", "mov %rax, -16(%rbp)"]);
+}
+
+#[test]
+fn branches_target_function_local_labels() {
+    let asm = asm("i32 main() { var i32 x = 3; while (x > 0) { x = x - 1; } return x; }");
+    let labels: Vec<_> = asm.lines().filter(|l| l.starts_with(".L")).collect();
+    assert_eq!(labels.len(), 2);
+    for label in labels {
+        let target = label.trim_end_matches(':');
+        assert!(asm.lines().any(|l| l.trim().starts_with('j') && l.ends_with(target)), "nothing jumps to {target}");
+    }
+}
+
+// `push imm32` sign-extends, so a constant beyond i32 goes through rax.
+#[test]
+fn wide_constants_load_an_imm64() {
+    let asm = asm("i32 main() { i64 x = 3000000000; print(x); return 0; }");
+    assert_eq!(lines(&asm)[4..6], ["movabs $3000000000, %rax", "push %rax"]);
+}
+
+#[test]
+fn overflow_checks_jump_to_a_shared_stub() {
+    let src = "i32 main() { i32 x = input(); return x * 3 + x; }";
+    assert!(!asm(src).contains("__overflow"));
+    let out = common::cosplae(&["--emit=asm", "--overflow-checks"], src);
+    let checked = String::from_utf8(out.stdout).unwrap();
+    let lines = lines(&checked);
+    let mul = lines.iter().position(|l| *l == "imul $3, %rax, %rax").unwrap();
+    assert_eq!(lines[mul + 1..mul + 6], ["jo __overflow", "movslq %eax, %rcx", "cmp %rax, %rcx", "jne __overflow", "movslq %eax, %rax"]);
+    assert_eq!(lines.iter().filter(|l| **l == "jo __overflow").count(), 2, "{checked}");
+    assert!(checked.ends_with("__overflow:\n    mov $1, %eax\n    mov $2, %edi\n    lea .Loverflow_msg(%rip), %rsi\n    mov $32, %edx\n    syscall\n    mov $60, %eax\n    mov $70, %edi\n    syscall\n"), "{checked}");
+}
+
+// Both indexed loads and stores check the index, unsigned so a negative
+// one fails too, and share one stub
+#[test]
+fn indices_jump_to_a_shared_bounds_stub() {
+    let asm = asm("i32 main() { var i32 a[3]; i32 i = input(); a[i] = 5; return a[i]; }");
+    let lines = lines(&asm);
+    let store = lines.iter().position(|l| *l == "mov %rbx, -8(%rbp,%rax,8)").unwrap();
+    assert_eq!(lines[store - 5..store], ["pop %rbx", "pop %rax", "cmp $3, %rax", "jae __bounds", "neg %rax"]);
+    let load = lines.iter().position(|l| *l == "mov -8(%rbp,%rax,8), %rax").unwrap();
+    assert_eq!(lines[load - 4..load], ["pop %rax", "cmp $3, %rax", "jae __bounds", "neg %rax"]);
+    assert!(asm.ends_with("__bounds:\n    mov $1, %eax\n    mov $2, %edi\n    lea .Lbounds_msg(%rip), %rsi\n    mov $40, %edx\n    syscall\n    mov $60, %eax\n    mov $70, %edi\n    syscall\n"), "{asm}");
+    // constant indices are checked when compiling instead
+    assert!(!self::asm("i32 main() { var i32 a[3]; a[2] = 5; return a[0]; }").contains("__bounds"));
+}
+
+#[test]
+fn repeated_strings_load_one_label() {
+    let asm = asm("i32 main() { var i32 i = 0; while (i < 3) { print(\"-\\n\"); i = i + 1; } print(\"-\\n\"); print(\"+\\n\"); return 0; }");
+    let leas: Vec<_> = lines(&asm).into_iter().filter(|l| l.starts_with("lea .Lstr")).collect();
+    assert_eq!(leas.len(), 3);
+    assert_eq!(leas[0], leas[1]);
+    assert_ne!(leas[1], leas[2]);
+}
+
+#[test]
+fn i64_arithmetic_keeps_all_64_bits() {
+    let src = "i32 main() { i64 x = 3000000000; print(x + x); return 0; }";
+    let asm = asm(src);
+    let lines = lines(&asm);
+    let add = lines.iter().position(|l| *l == "add %rbx, %rax").unwrap();
+    assert_eq!(lines[add + 1], "push %rax");
+    let out = common::cosplae(&["--emit=asm", "--overflow-checks"], src);
+    let checked = String::from_utf8(out.stdout).unwrap();
+    let lines = self::lines(&checked);
+    let add = lines.iter().position(|l| *l == "add %rbx, %rax").unwrap();
+    assert_eq!(lines[add + 1..add + 3], ["jo __overflow", "push %rax"]);
+}
+
+// A constant initializer is stored as an immediate, without a trip
+// through the operand stack.
+#[test]
+fn constant_locals_are_stored_as_immediates() {
+    let asm = asm("i32 f() { i32 a = 1; i32 b = -2; i64 c = 3; i32 d = 2 * 8; i32 e = -5 + 1; return 0; }\ni32 main() { return f(); }");
+    let lines = lines(&asm);
+    let f = lines.iter().position(|l| *l == "f:").unwrap();
+    assert_eq!(lines[f + 4..f + 10], ["movq $1, -8(%rbp)", "movq $-2, -16(%rbp)", "movq $3, -24(%rbp)",
+                                      "movq $16, -32(%rbp)", "movq $-4, -40(%rbp)", "push $0"], "{asm}");
+}
+
+// A local without an initializer is zeroed in place.
+#[test]
+fn defaulted_locals_are_zeroed_in_place() {
+    let asm = asm("i32 main() { i64 z; i32 a[2]; return z + a[1]; }");
+    assert_eq!(lines(&asm)[3..6], ["sub $24, %rsp", "movq $0, -8(%rbp)", "movq $0, -16(%rbp)"], "{asm}");
+    assert_eq!(lines(&asm)[6], "movq $0, -24(%rbp)", "{asm}");
+}
+
+// Without a frame pointer a leaf addresses its locals and arguments from
+// rsp, allowing for the operand values pushed since the prologue; a
+// function that calls keeps rbp.
+#[test]
+fn leaf_functions_can_omit_the_frame_pointer() {
+    let src = "i32 leaf(i32 a) { i32 b = a + 1; return a * b; }\ni32 main() { print(leaf(6)); return 0; }";
+    let out = common::cosplae(&["--emit=asm", "--omit-frame-pointer"], src);
+    let asm = String::from_utf8(out.stdout).unwrap();
+    let lines = lines(&asm);
+    assert_eq!(lines[..3], ["main:", "push %rbp", "mov %rsp, %rbp"], "{asm}");
+    let leaf = lines.iter().position(|l| *l == "leaf:").unwrap();
+    assert_eq!(lines[leaf + 1..leaf + 6], ["sub $16, %rsp", "mov 24(%rsp), %rax", "mov %rax, 8(%rsp)",
+                                           "mov 8(%rsp), %rax", "push %rax"], "{asm}");
+    // b is stored at 0(%rsp), then read at 8(%rsp) with a pushed above it
+    let store = lines.iter().position(|l| *l == "mov %rax, 0(%rsp)").unwrap();
+    assert_eq!(lines[store + 1..store + 5], ["mov 8(%rsp), %rax", "push %rax", "mov 8(%rsp), %rax", "push %rax"], "{asm}");
+    assert!(asm.ends_with("add $16, %rsp\n    ret\n"), "{asm}");
+    assert!(!lines[leaf..].contains(&"push %rbp"));
+}
@@ -0,0 +1,95 @@
+mod common;
+
+// The sample program main.rs used to embed
+const SAMPLE: &str = r#"
+    struct Point {
+        i32 x;
+        i32 y;
+    };
+
+    const i32 n = 5;
+
+    i32 main() {
+        i32 x = 10;
+        print(x);
+        print(n);
+        return 0;
+    }
+"#;
+
+fn ir(source: &str) -> String {
+    let out = common::cosplae(&["--emit=ir"], source);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn sample_listing() {
+    assert_eq!(ir(SAMPLE), "\
+global #0 n = 5
+
+func #0 main: 0 params, 1 locals, max stack 1
+0: PushI32(10)
+1: Store(0)  ; x
+2: Load(0)  ; x
+3: Print
+4: PrintNewline
+5: LoadGlobal(0)  ; n
+6: Print
+7: PrintNewline
+8: PushI32(0)
+9: Ret
+");
+}
+
+// Each plain `print` argument gets its own newline; with a string among
+// them, only the strings say where lines end.
+#[test]
+fn print_lowering() {
+    let plain = ir("i32 main() { print(1, 2); return 0; }");
+    assert!(plain.contains("0: PushI32(1)\n1: Print\n2: PrintNewline\n3: PushI32(2)\n4: Print\n5: PrintNewline\n"), "{plain}");
+    let mixed = ir("i32 main() { print(1, 2, \"\\n\"); return 0; }");
+    assert!(mixed.contains("0: PushI32(1)\n1: Print\n2: PushI32(2)\n3: Print\n4: PrintStr([10])\n"), "{mixed}");
+}
+
+#[test]
+fn functions_labels_and_calls() {
+    let listing = ir("i32 sq(i32 v) { return v * v; }\n\
+                      i32 main() { var i32 i = 3; while (i > 0) { i = i - 1; } return sq(i); }");
+    assert!(listing.starts_with("func #0 sq: 1 params, 1 locals, max stack 2\n0: Load(0)  ; v\n"), "{listing}");
+    assert!(listing.contains("\n\nfunc #1 main: 0 params, 1 locals, max stack 2\n"), "{listing}");
+    for line in ["Label(1)", "JumpIfZero(2)", "JumpIfNonZero(1)", "Label(2)", "Call(0, 1)  ; sq"] {
+        assert!(listing.lines().any(|l| l.split_once(": ").is_some_and(|(_, i)| i == line)), "no `{line}` in\n{listing}");
+    }
+}
+
+// A comparison's 0/1 feeds the branch directly, and a plain integer
+// condition is tested against zero by the branch itself.
+#[test]
+fn conditions_are_not_compared_twice() {
+    let listing = ir("i32 main() { i32 x = input(); if (x < 3) { return 1; } if (x) { return 2; } return 0; }");
+    assert!(listing.contains("4: CmpLt\n5: JumpIfZero(1)\n"), "{listing}");
+    assert!(listing.contains("9: Load(0)  ; x\n10: JumpIfZero(2)\n"), "{listing}");
+    assert!(!listing.contains("CmpNe"), "{listing}");
+}
+
+// The header's max stack is the deepest any path goes: `a + b` waits under
+// `c + d`, and the two arms of `||` push one result between them, so none
+// is left over underneath the product.
+#[test]
+fn max_stack_follows_branches() {
+    let header = |listing: String| listing.lines().next().unwrap().to_string();
+    let locals = "i32 a = input(); i32 b = input(); i32 c = input(); i32 d = input();";
+    let product = ir(&format!("i32 main() {{ {locals} return (a + b) * (c + d); }}"));
+    assert_eq!(header(product), "func #0 main: 0 params, 4 locals, max stack 3");
+    let after_or = ir(&format!("i32 main() {{ {locals} print(a < b || c < d); return (a + b) * (c + d); }}"));
+    assert_eq!(header(after_or), "func #0 main: 0 params, 4 locals, max stack 3");
+}
+
+// `x = x;` stores nothing: the only store is the declaration's.
+#[test]
+fn self_assignment_is_elided() {
+    let listing = ir("i32 main() { var i32 x = input(); x = x; return x; }");
+    assert!(listing.ends_with("0: Input\n1: Store(0)  ; x\n2: Load(0)  ; x\n3: Ret\n"), "{listing}");
+    assert_eq!(listing.matches("Store").count(), 1, "{listing}");
+}
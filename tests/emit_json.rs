@@ -0,0 +1,25 @@
+mod common;
+
+fn json(source: &str) -> String {
+    let out = common::cosplae(&["--emit=json"], source);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn function_and_return() {
+    assert_eq!(json("i32 main() { return 0; }"), concat!(
+        r#"{"node":"Program","decls":[{"node":"Func","ret_type":"i32","name":"main","params":[],"#,
+        r#""body":{"node":"Block","stmts":[{"node":"Return","value":{"node":"Number","value":0}}]}}]}"#,
+        "\n",
+    ));
+}
+
+// Parameters and locals keep their names and types
+#[test]
+fn names_and_types() {
+    let doc = json("i32 sq(i32 v) { i32 r = v * v; return r; } i32 main() { return sq(3); }");
+    assert!(doc.contains(r#""params":[{"node":"Param","ty":"i32","name":"v","default":null,"mutable":false}]"#), "{doc}");
+    assert!(doc.contains(r#"{"node":"VarDecl","ty":"i32","name":"r","len":null,"value":{"node":"Binary""#), "{doc}");
+    assert!(doc.contains(r#"{"node":"Call","name":"sq","args":[{"node":"Number","value":3}]}"#), "{doc}");
+}
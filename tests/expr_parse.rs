@@ -0,0 +1,127 @@
+mod common;
+
+use cosplae::ast::Expr;
+use cosplae::lexer::{Span, Token};
+use cosplae::Parser;
+
+// JSON of the expression in `return <expr>;`
+fn return_value_json(expr: &str) -> String {
+    let out = common::cosplae(&["--emit=json"], &format!("i32 main() {{ return {expr}; }}"));
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let json = String::from_utf8(out.stdout).unwrap();
+    let start = json.find(r#""node":"Return","value":"#).unwrap() + r#""node":"Return","value":"#.len();
+    json[start..].trim_end().trim_end_matches("}]}}]}").to_string()
+}
+
+fn num(n: i64) -> String {
+    format!(r#"{{"node":"Number","value":{n}}}"#)
+}
+
+fn bin(op: &str, left: &str, right: &str) -> String {
+    format!(r#"{{"node":"Binary","op":"{op}","left":{left},"right":{right}}}"#)
+}
+
+#[test]
+fn multiplication_binds_tighter_than_addition() {
+    assert_eq!(return_value_json("2 + 3 * 4"), bin("+", &num(2), &bin("*", &num(3), &num(4))));
+}
+
+#[test]
+fn parentheses_group() {
+    assert_eq!(return_value_json("(2 + 3) * 4"), bin("*", &bin("+", &num(2), &num(3)), &num(4)));
+}
+
+#[test]
+fn operators_are_left_associative() {
+    assert_eq!(return_value_json("10 - 3 - 2"), bin("-", &bin("-", &num(10), &num(3)), &num(2)));
+    assert_eq!(return_value_json("8 % 3 / 2"), bin("/", &bin("%", &num(8), &num(3)), &num(2)));
+}
+
+#[test]
+fn comparisons_bind_looser_than_arithmetic() {
+    assert_eq!(return_value_json("1 + 2 < 4 == 1"),
+               bin("==", &bin("<", &bin("+", &num(1), &num(2)), &num(4)), &num(1)));
+}
+
+fn unary(op: &str, expr: &str) -> String {
+    format!(r#"{{"node":"Unary","op":"{op}","expr":{expr}}}"#)
+}
+
+#[test]
+fn prefix_operators_bind_tightest_and_nest() {
+    let x = r#"{"node":"Ident","name":"x"}"#;
+    assert_eq!(return_value_json("-5 * 2"), bin("*", &unary("-", &num(5)), &num(2)));
+    assert_eq!(return_value_json("- -x"), unary("-", &unary("-", x)));
+    assert_eq!(return_value_json("!x == 0"), bin("==", &unary("!", x), &num(0)));
+}
+
+#[test]
+fn logical_operators_bind_loosest_with_and_over_or() {
+    assert_eq!(return_value_json("1 || 2 && 3 == 4"),
+               bin("||", &num(1), &bin("&&", &num(2), &bin("==", &num(3), &num(4)))));
+}
+
+#[test]
+fn field_access_binds_tighter_than_prefix_operators() {
+    let p = r#"{"node":"Ident","name":"p"}"#;
+    let field = |base: &str, f: &str| format!(r#"{{"node":"Field","base":{base},"field":"{f}"}}"#);
+    assert_eq!(return_value_json("-p.x"), unary("-", &field(p, "x")));
+    assert_eq!(return_value_json("p.x * p.y"), bin("*", &field(p, "x"), &field(p, "y")));
+}
+
+// A parser over `tokens` alone, without a surrounding program.
+fn parser(tokens: Vec<Token>) -> Parser {
+    Parser::new(tokens.into_iter().enumerate().map(|(i, t)| (t, Span { line: 1, col: 1 + 2 * i })).collect())
+}
+
+// The tree of the expression `tokens` make up, as nested `(op left right)`.
+fn tree(tokens: Vec<Token>) -> String {
+    fn shape(e: &Expr) -> String {
+        match e {
+            Expr::Number(n) => n.to_string(),
+            Expr::Binary { op, left, right, .. } => format!("({op} {} {})", shape(left), shape(right)),
+            e => panic!("unexpected {e:?}"),
+        }
+    }
+    let mut parser = parser(tokens);
+    let expr = parser.parse_expr_bp(0).unwrap();
+    assert_eq!(*parser.peek(), Token::EOF, "not all tokens were parsed");
+    shape(&expr)
+}
+
+#[test]
+fn precedence_and_associativity_without_a_program() {
+    use Token::{Minus, Number, Plus, Star};
+    assert_eq!(tree(vec![Number(1), Plus, Number(2), Star, Number(3)]), "(+ 1 (* 2 3))");
+    assert_eq!(tree(vec![Number(1), Star, Number(2), Plus, Number(3)]), "(+ (* 1 2) 3)");
+    assert_eq!(tree(vec![Number(1), Minus, Number(2), Minus, Number(3)]), "(- (- 1 2) 3)");
+
+    // a minimum binding power stops at operators that bind no tighter
+    let mut parser = parser(vec![Number(1), Plus, Number(2)]);
+    assert!(matches!(parser.parse_expr_bp(5), Ok(Expr::Number(1))));
+    assert_eq!(*parser.peek(), Plus);
+}
+
+#[test]
+fn comparisons_chain_only_through_parentheses_or_logical_operators() {
+    let (a, b, c) = (num(1), num(2), num(3));
+    assert_eq!(return_value_json("(1 < 2) && (2 < 3)"), bin("&&", &bin("<", &a, &b), &bin("<", &b, &c)));
+    assert_eq!(return_value_json("1 < 2 && 2 < 3"), bin("&&", &bin("<", &a, &b), &bin("<", &b, &c)));
+    assert_eq!(return_value_json("(1 < 2) < 3"), bin("<", &bin("<", &a, &b), &c));
+    assert_eq!(return_value_json("1 < 2 == 2 > 3"), bin("==", &bin("<", &a, &b), &bin(">", &b, &c)));
+}
+
+// Operands inside brackets and argument lists go through the same
+// precedence parser as a bare expression.
+#[test]
+fn nested_expressions_parse_with_full_precedence() {
+    let ident = |name: &str| format!(r#"{{"node":"Ident","name":"{name}"}}"#);
+    let index = bin("-", &ident("n"), &num(1));
+    assert_eq!(return_value_json("a[n - 1]"),
+               format!(r#"{{"node":"Index","base":{},"index":{index}}}"#, ident("a")));
+
+    let out = common::cosplae(&["--emit=json"], "i32 main() { print(x * 2 + 1); return 0; }");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let arg = bin("+", &bin("*", &ident("x"), &num(2)), &num(1));
+    assert!(String::from_utf8(out.stdout).unwrap().contains(&format!(r#"{{"node":"Print","args":[{arg}]}}"#)));
+}
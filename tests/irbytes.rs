@@ -0,0 +1,83 @@
+use cosplae::compile_source;
+use cosplae::ir::{ConstArray, Func, Global, Instr, ProgramIR};
+use cosplae::irbytes::DecodeError;
+use cosplae::span::Span;
+use cosplae::VM;
+
+// The sample program main.rs used to embed, with a call and a loop
+const SAMPLE: &str = r#"
+    struct Point {
+        i32 x;
+        i32 y;
+    };
+
+    const i32 n = 5;
+
+    i32 sum(i32 to) {
+        var i32 total = 0;
+        for (var i32 i = 1; i <= to; i += 1) { total += i; }
+        return total;
+    }
+
+    i32 main() {
+        i32 x = 10;
+        print(x);
+        print(n);
+        return sum(n);
+    }
+"#;
+
+#[test]
+fn sample_round_trips_and_runs() {
+    let ir = compile_source(SAMPLE).unwrap();
+    let reloaded = ProgramIR::from_bytes(&ir.to_bytes()).unwrap();
+    assert_eq!(reloaded, ir);
+    assert_eq!(VM::run(&reloaded).unwrap(), 15);
+}
+
+#[test]
+fn every_instruction_round_trips() {
+    let code = vec![
+        Instr::PushI32(-7), Instr::PushI64(i64::MIN), Instr::Pop, Instr::Dup, Instr::Swap,
+        Instr::Load(1), Instr::Store(2), Instr::LoadIndex(3, 2), Instr::StoreIndex(4, 5),
+        Instr::LoadGlobal(0), Instr::StoreGlobal(0), Instr::LoadConstIndex(0, 2),
+        Instr::Add, Instr::Sub, Instr::Mul, Instr::Div, Instr::Mod, Instr::Neg, Instr::Not,
+        Instr::AddI64, Instr::SubI64, Instr::MulI64, Instr::DivI64, Instr::ModI64, Instr::NegI64,
+        Instr::Narrow,
+        Instr::CmpLt, Instr::CmpGt, Instr::CmpLe, Instr::CmpGe, Instr::CmpEq, Instr::CmpNe,
+        Instr::Print, Instr::PrintUnsigned, Instr::PrintNewline, Instr::PrintStr(b"caf\xC3\xA9\n".to_vec()),
+        Instr::Input, Instr::Perform("ask".into(), 2), Instr::PushHandler("ask".into(), 0), Instr::PopHandler,
+        Instr::Label(u32::MAX), Instr::Jump(1), Instr::JumpIfZero(2), Instr::JumpIfNonZero(3), Instr::Call(0, 3), Instr::Ret,
+    ];
+    let spans = (1..=code.len()).map(|line| Span { line, col: 2 * line }).collect();
+    let func = Func {
+        name: "main".into(),
+        code,
+        n_locals: 5,
+        n_params: 1,
+        max_stack: 3,
+        locals_dbg: vec!["x".into(), "a[0]".into(), String::new()],
+        spans,
+    };
+    let ir = ProgramIR {
+        funcs: vec![func],
+        globals: vec![Global { name: "g".into(), value: -1 << 40 }],
+        rodata: vec![ConstArray { name: "a".into(), values: vec![2, i64::MIN] }],
+    };
+    assert_eq!(ProgramIR::from_bytes(&ir.to_bytes()).unwrap(), ir);
+}
+
+#[test]
+fn malformed_input() {
+    let bytes = compile_source(SAMPLE).unwrap().to_bytes();
+    assert_eq!(ProgramIR::from_bytes(b"ELF"), Err(DecodeError::NotIr));
+    assert_eq!(ProgramIR::from_bytes(b"CPIR\x09"), Err(DecodeError::UnsupportedVersion(9)));
+    assert_eq!(ProgramIR::from_bytes(&bytes[..bytes.len() - 1]), Err(DecodeError::Truncated));
+    assert_eq!(ProgramIR::from_bytes(&[&bytes[..], &[0]].concat()), Err(DecodeError::TrailingBytes(1)));
+
+    // no globals or const arrays and one function, `f`, whose only instruction is 0xFF
+    let mut bad = b"CPIR\x04\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00f".to_vec();
+    bad.extend_from_slice(&[0; 24]);
+    bad.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0xFF]);
+    assert_eq!(ProgramIR::from_bytes(&bad), Err(DecodeError::UnknownOpcode(0xFF)));
+}
@@ -0,0 +1,16 @@
+mod common;
+
+#[test]
+fn keyword_as_variable_name_is_reported() {
+    let out = common::cosplae(&["--run"], "i32 main() { i32 return = 5; return 0; }");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(65));
+    assert!(stderr.contains("`return` is a reserved keyword and cannot be used as a name"), "{}", stderr);
+}
+
+#[test]
+fn elif_is_reserved() {
+    let out = common::cosplae(&["--run"], "i32 main() { i32 elif = 5; return 0; }");
+    assert_eq!(out.status.code(), Some(65));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("`elif` is a reserved keyword"));
+}
@@ -0,0 +1,53 @@
+// Compiling a big program should cost allocations per statement, not per
+// identifier occurrence: names are interned once and tokens carry a `Sym`.
+use cosplae::intern::Sym;
+use cosplae::{compile_source, VM};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const LINES: usize = 10_000;
+
+// `LINES` statements that keep reusing the same handful of names.
+fn generated_program() -> String {
+    let mut src = String::from("i32 main() {\n    var i32 total = 0;\n    var i32 step = 1;\n");
+    for i in 0..LINES {
+        src += match i % 3 {
+            0 => "    total = total + step * 2;\n",
+            1 => "    if (total > 1000) { total = total - 1000; }\n",
+            _ => "    step = step + 1;\n",
+        };
+    }
+    src + "    return total % 100;\n}\n"
+}
+
+#[test]
+fn ten_thousand_lines_reuse_interned_names() {
+    let src = generated_program();
+    let names_before = Sym::count();
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+    let ir = compile_source(&src).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+    // main, total and step, plus the type name i32 and the checker's placeholder
+    assert!(Sym::count() - names_before <= 5);
+    // about 18 per line when every identifier was its own String
+    assert!(allocations < LINES * 16, "{allocations} allocations for {LINES} lines");
+    VM::run(&ir).unwrap();
+}
@@ -0,0 +1,47 @@
+mod common;
+
+// The lexer reports malformed tokens as values: no panic output, exit status 65.
+fn lex_error(source: &str) -> String {
+    let out = common::cosplae(&["--emit=json"], source);
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(65), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    stderr
+}
+
+#[test]
+fn oversized_integer_literal() {
+    let src = "i32 main() {\n    return 999999999999999999999999999999999999999999;\n}\n";
+    assert!(lex_error(src).contains("lex error: integer literal out of range at line 2, column 12"));
+}
+
+#[test]
+fn base_prefix_without_digits() {
+    assert!(lex_error("i32 main() { return 0x; }").contains("integer literal has no digits after its base prefix"));
+}
+
+#[test]
+fn malformed_char_and_string_literals() {
+    assert!(lex_error(r"i32 main() { return '\q'; }").contains(r"unknown escape sequence `\q`"));
+    assert!(lex_error(r"i32 main() { return '\x4'; }").contains(r"`\x` escape needs two hex digits"));
+    assert!(lex_error(r#"i32 main() { print("a\qb"); }"#).contains(r"unknown escape sequence `\q` at line 1, column 20"));
+    assert!(lex_error("i32 main() { return ''; }").contains("empty or unterminated char literal"));
+    assert!(lex_error(r#"i32 main() { print("abc); }"#).contains("unterminated string literal at line 1, column 20"));
+}
+
+#[test]
+fn float_literal() {
+    let err = lex_error("i32 main() {\n    i32 x = 3.14;\n    return x;\n}\n");
+    assert!(err.contains("lex error: floating-point literals are not supported; only integer types exist at line 2, column 13"), "{err}");
+    assert!(lex_error("i32 main() { return 0.5; }").contains("floating-point literals are not supported"));
+    assert!(lex_error("i32 main() { return 1_000.25; }").contains("floating-point literals are not supported"));
+}
+
+// Used to end the token stream there, dropping the rest of the program.
+#[test]
+fn unexpected_character() {
+    let err = lex_error("i32 main() { return @; }");
+    assert!(err.contains("lex error: unexpected character '@' at line 1, column 21"), "{err}");
+    assert!(lex_error("i32 main() {\n    return 1;\n}\n#").contains("unexpected character '#' at line 4, column 1"));
+    assert!(lex_error("i32 main() { return 1 & 2; }").contains("unexpected character '&'"));
+}
@@ -0,0 +1,243 @@
+use cosplae::ir::Instr;
+use cosplae::vm::StepResult;
+use cosplae::{compile_source, CompileError, VM};
+
+#[test]
+fn compile_and_interpret() {
+    let ir = compile_source("i32 sq(i32 v) { return v * v; } i32 main() { return sq(7); }").unwrap();
+    assert_eq!(VM::run(&ir).unwrap(), 49);
+}
+
+// The prelude's functions can be called without being defined.
+#[test]
+fn prelude_gcd() {
+    let ir = compile_source("i32 main() { return gcd(48, 36); }").unwrap();
+    assert_eq!(VM::run(&ir).unwrap(), 12);
+}
+
+#[test]
+fn errors_name_their_phase() {
+    let Err(e @ CompileError::Parse(_)) = compile_source("i32 main() { return 0 }") else { panic!() };
+    assert!(e.to_string().starts_with("parse error: "), "{e}");
+    let Err(e @ CompileError::Type(_)) = compile_source("struct P { i32 x; }; i32 main() { P p; print(p); return 0; }") else { panic!() };
+    assert!(e.to_string().starts_with("type error: "), "{e}");
+}
+
+// Comments are skipped in a loop, not a call per comment, so any number of
+// them in a row fits on the stack.
+#[test]
+fn many_comments_in_a_row() {
+    let comments = "// line\n/* block */ ".repeat(100_000);
+    let ir = compile_source(&format!("i32 main() {{ {comments} return 3; {comments} }}")).unwrap();
+    assert_eq!(VM::run(&ir).unwrap(), 3);
+    let Err(e @ CompileError::Lex(_)) = compile_source("i32 main() { return 0; } // a\n/* b") else { panic!() };
+    assert_eq!(e.to_string(), "lex error: unterminated block comment at line 2, column 1");
+}
+
+#[test]
+fn the_vm_needs_a_main() {
+    let mut ir = compile_source("i32 main() { return 1; }").unwrap();
+    ir.funcs[0].name = "start".into();
+    assert_eq!(VM::run(&ir).unwrap_err().to_string(), "no `main` function found");
+}
+
+// A sandboxed program may compute but not do I/O, and stops at its limits.
+#[test]
+fn sandboxed_programs() {
+    use cosplae::vm::{Sandbox, VmError};
+    let sandboxed = |source: &str, limits: Sandbox| VM::run_sandboxed(&compile_source(source).unwrap(), limits);
+    let forbidden = |source: &str| sandboxed(source, Sandbox::default()).unwrap_err().cause().clone();
+
+    assert_eq!(sandboxed("i32 sq(i32 v) { return v * v; } i32 main() { return sq(7); }", Sandbox::default()), Ok(49));
+    assert_eq!(forbidden("i32 main() { return input(); }"), VmError::ForbiddenOperation("input"));
+    assert_eq!(forbidden("i32 main() { print(1); return 0; }"), VmError::ForbiddenOperation("print"));
+    assert_eq!(forbidden("i32 main() { print(\"hi\"); return 0; }"), VmError::ForbiddenOperation("print"));
+    assert_eq!(forbidden("i32 main() { return perform ask(); }"), VmError::ForbiddenOperation("perform"));
+
+    let spin = "i32 main() { var i32 i = 0; while (i < 100) { i = i + 1; } return i; }";
+    assert_eq!(sandboxed(spin, Sandbox { max_steps: 10_000, max_stack: 16 }), Ok(100));
+    let err = sandboxed(spin, Sandbox { max_steps: 100, max_stack: 16 }).unwrap_err();
+    assert_eq!(err.cause(), &VmError::StepBudgetExhausted);
+    assert_eq!(sandboxed("i32 main() { while (1) { } return 0; }", Sandbox::default()).unwrap_err().cause(),
+               &VmError::StepBudgetExhausted);
+
+    // four arguments and then the nested additions' operands
+    let deep = "i32 f(i32 a, i32 b, i32 c, i32 d) { return a + (b + (c + d)); } i32 main() { return f(1, 2, 3, 4); }";
+    assert_eq!(sandboxed(deep, Sandbox { max_steps: 1000, max_stack: 8 }), Ok(10));
+    let err = sandboxed(deep, Sandbox { max_steps: 1000, max_stack: 3 }).unwrap_err();
+    assert_eq!(err.cause(), &VmError::MemoryBudgetExceeded);
+}
+
+// `perform` resumes with what the innermost installed handler returns.
+#[test]
+fn installed_handlers() {
+    use cosplae::vm::HandlerStack;
+    let ir = compile_source("i32 main() { return perform get() * 10 + perform add(3, 4); }").unwrap();
+    let mut handlers = HandlerStack::default();
+    handlers.push("get", |_| 1);
+    handlers.push("add", |args| args.iter().sum());
+    handlers.push("get", |_| 4);
+    assert_eq!(VM::run_with_handlers(&ir, handlers).unwrap(), 47);
+
+    let mut handlers = HandlerStack::default();
+    handlers.push("get", |_| 4);
+    let err = VM::run_with_handlers(&ir, handlers).unwrap_err();
+    assert_eq!(err.cause(), &cosplae::vm::VmError::UnhandledEffect("add".into()));
+}
+
+// A `handle` installs its handlers while its body runs, functions it calls
+// included, and a handler runs outside them: its own `perform` reaches the
+// handlers installed before. `break`, `continue` and `return` leave a
+// `handle` like its end does.
+#[test]
+fn handle_with() {
+    let ir = compile_source("i32 main() { handle { return perform get(); } with { i32 get() { return 42; } } }").unwrap();
+    assert_eq!(VM::run(&ir).unwrap(), 42);
+
+    let src = "i32 twice() { return perform get() * 2; }
+               i32 inner() { handle { return perform get(); } with { i32 get() { return perform get() + 100; } } }
+               i32 main() {
+                   var i32 sum = 0;
+                   handle {
+                       sum += twice() + inner();
+                       for (var i32 i = 0; i < 4; i += 1) {
+                           handle {
+                               if (i == 1) { continue; }
+                               if (i == 3) { break; }
+                               sum += perform get();
+                           } with { i32 get() { return 1000; } }
+                       }
+                       return sum * 10 + perform get();
+                   } with {
+                       i32 get() { return 4; }
+                   }
+               }";
+    // 8 + 104 + 1000 + 1000
+    assert_eq!(VM::run(&compile_source(src).unwrap()).unwrap(), 21124);
+
+    // the program's handlers go above the embedder's
+    let ir = compile_source("i32 main() { i32 a = perform get(); handle { return a * 10 + perform get(); } with { i32 get() { return 2; } } }").unwrap();
+    let mut handlers = cosplae::vm::HandlerStack::default();
+    handlers.push("get", |_| 1);
+    assert_eq!(VM::run_with_handlers(&ir, handlers).unwrap(), 12);
+}
+
+// `run_debug` hands back main's variables as they were when it returned.
+#[test]
+fn final_locals() {
+    let ir = compile_source("i32 main() { i32 x = 3; i32 y = x * x; return y - x; }").unwrap();
+    let run = VM::run_debug(&ir).unwrap();
+    assert_eq!(run.exit, 6);
+    assert_eq!(run.locals, [3, 9]);
+    assert!(run.stack.is_empty(), "{:?}", run.stack);
+}
+
+// One call reads, compiles and writes; a file that can't be read is an error.
+#[test]
+fn compile_a_file() {
+    use cosplae::{compile_file, CompileOptions, Emit};
+    let dir = std::env::temp_dir().join(format!("cosplae-{}-compile-file", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("prog.cosp");
+    std::fs::write(&source, "i32 main() { print(2 * 21); return 3; }").unwrap();
+
+    let ir = dir.join("prog.ir");
+    compile_file(&source, &ir, CompileOptions { emit: Emit::Ir, ..CompileOptions::default() }).unwrap();
+    assert!(std::fs::read_to_string(&ir).unwrap().contains("PushI32(42)"));
+    let unfolded = CompileOptions { emit: Emit::Ir, optimize: false, ..CompileOptions::default() };
+    compile_file(&source, &ir, unfolded).unwrap();
+    assert!(std::fs::read_to_string(&ir).unwrap().contains("Mul"));
+
+    if cosplae::NATIVE_HOST {
+        let binary = dir.join("prog");
+        compile_file(&source, &binary, CompileOptions::default()).unwrap();
+        assert!(binary.exists());
+        let run = std::process::Command::new(&binary).output().unwrap();
+        assert_eq!((run.stdout.as_slice(), run.status.code()), (&b"42\n"[..], Some(3)));
+    }
+
+    let Err(e @ CompileError::Read { .. }) = compile_file(&dir.join("missing.cosp"), &ir, CompileOptions::default()) else { panic!() };
+    assert!(e.to_string().starts_with("cannot read `"), "{e}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Evaluating the operand that needs more stack first lowers the peak
+// depth of a right-leaning chain, not of a balanced tree, and computes
+// the same values.
+#[test]
+fn evaluation_order_lowers_max_stack() {
+    let compiled = |expr: &str, reorder: bool| {
+        let locals = "i32 a = 1; i32 b = 2; i32 c = 4; i32 d = 8; i32 e = 16; i32 f = 32; i32 g = 64; i32 h = 128;";
+        let program = cosplae::parse(&format!("i32 main() {{ {locals} return {expr}; }}")).unwrap();
+        let mut cg = cosplae::Codegen::new();
+        cg.reorder = reorder;
+        let ir = cosplae::codegen(&mut cg, &program).unwrap();
+        (ir.funcs[0].max_stack, VM::run(&ir).unwrap())
+    };
+    let balanced = "((a+b)+(c+d))+((e+f)+(g+h))";
+    assert_eq!(compiled(balanced, false), (4, 255));
+    assert_eq!(compiled(balanced, true), (4, 255));
+    for (chain, value) in [("a + (b + (c + (d + e)))", 31), ("a - (b - (c - (d - e)))", 11), ("a < (b * (c + d))", 1)] {
+        assert_eq!(compiled(chain, false).1, value, "{chain}");
+        assert_eq!(compiled(chain, true), (2, value), "{chain}");
+    }
+    // `-a` and `c - d` may both overflow, so the product waits under `-a`
+    assert_eq!(compiled("-a + (b * (c - d))", true), (3, -9));
+}
+
+// A rotated loop tests its condition at the bottom, so an iteration takes
+// one branch, the one back up, instead of the exit test and a jump back to
+// it. A loop that never runs still takes the one test before it.
+#[test]
+fn rotated_loops_branch_once_per_iteration() {
+    let branches = |src: &str, rotate: bool| {
+        let program = cosplae::parse(src).unwrap();
+        let mut cg = cosplae::Codegen::new();
+        cg.rotate_loops = rotate;
+        let ir = cosplae::codegen(&mut cg, &program).unwrap();
+        let code = &ir.funcs.iter().find(|f| f.name == "main").unwrap().code;
+        let mut state = cosplae::vm::VmState::new(&ir).unwrap();
+        let mut taken = 0;
+        loop {
+            if matches!(code[state.ip], Instr::Jump(_) | Instr::JumpIfZero(_) | Instr::JumpIfNonZero(_)) {
+                taken += 1;
+            }
+            match state.step() {
+                StepResult::Continue => {}
+                StepResult::Halted(exit) => return (taken, exit),
+                StepResult::Error(e) => panic!("{e}"),
+            }
+        }
+    };
+    for n in [0usize, 1, 10] {
+        let while_loop = format!("i32 main() {{ var i32 s = 0; var i32 i = 0; while (i < {n}) {{ s += i; i += 1; }} return s; }}");
+        let for_loop = format!("i32 main() {{ var i32 s = 0; for (var i32 i = 0; i < {n}; i += 1) {{ s += i; }} return s; }}");
+        let sum = (0..n as i64).sum::<i64>();
+        for src in [&while_loop, &for_loop] {
+            assert_eq!(branches(src, false), (2 * n + 1, sum), "{src}");
+            assert_eq!(branches(src, true), (n + 1, sum), "{src}");
+        }
+    }
+}
+
+// A program mapped into this process runs as a call to its main.
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn run_in_process() {
+    use cosplae::Compiler;
+    let compile = |source: &str| {
+        let mut compiler = Compiler::new().in_process(true);
+        compiler.compile_program(&compile_source(source).unwrap()).unwrap();
+        compiler
+    };
+    let mapped = compile("i32 main() { return 42; }").map_executable().unwrap();
+    assert_eq!(unsafe { mapped.main()() }, 42);
+
+    let mapped = compile("i32 g = 5; i32 sq(i32 v) { return v * v; } i32 main() { g = g + sq(6); return -g; }").map_executable().unwrap();
+    assert_eq!(unsafe { mapped.main()() }, -41);
+    assert_eq!(unsafe { mapped.main()() }, -77, "globals live in the mapping");
+
+    let mut exiting = Compiler::new();
+    exiting.compile_program(&compile_source("i32 main() { return 42; }").unwrap()).unwrap();
+    assert_eq!(exiting.map_executable().err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+}
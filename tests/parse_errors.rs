@@ -0,0 +1,85 @@
+mod common;
+
+// The parser reports errors as values: no panic output, exit status 65.
+fn parse_error(source: &str) -> String {
+    let out = common::cosplae(&["--emit=json"], source);
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(65), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    stderr
+}
+
+#[test]
+fn unexpected_token() {
+    let err = parse_error("i32 main( {");
+    assert!(err.contains("parse error: unclosed `(` opened at line 1, column 9: expected RParen, got LBrace at line 1, column 11"), "{err}");
+}
+
+#[test]
+fn unexpected_end_of_file() {
+    assert!(parse_error("i32 main() { return 1").contains("parse error: expected Semicolon, got end of file"));
+    let err = parse_error("i32 main() { return 1;");
+    assert!(err.contains("parse error: unclosed `{` opened at line 1, column 12: expected RBrace, got end of file"), "{err}");
+}
+
+// A missing `)`, `]` or `}` is reported with where its opener was.
+#[test]
+fn unclosed_brackets() {
+    let err = parse_error("i32 f(i32 a) { return a; }\ni32 main() {\n    print(f(1);\n    return 0;\n}\n");
+    assert!(err.contains("unclosed `(` opened at line 3, column 10: expected RParen, got Semicolon at line 3, column 15"), "{err}");
+    let err = parse_error("i32 main() { var i32 a[2]; a[0 = 1; return 0; }");
+    assert!(err.contains("unclosed `[` opened at line 1, column 29: expected RBracket, got Eq"), "{err}");
+    let err = parse_error("i32 main() {\n    while (1) {\n        return 0;\n}\n");
+    assert!(err.contains("unclosed `{` opened at line 1, column 12: expected RBrace, got end of file"), "{err}");
+}
+
+#[test]
+fn errors_report_line_and_column() {
+    let src = "i32 main() {\n    i32 x = 1;\n    /* comment */ print(x)\n    return x;\n}\n";
+    assert!(parse_error(src).contains("expected Semicolon, got Return at line 4, column 5"));
+    // the very first token
+    assert!(parse_error("5").contains("got Number(5) at line 1, column 1"));
+}
+
+// Parsing resumes at the next declaration, so independent errors are all
+// reported; a declaration inside the broken function's braces isn't one.
+#[test]
+fn every_broken_declaration_is_reported() {
+    let src = "i32 f( { i32 x = 1; return x; }\nstruct P { i32 x; };\ni32 main() { i32 y = ; return 0; }\n";
+    let stderr = parse_error(src);
+    assert_eq!(stderr.matches("parse error:").count(), 2, "{stderr}");
+    assert!(stderr.contains("expected RParen, got LBrace at line 1, column 8"), "{stderr}");
+    assert!(stderr.contains("expected expression, got Semicolon at line 3, column 22"), "{stderr}");
+}
+
+#[test]
+fn var_needs_a_declaration() {
+    let err = parse_error("i32 main() { var x = 1; return x; }");
+    assert!(err.contains("parse error: expected a variable declaration after `var`, got Ident(\"x\")"), "{err}");
+}
+
+#[test]
+fn chained_comparison() {
+    let stderr = parse_error("i32 main() { i32 a = 1; return a < 2 < 3; }");
+    assert!(stderr.contains("parse error: `<` compares the result of another comparison; \
+                             parenthesize that one or join them with `&&` at line 1, column 38"), "{stderr}");
+    assert!(parse_error("i32 main() { return 1 >= 2 <= 3 == 1; }").contains("`<=` compares the result of another comparison"));
+}
+
+// A nested function is hoisted out, so it cannot see the locals around it.
+#[test]
+fn nested_functions_cannot_capture() {
+    let err = parse_error("i32 main() {\n    var i32 total = 0;\n    void add(i32 v) {\n        total = total + v;\n    }\n    add(2);\n    return total;\n}\n");
+    assert!(err.contains("nested function `add` uses `total`, a local of `main`; nested functions cannot capture locals, pass it as a parameter at line 3, column 5"), "{err}");
+    let err = parse_error("i32 f(i32 n) { i32 g() { return n; } return g(); } i32 main() { return f(1); }");
+    assert!(err.contains("nested function `g` uses `n`, a local of `f`"), "{err}");
+}
+
+// A `with` handler is hoisted like a nested function.
+#[test]
+fn handlers_cannot_capture_and_are_one_per_effect() {
+    let err = parse_error("i32 main() {\n    i32 n = 1;\n    handle { return perform get(); } with {\n        i32 get() { return n; }\n    }\n}\n");
+    assert!(err.contains("the `with` handler of `get` uses `n`, a local of `main`; handlers cannot capture locals either at line 4, column 9"), "{err}");
+    let err = parse_error("i32 main() { handle { } with { i32 get() { return 1; } i32 get() { return 2; } } return 0; }");
+    assert!(err.contains("parse error: effect `get` has more than one handler in this `with` at line 1, column 56"), "{err}");
+}
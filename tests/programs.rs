@@ -0,0 +1,89 @@
+// Runs every `tests/programs/NAME.cpl` through `cosplae --run` and compares
+// stdout with `NAME.stdout` and the exit code with `NAME.exit`, feeding
+// `NAME.stdin` (if present) to the program.
+// On x86-64 Linux the programs that compile are also built natively and
+// must behave the same.
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+
+// Exit statuses cosplae itself uses for compile and VM runtime errors
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<_> = fs::read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "cpl"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in {}", dir.display());
+    programs
+}
+
+fn expected_exit(path: &Path) -> i32 {
+    fs::read_to_string(path.with_extension("exit")).unwrap().trim().parse().unwrap()
+}
+
+fn stdin_for(path: &Path) -> String {
+    fs::read_to_string(path.with_extension("stdin")).unwrap_or_default()
+}
+
+// Runs each program with `run(path, stdin)` and checks it against the
+// expected files.
+fn check_all(programs: &[PathBuf], run: impl Fn(&Path, &str) -> Output) {
+    let mut failed = Vec::new();
+    for path in programs {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        let want_stdout = fs::read_to_string(path.with_extension("stdout")).unwrap();
+        let want_exit = expected_exit(path);
+
+        let out = run(path, &stdin_for(path));
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let exit = out.status.code();
+        if stdout == want_stdout && exit == Some(want_exit) {
+            eprintln!("pass  {name}");
+        } else {
+            eprintln!("FAIL  {name}: exit {exit:?} (want {want_exit}), stdout {stdout:?} (want {want_stdout:?})");
+            failed.push(name.into_owned());
+        }
+    }
+    assert!(failed.is_empty(), "{} of {} programs failed: {}",
+            failed.len(), programs.len(), failed.join(", "));
+}
+
+#[test]
+fn example_programs() {
+    check_all(&programs(), |path, stdin| {
+        common::cosplae(&["--run", path.to_str().unwrap()], stdin)
+    });
+}
+
+// Compile errors and VM runtime errors have no native counterpart to compare.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn example_programs_native() {
+    let programs: Vec<_> = programs().into_iter()
+        .filter(|p| ![EXIT_COMPILE_ERROR, EXIT_RUNTIME_ERROR].contains(&expected_exit(p)))
+        .collect();
+    check_all(&programs, |path, stdin| {
+        let name = path.file_stem().unwrap().to_string_lossy();
+        common::native_with_stdin(&name, &fs::read_to_string(path).unwrap(), stdin)
+    });
+}
+
+// Leaf functions addressing their locals from rsp behave the same.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn example_programs_without_frame_pointers() {
+    let programs: Vec<_> = programs().into_iter()
+        .filter(|p| ![EXIT_COMPILE_ERROR, EXIT_RUNTIME_ERROR].contains(&expected_exit(p)))
+        .collect();
+    check_all(&programs, |path, stdin| {
+        let name = format!("{}-nofp", path.file_stem().unwrap().to_string_lossy());
+        common::native_with_flags(&name, &["--omit-frame-pointer"], &fs::read_to_string(path).unwrap(), stdin)
+    });
+}
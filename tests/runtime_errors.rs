@@ -0,0 +1,82 @@
+mod common;
+
+// The VM reports runtime errors as values: a message, no panic output, and
+// exit status 70. Output printed before the error is kept.
+fn runtime_error(source: &str) -> (String, String) {
+    let out = common::cosplae(&["--run"], source);
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(70), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    (String::from_utf8(out.stdout).unwrap(), stderr)
+}
+
+#[test]
+fn division_by_zero() {
+    let (stdout, stderr) = runtime_error("i32 main() { i32 x = 3; print(x); return 10 / (x - x); }");
+    assert_eq!(stdout, "3\n");
+    assert!(stderr.contains("runtime error: division by zero in Div"), "{stderr}");
+}
+
+// The position is the operator's, not the statement's.
+#[test]
+fn errors_name_the_source_position() {
+    let (_, stderr) = runtime_error("i32 main() {\n    i32 zero = 0;\n    print(1);\n    return 1 +\n        7 / zero;\n}");
+    assert!(stderr.contains("division by zero in Div at line 5, column 11"), "{stderr}");
+    let (_, stderr) = runtime_error("i32 main() {\n    i32 x = 0;\n    return perform ask(x);\n}");
+    assert!(stderr.contains("unhandled effect `ask` at line 3, column 5"), "{stderr}");
+}
+
+#[test]
+fn modulo_by_zero() {
+    let (_, stderr) = runtime_error("i32 main() { i32 zero = 0; return 7 % zero; }");
+    assert!(stderr.contains("runtime error: division by zero in Mod"), "{stderr}");
+}
+
+// Folding leaves a constant division by zero for run time.
+#[test]
+fn constant_division_by_zero() {
+    let (_, stderr) = runtime_error("i32 main() { return 1 / 0; }");
+    assert!(stderr.contains("division by zero"), "{stderr}");
+}
+
+#[test]
+fn unhandled_effect() {
+    let (_, stderr) = runtime_error("i32 main() { return perform ask(); }");
+    assert!(stderr.contains("runtime error: unhandled effect `ask`"), "{stderr}");
+    // a `handle`'s handlers end with it
+    let (stdout, stderr) = runtime_error("i32 main() { handle { print(perform ask()); } with { i32 ask() { return 1; } } return perform ask(); }");
+    assert_eq!(stdout, "1\n");
+    assert!(stderr.contains("runtime error: unhandled effect `ask`"), "{stderr}");
+}
+
+// Nothing checks an undeclared effect's arguments before its handler runs.
+#[test]
+fn handler_argument_count() {
+    let (_, stderr) = runtime_error("i32 main() { handle { return perform ask(1); } with { i32 ask() { return 1; } } }");
+    assert!(stderr.contains("runtime error: the handler of effect `ask` takes 0 argument(s), but `perform` passes 1"), "{stderr}");
+}
+
+// An index computed at run time is checked against the array's length,
+// even where it would land on another local.
+#[test]
+fn index_out_of_bounds() {
+    let (_, stderr) = runtime_error("i32 main() { i32 a[4]; i32 b = 7; i32 i = 4; return a[i]; }");
+    assert!(stderr.contains("runtime error: array index 4 is out of range for length 4 at line 1, column 46"), "{stderr}");
+    let (_, stderr) = runtime_error("i32 main() { var i32 a[2]; i32 i = -1; a[i] = 1; return 0; }");
+    assert!(stderr.contains("runtime error: array index -1 is out of range for length 2"), "{stderr}");
+}
+
+#[test]
+fn unbounded_recursion() {
+    let (stdout, stderr) = runtime_error("i32 down(i32 n) { return down(n + 1) + 1; } i32 main() { print(1); return down(0); }");
+    assert_eq!(stdout, "1\n");
+    assert!(stderr.contains("runtime error: stack overflow in `down`: more than 10000 nested calls at line 1, column 19"), "{stderr}");
+    // the function that recurses, not its caller
+    let (_, stderr) = runtime_error("i32 spin(i32 n) { return spin(n); }
+i32 start() { return spin(0); }
+i32 main() { return start(); }");
+    assert!(stderr.contains("stack overflow in `spin`"), "{stderr}");
+    // deep but bounded recursion still runs
+    let out = common::cosplae(&["--run"], "i32 sum(i32 n) { if (n == 0) { return 0; } return n + sum(n - 1); } i32 main() { return sum(5000) % 256; }");
+    assert_eq!(out.status.code(), Some(5000 * 5001 / 2 % 256));
+}
@@ -0,0 +1,161 @@
+mod common;
+
+// Type errors stop compilation before codegen: no panic output, exit status 65.
+fn type_errors(source: &str) -> String {
+    let out = common::cosplae(&["--run"], source);
+    let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+    assert_eq!(out.status.code(), Some(65), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    stderr
+}
+
+const POINT: &str = "struct Point { i32 x; i32 y; };";
+
+#[test]
+fn binary_operator_on_a_struct() {
+    let src = format!("{POINT} i32 main() {{ Point p; i32 n = p + 1; return 0; }}");
+    assert!(type_errors(&src).contains("type error: cannot add `Point` and `i32`"));
+    let src = format!("{POINT} i32 main() {{ Point p; if (1 < p) {{ }} return 0; }}");
+    assert!(type_errors(&src).contains("type error: cannot compare `i32` and `Point`"));
+}
+
+#[test]
+fn printing_a_struct() {
+    let src = format!("{POINT} i32 main() {{ Point p; print(p.x, p); return 0; }}");
+    assert!(type_errors(&src).contains("type error: print expects i32, got `Point`"));
+}
+
+#[test]
+fn strings_are_only_print_arguments() {
+    let stderr = type_errors("i32 main() { i32 x = \"ten\"; print(\"x\", \"y\" + 1); return 0; }");
+    assert_eq!(stderr.matches("type error: string literals can only be arguments of `print`").count(), 2, "{stderr}");
+}
+
+#[test]
+fn assignments_and_conditions() {
+    let src = format!("{POINT} i32 main() {{ Point p; i32 n = p; n = print(1); while (p) {{ }} return 0; }}");
+    let stderr = type_errors(&src);
+    // every error is reported, not just the first
+    assert!(stderr.contains("cannot assign `Point` to `n` of type `i32`"), "{stderr}");
+    assert!(stderr.contains("cannot assign `void` to `n` of type `i32`"), "{stderr}");
+    assert!(stderr.contains("condition must be i32, got `Point`"), "{stderr}");
+}
+
+#[test]
+fn indexing() {
+    let src = format!("{POINT} i32 main() {{ Point p; i32 a[2]; i32 n = 1; a[p] = 1; a[0] = p; return n[0] + a; }}");
+    let stderr = type_errors(&src);
+    assert!(stderr.contains("type error: array index must be an integer, got `Point`"), "{stderr}");
+    assert!(stderr.contains("type error: cannot assign `Point` to `a[..]` of type `i32`"), "{stderr}");
+    assert!(stderr.contains("type error: cannot index `i32`: only arrays have elements"), "{stderr}");
+}
+
+#[test]
+fn unknown_and_void_variable_types() {
+    assert!(type_errors("i32 main() { Pt p; return 0; }").contains("type error: unknown type `Pt`"));
+    assert!(type_errors("i32 main() { void v; return 0; }").contains("variable `v` cannot have type `void`"));
+}
+
+// Fields, i64 and `print` as an operand of `&&` are all fine.
+#[test]
+fn well_typed_program_runs() {
+    let src = format!("{POINT} i32 main() {{ var Point p; p.x = 2; i64 w = p.x * 3000000000; print(w); 0 && print(1); return p.x; }}");
+    let out = common::cosplae(&["--run"], &src);
+    assert_eq!(out.status.code(), Some(2), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn missing_return() {
+    let src = "i32 f(i32 x) { if (x) { return 1; } } i32 main() { return f(1); }";
+    assert!(type_errors(src).contains("type error: `f` can reach its end without returning a `i32`"));
+    // returning from both branches, or looping forever, is enough
+    let src = "i32 f(i32 x) { if (x) { return 1; } else { return 2; } }
+               i32 g() { while (1) { } }
+               i32 main() { return f(0); }";
+    let out = common::cosplae(&["--run"], src);
+    assert_eq!(out.status.code(), Some(2), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn return_without_a_value_in_an_i32_function() {
+    let src = "i32 f() { return; } i32 main() { return f(); }";
+    assert!(type_errors(src).contains("type error: `return;` needs a value: `f` returns `i32`"));
+}
+
+#[test]
+fn void_functions_return_no_value() {
+    let src = "void f() { return 1; } i32 main() { f(); return 0; }";
+    assert!(type_errors(src).contains("type error: `void` function `f` cannot return a value"));
+    let src = "void f() { print(1); return; } void g() { } i32 main() { f(); g(); return 0; }";
+    let out = common::cosplae(&["--run"], src);
+    assert_eq!(out.status.code(), Some(0), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn void_calls_have_no_value() {
+    let src = "void g() { } i32 main() { i32 x = g(); if (g() && 1) { } print(g()); return g() + 1; }";
+    let stderr = type_errors(src);
+    assert_eq!(stderr.matches("type error: `g` returns `void`, so its call has no value").count(), 4, "{stderr}");
+}
+
+#[test]
+fn struct_passed_for_an_i32_parameter() {
+    let src = format!("{POINT} i32 f(i32 a, i32 b) {{ return a; }} i32 main() {{ Point p; return f(1, p); }}");
+    assert!(type_errors(&src).contains("type error: argument 2 of `f` must be `i32`, got `Point`"));
+}
+
+#[test]
+fn initializer_values_must_fit_their_fields() {
+    let src = format!("{POINT} i32 main() {{ Point p; Point q = {{ 1, p }}; return q.x; }}");
+    assert!(type_errors(&src).contains("type error: cannot assign `Point` to `q.y` of type `i32`"));
+}
+
+#[test]
+fn effect_handlers_match_their_declaration() {
+    let src = "effect i32 ask(i32); effect void log(i32); i32 ask(i32 n, i32 m) { return n; } i32 log(i32 v) { return v; } \
+               i32 main() { perform log(1); i32 x = perform log(2); return perform ask(1, 2); }";
+    let stderr = type_errors(src);
+    assert!(stderr.contains("type error: the handler of effect `ask` must be declared as the effect is: `i32 ask(i32)`"), "{stderr}");
+    assert!(stderr.contains("type error: the handler of effect `log` must be declared as the effect is: `void log(i32)`"), "{stderr}");
+    let src = "effect void log(i32); void log(i32 v) { } i32 main() { return perform log(1); }";
+    assert!(type_errors(src).contains("type error: `log` returns `void`, so its call has no value"));
+}
+
+// `perform` of an undeclared effect passes and gets i32s, so a `with`
+// handler deals in them; a declared effect keeps the function of its name.
+#[test]
+fn with_handlers_take_and_return_i32() {
+    let src = "i32 main() { handle { return perform get(1); } with { i64 get(i32 n) { return n; } i32 put(i64 v) { return 0; } } }";
+    let stderr = type_errors(src);
+    assert!(stderr.contains("type error: a `with` handler of `get` takes and returns `i32`: `i32 get(i32)`"), "{stderr}");
+    assert!(stderr.contains("type error: a `with` handler of `put` takes and returns `i32`: `i32 put(i32)`"), "{stderr}");
+    let src = "effect i32 ask(); i32 ask() { return 1; } i32 main() { handle { return perform ask(); } with { i32 ask() { return 2; } } }";
+    assert!(type_errors(src).contains("type error: effect `ask` is declared, so the function `ask` handles it; a `with` cannot"));
+}
+
+#[test]
+fn defaults_are_checked() {
+    let src = "void g() { }\ni32 f(i32 y = g()) { return y; }\ni32 main() { return f(); }";
+    let stderr = type_errors(src);
+    assert!(stderr.contains("type error: `g` returns `void`, so its call has no value"), "{stderr}");
+    assert!(type_errors("i32 f(i32 y = -\"s\") { return y; }\ni32 main() { return f(); }")
+        .contains("string literals can only be arguments of `print`"));
+}
+
+// `typeof` folds to a string, which only `print` takes; what it names must
+// still resolve.
+#[test]
+fn typeof_is_only_a_print_argument() {
+    let stderr = type_errors("i32 main() { i32 t = typeof(1); return t; }");
+    assert!(stderr.contains("type error: `typeof` is a string, so it can only be an argument of `print`"), "{stderr}");
+    let out = common::cosplae(&["--run"], "i32 main() { print(typeof(nope)); return 0; }");
+    assert!(String::from_utf8_lossy(&out.stderr).contains("error: use of undeclared variable `nope`"));
+}
+
+#[test]
+fn const_array_element_count() {
+    let stderr = type_errors("const i32[3] primes = { 2, 3 }; i32 main() { return primes[0]; }");
+    assert!(stderr.contains("type error: `primes` has 3 element(s) but its initializer gives 2"), "{stderr}");
+    let stderr = type_errors("const i32[2] a = { 1, 2 }; i32 main() { return a; }");
+    assert!(stderr.contains("type error: `main` returns `i32`, but this returns `i32[]`"), "{stderr}");
+}
@@ -0,0 +1,153 @@
+// Exercises the VM's stack primitives directly on hand-written IR.
+#[path = "../src/span.rs"]
+mod span;
+#[path = "../src/ir.rs"]
+#[allow(dead_code)]
+mod ir;
+#[path = "../src/vm.rs"]
+#[allow(dead_code)]
+mod vm;
+
+use ir::{Func, Instr, ProgramIR, VerifyError};
+use vm::{StepResult, VmError, VmState};
+
+fn program(code: Vec<Instr>) -> ProgramIR {
+    let func = Func {
+        name: "main".to_string(),
+        max_stack: ir::max_stack_depth(&code),
+        code,
+        n_locals: 0,
+        n_params: 0,
+        locals_dbg: Vec::new(),
+        spans: Vec::new(),
+    };
+    ProgramIR { funcs: vec![func], globals: Vec::new(), rodata: Vec::new() }
+}
+
+// `add(a, b)`, leaving a stray value under its result.
+fn add() -> Func {
+    let code = vec![Instr::PushI32(99), Instr::Load(0), Instr::Load(1), Instr::Add, Instr::Ret];
+    Func {
+        name: "add".to_string(),
+        max_stack: ir::max_stack_depth(&code),
+        code,
+        n_locals: 2,
+        n_params: 2,
+        locals_dbg: Vec::new(),
+        spans: Vec::new(),
+    }
+}
+
+// The operand stack after running every instruction but the final Ret.
+fn stack_before_ret(code: Vec<Instr>) -> Vec<i64> {
+    let prog = program(code);
+    let mut state = VmState::new(&prog).unwrap();
+    while !matches!(prog.funcs[0].code[state.ip], Instr::Ret) {
+        assert_eq!(state.step(), StepResult::Continue);
+    }
+    state.stack
+}
+
+// Each step runs one instruction; the state in between is visible.
+#[test]
+fn stepping_shows_each_state() {
+    let prog = program(vec![Instr::PushI32(6), Instr::Neg, Instr::Ret]);
+    let mut state = VmState::new(&prog).unwrap();
+    assert_eq!((state.ip, state.stack.clone()), (0, vec![]));
+    assert_eq!(state.step(), StepResult::Continue);
+    assert_eq!((state.ip, state.stack.clone()), (1, vec![6]));
+    assert_eq!(state.step(), StepResult::Continue);
+    assert_eq!((state.ip, state.stack.clone()), (2, vec![-6]));
+    assert_eq!(state.step(), StepResult::Halted(-6));
+    assert_eq!(state.steps, 3);
+}
+
+#[test]
+fn dup_copies_the_top() {
+    let stack = stack_before_ret(vec![Instr::PushI32(1), Instr::PushI32(7), Instr::Dup, Instr::Ret]);
+    assert_eq!(stack, [1, 7, 7]);
+}
+
+#[test]
+fn swap_exchanges_the_top_two() {
+    let stack = stack_before_ret(vec![
+        Instr::PushI32(1), Instr::PushI32(2), Instr::PushI32(3), Instr::Swap, Instr::Ret,
+    ]);
+    assert_eq!(stack, [1, 3, 2]);
+    // `a - b` with the operands pushed the other way round
+    let prog = program(vec![Instr::PushI32(2), Instr::PushI32(10), Instr::Swap, Instr::Sub, Instr::Ret]);
+    assert_eq!(vm::VM::run(&prog), Ok(8));
+}
+
+// Code that would pop an empty stack, meet a label at two depths or outgrow
+// its recorded max stack is refused before any of it runs.
+#[test]
+fn unverifiable_code_is_rejected() {
+    let invalid = |e| Err(VmError::Invalid(e));
+    let underflow = |at| VerifyError::Underflow { func: "main".into(), at };
+    assert_eq!(vm::VM::run(&program(vec![Instr::Dup, Instr::Ret])), invalid(underflow(0)));
+    let prog = program(vec![Instr::PushI32(1), Instr::Swap, Instr::Ret]);
+    assert_eq!(vm::VM::run(&prog), invalid(underflow(1)));
+    // `if (1) { push 2 }` falls into L1 with one value more than the jump
+    let prog = program(vec![
+        Instr::PushI32(1), Instr::JumpIfZero(1), Instr::PushI32(2), Instr::Label(1), Instr::PushI32(0), Instr::Ret,
+    ]);
+    let unbalanced = VerifyError::Unbalanced { func: "main".into(), label: 1, depths: (0, 1) };
+    assert_eq!(vm::VM::run(&prog), invalid(unbalanced));
+    let mut prog = program(vec![Instr::PushI32(1), Instr::PushI32(2), Instr::Add, Instr::Ret]);
+    prog.funcs[0].max_stack = 1;
+    let short = VerifyError::MaxStack { func: "main".into(), recorded: 1, needed: 2 };
+    assert_eq!(vm::VM::run(&prog), invalid(short));
+}
+
+// A bare `return;` pops nothing and returns 0.
+#[test]
+fn bare_return_verifies() {
+    assert_eq!(vm::VM::run(&program(vec![Instr::Ret])), Ok(0));
+}
+
+// The operand stack starts with room for main's recorded max stack.
+#[test]
+fn stack_is_sized_from_max_stack() {
+    let prog = program(vec![Instr::PushI32(1), Instr::PushI32(2), Instr::PushI32(3), Instr::Add, Instr::Add, Instr::Ret]);
+    assert_eq!(prog.funcs[0].max_stack, 3);
+    assert!(VmState::new(&prog).unwrap().stack.capacity() >= 3);
+}
+
+// The caller's pending operands survive the call, and the callee's leftovers
+// don't leak into them: `7 + add(add(1, 2), 3) * 2`.
+#[test]
+fn a_call_leaves_exactly_its_result_on_the_callers_stack() {
+    let mut prog = program(vec![
+        Instr::PushI32(7),
+        Instr::PushI32(1), Instr::PushI32(2), Instr::Call(1, 2),
+        Instr::PushI32(3), Instr::Call(1, 2),
+        Instr::PushI32(2), Instr::Mul, Instr::Add, Instr::Ret,
+    ]);
+    prog.funcs.push(add());
+    assert_eq!(vm::VM::run(&prog), Ok(19));
+}
+
+// A function that calls itself forever stops at `max_depth` nested calls.
+#[test]
+fn unbounded_recursion_overflows() {
+    let mut prog = program(vec![Instr::Call(1, 0), Instr::Ret]);
+    let code = vec![Instr::Call(1, 0), Instr::Ret];
+    prog.funcs.push(Func { name: "forever".to_string(), max_stack: 1, code, n_locals: 0, n_params: 0, locals_dbg: Vec::new(), spans: Vec::new() });
+    let mut state = VmState::new(&prog).unwrap();
+    state.max_depth = 5;
+    let mut calls = 0;
+    loop {
+        match state.step() {
+            StepResult::Continue => calls += 1,
+            result => {
+                let overflow = VmError::StackOverflow { func: "forever".into(), depth: 5 };
+                assert_eq!(result, StepResult::Error(overflow));
+                break;
+            }
+        }
+    }
+    assert_eq!(calls, 5);
+    let overflow = VmError::StackOverflow { func: "forever".into(), depth: vm::DEFAULT_MAX_DEPTH };
+    assert_eq!(vm::VM::run(&prog), Err(overflow));
+}
@@ -0,0 +1,22 @@
+mod common;
+
+// `--vm-trace` prints each instruction to stderr before it runs, with the
+// operand stack and locals it sees; stdout is left to the program.
+#[test]
+fn traces_each_instruction() {
+    let out = common::cosplae(&["--run", "--vm-trace"], "i32 main() { i32 x = 10; return x; }");
+    assert_eq!(out.status.code(), Some(10));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines[0], "main:0: PushI32(10)  stack=[] locals=[0]");
+    assert_eq!(lines[1], "main:1: Store(0)  stack=[10] locals=[0]");
+    assert_eq!(lines[2], "main:2: Load(0)  stack=[] locals=[10]");
+}
+
+#[test]
+fn silent_without_the_flag() {
+    let out = common::cosplae(&["--run"], "i32 main() { i32 x = 10; return x; }");
+    assert_eq!(out.status.code(), Some(10));
+    assert!(out.stderr.is_empty(), "{}", String::from_utf8_lossy(&out.stderr));
+}